@@ -0,0 +1,18 @@
+CREATE TABLE UploadSession(
+    "upload_id" UUID DEFAULT gen_random_uuid() NOT NULL,
+    "user_id" UUID NOT NULL,
+    "bucket_id" UUID NOT NULL,
+    "file_name" VARCHAR(255) NOT NULL,
+    "upload_length" BIGINT NOT NULL,
+    "upload_offset" BIGINT DEFAULT 0 NOT NULL,
+    "created_date" TIMESTAMP WITHOUT TIME ZONE DEFAULT now() NOT NULL,
+    "expires_date" TIMESTAMP WITHOUT TIME ZONE NOT NULL
+);
+ALTER TABLE
+    UploadSession ADD PRIMARY KEY("upload_id");
+CREATE INDEX "uploadsession_expires_date_index" ON
+    UploadSession("expires_date");
+ALTER TABLE
+    UploadSession ADD CONSTRAINT "uploadsession_user_id_foreign" FOREIGN KEY("user_id") REFERENCES UserInfo("user_id");
+ALTER TABLE
+    UploadSession ADD CONSTRAINT "uploadsession_bucket_id_foreign" FOREIGN KEY("bucket_id") REFERENCES Bucket("bucket_id");
@@ -0,0 +1,6 @@
+ALTER TABLE
+    FileMetadata ALTER COLUMN "file_type" TYPE VARCHAR(255);
+ALTER TABLE
+    FileMetadata DROP CONSTRAINT "filemetadata_file_id_foreign";
+ALTER TABLE
+    FileMetadata ADD CONSTRAINT "filemetadata_file_id_foreign" FOREIGN KEY("file_id") REFERENCES UserFile("file_id") ON DELETE CASCADE;
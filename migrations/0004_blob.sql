@@ -0,0 +1,20 @@
+CREATE TABLE Blob(
+    "file_hash" VARCHAR(255) NOT NULL,
+    "blob_size" BIGINT NOT NULL,
+    "ref_count" INTEGER DEFAULT 1 NOT NULL,
+    "created_date" TIMESTAMP WITHOUT TIME ZONE DEFAULT now() NOT NULL
+);
+ALTER TABLE
+    Blob ADD PRIMARY KEY("file_hash");
+INSERT INTO
+    Blob ("file_hash", "blob_size", "ref_count")
+SELECT
+    "file_hash", MAX("file_size"), COUNT(*)
+FROM
+    FileMetadata
+GROUP BY
+    "file_hash";
+CREATE INDEX "filemetadata_file_hash_index" ON
+    FileMetadata("file_hash");
+ALTER TABLE
+    FileMetadata ADD CONSTRAINT "filemetadata_file_hash_foreign" FOREIGN KEY("file_hash") REFERENCES Blob("file_hash");
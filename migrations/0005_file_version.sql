@@ -0,0 +1,39 @@
+ALTER TABLE
+    FileMetadata ADD COLUMN "version_number" INTEGER DEFAULT 1 NOT NULL;
+ALTER TABLE
+    FileMetadata ADD COLUMN "uploaded_by" UUID NULL;
+UPDATE
+    FileMetadata fm SET "uploaded_by" = uf."user_id"
+FROM
+    UserFile uf
+WHERE
+    uf."file_id" = fm."file_id";
+ALTER TABLE
+    FileMetadata ADD CONSTRAINT "filemetadata_uploaded_by_foreign" FOREIGN KEY("uploaded_by") REFERENCES UserInfo("user_id") ON DELETE SET NULL;
+CREATE TABLE FileVersion(
+    "version_id" UUID DEFAULT gen_random_uuid() NOT NULL,
+    "file_id" UUID NOT NULL,
+    "version_number" INTEGER NOT NULL,
+    "file_name" VARCHAR(255) NOT NULL,
+    "file_size" BIGINT NOT NULL,
+    "file_type" VARCHAR(255) NOT NULL,
+    "file_hash" VARCHAR(255) NOT NULL,
+    "uploaded_by" UUID NULL,
+    "created_date" TIMESTAMP WITHOUT TIME ZONE DEFAULT now() NOT NULL
+);
+ALTER TABLE
+    FileVersion ADD PRIMARY KEY("version_id");
+CREATE INDEX "fileversion_file_id_index" ON
+    FileVersion("file_id");
+ALTER TABLE
+    FileVersion ADD CONSTRAINT "fileversion_file_id_version_number_unique" UNIQUE("file_id", "version_number");
+ALTER TABLE
+    FileVersion ADD CONSTRAINT "fileversion_file_id_foreign" FOREIGN KEY("file_id") REFERENCES UserFile("file_id") ON DELETE CASCADE;
+ALTER TABLE
+    FileVersion ADD CONSTRAINT "fileversion_file_hash_foreign" FOREIGN KEY("file_hash") REFERENCES Blob("file_hash");
+ALTER TABLE
+    FileVersion ADD CONSTRAINT "fileversion_uploaded_by_foreign" FOREIGN KEY("uploaded_by") REFERENCES UserInfo("user_id") ON DELETE SET NULL;
+ALTER TABLE
+    Bucket ADD COLUMN "max_versions" INTEGER DEFAULT 10 NOT NULL;
+ALTER TABLE
+    Bucket ADD COLUMN "version_retention_days" INTEGER NULL;
@@ -0,0 +1,4 @@
+ALTER TABLE
+    UserFile ADD COLUMN "deleted_date" TIMESTAMP WITHOUT TIME ZONE NULL;
+CREATE INDEX "userfile_deleted_date_index" ON
+    UserFile("deleted_date");
@@ -0,0 +1,27 @@
+CREATE TABLE Folder(
+    "folder_id" UUID DEFAULT gen_random_uuid() NOT NULL,
+    "bucket_id" UUID NOT NULL,
+    "parent_id" UUID NULL,
+    "folder_name" VARCHAR(255) NOT NULL,
+    "created_date" TIMESTAMP WITHOUT TIME ZONE DEFAULT now() NOT NULL
+);
+ALTER TABLE
+    Folder ADD PRIMARY KEY("folder_id");
+CREATE INDEX "folder_parent_id_index" ON
+    Folder("parent_id");
+CREATE UNIQUE INDEX "folder_bucket_id_parent_id_folder_name_unique" ON
+    Folder("bucket_id", COALESCE("parent_id", '00000000-0000-0000-0000-000000000000'), "folder_name");
+ALTER TABLE
+    Folder ADD CONSTRAINT "folder_bucket_id_foreign" FOREIGN KEY("bucket_id") REFERENCES Bucket("bucket_id") ON DELETE CASCADE;
+ALTER TABLE
+    Folder ADD CONSTRAINT "folder_parent_id_foreign" FOREIGN KEY("parent_id") REFERENCES Folder("folder_id") ON DELETE CASCADE;
+ALTER TABLE
+    UserFile ADD COLUMN "folder_id" UUID NULL;
+CREATE INDEX "userfile_folder_id_index" ON
+    UserFile("folder_id");
+ALTER TABLE
+    UserFile ADD CONSTRAINT "userfile_folder_id_foreign" FOREIGN KEY("folder_id") REFERENCES Folder("folder_id") ON DELETE SET NULL;
+ALTER TABLE
+    UploadSession ADD COLUMN "folder_id" UUID NULL;
+ALTER TABLE
+    UploadSession ADD CONSTRAINT "uploadsession_folder_id_foreign" FOREIGN KEY("folder_id") REFERENCES Folder("folder_id") ON DELETE SET NULL;
@@ -0,0 +1,10 @@
+ALTER TABLE
+    "FileUsers" DROP CONSTRAINT "fileusers_file_id_foreign";
+ALTER TABLE
+    "FileUsers" ADD CONSTRAINT "fileusers_file_id_foreign" FOREIGN KEY("file_id") REFERENCES UserFile("file_id") ON DELETE CASCADE;
+ALTER TABLE
+    "FileUsers" DROP CONSTRAINT "fileusers_user_id_foreign";
+ALTER TABLE
+    "FileUsers" ADD CONSTRAINT "fileusers_user_id_foreign" FOREIGN KEY("user_id") REFERENCES UserInfo("user_id") ON DELETE CASCADE;
+CREATE INDEX "fileusers_user_id_index" ON
+    "FileUsers"("user_id");
@@ -0,0 +1,10 @@
+ALTER TABLE
+    BucketUsers DROP CONSTRAINT "bucketusers_bucket_id_foreign";
+ALTER TABLE
+    BucketUsers ADD CONSTRAINT "bucketusers_bucket_id_foreign" FOREIGN KEY("bucket_id") REFERENCES Bucket("bucket_id") ON DELETE CASCADE;
+ALTER TABLE
+    BucketUsers DROP CONSTRAINT "bucketusers_user_id_foreign";
+ALTER TABLE
+    BucketUsers ADD CONSTRAINT "bucketusers_user_id_foreign" FOREIGN KEY("user_id") REFERENCES UserInfo("user_id") ON DELETE CASCADE;
+CREATE INDEX "bucketusers_user_id_index" ON
+    BucketUsers("user_id");
@@ -0,0 +1,21 @@
+CREATE TABLE PublicLink(
+    "link_id" UUID DEFAULT gen_random_uuid() NOT NULL,
+    "file_id" UUID NOT NULL,
+    "token" VARCHAR(64) NOT NULL,
+    "created_by" UUID NULL,
+    "password_hash" VARCHAR(255) NULL,
+    "expires_date" TIMESTAMP WITHOUT TIME ZONE NULL,
+    "max_downloads" INTEGER NULL,
+    "download_count" INTEGER DEFAULT 0 NOT NULL,
+    "created_date" TIMESTAMP WITHOUT TIME ZONE DEFAULT now() NOT NULL
+);
+ALTER TABLE
+    PublicLink ADD PRIMARY KEY("link_id");
+ALTER TABLE
+    PublicLink ADD CONSTRAINT "publiclink_token_unique" UNIQUE("token");
+CREATE INDEX "publiclink_file_id_index" ON
+    PublicLink("file_id");
+ALTER TABLE
+    PublicLink ADD CONSTRAINT "publiclink_file_id_foreign" FOREIGN KEY("file_id") REFERENCES UserFile("file_id") ON DELETE CASCADE;
+ALTER TABLE
+    PublicLink ADD CONSTRAINT "publiclink_created_by_foreign" FOREIGN KEY("created_by") REFERENCES UserInfo("user_id") ON DELETE SET NULL;
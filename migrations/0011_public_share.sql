@@ -0,0 +1,22 @@
+CREATE TABLE PublicShare(
+    "share_id" UUID DEFAULT gen_random_uuid() NOT NULL,
+    "bucket_id" UUID NOT NULL,
+    "folder_id" UUID NULL,
+    "token" VARCHAR(64) NOT NULL,
+    "created_by" UUID NULL,
+    "password_hash" VARCHAR(255) NULL,
+    "expires_date" TIMESTAMP WITHOUT TIME ZONE NULL,
+    "created_date" TIMESTAMP WITHOUT TIME ZONE DEFAULT now() NOT NULL
+);
+ALTER TABLE
+    PublicShare ADD PRIMARY KEY("share_id");
+ALTER TABLE
+    PublicShare ADD CONSTRAINT "publicshare_token_unique" UNIQUE("token");
+CREATE INDEX "publicshare_bucket_id_index" ON
+    PublicShare("bucket_id");
+ALTER TABLE
+    PublicShare ADD CONSTRAINT "publicshare_bucket_id_foreign" FOREIGN KEY("bucket_id") REFERENCES Bucket("bucket_id") ON DELETE CASCADE;
+ALTER TABLE
+    PublicShare ADD CONSTRAINT "publicshare_folder_id_foreign" FOREIGN KEY("folder_id") REFERENCES Folder("folder_id") ON DELETE CASCADE;
+ALTER TABLE
+    PublicShare ADD CONSTRAINT "publicshare_created_by_foreign" FOREIGN KEY("created_by") REFERENCES UserInfo("user_id") ON DELETE SET NULL;
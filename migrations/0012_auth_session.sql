@@ -0,0 +1,34 @@
+CREATE TABLE AuthSession(
+    "session_id" UUID DEFAULT gen_random_uuid() NOT NULL,
+    "user_id" UUID NOT NULL,
+    "created_date" TIMESTAMP WITHOUT TIME ZONE DEFAULT now() NOT NULL,
+    "revoked_date" TIMESTAMP WITHOUT TIME ZONE NULL
+);
+ALTER TABLE
+    AuthSession ADD PRIMARY KEY("session_id");
+CREATE INDEX "authsession_user_id_index" ON
+    AuthSession("user_id");
+ALTER TABLE
+    AuthSession ADD CONSTRAINT "authsession_user_id_foreign" FOREIGN KEY("user_id") REFERENCES UserInfo("user_id") ON DELETE CASCADE;
+CREATE TABLE RefreshToken(
+    "token_id" UUID DEFAULT gen_random_uuid() NOT NULL,
+    "session_id" UUID NOT NULL,
+    "token_hash" VARCHAR(64) NOT NULL,
+    "expires_date" TIMESTAMP WITHOUT TIME ZONE NOT NULL,
+    "used_date" TIMESTAMP WITHOUT TIME ZONE NULL,
+    "created_date" TIMESTAMP WITHOUT TIME ZONE DEFAULT now() NOT NULL
+);
+ALTER TABLE
+    RefreshToken ADD PRIMARY KEY("token_id");
+ALTER TABLE
+    RefreshToken ADD CONSTRAINT "refreshtoken_token_hash_unique" UNIQUE("token_hash");
+CREATE INDEX "refreshtoken_session_id_index" ON
+    RefreshToken("session_id");
+ALTER TABLE
+    RefreshToken ADD CONSTRAINT "refreshtoken_session_id_foreign" FOREIGN KEY("session_id") REFERENCES AuthSession("session_id") ON DELETE CASCADE;
+CREATE TABLE RevokedToken(
+    "jti" UUID NOT NULL,
+    "expires_date" TIMESTAMP WITHOUT TIME ZONE NOT NULL
+);
+ALTER TABLE
+    RevokedToken ADD PRIMARY KEY("jti");
@@ -0,0 +1,16 @@
+CREATE TABLE PasswordReset(
+    "reset_id" UUID DEFAULT gen_random_uuid() NOT NULL,
+    "user_id" UUID NOT NULL,
+    "token_hash" VARCHAR(64) NOT NULL,
+    "expires_date" TIMESTAMP WITHOUT TIME ZONE NOT NULL,
+    "used_date" TIMESTAMP WITHOUT TIME ZONE NULL,
+    "created_date" TIMESTAMP WITHOUT TIME ZONE DEFAULT now() NOT NULL
+);
+ALTER TABLE
+    PasswordReset ADD PRIMARY KEY("reset_id");
+ALTER TABLE
+    PasswordReset ADD CONSTRAINT "passwordreset_token_hash_unique" UNIQUE("token_hash");
+CREATE INDEX "passwordreset_user_id_index" ON
+    PasswordReset("user_id");
+ALTER TABLE
+    PasswordReset ADD CONSTRAINT "passwordreset_user_id_foreign" FOREIGN KEY("user_id") REFERENCES UserInfo("user_id") ON DELETE CASCADE;
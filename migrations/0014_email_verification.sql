@@ -0,0 +1,37 @@
+ALTER TABLE
+    UserInfo ADD "email_verified_date" TIMESTAMP WITHOUT TIME ZONE NULL;
+-- accounts made before verification existed are trusted as they are
+UPDATE
+    UserInfo SET "email_verified_date" = "created_date";
+-- emails are unique regardless of case, accounts that only differ in the
+-- case of their email have to be merged or renamed by hand first
+DO $$
+DECLARE
+    duplicate_emails TEXT;
+BEGIN
+    SELECT
+        string_agg(email, ', ') INTO duplicate_emails
+    FROM
+        (SELECT lower("email") AS email FROM UserInfo GROUP BY lower("email") HAVING count(*) > 1) AS duplicate;
+    IF duplicate_emails IS NOT NULL THEN
+        RAISE EXCEPTION 'more than one account uses each of these emails, ignoring case: %. Change the email of all but one of them and restart.', duplicate_emails;
+    END IF;
+END $$;
+CREATE UNIQUE INDEX "userinfo_email_unique" ON
+    UserInfo(lower("email"));
+CREATE TABLE EmailVerification(
+    "verification_id" UUID DEFAULT gen_random_uuid() NOT NULL,
+    "user_id" UUID NOT NULL,
+    "token_hash" VARCHAR(64) NOT NULL,
+    "expires_date" TIMESTAMP WITHOUT TIME ZONE NOT NULL,
+    "used_date" TIMESTAMP WITHOUT TIME ZONE NULL,
+    "created_date" TIMESTAMP WITHOUT TIME ZONE DEFAULT now() NOT NULL
+);
+ALTER TABLE
+    EmailVerification ADD PRIMARY KEY("verification_id");
+ALTER TABLE
+    EmailVerification ADD CONSTRAINT "emailverification_token_hash_unique" UNIQUE("token_hash");
+CREATE INDEX "emailverification_user_id_index" ON
+    EmailVerification("user_id");
+ALTER TABLE
+    EmailVerification ADD CONSTRAINT "emailverification_user_id_foreign" FOREIGN KEY("user_id") REFERENCES UserInfo("user_id") ON DELETE CASCADE;
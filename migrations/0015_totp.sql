@@ -0,0 +1,39 @@
+CREATE TABLE UserTotp(
+    "user_id" UUID NOT NULL,
+    "secret" VARCHAR(64) NOT NULL,
+    "last_used_step" BIGINT NULL,
+    "enabled_date" TIMESTAMP WITHOUT TIME ZONE NULL,
+    "created_date" TIMESTAMP WITHOUT TIME ZONE DEFAULT now() NOT NULL
+);
+ALTER TABLE
+    UserTotp ADD PRIMARY KEY("user_id");
+ALTER TABLE
+    UserTotp ADD CONSTRAINT "usertotp_user_id_foreign" FOREIGN KEY("user_id") REFERENCES UserInfo("user_id") ON DELETE CASCADE;
+CREATE TABLE RecoveryCode(
+    "code_id" UUID DEFAULT gen_random_uuid() NOT NULL,
+    "user_id" UUID NOT NULL,
+    "code_hash" VARCHAR(64) NOT NULL,
+    "used_date" TIMESTAMP WITHOUT TIME ZONE NULL,
+    "created_date" TIMESTAMP WITHOUT TIME ZONE DEFAULT now() NOT NULL
+);
+ALTER TABLE
+    RecoveryCode ADD PRIMARY KEY("code_id");
+CREATE INDEX "recoverycode_user_id_index" ON
+    RecoveryCode("user_id");
+ALTER TABLE
+    RecoveryCode ADD CONSTRAINT "recoverycode_user_id_foreign" FOREIGN KEY("user_id") REFERENCES UserInfo("user_id") ON DELETE CASCADE;
+CREATE TABLE LoginChallenge(
+    "challenge_id" UUID DEFAULT gen_random_uuid() NOT NULL,
+    "user_id" UUID NOT NULL,
+    "token_hash" VARCHAR(64) NOT NULL,
+    "attempts" INTEGER DEFAULT 0 NOT NULL,
+    "expires_date" TIMESTAMP WITHOUT TIME ZONE NOT NULL,
+    "used_date" TIMESTAMP WITHOUT TIME ZONE NULL,
+    "created_date" TIMESTAMP WITHOUT TIME ZONE DEFAULT now() NOT NULL
+);
+ALTER TABLE
+    LoginChallenge ADD PRIMARY KEY("challenge_id");
+ALTER TABLE
+    LoginChallenge ADD CONSTRAINT "loginchallenge_token_hash_unique" UNIQUE("token_hash");
+ALTER TABLE
+    LoginChallenge ADD CONSTRAINT "loginchallenge_user_id_foreign" FOREIGN KEY("user_id") REFERENCES UserInfo("user_id") ON DELETE CASCADE;
@@ -0,0 +1,21 @@
+CREATE TABLE ApiKey(
+    "key_id" UUID DEFAULT gen_random_uuid() NOT NULL,
+    "user_id" UUID NOT NULL,
+    "key_name" VARCHAR(255) NOT NULL,
+    "key_prefix" VARCHAR(16) NOT NULL,
+    "key_hash" VARCHAR(64) NOT NULL,
+    "scopes" VARCHAR(32)[] NOT NULL,
+    -- NULL lets the key use every bucket the user can
+    "bucket_ids" UUID[] NULL,
+    "expires_date" TIMESTAMP WITHOUT TIME ZONE NULL,
+    "last_used_date" TIMESTAMP WITHOUT TIME ZONE NULL,
+    "created_date" TIMESTAMP WITHOUT TIME ZONE DEFAULT now() NOT NULL
+);
+ALTER TABLE
+    ApiKey ADD PRIMARY KEY("key_id");
+ALTER TABLE
+    ApiKey ADD CONSTRAINT "apikey_key_hash_unique" UNIQUE("key_hash");
+CREATE INDEX "apikey_user_id_index" ON
+    ApiKey("user_id");
+ALTER TABLE
+    ApiKey ADD CONSTRAINT "apikey_user_id_foreign" FOREIGN KEY("user_id") REFERENCES UserInfo("user_id") ON DELETE CASCADE;
@@ -0,0 +1,15 @@
+ALTER TABLE
+    UserInfo ADD COLUMN "role" VARCHAR(16) DEFAULT 'user' NOT NULL;
+ALTER TABLE
+    UserInfo ADD COLUMN "disabled_date" TIMESTAMP WITHOUT TIME ZONE NULL;
+ALTER TABLE
+    UserInfo ADD CONSTRAINT "userinfo_role_check" CHECK("role" IN ('user', 'admin'));
+-- the oldest account of an existing install becomes its admin
+UPDATE
+    UserInfo SET "role" = 'admin' WHERE "user_id" = (
+        SELECT "user_id" FROM UserInfo ORDER BY "created_date" LIMIT 1
+    );
+ALTER TABLE
+    AuthSession ADD COLUMN "impersonated_by" UUID NULL;
+ALTER TABLE
+    AuthSession ADD CONSTRAINT "authsession_impersonated_by_foreign" FOREIGN KEY("impersonated_by") REFERENCES UserInfo("user_id") ON DELETE CASCADE;
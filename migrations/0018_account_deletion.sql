@@ -0,0 +1,18 @@
+-- no foreign key on user_id, the row outlives the account so the
+-- deletion can be followed up on
+CREATE TABLE AccountDeletion(
+    "user_id" UUID NOT NULL,
+    "transfer_to" UUID NULL,
+    "scheduled_date" TIMESTAMP WITHOUT TIME ZONE NOT NULL,
+    "started_date" TIMESTAMP WITHOUT TIME ZONE NULL,
+    "completed_date" TIMESTAMP WITHOUT TIME ZONE NULL,
+    "attempts" INTEGER DEFAULT 0 NOT NULL,
+    "last_error" TEXT NULL,
+    "created_date" TIMESTAMP WITHOUT TIME ZONE DEFAULT now() NOT NULL
+);
+ALTER TABLE
+    AccountDeletion ADD PRIMARY KEY("user_id");
+CREATE INDEX "accountdeletion_scheduled_date_index" ON
+    AccountDeletion("scheduled_date");
+ALTER TABLE
+    AccountDeletion ADD CONSTRAINT "accountdeletion_transfer_to_foreign" FOREIGN KEY("transfer_to") REFERENCES UserInfo("user_id") ON DELETE SET NULL;
@@ -0,0 +1,18 @@
+CREATE TABLE Invite(
+    "invite_id" UUID DEFAULT gen_random_uuid() NOT NULL,
+    "code_prefix" VARCHAR(16) NOT NULL,
+    "code_hash" VARCHAR(64) NOT NULL,
+    "max_uses" INTEGER DEFAULT 1 NOT NULL,
+    "use_count" INTEGER DEFAULT 0 NOT NULL,
+    -- quota of the first bucket of invited users, NULL keeps the default
+    "max_bucket_size" BIGINT NULL,
+    "expires_date" TIMESTAMP WITHOUT TIME ZONE NOT NULL,
+    "created_by" UUID NULL,
+    "created_date" TIMESTAMP WITHOUT TIME ZONE DEFAULT now() NOT NULL
+);
+ALTER TABLE
+    Invite ADD PRIMARY KEY("invite_id");
+ALTER TABLE
+    Invite ADD CONSTRAINT "invite_code_hash_unique" UNIQUE("code_hash");
+ALTER TABLE
+    Invite ADD CONSTRAINT "invite_created_by_foreign" FOREIGN KEY("created_by") REFERENCES UserInfo("user_id") ON DELETE SET NULL;
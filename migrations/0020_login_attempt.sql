@@ -0,0 +1,26 @@
+-- failed logins per account (by email) and per IP address
+CREATE TABLE LoginThrottle(
+    "throttle_key" VARCHAR(320) NOT NULL,
+    "failures" INTEGER DEFAULT 0 NOT NULL,
+    "locked_until" TIMESTAMP WITHOUT TIME ZONE NULL,
+    "last_failure_date" TIMESTAMP WITHOUT TIME ZONE NOT NULL
+);
+ALTER TABLE
+    LoginThrottle ADD PRIMARY KEY("throttle_key");
+CREATE TABLE LoginAttempt(
+    "attempt_id" UUID DEFAULT gen_random_uuid() NOT NULL,
+    "email" VARCHAR(255) NOT NULL,
+    -- NULL when the email is unknown
+    "user_id" UUID NULL,
+    "ip_address" VARCHAR(64) NOT NULL,
+    "result" VARCHAR(16) NOT NULL,
+    "created_date" TIMESTAMP WITHOUT TIME ZONE DEFAULT now() NOT NULL
+);
+ALTER TABLE
+    LoginAttempt ADD PRIMARY KEY("attempt_id");
+CREATE INDEX "loginattempt_user_id_index" ON
+    LoginAttempt("user_id");
+CREATE INDEX "loginattempt_created_date_index" ON
+    LoginAttempt("created_date");
+ALTER TABLE
+    LoginAttempt ADD CONSTRAINT "loginattempt_user_id_foreign" FOREIGN KEY("user_id") REFERENCES UserInfo("user_id") ON DELETE SET NULL;
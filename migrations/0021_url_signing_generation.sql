@@ -0,0 +1,3 @@
+-- part of every pre-signed URL, bumping it invalidates the URLs of a user
+ALTER TABLE
+    UserInfo ADD COLUMN "url_signing_generation" INTEGER DEFAULT 0 NOT NULL;
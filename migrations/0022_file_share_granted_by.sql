@@ -0,0 +1,6 @@
+-- who shared the file, users who can only reshare may remove just their own
+-- grants. Grants made before this are left to the owner and the managers
+ALTER TABLE
+    "FileUsers" ADD "granted_by" UUID NULL;
+ALTER TABLE
+    "FileUsers" ADD CONSTRAINT "fileusers_granted_by_foreign" FOREIGN KEY("granted_by") REFERENCES UserInfo("user_id") ON DELETE SET NULL;
@@ -0,0 +1,4 @@
+-- folders of the upload path that don't exist yet, they are created when
+-- the finished upload is stored
+ALTER TABLE
+    UploadSession ADD "folder_path" TEXT NULL;
@@ -1,7 +1,18 @@
 use sqlx::PgPool;
+use std::sync::Arc;
+
+use crate::{models::invite::RegistrationMode, utility::mailer::Mailer};
 
 #[derive(Debug, Clone)]
 pub struct AppData {
     pub pg_conn: PgPool,
     pub data_path: String,
+    pub max_upload_size: Option<i64>,
+    pub upload_expiry_hours: i64,
+    pub trash_retention_days: i64,
+    pub refresh_token_days: i64,
+    pub account_deletion_grace_days: i64,
+    pub registration_mode: RegistrationMode,
+    pub trust_proxy_headers: bool,
+    pub mailer: Arc<dyn Mailer>,
 }
@@ -1,3 +1,12 @@
+pub mod admin;
+pub mod api_key;
 pub mod bucket;
+pub mod folder;
+pub mod public_link;
+pub mod public_share;
+pub mod signed_url;
+pub mod totp;
+pub mod trash;
+pub mod upload;
 pub mod user_file;
 pub mod user_info;
@@ -0,0 +1,286 @@
+use actix_web::{
+    delete, get, post,
+    web::{self, ReqData},
+    HttpResponse, Responder,
+};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    app_data::AppData,
+    models::{
+        admin::{
+            delete_user_account, impersonate_user, is_user_admin, reset_user_quota,
+            set_user_disabled, unlock_user_logins, AdminErrors,
+        },
+        invite::{create_invite, delete_invite, get_invites, InviteErrors, NewInvite},
+        login_attempt::{get_login_attempts, LoginAttemptQuery},
+        user_info::get_all_user_info,
+    },
+    utility::jwt_token::Claims,
+};
+
+pub fn admin_config(config: &mut web::ServiceConfig) {
+    let scope = web::scope("/admin")
+        .service(get_users)
+        .service(disable_user)
+        .service(enable_user)
+        .service(delete_user)
+        .service(reset_quota)
+        .service(impersonate)
+        .service(unlock_user)
+        .service(get_logins)
+        .service(get_all_invites)
+        .service(create_new_invite)
+        .service(remove_invite);
+
+    config.service(scope);
+}
+
+/// Stops a request from anyone but an admin, API keys can't be used for
+/// admin requests.
+pub async fn require_admin(data: &AppData, claims: &Claims) -> Option<HttpResponse> {
+    if claims.api_key.is_some() {
+        return Some(HttpResponse::Forbidden().body("API keys can't be used for admin requests."));
+    }
+
+    match is_user_admin(&data.pg_conn, &claims.id).await {
+        Ok(true) => None,
+        Ok(false) => Some(HttpResponse::Forbidden().finish()),
+        Err(error) => {
+            println!("error while checking the role of {}: {}", claims.id, error);
+            Some(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+fn admin_error_response(error: AdminErrors) -> HttpResponse {
+    match error {
+        AdminErrors::NotFound => HttpResponse::NotFound().finish(),
+        AdminErrors::OwnAccount => {
+            HttpResponse::BadRequest().body("Admins can't do this to their own account.")
+        }
+        AdminErrors::AdminAccount => {
+            HttpResponse::Forbidden().body("Admins can't do this to another admin.")
+        }
+        AdminErrors::Disabled => HttpResponse::Conflict().body("This account is disabled."),
+        AdminErrors::Failed => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[get("/users")]
+pub async fn get_users(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    if let Some(response) = require_admin(&data, &req_user.unwrap()).await {
+        return response;
+    }
+
+    match get_all_user_info(&data.pg_conn).await {
+        Some(users) => HttpResponse::Ok().json(json!(users)),
+        None => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[post("/users/{user_id}/disable")]
+pub async fn disable_user(
+    user_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = require_admin(&data, &claims).await {
+        return response;
+    }
+
+    match set_user_disabled(&data.pg_conn, &claims.id, &user_id, true).await {
+        Ok(user) => HttpResponse::Ok().json(json!(user)),
+        Err(error) => admin_error_response(error),
+    }
+}
+
+#[post("/users/{user_id}/enable")]
+pub async fn enable_user(
+    user_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = require_admin(&data, &claims).await {
+        return response;
+    }
+
+    match set_user_disabled(&data.pg_conn, &claims.id, &user_id, false).await {
+        Ok(user) => HttpResponse::Ok().json(json!(user)),
+        Err(error) => admin_error_response(error),
+    }
+}
+
+#[delete("/users/{user_id}")]
+pub async fn delete_user(
+    user_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = require_admin(&data, &claims).await {
+        return response;
+    }
+
+    let account_deletion =
+        delete_user_account(&data.pg_conn, &data.data_path, &claims.id, &user_id).await;
+
+    match account_deletion {
+        Ok(account_deletion) if account_deletion.completed_date.is_some() => {
+            HttpResponse::Ok().json(json!(account_deletion))
+        }
+        // it failed part way, the job keeps retrying it
+        Ok(account_deletion) => HttpResponse::InternalServerError().json(json!(account_deletion)),
+        Err(error) => admin_error_response(error),
+    }
+}
+
+#[post("/users/{user_id}/quota/reset")]
+pub async fn reset_quota(
+    user_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = require_admin(&data, &claims).await {
+        return response;
+    }
+
+    match reset_user_quota(&data.pg_conn, &claims.id, &user_id).await {
+        Ok(buckets) => HttpResponse::Ok().json(json!(buckets)),
+        Err(error) => admin_error_response(error),
+    }
+}
+
+#[post("/users/{user_id}/impersonate")]
+pub async fn impersonate(
+    user_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = require_admin(&data, &claims).await {
+        return response;
+    }
+
+    match impersonate_user(&data.pg_conn, &claims.id, &user_id).await {
+        Ok(auth_tokens) => HttpResponse::Ok().json(json!(auth_tokens)),
+        Err(error) => admin_error_response(error),
+    }
+}
+
+#[post("/users/{user_id}/unlock")]
+pub async fn unlock_user(
+    user_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = require_admin(&data, &claims).await {
+        return response;
+    }
+
+    match unlock_user_logins(&data.pg_conn, &claims.id, &user_id).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(error) => admin_error_response(error),
+    }
+}
+
+/// The most recent login attempts, of the user in `?user_id=` or of
+/// everyone, unknown emails included.
+#[get("/logins")]
+pub async fn get_logins(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    login_attempt_query: web::Query<LoginAttemptQuery>,
+) -> impl Responder {
+    if let Some(response) = require_admin(&data, &req_user.unwrap()).await {
+        return response;
+    }
+
+    let login_attempts =
+        get_login_attempts(&data.pg_conn, login_attempt_query.user_id.as_ref()).await;
+
+    match login_attempts {
+        Ok(login_attempts) => HttpResponse::Ok().json(json!(login_attempts)),
+        Err(error) => {
+            println!("error while fetching login attempts: {}", error);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+fn invite_error_response(error: InviteErrors) -> HttpResponse {
+    match error {
+        InviteErrors::NotFound => HttpResponse::NotFound().finish(),
+        InviteErrors::InvalidUses => HttpResponse::BadRequest().body("max_uses must be positive."),
+        InviteErrors::InvalidExpiry => {
+            HttpResponse::BadRequest().body("expires_in_days must be positive.")
+        }
+        InviteErrors::InvalidQuota => {
+            HttpResponse::BadRequest().body("max_bucket_size can not be negative.")
+        }
+        InviteErrors::Failed => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[get("/invites")]
+pub async fn get_all_invites(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    if let Some(response) = require_admin(&data, &req_user.unwrap()).await {
+        return response;
+    }
+
+    match get_invites(&data.pg_conn).await {
+        Ok(invites) => HttpResponse::Ok().json(json!(invites)),
+        Err(error) => invite_error_response(error),
+    }
+}
+
+#[post("/invites")]
+pub async fn create_new_invite(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    new_invite: web::Json<NewInvite>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = require_admin(&data, &claims).await {
+        return response;
+    }
+
+    match create_invite(&data.pg_conn, &claims.id, &new_invite).await {
+        Ok(invite) => HttpResponse::Created().json(json!(invite)),
+        Err(error) => invite_error_response(error),
+    }
+}
+
+#[delete("/invites/{invite_id}")]
+pub async fn remove_invite(
+    invite_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    if let Some(response) = require_admin(&data, &req_user.unwrap()).await {
+        return response;
+    }
+
+    match delete_invite(&data.pg_conn, &invite_id).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(error) => invite_error_response(error),
+    }
+}
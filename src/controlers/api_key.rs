@@ -0,0 +1,125 @@
+use actix_web::{
+    delete, get, post,
+    web::{self, ReqData},
+    HttpResponse, Responder,
+};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    app_data::AppData,
+    models::api_key::{
+        check_api_key_access, create_api_key, delete_api_key, get_api_keys, ApiKeyErrors,
+        ApiKeyScope, ApiKeyTarget, NewApiKey,
+    },
+    utility::jwt_token::Claims,
+};
+
+pub fn api_key_config(config: &mut web::ServiceConfig) {
+    let scope = web::scope("/keys")
+        .service(get_user_api_keys)
+        .service(create_user_api_key)
+        .service(delete_user_api_key);
+
+    config.service(scope);
+}
+
+/// Stops a request made with an API key that lacks `scope`, or is limited
+/// to other buckets than the one `target` is in. Requests with an access
+/// token are let through.
+pub async fn check_api_key_scope(
+    data: &AppData,
+    claims: &Claims,
+    scope: ApiKeyScope,
+    target: ApiKeyTarget<'_>,
+) -> Option<HttpResponse> {
+    let api_key = claims.api_key.as_ref()?;
+
+    match check_api_key_access(&data.pg_conn, api_key, scope, target).await {
+        Ok(_) => None,
+        Err(ApiKeyErrors::Failed) => Some(HttpResponse::InternalServerError().finish()),
+        Err(_) => Some(HttpResponse::Forbidden().body(format!(
+            "This API key needs the {} scope for this request and access to its bucket.",
+            scope.as_str()
+        ))),
+    }
+}
+
+/// Stops a request made with an API key, these can't manage the account.
+pub fn reject_api_key(claims: &Claims) -> Option<HttpResponse> {
+    claims
+        .api_key
+        .as_ref()
+        .map(|_| HttpResponse::Forbidden().body("API keys can't be used to manage the account."))
+}
+
+fn api_key_error_response(error: ApiKeyErrors) -> HttpResponse {
+    match error {
+        ApiKeyErrors::NotFound => HttpResponse::NotFound().finish(),
+        ApiKeyErrors::Forbidden => HttpResponse::Forbidden().finish(),
+        ApiKeyErrors::InvalidName => {
+            HttpResponse::BadRequest().body("key_name can not be empty or longer than 255 bytes.")
+        }
+        ApiKeyErrors::InvalidScopes => HttpResponse::BadRequest()
+            .body("scopes needs at least one of files:read, files:write or buckets:admin."),
+        ApiKeyErrors::InvalidBucket => HttpResponse::BadRequest()
+            .body("bucket_ids can not be empty and must be buckets you have access to."),
+        ApiKeyErrors::InvalidExpiry => {
+            HttpResponse::BadRequest().body("expires_in_days must be positive.")
+        }
+        ApiKeyErrors::Failed => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[get("/")]
+pub async fn get_user_api_keys(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = reject_api_key(&claims) {
+        return response;
+    }
+
+    match get_api_keys(&data.pg_conn, &claims.id).await {
+        Ok(api_keys) => HttpResponse::Ok().json(json!(api_keys)),
+        Err(error) => api_key_error_response(error),
+    }
+}
+
+#[post("/")]
+pub async fn create_user_api_key(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    new_api_key: web::Json<NewApiKey>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = reject_api_key(&claims) {
+        return response;
+    }
+
+    match create_api_key(&data.pg_conn, &claims.id, &new_api_key).await {
+        Ok(api_key) => HttpResponse::Created().json(json!(api_key)),
+        Err(error) => api_key_error_response(error),
+    }
+}
+
+#[delete("/{key_id}")]
+pub async fn delete_user_api_key(
+    key_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = reject_api_key(&claims) {
+        return response;
+    }
+
+    match delete_api_key(&data.pg_conn, &claims.id, &key_id).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(error) => api_key_error_response(error),
+    }
+}
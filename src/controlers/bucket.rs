@@ -1,15 +1,22 @@
 use actix_web::{
-    delete, get, post,
+    delete, get, post, put,
     web::{self, ReqData},
     HttpResponse, Responder,
 };
 use serde_json::json;
+use uuid::Uuid;
 
 use crate::{
     app_data::AppData,
-    models::bucket::{
-        create_user_bucket, delete_user_buckets, get_all_user_bucket_info, BucketDeletionError,
-        NewBucket,
+    controlers::{admin::require_admin, api_key::check_api_key_scope},
+    models::{
+        api_key::{ApiKeyScope, ApiKeyTarget},
+        bucket::{
+            add_bucket_member, create_user_bucket, delete_user_buckets, get_all_user_bucket_info,
+            get_bucket_members, get_user_bucket_usage, remove_bucket_member, set_bucket_max_size,
+            set_bucket_versioning, BucketDeletionError, BucketMemberErrors, BucketQuota,
+            BucketVersioning, NewBucket, NewBucketMember,
+        },
     },
     utility::jwt_token::Claims,
 };
@@ -18,7 +25,13 @@ pub fn bucket_config(config: &mut web::ServiceConfig) {
     let scope = web::scope("/bucket")
         .service(get_all_buckets)
         .service(create_bucket)
-        .service(delete_bucket);
+        .service(delete_bucket)
+        .service(get_bucket_usage)
+        .service(set_bucket_quota)
+        .service(set_bucket_version_retention)
+        .service(get_bucket_users)
+        .service(add_bucket_user)
+        .service(remove_bucket_user);
 
     config.service(scope);
 }
@@ -28,7 +41,20 @@ pub async fn get_all_buckets(
     data: web::Data<AppData>,
     req_user: Option<ReqData<Claims>>,
 ) -> impl Responder {
-    let user_id = req_user.unwrap().id;
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesRead,
+        ApiKeyTarget::AllBuckets,
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
 
     let buckets = get_all_user_bucket_info(&data.pg_conn, &user_id).await;
 
@@ -41,7 +67,20 @@ pub async fn create_bucket(
     req_user: Option<ReqData<Claims>>,
     bucket_name: web::Json<NewBucket>,
 ) -> impl Responder {
-    let user_id = req_user.unwrap().id;
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::BucketsAdmin,
+        ApiKeyTarget::AllBuckets,
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
 
     let bucket = create_user_bucket(
         &data.pg_conn,
@@ -62,7 +101,20 @@ pub async fn delete_bucket(
     data: web::Data<AppData>,
     req_user: Option<ReqData<Claims>>,
 ) -> impl Responder {
-    let user_id = req_user.unwrap().id;
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::BucketsAdmin,
+        ApiKeyTarget::AllBuckets,
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
 
     let delete_user_bucket = delete_user_buckets(&data.pg_conn, &data.data_path, &user_id).await;
 
@@ -80,3 +132,201 @@ pub async fn delete_bucket(
         }
     }
 }
+
+#[get("/usage")]
+pub async fn get_bucket_usage(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesRead,
+        ApiKeyTarget::AllBuckets,
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let usage = get_user_bucket_usage(&data.pg_conn, &user_id).await;
+
+    match usage {
+        Some(usage) => HttpResponse::Ok().json(json!(usage)),
+        None => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[put("/{bucket_id}/quota")]
+pub async fn set_bucket_quota(
+    bucket_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    quota: web::Json<BucketQuota>,
+) -> impl Responder {
+    // only admins, by their role, can change quotas
+    let claims = req_user.unwrap();
+
+    if let Some(response) = require_admin(&data, &claims).await {
+        return response;
+    }
+
+    if quota.max_bucket_size < 0 {
+        return HttpResponse::BadRequest().body("max_bucket_size can not be negative.");
+    }
+
+    let bucket = set_bucket_max_size(&data.pg_conn, &bucket_id, quota.max_bucket_size).await;
+
+    match bucket {
+        Some(bucket) => {
+            println!(
+                "admin {} set the quota of bucket {} to {}",
+                claims.id, bucket_id, quota.max_bucket_size
+            );
+            HttpResponse::Ok().json(json!(bucket))
+        }
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[put("/{bucket_id}/versioning")]
+pub async fn set_bucket_version_retention(
+    bucket_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    versioning: web::Json<BucketVersioning>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::BucketsAdmin,
+        ApiKeyTarget::Bucket(&bucket_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    if versioning.max_versions < 0 || versioning.version_retention_days.unwrap_or_default() < 0 {
+        return HttpResponse::BadRequest()
+            .body("max_versions and version_retention_days can not be negative.");
+    }
+
+    let bucket = set_bucket_versioning(&data.pg_conn, &bucket_id, &user_id, &versioning).await;
+
+    match bucket {
+        Some(bucket) => HttpResponse::Ok().json(json!(bucket)),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+fn bucket_member_error_response(error: BucketMemberErrors) -> HttpResponse {
+    match error {
+        BucketMemberErrors::NotFound => HttpResponse::NotFound().finish(),
+        BucketMemberErrors::Forbidden => HttpResponse::Forbidden().finish(),
+        BucketMemberErrors::InvalidRole => {
+            HttpResponse::BadRequest().body("role must be one of viewer, contributor or manager.")
+        }
+        BucketMemberErrors::InvalidUser => {
+            HttpResponse::BadRequest().body("The bucket can not be shared with this user.")
+        }
+        BucketMemberErrors::Failed => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[get("/{bucket_id}/users")]
+pub async fn get_bucket_users(
+    bucket_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::BucketsAdmin,
+        ApiKeyTarget::Bucket(&bucket_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let members = get_bucket_members(&data.pg_conn, &user_id, &bucket_id).await;
+
+    match members {
+        Ok(members) => HttpResponse::Ok().json(json!(members)),
+        Err(error) => bucket_member_error_response(error),
+    }
+}
+
+#[put("/{bucket_id}/users")]
+pub async fn add_bucket_user(
+    bucket_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    new_member: web::Json<NewBucketMember>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::BucketsAdmin,
+        ApiKeyTarget::Bucket(&bucket_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let added = add_bucket_member(&data.pg_conn, &user_id, &bucket_id, &new_member).await;
+
+    match added {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(error) => bucket_member_error_response(error),
+    }
+}
+
+#[delete("/{bucket_id}/users/{user_id}")]
+pub async fn remove_bucket_user(
+    path: web::Path<(Uuid, Uuid)>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    let (bucket_id, member_id) = path.into_inner();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::BucketsAdmin,
+        ApiKeyTarget::Bucket(&bucket_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let removed = remove_bucket_member(&data.pg_conn, &user_id, &bucket_id, &member_id).await;
+
+    match removed {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(error) => bucket_member_error_response(error),
+    }
+}
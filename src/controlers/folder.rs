@@ -0,0 +1,192 @@
+use actix_web::{
+    delete, get, patch, post,
+    web::{self, ReqData},
+    HttpResponse, Responder,
+};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    app_data::AppData,
+    controlers::api_key::check_api_key_scope,
+    models::{
+        api_key::{ApiKeyScope, ApiKeyTarget},
+        bucket::TargetBucket,
+        folder::{
+            create_folder, delete_folder, get_folder_contents, get_folder_contents_by_path,
+            update_folder, FolderErrors, FolderUpdate, NewFolder,
+        },
+    },
+    utility::jwt_token::Claims,
+};
+
+pub fn folder_config(config: &mut web::ServiceConfig) {
+    let scope = web::scope("/folder")
+        .service(get_folder_by_path)
+        .service(create_new_folder)
+        .service(get_folder_by_id)
+        .service(update_folder_by_id)
+        .service(delete_folder_by_id);
+
+    config.service(scope);
+}
+
+fn folder_error_response(error: FolderErrors) -> HttpResponse {
+    match error {
+        FolderErrors::NotFound => HttpResponse::NotFound().finish(),
+        FolderErrors::Forbidden => HttpResponse::Forbidden().finish(),
+        FolderErrors::InvalidName => HttpResponse::BadRequest()
+            .body("Folder names can not be empty, `.`, `..` or contain slashes."),
+        FolderErrors::InvalidMove => HttpResponse::BadRequest()
+            .body("A folder can only be moved inside its own bucket and not into itself."),
+        FolderErrors::AlreadyExists => HttpResponse::Conflict().finish(),
+        FolderErrors::Failed => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[get("/")]
+pub async fn get_folder_by_path(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    target_bucket: web::Query<TargetBucket>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesRead,
+        ApiKeyTarget::TargetBucket(&target_bucket),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let folder_contents =
+        get_folder_contents_by_path(&data.pg_conn, &user_id, &target_bucket).await;
+
+    match folder_contents {
+        Ok(folder_contents) => HttpResponse::Ok().json(json!(folder_contents)),
+        Err(error) => folder_error_response(error),
+    }
+}
+
+#[post("/")]
+pub async fn create_new_folder(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    new_folder: web::Json<NewFolder>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesWrite,
+        ApiKeyTarget::Bucket(&new_folder.bucket_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let folder = create_folder(&data.pg_conn, &user_id, &new_folder).await;
+
+    match folder {
+        Ok(folder) => HttpResponse::Created().json(json!(folder)),
+        Err(error) => folder_error_response(error),
+    }
+}
+
+#[get("/{folder_id}")]
+pub async fn get_folder_by_id(
+    folder_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesRead,
+        ApiKeyTarget::Folder(&folder_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let folder_contents = get_folder_contents(&data.pg_conn, &user_id, &folder_id).await;
+
+    match folder_contents {
+        Ok(folder_contents) => HttpResponse::Ok().json(json!(folder_contents)),
+        Err(error) => folder_error_response(error),
+    }
+}
+
+#[patch("/{folder_id}")]
+pub async fn update_folder_by_id(
+    folder_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    folder_update: web::Json<FolderUpdate>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesWrite,
+        ApiKeyTarget::Folder(&folder_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let folder = update_folder(&data.pg_conn, &user_id, &folder_id, &folder_update).await;
+
+    match folder {
+        Ok(folder) => HttpResponse::Ok().json(json!(folder)),
+        Err(error) => folder_error_response(error),
+    }
+}
+
+#[delete("/{folder_id}")]
+pub async fn delete_folder_by_id(
+    folder_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesWrite,
+        ApiKeyTarget::Folder(&folder_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let trashed = delete_folder(&data.pg_conn, &user_id, &folder_id).await;
+
+    match trashed {
+        Ok(trashed) => HttpResponse::Ok().json(json!({ "trashed_files": trashed })),
+        Err(error) => folder_error_response(error),
+    }
+}
@@ -0,0 +1,205 @@
+use actix_web::{
+    delete, get,
+    http::header,
+    post,
+    web::{self, ReqData},
+    HttpRequest, HttpResponse, Responder,
+};
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    app_data::AppData,
+    controlers::{api_key::check_api_key_scope, user_file::serve_user_file},
+    models::{
+        api_key::{ApiKeyScope, ApiKeyTarget},
+        public_link::{
+            create_public_link, get_public_links, open_public_link, revoke_public_link,
+            NewPublicLink, PublicLinkErrors, PublicLinkFilter,
+        },
+        user_file::UserFileErrors,
+    },
+    utility::jwt_token::Claims,
+};
+
+const LINK_PASSWORD_HEADER: &str = "X-Link-Password";
+
+#[derive(Debug, Deserialize)]
+pub struct PublicLinkPassword {
+    pub password: Option<String>,
+}
+
+pub fn public_link_config(config: &mut web::ServiceConfig) {
+    let scope = web::scope("/link")
+        .service(get_file_public_links)
+        .service(create_file_public_link)
+        .service(revoke_file_public_link);
+
+    config.service(scope);
+}
+
+pub fn public_download_config(config: &mut web::ServiceConfig) {
+    config
+        .service(download_public_link)
+        .service(download_public_link_with_password);
+}
+
+fn public_link_error_response(error: PublicLinkErrors) -> HttpResponse {
+    match error {
+        PublicLinkErrors::NotFound => HttpResponse::NotFound().finish(),
+        PublicLinkErrors::Forbidden => HttpResponse::Forbidden().finish(),
+        PublicLinkErrors::Expired => HttpResponse::Gone().body("This link has expired."),
+        PublicLinkErrors::PasswordRequired | PublicLinkErrors::WrongPassword => {
+            HttpResponse::Unauthorized().body("This link needs a valid password.")
+        }
+        PublicLinkErrors::Locked(retry_after) => HttpResponse::TooManyRequests()
+            .append_header(("Retry-After", retry_after.to_string()))
+            .body("Too many wrong passwords, try again later."),
+        PublicLinkErrors::InvalidLink => HttpResponse::BadRequest().body(
+            "expires_in_hours and max_downloads must be positive and password can not be empty.",
+        ),
+        PublicLinkErrors::File(error) => match error {
+            UserFileErrors::NotFound => HttpResponse::NotFound().finish(),
+            UserFileErrors::Deleted => HttpResponse::Gone().finish(),
+            _ => HttpResponse::InternalServerError().finish(),
+        },
+        PublicLinkErrors::Failed => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[get("/")]
+pub async fn get_file_public_links(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    link_filter: web::Query<PublicLinkFilter>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesRead,
+        ApiKeyTarget::File(&link_filter.file_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let public_links = get_public_links(&data.pg_conn, &user_id, &link_filter.file_id).await;
+
+    match public_links {
+        Ok(public_links) => HttpResponse::Ok().json(json!(public_links)),
+        Err(error) => public_link_error_response(error),
+    }
+}
+
+#[post("/")]
+pub async fn create_file_public_link(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    new_public_link: web::Json<NewPublicLink>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesWrite,
+        ApiKeyTarget::File(&new_public_link.file_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let public_link = create_public_link(&data.pg_conn, &user_id, &new_public_link).await;
+
+    match public_link {
+        Ok(public_link) => HttpResponse::Created().json(json!(public_link)),
+        Err(error) => public_link_error_response(error),
+    }
+}
+
+#[delete("/{link_id}")]
+pub async fn revoke_file_public_link(
+    link_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesWrite,
+        ApiKeyTarget::Link(&link_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let revoked = revoke_public_link(&data.pg_conn, &user_id, &link_id).await;
+
+    match revoked {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(error) => public_link_error_response(error),
+    }
+}
+
+/// The link password from the `X-Link-Password` header. It is never taken
+/// from the query, where it would end up in logs and Referer headers.
+pub fn get_link_password(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(LINK_PASSWORD_HEADER)
+        .and_then(|password| password.to_str().ok())
+}
+
+async fn serve_public_link(
+    req: &HttpRequest,
+    token: &str,
+    data: &AppData,
+    password: Option<&str>,
+) -> HttpResponse {
+    let range = req
+        .headers()
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok());
+
+    let file_data = open_public_link(&data.pg_conn, &data.data_path, token, password, range).await;
+
+    match file_data {
+        Ok((file_info, file_path)) => serve_user_file(req, &file_info, file_path).await,
+        Err(error) => public_link_error_response(error),
+    }
+}
+
+#[get("/public/{token}")]
+pub async fn download_public_link(
+    req: HttpRequest,
+    token: web::Path<String>,
+    data: web::Data<AppData>,
+) -> impl Responder {
+    serve_public_link(&req, &token, &data, get_link_password(&req)).await
+}
+
+/// For browsers, which can only send the password from a form.
+#[post("/public/{token}")]
+pub async fn download_public_link_with_password(
+    req: HttpRequest,
+    token: web::Path<String>,
+    data: web::Data<AppData>,
+    link_password: web::Form<PublicLinkPassword>,
+) -> impl Responder {
+    let password = get_link_password(&req).or(link_password.password.as_deref());
+
+    serve_public_link(&req, &token, &data, password).await
+}
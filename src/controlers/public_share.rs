@@ -0,0 +1,423 @@
+use actix_web::{
+    cookie::{Cookie, SameSite},
+    delete, get,
+    http::header::{self, ContentDisposition, DispositionParam, DispositionType},
+    post,
+    web::{self, ReqData},
+    HttpRequest, HttpResponse, Responder,
+};
+use futures_util::stream;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    app_data::AppData,
+    controlers::{
+        api_key::check_api_key_scope, public_link::get_link_password, user_file::serve_user_file,
+    },
+    models::{
+        api_key::{ApiKeyScope, ApiKeyTarget},
+        folder::FolderErrors,
+        public_share::{
+            create_public_share, get_public_share_archive, get_public_share_contents,
+            get_public_share_file, get_public_shares, revoke_public_share, unlock_public_share,
+            NewPublicShare, PublicShareContents, PublicShareErrors, PublicShareFilter,
+            ShareCredentials, PUBLIC_SHARE_PATH,
+        },
+        user_file::UserFileErrors,
+    },
+    utility::{jwt_token::Claims, zip_stream::ZipStream},
+};
+
+// keeps the access key of a share with a password, scoped to its path
+const SHARE_ACCESS_COOKIE: &str = "share_access";
+
+#[derive(Debug, Deserialize)]
+pub struct PublicSharePage {
+    // folder path inside the share, like `photos/2023`
+    pub path: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PublicShareUnlock {
+    pub password: String,
+    pub path: Option<String>,
+}
+
+pub fn public_share_config(config: &mut web::ServiceConfig) {
+    let scope = web::scope("/share")
+        .service(get_bucket_public_shares)
+        .service(create_bucket_public_share)
+        .service(revoke_bucket_public_share);
+
+    config.service(scope);
+}
+
+pub fn public_share_page_config(config: &mut web::ServiceConfig) {
+    config
+        .service(get_public_share_page)
+        .service(unlock_public_share_page)
+        .service(download_public_share_file)
+        .service(download_public_share_archive);
+}
+
+fn public_share_error_response(error: PublicShareErrors) -> HttpResponse {
+    match error {
+        PublicShareErrors::NotFound => HttpResponse::NotFound().finish(),
+        PublicShareErrors::Forbidden => HttpResponse::Forbidden().finish(),
+        PublicShareErrors::Expired => HttpResponse::Gone().body("This share has expired."),
+        PublicShareErrors::PasswordRequired | PublicShareErrors::WrongPassword => {
+            HttpResponse::Unauthorized().body("This share needs a valid password.")
+        }
+        PublicShareErrors::Locked(retry_after) => HttpResponse::TooManyRequests()
+            .append_header(("Retry-After", retry_after.to_string()))
+            .body("Too many wrong passwords, try again later."),
+        PublicShareErrors::InvalidShare => HttpResponse::BadRequest()
+            .body("expires_in_hours must be positive and password can not be empty."),
+        PublicShareErrors::Folder(error) => match error {
+            FolderErrors::NotFound => HttpResponse::NotFound().finish(),
+            FolderErrors::InvalidName => HttpResponse::BadRequest().body("Invalid path."),
+            _ => HttpResponse::InternalServerError().finish(),
+        },
+        PublicShareErrors::File(error) => match error {
+            UserFileErrors::NotFound => HttpResponse::NotFound().finish(),
+            UserFileErrors::Deleted => HttpResponse::Gone().finish(),
+            _ => HttpResponse::InternalServerError().finish(),
+        },
+        PublicShareErrors::Failed => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[get("/")]
+pub async fn get_bucket_public_shares(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    share_filter: web::Query<PublicShareFilter>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesRead,
+        ApiKeyTarget::Bucket(&share_filter.bucket_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let public_shares = get_public_shares(&data.pg_conn, &user_id, &share_filter.bucket_id).await;
+
+    match public_shares {
+        Ok(public_shares) => HttpResponse::Ok().json(json!(public_shares)),
+        Err(error) => public_share_error_response(error),
+    }
+}
+
+#[post("/")]
+pub async fn create_bucket_public_share(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    new_public_share: web::Json<NewPublicShare>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesWrite,
+        ApiKeyTarget::Bucket(&new_public_share.bucket_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let public_share = create_public_share(&data.pg_conn, &user_id, &new_public_share).await;
+
+    match public_share {
+        Ok(public_share) => HttpResponse::Created().json(json!(public_share)),
+        Err(error) => public_share_error_response(error),
+    }
+}
+
+#[delete("/{share_id}")]
+pub async fn revoke_bucket_public_share(
+    share_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesWrite,
+        ApiKeyTarget::Share(&share_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let revoked = revoke_public_share(&data.pg_conn, &user_id, &share_id).await;
+
+    match revoked {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(error) => public_share_error_response(error),
+    }
+}
+
+fn wants_html(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"))
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn encode_query(value: &str) -> String {
+    utf8_percent_encode(value, NON_ALPHANUMERIC).to_string()
+}
+
+fn get_share_url(token: &str) -> String {
+    format!("{}/{}", PUBLIC_SHARE_PATH, token)
+}
+
+/// The password from the `X-Link-Password` header, or the access key a
+/// browser got for it in a cookie.
+fn get_share_credentials<'a>(
+    req: &'a HttpRequest,
+    access_cookie: Option<&'a Cookie<'static>>,
+) -> ShareCredentials<'a> {
+    match (get_link_password(req), access_cookie) {
+        (Some(password), _) => ShareCredentials::Password(password),
+        (None, Some(access_cookie)) => ShareCredentials::AccessKey(access_cookie.value()),
+        (None, None) => ShareCredentials::None,
+    }
+}
+
+fn render_password_page(path: Option<&str>, is_wrong_password: bool) -> String {
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Password required</title></head><body>\
+        <p>{}</p><form method=\"post\">\
+        <input type=\"hidden\" name=\"path\" value=\"{}\">\
+        <input type=\"password\" name=\"password\" autofocus> <button type=\"submit\">Open</button>\
+        </form></body></html>",
+        if is_wrong_password {
+            "Wrong password, try again."
+        } else {
+            "This share needs a password."
+        },
+        escape_html(path.unwrap_or_default()),
+    )
+}
+
+/// Renders the listing of a share as a plain HTML index.
+fn render_share_index(token: &str, contents: &PublicShareContents) -> String {
+    let share_url = get_share_url(token);
+
+    let mut rows = Vec::new();
+
+    if !contents.path.is_empty() {
+        let parent_url = match contents.path.rsplit_once('/') {
+            Some((parent_path, _)) => format!("{}?path={}", share_url, encode_query(parent_path)),
+            None => share_url.to_owned(),
+        };
+        rows.push(format!(
+            "<li><a href=\"{}\">../</a></li>",
+            escape_html(&parent_url)
+        ));
+    }
+
+    for folder_name in &contents.folders {
+        let folder_path = match contents.path.is_empty() {
+            true => folder_name.to_owned(),
+            false => format!("{}/{}", contents.path, folder_name),
+        };
+        let folder_url = format!("{}?path={}", share_url, encode_query(&folder_path));
+        rows.push(format!(
+            "<li><a href=\"{}\">{}/</a></li>",
+            escape_html(&folder_url),
+            escape_html(folder_name)
+        ));
+    }
+
+    for file in &contents.files {
+        let file_url = format!("{}/file/{}", share_url, file.file_id);
+        rows.push(format!(
+            "<li><a href=\"{}\">{}</a> ({} bytes)</li>",
+            escape_html(&file_url),
+            escape_html(&file.file_name),
+            file.file_size
+        ));
+    }
+
+    let title = match contents.path.is_empty() {
+        true => contents.name.to_owned(),
+        false => format!("{}/{}", contents.name, contents.path),
+    };
+
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{0}</title></head><body>\
+        <h1>{0}</h1><p><a href=\"{1}\">Download everything as zip</a></p><ul>{2}</ul></body></html>",
+        escape_html(&title),
+        escape_html(&format!("{}/zip", share_url)),
+        rows.join("")
+    )
+}
+
+fn password_page_response(error: PublicShareErrors, path: Option<&str>) -> HttpResponse {
+    match error {
+        PublicShareErrors::PasswordRequired | PublicShareErrors::WrongPassword => {
+            HttpResponse::Unauthorized()
+                .content_type(mime::TEXT_HTML_UTF_8)
+                .body(render_password_page(
+                    path,
+                    matches!(error, PublicShareErrors::WrongPassword),
+                ))
+        }
+        error => public_share_error_response(error),
+    }
+}
+
+/// Lists a share as JSON, or as an HTML index for browsers.
+#[get("/public/share/{token}")]
+pub async fn get_public_share_page(
+    req: HttpRequest,
+    token: web::Path<String>,
+    data: web::Data<AppData>,
+    share_page: web::Query<PublicSharePage>,
+) -> impl Responder {
+    let access_cookie = req.cookie(SHARE_ACCESS_COOKIE);
+    let contents = get_public_share_contents(
+        &data.pg_conn,
+        &token,
+        get_share_credentials(&req, access_cookie.as_ref()),
+        share_page.path.as_deref(),
+    )
+    .await;
+
+    match contents {
+        Ok(contents) if wants_html(&req) => HttpResponse::Ok()
+            .content_type(mime::TEXT_HTML_UTF_8)
+            .body(render_share_index(&token, &contents)),
+        Ok(contents) => HttpResponse::Ok().json(json!(contents)),
+        Err(error) if wants_html(&req) => password_page_response(error, share_page.path.as_deref()),
+        Err(error) => public_share_error_response(error),
+    }
+}
+
+/// Takes the password from the form of the password page and keeps an
+/// access key for the share in a cookie.
+#[post("/public/share/{token}")]
+pub async fn unlock_public_share_page(
+    req: HttpRequest,
+    token: web::Path<String>,
+    data: web::Data<AppData>,
+    share_unlock: web::Form<PublicShareUnlock>,
+) -> impl Responder {
+    let unlocked = unlock_public_share(&data.pg_conn, &token, &share_unlock.password).await;
+
+    let share_url = get_share_url(&token);
+    let page_url = match share_unlock.path.as_deref() {
+        Some(path) if !path.is_empty() => format!("{}?path={}", share_url, encode_query(path)),
+        _ => share_url.to_owned(),
+    };
+
+    match unlocked {
+        Ok(access_key) => {
+            let mut response = HttpResponse::SeeOther();
+            response.insert_header((header::LOCATION, page_url));
+
+            if let Some(access_key) = access_key {
+                response.cookie(
+                    Cookie::build(SHARE_ACCESS_COOKIE, access_key)
+                        .path(share_url)
+                        .http_only(true)
+                        .same_site(SameSite::Lax)
+                        .secure(req.connection_info().scheme() == "https")
+                        .finish(),
+                );
+            }
+
+            response.finish()
+        }
+        Err(error) => password_page_response(error, share_unlock.path.as_deref()),
+    }
+}
+
+#[get("/public/share/{token}/file/{file_id}")]
+pub async fn download_public_share_file(
+    req: HttpRequest,
+    path: web::Path<(String, Uuid)>,
+    data: web::Data<AppData>,
+) -> impl Responder {
+    let (token, file_id) = path.into_inner();
+
+    let access_cookie = req.cookie(SHARE_ACCESS_COOKIE);
+    let file_data = get_public_share_file(
+        &data.pg_conn,
+        &data.data_path,
+        &token,
+        get_share_credentials(&req, access_cookie.as_ref()),
+        &file_id,
+    )
+    .await;
+
+    match file_data {
+        Ok((file_info, file_path)) => serve_user_file(&req, &file_info, file_path).await,
+        Err(error) => public_share_error_response(error),
+    }
+}
+
+/// Sends everything in a share as one zip, written while it is sent.
+#[get("/public/share/{token}/zip")]
+pub async fn download_public_share_archive(
+    req: HttpRequest,
+    token: web::Path<String>,
+    data: web::Data<AppData>,
+) -> impl Responder {
+    let access_cookie = req.cookie(SHARE_ACCESS_COOKIE);
+    let archive = get_public_share_archive(
+        &data.pg_conn,
+        &data.data_path,
+        &token,
+        get_share_credentials(&req, access_cookie.as_ref()),
+    )
+    .await;
+
+    let (archive_name, entries) = match archive {
+        Ok(archive) => archive,
+        Err(error) => return public_share_error_response(error),
+    };
+
+    HttpResponse::Ok()
+        .content_type(mime::APPLICATION_OCTET_STREAM)
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(
+                archive_name
+                    .chars()
+                    .map(|c| if c.is_ascii() { c } else { '_' })
+                    .collect(),
+            )],
+        })
+        .streaming(stream::iter(ZipStream::new(entries)))
+}
@@ -0,0 +1,149 @@
+use actix_web::{
+    get, post, put,
+    web::{self, ReqData},
+    HttpRequest, HttpResponse, Responder,
+};
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    app_data::AppData,
+    controlers::{
+        api_key::check_api_key_scope,
+        user_file::{save_file_error_response, serve_user_file},
+    },
+    models::{
+        api_key::{ApiKeyScope, ApiKeyTarget},
+        bucket::TargetBucket,
+        signed_url::{create_signed_url, NewSignedUrl, SignedUrlErrors, SignedUrlMethod},
+        user_file::{get_user_file_by_file_id, save_raw_user_file, UserFileErrors},
+    },
+    utility::{jwt_token::Claims, signed_url::SignedUrlUser},
+};
+
+#[derive(Debug, Deserialize)]
+pub struct SignedUpload {
+    pub file_name: String,
+}
+
+pub fn signed_url_config(config: &mut web::ServiceConfig) {
+    let scope = web::scope("/presign").service(create_file_signed_url);
+
+    config.service(scope);
+}
+
+pub fn signed_access_config(config: &mut web::ServiceConfig) {
+    config
+        .service(download_signed_file)
+        .service(upload_signed_file);
+}
+
+fn signed_url_error_response(error: SignedUrlErrors) -> HttpResponse {
+    match error {
+        SignedUrlErrors::NotFound => HttpResponse::NotFound().finish(),
+        SignedUrlErrors::Forbidden => HttpResponse::Forbidden().finish(),
+        SignedUrlErrors::InvalidRequest => HttpResponse::BadRequest().body(
+            "GET needs a file_id, PUT needs a bucket_id and expires_in_seconds must be between 1 second and 7 days.",
+        ),
+        SignedUrlErrors::File(error) => match error {
+            UserFileErrors::NotFound => HttpResponse::NotFound().finish(),
+            UserFileErrors::Deleted => HttpResponse::Gone().finish(),
+            _ => HttpResponse::InternalServerError().finish(),
+        },
+        SignedUrlErrors::Failed => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[post("/")]
+pub async fn create_file_signed_url(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    new_signed_url: web::Json<NewSignedUrl>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    let (scope, target) = match (
+        &new_signed_url.method,
+        &new_signed_url.file_id,
+        &new_signed_url.bucket_id,
+    ) {
+        (SignedUrlMethod::Get, Some(file_id), _) => {
+            (ApiKeyScope::FilesRead, ApiKeyTarget::File(file_id))
+        }
+        (SignedUrlMethod::Put, _, Some(bucket_id)) => {
+            (ApiKeyScope::FilesWrite, ApiKeyTarget::Bucket(bucket_id))
+        }
+        (SignedUrlMethod::Get, None, _) => (ApiKeyScope::FilesRead, ApiKeyTarget::AllBuckets),
+        (SignedUrlMethod::Put, _, None) => (ApiKeyScope::FilesWrite, ApiKeyTarget::AllBuckets),
+    };
+
+    if let Some(response) = check_api_key_scope(&data, &claims, scope, target).await {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let signed_url = create_signed_url(&data.pg_conn, &user_id, &new_signed_url).await;
+
+    match signed_url {
+        Ok(signed_url) => HttpResponse::Created().json(json!(signed_url)),
+        Err(error) => signed_url_error_response(error),
+    }
+}
+
+#[get("/file/{file_id}")]
+pub async fn download_signed_file(
+    req: HttpRequest,
+    file_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<SignedUrlUser>>,
+) -> impl Responder {
+    let user_id = req_user.unwrap().id;
+
+    let file_data =
+        get_user_file_by_file_id(&data.pg_conn, &data.data_path, &user_id, &file_id).await;
+
+    match file_data {
+        Ok((file_info, file_path)) => serve_user_file(&req, &file_info, file_path).await,
+        Err(error) => match error {
+            UserFileErrors::Forbidden => HttpResponse::Forbidden().finish(),
+            UserFileErrors::NotFound => HttpResponse::NotFound().finish(),
+            UserFileErrors::Deleted => HttpResponse::Gone().finish(),
+            _ => HttpResponse::InternalServerError().finish(),
+        },
+    }
+}
+
+#[put("/upload/{bucket_id}")]
+pub async fn upload_signed_file(
+    bucket_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<SignedUrlUser>>,
+    signed_upload: web::Query<SignedUpload>,
+    payload: web::Payload,
+) -> impl Responder {
+    let user_id = req_user.unwrap().id;
+
+    let target_bucket = TargetBucket {
+        bucket_id: Some(bucket_id.into_inner()),
+        bucket_name: None,
+        path: None,
+    };
+
+    let saved_file = save_raw_user_file(
+        &data.pg_conn,
+        &data.data_path,
+        &user_id,
+        &target_bucket,
+        data.max_upload_size,
+        signed_upload.into_inner().file_name,
+        payload,
+    )
+    .await;
+
+    match saved_file {
+        Ok(saved_file) => HttpResponse::Created().json(json!(saved_file)),
+        Err(error) => save_file_error_response(error, &data),
+    }
+}
@@ -0,0 +1,145 @@
+use actix_web::{
+    delete, post,
+    web::{self, ReqData},
+    HttpResponse, Responder,
+};
+use serde_json::json;
+
+use crate::{
+    app_data::AppData,
+    controlers::api_key::reject_api_key,
+    models::totp::{
+        complete_totp_login, confirm_totp_enrollment, disable_totp, regenerate_recovery_codes,
+        start_totp_enrollment, TotpCode, TotpErrors, TotpLogin,
+    },
+    utility::jwt_token::Claims,
+};
+
+pub fn totp_config(config: &mut web::ServiceConfig) {
+    let scope = web::scope("/totp")
+        .service(enroll_totp)
+        .service(confirm_totp)
+        .service(new_recovery_codes)
+        .service(remove_totp);
+
+    config.service(scope);
+}
+
+fn totp_error_response(error: TotpErrors) -> HttpResponse {
+    println!("TotpErrors: {:?}", error);
+    match error {
+        TotpErrors::AlreadyEnabled => {
+            HttpResponse::Conflict().body("Two factor authentication is already enabled.")
+        }
+        TotpErrors::NotEnabled => {
+            HttpResponse::NotFound().body("Two factor authentication is not enabled.")
+        }
+        TotpErrors::InvalidCode => HttpResponse::Unauthorized().body("Invalid code."),
+        TotpErrors::InvalidChallenge => {
+            HttpResponse::Unauthorized().body("Invalid or expired login challenge.")
+        }
+        TotpErrors::Locked(retry_after) => HttpResponse::TooManyRequests()
+            .append_header(("Retry-After", retry_after.to_string()))
+            .body("Too many wrong codes, try again later."),
+        TotpErrors::Disabled => HttpResponse::Forbidden().body("This account is disabled."),
+        TotpErrors::Failed => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[post("/")]
+pub async fn enroll_totp(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = reject_api_key(&claims) {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    match start_totp_enrollment(&data.pg_conn, &user_id).await {
+        Ok(totp_enrollment) => HttpResponse::Ok().json(json!(totp_enrollment)),
+        Err(error) => totp_error_response(error),
+    }
+}
+
+#[post("/confirm")]
+pub async fn confirm_totp(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    totp_code: web::Json<TotpCode>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = reject_api_key(&claims) {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    match confirm_totp_enrollment(&data.pg_conn, &user_id, &totp_code.code).await {
+        Ok(recovery_codes) => HttpResponse::Ok().json(json!(recovery_codes)),
+        Err(error) => totp_error_response(error),
+    }
+}
+
+#[post("/recovery")]
+pub async fn new_recovery_codes(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    totp_code: web::Json<TotpCode>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = reject_api_key(&claims) {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    match regenerate_recovery_codes(&data.pg_conn, &user_id, &totp_code.code).await {
+        Ok(recovery_codes) => HttpResponse::Ok().json(json!(recovery_codes)),
+        Err(error) => totp_error_response(error),
+    }
+}
+
+#[delete("/")]
+pub async fn remove_totp(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    totp_code: web::Json<TotpCode>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = reject_api_key(&claims) {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    match disable_totp(&data.pg_conn, &user_id, &totp_code.code).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(error) => totp_error_response(error),
+    }
+}
+
+#[post("/login/totp")]
+pub async fn totp_login(
+    data: web::Data<AppData>,
+    totp_login: web::Json<TotpLogin>,
+) -> impl Responder {
+    let auth_tokens =
+        complete_totp_login(&data.pg_conn, &totp_login, data.refresh_token_days).await;
+
+    match auth_tokens {
+        Ok(auth_tokens) => HttpResponse::Ok()
+            .append_header((
+                "Authorization",
+                "Bearer ".to_owned() + &auth_tokens.access_token,
+            ))
+            .json(json!(auth_tokens)),
+        Err(error) => totp_error_response(error),
+    }
+}
@@ -0,0 +1,150 @@
+use actix_web::{
+    delete, get, post,
+    web::{self, ReqData},
+    HttpResponse, Responder,
+};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    app_data::AppData,
+    controlers::api_key::check_api_key_scope,
+    models::{
+        api_key::{ApiKeyScope, ApiKeyTarget},
+        trash::{empty_user_trash, get_user_trash, purge_trashed_file, restore_trashed_file},
+        user_file::UserFileErrors,
+    },
+    utility::jwt_token::Claims,
+};
+
+pub fn trash_config(config: &mut web::ServiceConfig) {
+    let scope = web::scope("/trash")
+        .service(get_trash)
+        .service(empty_trash)
+        .service(restore_file)
+        .service(purge_file);
+
+    config.service(scope);
+}
+
+#[get("/")]
+pub async fn get_trash(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesRead,
+        ApiKeyTarget::AllBuckets,
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let files = get_user_trash(&data.pg_conn, &user_id).await;
+
+    match files {
+        Some(files) => HttpResponse::Ok().json(json!(files)),
+        None => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[delete("/")]
+pub async fn empty_trash(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesWrite,
+        ApiKeyTarget::AllBuckets,
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let purged = empty_user_trash(&data.pg_conn, &data.data_path, &user_id).await;
+
+    match purged {
+        Some(purged) => HttpResponse::Ok().json(json!({ "purged": purged })),
+        None => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[post("/{file_id}/restore")]
+pub async fn restore_file(
+    file_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesWrite,
+        ApiKeyTarget::File(&file_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let restored_file = restore_trashed_file(&data.pg_conn, &user_id, &file_id).await;
+
+    match restored_file {
+        Ok(restored_file) => HttpResponse::Ok().json(json!(restored_file)),
+        Err(error) => match error {
+            UserFileErrors::Forbidden => HttpResponse::Forbidden().finish(),
+            UserFileErrors::NotFound => HttpResponse::NotFound().finish(),
+            _ => HttpResponse::InternalServerError().finish(),
+        },
+    }
+}
+
+#[delete("/{file_id}")]
+pub async fn purge_file(
+    file_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesWrite,
+        ApiKeyTarget::File(&file_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let purged = purge_trashed_file(&data.pg_conn, &data.data_path, &user_id, &file_id).await;
+
+    match purged {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(error) => match error {
+            UserFileErrors::Forbidden => HttpResponse::Forbidden().finish(),
+            UserFileErrors::NotFound => HttpResponse::NotFound().finish(),
+            _ => HttpResponse::InternalServerError().finish(),
+        },
+    }
+}
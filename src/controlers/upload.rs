@@ -0,0 +1,475 @@
+use actix_web::{
+    delete, head,
+    http::StatusCode,
+    options, patch, post,
+    web::{self, ReqData},
+    HttpRequest, HttpResponse, HttpResponseBuilder, Responder,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{Duration, NaiveDateTime};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::{
+    app_data::AppData,
+    controlers::api_key::check_api_key_scope,
+    models::{
+        api_key::{ApiKeyScope, ApiKeyTarget},
+        bucket::TargetBucket,
+        upload_session::{
+            append_to_upload_session, create_upload_session, delete_upload_session,
+            finish_empty_upload_session, get_upload_session, ChecksumAlgorithm, UploadChecksum,
+            UploadSessionErrors,
+        },
+        user_file::UserFileErrors,
+    },
+    utility::jwt_token::Claims,
+};
+
+/*
+  resumable uploads following the tus 1.0 protocol
+
+  spec : https://tus.io/protocols/resumable-upload
+*/
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,expiration,termination,checksum";
+const TUS_CHECKSUM_ALGORITHMS: &str = "sha1,sha256";
+const CHECKSUM_MISMATCH: u16 = 460;
+
+pub fn upload_config(config: &mut web::ServiceConfig) {
+    let scope = web::scope("/upload")
+        .service(upload_options)
+        .service(create_upload)
+        .service(get_upload_offset)
+        .service(append_upload)
+        .service(terminate_upload);
+
+    config.service(scope);
+}
+
+fn tus_response(status: StatusCode) -> HttpResponseBuilder {
+    let mut response = HttpResponse::build(status);
+    response.insert_header(("Tus-Resumable", TUS_VERSION));
+    response
+}
+
+fn get_header<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+}
+
+fn check_tus_resumable(req: &HttpRequest) -> Option<HttpResponse> {
+    if get_header(req, "Tus-Resumable") == Some(TUS_VERSION) {
+        None
+    } else {
+        Some(
+            tus_response(StatusCode::PRECONDITION_FAILED)
+                .insert_header(("Tus-Version", TUS_VERSION))
+                .finish(),
+        )
+    }
+}
+
+fn format_http_date(date: &NaiveDateTime) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn parse_upload_metadata(upload_metadata: &str) -> HashMap<String, String> {
+    let mut metadata = HashMap::new();
+
+    for pair in upload_metadata.split(',') {
+        let mut parts = pair.trim().splitn(2, ' ');
+        let key = match parts.next() {
+            Some(key) if !key.is_empty() => key,
+            _ => continue,
+        };
+        let value = parts
+            .next()
+            .and_then(|value| STANDARD.decode(value).ok())
+            .and_then(|value| String::from_utf8(value).ok())
+            .unwrap_or_default();
+
+        metadata.insert(key.to_owned(), value);
+    }
+
+    metadata
+}
+
+fn parse_upload_checksum(upload_checksum: &str) -> Option<UploadChecksum> {
+    let (algorithm, digest) = upload_checksum.split_once(' ')?;
+
+    let algorithm = match algorithm {
+        "sha1" => ChecksumAlgorithm::Sha1,
+        "sha256" => ChecksumAlgorithm::Sha256,
+        _ => return None,
+    };
+
+    let digest = STANDARD.decode(digest.trim()).ok()?;
+
+    Some(UploadChecksum { algorithm, digest })
+}
+
+fn upload_session_error_response(error: UploadSessionErrors, data: &AppData) -> HttpResponse {
+    println!("upload session error: {:?}", error);
+
+    match error {
+        UploadSessionErrors::NotFound => tus_response(StatusCode::NOT_FOUND).finish(),
+        UploadSessionErrors::Forbidden => tus_response(StatusCode::FORBIDDEN).finish(),
+        UploadSessionErrors::Expired => tus_response(StatusCode::GONE).finish(),
+        UploadSessionErrors::OffsetMismatch => tus_response(StatusCode::CONFLICT).finish(),
+        UploadSessionErrors::Locked => {
+            tus_response(StatusCode::LOCKED).body("Another request is writing to this upload")
+        }
+        UploadSessionErrors::ExceedsUploadLength => tus_response(StatusCode::PAYLOAD_TOO_LARGE)
+            .body("The request body exceeds the Upload-Length of the upload."),
+        UploadSessionErrors::ChecksumMismatch => {
+            tus_response(StatusCode::from_u16(CHECKSUM_MISMATCH).unwrap()).body("Checksum Mismatch")
+        }
+        UploadSessionErrors::FailedToSave => {
+            tus_response(StatusCode::INTERNAL_SERVER_ERROR).finish()
+        }
+        UploadSessionErrors::File(error) => match error {
+            UserFileErrors::Forbidden => tus_response(StatusCode::FORBIDDEN).finish(),
+            UserFileErrors::InvalidBucket | UserFileErrors::InvalidPath => {
+                tus_response(StatusCode::BAD_REQUEST).body(format!("{:?}", error))
+            }
+            UserFileErrors::QuotaExceeded => tus_response(StatusCode::PAYLOAD_TOO_LARGE)
+                .body("The upload would exceed the bucket's maximum size."),
+            UserFileErrors::FileTooLarge => {
+                tus_response(StatusCode::PAYLOAD_TOO_LARGE).body(format!(
+                    "The upload is too large. Maximum size is {} bytes.",
+                    data.max_upload_size.unwrap_or_default()
+                ))
+            }
+            _ => tus_response(StatusCode::INTERNAL_SERVER_ERROR).finish(),
+        },
+    }
+}
+
+#[options("")]
+pub async fn upload_options(data: web::Data<AppData>) -> impl Responder {
+    let mut response = tus_response(StatusCode::NO_CONTENT);
+    response
+        .insert_header(("Tus-Version", TUS_VERSION))
+        .insert_header(("Tus-Extension", TUS_EXTENSIONS))
+        .insert_header(("Tus-Checksum-Algorithm", TUS_CHECKSUM_ALGORITHMS));
+
+    if let Some(max_upload_size) = data.max_upload_size {
+        response.insert_header(("Tus-Max-Size", max_upload_size.to_string()));
+    }
+
+    response.finish()
+}
+
+#[post("")]
+pub async fn create_upload(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    if let Some(response) = check_tus_resumable(&req) {
+        return response;
+    }
+
+    let upload_length = match get_header(&req, "Upload-Length").map(str::parse::<i64>) {
+        Some(Ok(upload_length)) if upload_length >= 0 => upload_length,
+        _ => {
+            return tus_response(StatusCode::BAD_REQUEST)
+                .body("A valid Upload-Length header is required.")
+        }
+    };
+
+    let metadata = parse_upload_metadata(get_header(&req, "Upload-Metadata").unwrap_or_default());
+
+    let target_bucket = TargetBucket {
+        bucket_id: metadata
+            .get("bucket_id")
+            .and_then(|bucket_id| bucket_id.parse::<Uuid>().ok()),
+        bucket_name: metadata.get("bucket_name").cloned(),
+        path: metadata.get("path").cloned(),
+    };
+    let file_name = metadata
+        .get("filename")
+        .or_else(|| metadata.get("name"))
+        .cloned()
+        .unwrap_or_default();
+
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesWrite,
+        ApiKeyTarget::TargetBucket(&target_bucket),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let upload_session = create_upload_session(
+        &data.pg_conn,
+        &data.data_path,
+        &user_id,
+        &target_bucket,
+        &file_name,
+        upload_length,
+        data.max_upload_size,
+        Duration::hours(data.upload_expiry_hours),
+    )
+    .await;
+
+    let upload_session = match upload_session {
+        Ok(upload_session) => upload_session,
+        Err(error) => return upload_session_error_response(error, &data),
+    };
+
+    let mut response = tus_response(StatusCode::CREATED);
+    response
+        .insert_header((
+            "Location",
+            format!("{}/{}", req.path(), upload_session.upload_id),
+        ))
+        .insert_header((
+            "Upload-Expires",
+            format_http_date(&upload_session.expires_date),
+        ));
+
+    // an empty upload is complete already, it is stored right away
+    if upload_session.upload_length == 0 {
+        let upload_id = upload_session.upload_id;
+        let finished =
+            finish_empty_upload_session(&data.pg_conn, &data.data_path, &user_id, &upload_id).await;
+
+        match finished {
+            Ok((_, Some(user_file))) => {
+                response
+                    .insert_header(("Upload-Offset", "0"))
+                    .insert_header(("File-Id", user_file.file_id.to_string()));
+            }
+            Ok((_, None)) => {}
+            Err(error) => {
+                let _ = delete_upload_session(&data.pg_conn, &data.data_path, &user_id, &upload_id)
+                    .await;
+                return upload_session_error_response(error, &data);
+            }
+        }
+    }
+
+    response.finish()
+}
+
+#[head("/{upload_id}")]
+pub async fn get_upload_offset(
+    req: HttpRequest,
+    upload_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    if let Some(response) = check_tus_resumable(&req) {
+        return response;
+    }
+
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesRead,
+        ApiKeyTarget::Upload(&upload_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let upload_session = get_upload_session(&data.pg_conn, &user_id, &upload_id).await;
+
+    match upload_session {
+        Ok(upload_session) => tus_response(StatusCode::OK)
+            .insert_header(("Upload-Offset", upload_session.upload_offset.to_string()))
+            .insert_header(("Upload-Length", upload_session.upload_length.to_string()))
+            .insert_header((
+                "Upload-Expires",
+                format_http_date(&upload_session.expires_date),
+            ))
+            .insert_header(("Cache-Control", "no-store"))
+            .finish(),
+        Err(error) => upload_session_error_response(error, &data),
+    }
+}
+
+#[patch("/{upload_id}")]
+pub async fn append_upload(
+    req: HttpRequest,
+    upload_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    payload: web::Payload,
+) -> impl Responder {
+    if let Some(response) = check_tus_resumable(&req) {
+        return response;
+    }
+
+    if get_header(&req, "Content-Type") != Some("application/offset+octet-stream") {
+        return tus_response(StatusCode::UNSUPPORTED_MEDIA_TYPE).finish();
+    }
+
+    let upload_offset = match get_header(&req, "Upload-Offset").map(str::parse::<i64>) {
+        Some(Ok(upload_offset)) if upload_offset >= 0 => upload_offset,
+        _ => {
+            return tus_response(StatusCode::BAD_REQUEST)
+                .body("A valid Upload-Offset header is required.")
+        }
+    };
+
+    let checksum = match get_header(&req, "Upload-Checksum") {
+        Some(upload_checksum) => match parse_upload_checksum(upload_checksum) {
+            Some(checksum) => Some(checksum),
+            None => {
+                return tus_response(StatusCode::BAD_REQUEST)
+                    .body("Unsupported Upload-Checksum algorithm or digest.")
+            }
+        },
+        None => None,
+    };
+
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesWrite,
+        ApiKeyTarget::Upload(&upload_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let appended = append_to_upload_session(
+        &data.pg_conn,
+        &data.data_path,
+        &user_id,
+        &upload_id,
+        upload_offset,
+        checksum,
+        Duration::hours(data.upload_expiry_hours),
+        payload,
+    )
+    .await;
+
+    match appended {
+        Ok((upload_session, user_file)) => {
+            let mut response = tus_response(StatusCode::NO_CONTENT);
+            response
+                .insert_header(("Upload-Offset", upload_session.upload_offset.to_string()))
+                .insert_header((
+                    "Upload-Expires",
+                    format_http_date(&upload_session.expires_date),
+                ));
+
+            if let Some(user_file) = user_file {
+                response.insert_header(("File-Id", user_file.file_id.to_string()));
+            }
+
+            response.finish()
+        }
+        Err(error) => upload_session_error_response(error, &data),
+    }
+}
+
+#[delete("/{upload_id}")]
+pub async fn terminate_upload(
+    req: HttpRequest,
+    upload_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    if let Some(response) = check_tus_resumable(&req) {
+        return response;
+    }
+
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesWrite,
+        ApiKeyTarget::Upload(&upload_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let deleted = delete_upload_session(&data.pg_conn, &data.data_path, &user_id, &upload_id).await;
+
+    match deleted {
+        Ok(_) => tus_response(StatusCode::NO_CONTENT).finish(),
+        Err(error) => upload_session_error_response(error, &data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_upload_metadata() {
+        // "photo.jpg" and "image/jpeg"
+        let metadata =
+            parse_upload_metadata("filename cGhvdG8uanBn, filetype aW1hZ2UvanBlZw==,is_private");
+
+        assert_eq!(metadata.len(), 3);
+        assert_eq!(metadata["filename"], "photo.jpg");
+        assert_eq!(metadata["filetype"], "image/jpeg");
+        assert_eq!(metadata["is_private"], "");
+    }
+
+    #[test]
+    fn ignores_malformed_metadata() {
+        let metadata = parse_upload_metadata(",  ,filename not*base64, name //8=");
+
+        assert_eq!(metadata.len(), 2);
+        assert_eq!(metadata["filename"], "");
+        // not UTF-8
+        assert_eq!(metadata["name"], "");
+
+        assert!(parse_upload_metadata("").is_empty());
+    }
+
+    #[test]
+    fn parses_upload_checksum() {
+        // sha1 of "hello"
+        let checksum = parse_upload_checksum("sha1 qvTGHdzF6KLavt4PO0gs2a6pQ00=").unwrap();
+        assert!(matches!(checksum.algorithm, ChecksumAlgorithm::Sha1));
+        assert_eq!(
+            data_encoding::HEXLOWER.encode(&checksum.digest),
+            "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d"
+        );
+
+        let checksum =
+            parse_upload_checksum("sha256 LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=").unwrap();
+        assert!(matches!(checksum.algorithm, ChecksumAlgorithm::Sha256));
+        assert_eq!(checksum.digest.len(), 32);
+    }
+
+    #[test]
+    fn rejects_unknown_checksums() {
+        assert!(parse_upload_checksum("md5 XUFAKrxLKna5cZ2REBfFkg==").is_none());
+        assert!(parse_upload_checksum("sha1").is_none());
+        assert!(parse_upload_checksum("sha1 not*base64").is_none());
+        assert!(parse_upload_checksum("").is_none());
+    }
+}
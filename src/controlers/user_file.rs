@@ -1,18 +1,34 @@
 use actix_files::NamedFile;
-use actix_multipart::form::MultipartForm;
+use actix_multipart::Multipart;
 use actix_web::{
-    delete, get, post,
+    delete, get,
+    http::header::{
+        self, Charset, ContentDisposition, DispositionParam, DispositionType, ExtendedValue,
+        HeaderValue,
+    },
+    patch, post,
     web::{self, ReqData},
     HttpRequest, HttpResponse, Responder,
 };
 use serde_json::json;
+use std::path::PathBuf;
 use uuid::Uuid;
 
 use crate::{
     app_data::AppData,
-    models::user_file::{
-        delete_user_file_by_file_id, get_all_user_files, get_user_file_by_file_id, save_user_file,
-        UploadFile, UserFileErrors,
+    controlers::api_key::check_api_key_scope,
+    models::{
+        api_key::{ApiKeyScope, ApiKeyTarget},
+        bucket::TargetBucket,
+        file_user::{get_file_shares, share_user_file, unshare_user_file, NewFileShare},
+        file_version::{
+            get_file_version, get_file_versions, restore_file_version, save_file_version,
+        },
+        user_file::{
+            copy_user_file, delete_user_file_by_file_id, get_all_user_files,
+            get_files_shared_with_user, get_user_file_by_file_id, save_user_file, update_user_file,
+            FileCopy, FileFilter, FileUpdate, UserFile, UserFileErrors,
+        },
     },
     utility::jwt_token::Claims,
 };
@@ -20,9 +36,19 @@ use crate::{
 pub fn user_file_config(config: &mut web::ServiceConfig) {
     let scope = web::scope("/file")
         .service(get_all_files)
+        .service(get_shared_files)
         .service(save_file)
         .service(get_file_by_id)
-        .service(delete_file_by_id);
+        .service(delete_file_by_id)
+        .service(update_file_by_id)
+        .service(copy_file_by_id)
+        .service(get_file_shares_by_id)
+        .service(share_file_by_id)
+        .service(unshare_file_by_id)
+        .service(get_all_file_versions)
+        .service(save_new_file_version)
+        .service(get_file_version_by_id)
+        .service(restore_file_version_by_id);
 
     config.service(scope);
 }
@@ -31,55 +57,207 @@ pub fn user_file_config(config: &mut web::ServiceConfig) {
 pub async fn get_all_files(
     data: web::Data<AppData>,
     req_user: Option<ReqData<Claims>>,
+    file_filter: web::Query<FileFilter>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesRead,
+        match (&file_filter.folder_id, &file_filter.bucket_id) {
+            (Some(folder_id), _) => ApiKeyTarget::Folder(folder_id),
+            (None, Some(bucket_id)) => ApiKeyTarget::Bucket(bucket_id),
+            (None, None) => ApiKeyTarget::AllBuckets,
+        },
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let files = get_all_user_files(&data.pg_conn, &user_id, &file_filter).await;
+
+    match files {
+        Ok(files) => HttpResponse::Ok().json(json!(files)),
+        Err(error) => match error {
+            UserFileErrors::Forbidden => HttpResponse::Forbidden().finish(),
+            UserFileErrors::NotFound => HttpResponse::NotFound().finish(),
+            _ => HttpResponse::InternalServerError().finish(),
+        },
+    }
+}
+
+#[get("/shared")]
+pub async fn get_shared_files(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
 ) -> impl Responder {
-    let user_id = req_user.unwrap().id;
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesRead,
+        ApiKeyTarget::AllBuckets,
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
 
-    let files = get_all_user_files(&data.pg_conn, &user_id).await;
+    let files = get_files_shared_with_user(&data.pg_conn, &user_id).await;
 
-    HttpResponse::Ok().json(json!(files))
+    match files {
+        Some(files) => HttpResponse::Ok().json(json!(files)),
+        None => HttpResponse::InternalServerError().finish(),
+    }
 }
 
 #[post("/save")]
 pub async fn save_file(
     data: web::Data<AppData>,
     req_user: Option<ReqData<Claims>>,
-    form: MultipartForm<UploadFile>,
+    target_bucket: web::Query<TargetBucket>,
+    payload: Multipart,
 ) -> impl Responder {
-    /*
-      part of the solution was referenced
-      from a post on stackoverflow
+    let claims = req_user.unwrap();
 
-      post : https://stackoverflow.com/a/75849261/13026811
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesWrite,
+        ApiKeyTarget::TargetBucket(&target_bucket),
+    )
+    .await
+    {
+        return response;
+    }
 
-      refere for more information
-    */
+    let user_id = claims.id;
 
-    // 10 MB
-    const MAX_FILE_SIZE: usize = 1024 * 1024 * 10;
-    // const MAX_FILE_COUNT: i32 = 1;
+    let saved_file = save_user_file(
+        &data.pg_conn,
+        &data.data_path,
+        &user_id,
+        &target_bucket,
+        data.max_upload_size,
+        payload,
+    )
+    .await;
 
-    // reject malformed requests
-    match form.file.size {
-        0 => return HttpResponse::BadRequest().finish(),
-        length if length > MAX_FILE_SIZE => {
-            return HttpResponse::BadRequest().body(format!(
-                "The uploaded file is too large. Maximum size is {} bytes.",
-                MAX_FILE_SIZE
-            ));
-        }
-        _ => {}
+    match saved_file {
+        Ok(saved_file) => HttpResponse::Created().json(json!(saved_file)),
+        Err(error) => save_file_error_response(error, &data),
+    }
+}
+
+pub fn save_file_error_response(error: UserFileErrors, data: &AppData) -> HttpResponse {
+    match error {
+        UserFileErrors::Forbidden => HttpResponse::Forbidden().finish(),
+        UserFileErrors::InvalidBucket
+        | UserFileErrors::InvalidUpload
+        | UserFileErrors::InvalidPath
+        | UserFileErrors::InvalidName => HttpResponse::BadRequest().body(format!("{:?}", error)),
+        UserFileErrors::QuotaExceeded => HttpResponse::PayloadTooLarge()
+            .body("The uploaded file would exceed the bucket's maximum size."),
+        UserFileErrors::FileTooLarge => HttpResponse::PayloadTooLarge().body(format!(
+            "The uploaded file is too large. Maximum size is {} bytes.",
+            data.max_upload_size.unwrap_or_default()
+        )),
+        _ => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+// types a browser would run script from, they are always downloaded
+fn is_active_content(mime_type: &mime::Mime) -> bool {
+    let subtype = mime_type.subtype();
+
+    subtype == mime::HTML
+        || subtype == mime::XML
+        || subtype == mime::JAVASCRIPT
+        || subtype == "xhtml"
+        || subtype == "ecmascript"
+        || mime_type.suffix() == Some(mime::XML)
+}
+
+fn get_content_disposition(file_info: &UserFile, mime_type: &mime::Mime) -> ContentDisposition {
+    let disposition = match mime_type.type_() {
+        _ if is_active_content(mime_type) => DispositionType::Attachment,
+        mime::IMAGE | mime::TEXT | mime::AUDIO | mime::VIDEO => DispositionType::Inline,
+        _ if mime_type.subtype() == mime::PDF => DispositionType::Inline,
+        _ => DispositionType::Attachment,
     };
 
-    let user_id = req_user.unwrap().id;
+    let ascii_file_name = file_info
+        .file_name
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect::<String>();
 
-    let saved_file = save_user_file(&data.pg_conn, &data.data_path, &user_id, form.0).await;
+    let mut parameters = vec![DispositionParam::Filename(ascii_file_name)];
 
-    match saved_file {
-        Some(saved_file) => HttpResponse::Created().json(json!(saved_file)),
-        None => HttpResponse::InternalServerError().finish(),
+    if !file_info.file_name.is_ascii() {
+        parameters.push(DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext("UTF-8".to_owned()),
+            language_tag: None,
+            value: file_info.file_name.as_bytes().to_vec(),
+        }));
+    }
+
+    ContentDisposition {
+        disposition,
+        parameters,
     }
 }
 
+/// Streams a stored file with its recorded MIME type and original name,
+/// range requests are handled by `NamedFile`. Uploaded content is never
+/// sniffed and runs sandboxed, it can't act on the app's origin.
+pub async fn serve_user_file(
+    req: &HttpRequest,
+    file_info: &UserFile,
+    file_path: PathBuf,
+) -> HttpResponse {
+    let named_file = match NamedFile::open_async(file_path).await {
+        Ok(named_file) => named_file,
+        Err(error) => {
+            println!(
+                "error while opening file {} for download: {}",
+                file_info.file_id, error
+            );
+            return HttpResponse::Gone().finish();
+        }
+    };
+
+    let mime_type = file_info
+        .file_type
+        .parse::<mime::Mime>()
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+    let content_disposition = get_content_disposition(file_info, &mime_type);
+
+    let mut response = named_file
+        .set_content_type(mime_type)
+        .set_content_disposition(content_disposition)
+        .into_response(req);
+
+    let headers = response.headers_mut();
+    headers.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    headers.insert(
+        header::CONTENT_SECURITY_POLICY,
+        HeaderValue::from_static("sandbox"),
+    );
+
+    response
+}
+
 #[get("/{file_id}")]
 pub async fn get_file_by_id(
     req: HttpRequest,
@@ -87,16 +265,26 @@ pub async fn get_file_by_id(
     data: web::Data<AppData>,
     req_user: Option<ReqData<Claims>>,
 ) -> impl Responder {
-    let user_id = req_user.unwrap().id;
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesRead,
+        ApiKeyTarget::File(&file_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
 
-    let file_path =
+    let file_data =
         get_user_file_by_file_id(&data.pg_conn, &data.data_path, &user_id, &file_id).await;
 
-    match file_path {
-        Ok(file_path) => NamedFile::open_async(file_path)
-            .await
-            .unwrap()
-            .into_response(&req),
+    match file_data {
+        Ok((file_info, file_path)) => serve_user_file(&req, &file_info, file_path).await,
         Err(error) => match error {
             UserFileErrors::Forbidden => HttpResponse::Forbidden().finish(),
             UserFileErrors::NotFound => HttpResponse::NotFound().finish(),
@@ -112,23 +300,419 @@ pub async fn delete_file_by_id(
     data: web::Data<AppData>,
     req_user: Option<ReqData<Claims>>,
 ) -> impl Responder {
-    let user_id = req_user.unwrap().id;
+    let claims = req_user.unwrap();
 
-    let file_data =
-        delete_user_file_by_file_id(&data.pg_conn, &data.data_path, &user_id, &file_id).await;
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesWrite,
+        ApiKeyTarget::File(&file_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let file_data = delete_user_file_by_file_id(&data.pg_conn, &user_id, &file_id).await;
 
     match file_data {
-        Ok(is_deleted) => {
-            if is_deleted {
-                HttpResponse::NoContent().finish()
-            } else {
-                HttpResponse::Accepted().finish()
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(error) => match error {
+            UserFileErrors::Forbidden => HttpResponse::Forbidden().finish(),
+            UserFileErrors::NotFound => HttpResponse::NotFound().finish(),
+            UserFileErrors::Deleted => HttpResponse::Gone().finish(),
+            UserFileErrors::FailedToDelete => HttpResponse::InternalServerError().finish(),
+            _ => HttpResponse::InternalServerError().finish(),
+        },
+    }
+}
+
+#[get("/{file_id}/versions")]
+pub async fn get_all_file_versions(
+    file_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesRead,
+        ApiKeyTarget::File(&file_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let file_versions = get_file_versions(&data.pg_conn, &user_id, &file_id).await;
+
+    match file_versions {
+        Ok(file_versions) => HttpResponse::Ok().json(json!(file_versions)),
+        Err(error) => match error {
+            UserFileErrors::Forbidden => HttpResponse::Forbidden().finish(),
+            UserFileErrors::NotFound => HttpResponse::NotFound().finish(),
+            UserFileErrors::Deleted => HttpResponse::Gone().finish(),
+            _ => HttpResponse::InternalServerError().finish(),
+        },
+    }
+}
+
+#[post("/{file_id}/versions")]
+pub async fn save_new_file_version(
+    file_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    payload: Multipart,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesWrite,
+        ApiKeyTarget::File(&file_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let saved_file = save_file_version(
+        &data.pg_conn,
+        &data.data_path,
+        &user_id,
+        &file_id,
+        data.max_upload_size,
+        payload,
+    )
+    .await;
+
+    match saved_file {
+        Ok(saved_file) => HttpResponse::Created().json(json!(saved_file)),
+        Err(error) => match error {
+            UserFileErrors::Forbidden => HttpResponse::Forbidden().finish(),
+            UserFileErrors::NotFound => HttpResponse::NotFound().finish(),
+            UserFileErrors::Deleted => HttpResponse::Gone().finish(),
+            UserFileErrors::InvalidBucket | UserFileErrors::InvalidUpload => {
+                HttpResponse::BadRequest().body(format!("{:?}", error))
             }
+            UserFileErrors::QuotaExceeded => HttpResponse::PayloadTooLarge()
+                .body("The uploaded file would exceed the bucket's maximum size."),
+            UserFileErrors::FileTooLarge => HttpResponse::PayloadTooLarge().body(format!(
+                "The uploaded file is too large. Maximum size is {} bytes.",
+                data.max_upload_size.unwrap_or_default()
+            )),
+            _ => HttpResponse::InternalServerError().finish(),
+        },
+    }
+}
+
+#[get("/{file_id}/versions/{version_id}")]
+pub async fn get_file_version_by_id(
+    req: HttpRequest,
+    path: web::Path<(Uuid, Uuid)>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    let (file_id, version_id) = path.into_inner();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesRead,
+        ApiKeyTarget::File(&file_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let file_data = get_file_version(
+        &data.pg_conn,
+        &data.data_path,
+        &user_id,
+        &file_id,
+        &version_id,
+    )
+    .await;
+
+    match file_data {
+        Ok((file_info, file_path)) => serve_user_file(&req, &file_info, file_path).await,
+        Err(error) => match error {
+            UserFileErrors::Forbidden => HttpResponse::Forbidden().finish(),
+            UserFileErrors::NotFound => HttpResponse::NotFound().finish(),
+            UserFileErrors::Deleted => HttpResponse::Gone().finish(),
+            _ => HttpResponse::InternalServerError().finish(),
+        },
+    }
+}
+
+#[post("/{file_id}/versions/{version_id}/restore")]
+pub async fn restore_file_version_by_id(
+    path: web::Path<(Uuid, Uuid)>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    let (file_id, version_id) = path.into_inner();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesWrite,
+        ApiKeyTarget::File(&file_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let restored_file = restore_file_version(
+        &data.pg_conn,
+        &data.data_path,
+        &user_id,
+        &file_id,
+        &version_id,
+    )
+    .await;
+
+    match restored_file {
+        Ok(restored_file) => HttpResponse::Ok().json(json!(restored_file)),
+        Err(error) => match error {
+            UserFileErrors::Forbidden => HttpResponse::Forbidden().finish(),
+            UserFileErrors::NotFound => HttpResponse::NotFound().finish(),
+            UserFileErrors::Deleted => HttpResponse::Gone().finish(),
+            UserFileErrors::QuotaExceeded => HttpResponse::PayloadTooLarge()
+                .body("The restored version would exceed the bucket's maximum size."),
+            _ => HttpResponse::InternalServerError().finish(),
+        },
+    }
+}
+
+fn file_change_error_response(error: UserFileErrors) -> HttpResponse {
+    match error {
+        UserFileErrors::Forbidden => HttpResponse::Forbidden().finish(),
+        UserFileErrors::NotFound => HttpResponse::NotFound().finish(),
+        UserFileErrors::Deleted => HttpResponse::Gone().finish(),
+        UserFileErrors::InvalidBucket | UserFileErrors::InvalidPath => {
+            HttpResponse::BadRequest().body(format!("{:?}", error))
+        }
+        UserFileErrors::InvalidName => HttpResponse::BadRequest()
+            .body("File names can not be empty, longer than 255 bytes or contain slashes."),
+        UserFileErrors::QuotaExceeded => HttpResponse::PayloadTooLarge()
+            .body("The file would exceed the target bucket's maximum size."),
+        _ => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[patch("/{file_id}")]
+pub async fn update_file_by_id(
+    file_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    file_update: web::Json<FileUpdate>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesWrite,
+        ApiKeyTarget::File(&file_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    if let Some(bucket_id) = &file_update.bucket_id {
+        if let Some(response) = check_api_key_scope(
+            &data,
+            &claims,
+            ApiKeyScope::FilesWrite,
+            ApiKeyTarget::Bucket(bucket_id),
+        )
+        .await
+        {
+            return response;
         }
+    }
+
+    let user_id = claims.id;
+
+    let updated_file = update_user_file(&data.pg_conn, &user_id, &file_id, &file_update).await;
+
+    match updated_file {
+        Ok(updated_file) => HttpResponse::Ok().json(json!(updated_file)),
+        Err(error) => file_change_error_response(error),
+    }
+}
+
+#[post("/{file_id}/copy")]
+pub async fn copy_file_by_id(
+    file_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    file_copy: web::Json<FileCopy>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesRead,
+        ApiKeyTarget::File(&file_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let copy_target = match &file_copy.bucket_id {
+        Some(bucket_id) => ApiKeyTarget::Bucket(bucket_id),
+        None => ApiKeyTarget::File(&file_id),
+    };
+
+    if let Some(response) =
+        check_api_key_scope(&data, &claims, ApiKeyScope::FilesWrite, copy_target).await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let copied_file = copy_user_file(
+        &data.pg_conn,
+        &data.data_path,
+        &user_id,
+        &file_id,
+        &file_copy,
+    )
+    .await;
+
+    match copied_file {
+        Ok(copied_file) => HttpResponse::Created().json(json!(copied_file)),
+        Err(error) => file_change_error_response(error),
+    }
+}
+
+#[get("/{file_id}/share")]
+pub async fn get_file_shares_by_id(
+    file_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesRead,
+        ApiKeyTarget::File(&file_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let file_shares = get_file_shares(&data.pg_conn, &user_id, &file_id).await;
+
+    match file_shares {
+        Ok(file_shares) => HttpResponse::Ok().json(json!(file_shares)),
+        Err(error) => match error {
+            UserFileErrors::Forbidden => HttpResponse::Forbidden().finish(),
+            UserFileErrors::NotFound => HttpResponse::NotFound().finish(),
+            _ => HttpResponse::InternalServerError().finish(),
+        },
+    }
+}
+
+#[post("/{file_id}/share")]
+pub async fn share_file_by_id(
+    file_id: web::Path<Uuid>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    new_file_share: web::Json<NewFileShare>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesWrite,
+        ApiKeyTarget::File(&file_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let shared = share_user_file(&data.pg_conn, &user_id, &file_id, &new_file_share).await;
+
+    match shared {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(error) => match error {
+            UserFileErrors::Forbidden => HttpResponse::Forbidden().finish(),
+            UserFileErrors::NotFound => HttpResponse::NotFound().finish(),
+            UserFileErrors::Deleted => HttpResponse::Gone().finish(),
+            UserFileErrors::InvalidPermissions => HttpResponse::BadRequest().body(
+                "Permissions must include read (1) and only bits you hold yourself: \
+                read 1, write 2, delete 4, reshare 8.",
+            ),
+            UserFileErrors::InvalidShareUser => {
+                HttpResponse::BadRequest().body("The file can not be shared with this user.")
+            }
+            _ => HttpResponse::InternalServerError().finish(),
+        },
+    }
+}
+
+#[delete("/{file_id}/share/{user_id}")]
+pub async fn unshare_file_by_id(
+    path: web::Path<(Uuid, Uuid)>,
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+    let (file_id, share_user_id) = path.into_inner();
+
+    if let Some(response) = check_api_key_scope(
+        &data,
+        &claims,
+        ApiKeyScope::FilesWrite,
+        ApiKeyTarget::File(&file_id),
+    )
+    .await
+    {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let unshared = unshare_user_file(&data.pg_conn, &user_id, &file_id, &share_user_id).await;
+
+    match unshared {
+        Ok(_) => HttpResponse::NoContent().finish(),
         Err(error) => match error {
             UserFileErrors::Forbidden => HttpResponse::Forbidden().finish(),
             UserFileErrors::NotFound => HttpResponse::NotFound().finish(),
-            UserFileErrors::FailedToDelete => HttpResponse::InternalServerError().finish(),
             _ => HttpResponse::InternalServerError().finish(),
         },
     }
@@ -1,23 +1,48 @@
 use actix_web::{
     delete, get, post,
     web::{self, ReqData},
-    HttpResponse, Responder,
+    HttpRequest, HttpResponse, Responder,
 };
+use actix_web_httpauth::extractors::bearer::BearerAuth;
 use serde_json::json;
 
 use crate::{
     app_data::AppData,
-    models::user_info::{
-        delete_user, get_all_user_info, get_user_info_by_user_id, insert_user, login_user_by_email,
-        NewUser, NewUserError, UserError, UserLogin,
+    controlers::{admin::require_admin, api_key::reject_api_key, totp::totp_config},
+    models::{
+        account_deletion::{
+            cancel_account_deletion, get_account_deletion, request_account_deletion,
+            AccountDeletionErrors, AccountDeletionRequest,
+        },
+        auth_session::{
+            refresh_auth_session, revoke_access_token, revoke_auth_session,
+            revoke_refresh_token_session, AuthSessionErrors, RefreshTokenRequest,
+        },
+        email_verification::{
+            resend_verification_mail, send_verification_mail, verify_user_email, EmailVerification,
+            EmailVerificationErrors,
+        },
+        password_reset::{
+            request_password_reset, reset_user_passcode, PasswordReset, PasswordResetRequest,
+        },
+        user_info::{
+            change_user_passcode, get_all_user_info, get_user_info_by_user_id, insert_user,
+            login_user_by_email, LoginResponse, NewUser, NewUserError, PasscodeChange,
+            PasscodeError, UserError, UserLogin,
+        },
     },
-    utility::jwt_token::Claims,
+    utility::jwt_token::{validate_token, Claims},
 };
 
 pub fn user_info_config(config: &mut web::ServiceConfig) {
     let scope = web::scope("/user")
         .service(get_all_users)
         .service(get_login_user)
+        .service(change_password)
+        .service(resend_verification)
+        .configure(totp_config)
+        .service(get_deletion_status)
+        .service(cancel_deletion)
         .service(delete_user_data);
     // .service(user_login);
 
@@ -25,7 +50,14 @@ pub fn user_info_config(config: &mut web::ServiceConfig) {
 }
 
 #[get("/all")]
-pub async fn get_all_users(data: web::Data<AppData>) -> impl Responder {
+pub async fn get_all_users(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    if let Some(response) = require_admin(&data, &req_user.unwrap()).await {
+        return response;
+    }
+
     let users = get_all_user_info(&data.pg_conn).await;
 
     match users {
@@ -34,16 +66,80 @@ pub async fn get_all_users(data: web::Data<AppData>) -> impl Responder {
     }
 }
 
+fn account_deletion_error_response(error: AccountDeletionErrors) -> HttpResponse {
+    match error {
+        AccountDeletionErrors::NotFound => HttpResponse::NotFound().finish(),
+        AccountDeletionErrors::WrongPasscode => HttpResponse::Forbidden().body("Wrong password."),
+        AccountDeletionErrors::InvalidTransferUser => {
+            HttpResponse::BadRequest().body("transfer_to must be the email of another active user.")
+        }
+        AccountDeletionErrors::AlreadyStarted => {
+            HttpResponse::Conflict().body("The account is already being deleted.")
+        }
+        AccountDeletionErrors::Failed => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+/// Schedules the deletion of the account, see `request_account_deletion`.
 #[delete("/")]
 pub async fn delete_user_data(
     data: web::Data<AppData>,
     req_user: Option<ReqData<Claims>>,
+    account_deletion_request: web::Json<AccountDeletionRequest>,
 ) -> impl Responder {
-    let user_id = req_user.unwrap().id;
+    let claims = req_user.unwrap();
 
-    let _ = delete_user(&data.pg_conn, &data.data_path, &user_id).await;
+    if let Some(response) = reject_api_key(&claims) {
+        return response;
+    }
 
-    HttpResponse::Ok()
+    let account_deletion = request_account_deletion(
+        &data.pg_conn,
+        &data.mailer,
+        &claims.id,
+        &account_deletion_request,
+        data.account_deletion_grace_days,
+    )
+    .await;
+
+    match account_deletion {
+        Ok(account_deletion) => HttpResponse::Accepted().json(json!(account_deletion)),
+        Err(error) => account_deletion_error_response(error),
+    }
+}
+
+#[get("/deletion")]
+pub async fn get_deletion_status(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = reject_api_key(&claims) {
+        return response;
+    }
+
+    match get_account_deletion(&data.pg_conn, &claims.id).await {
+        Ok(account_deletion) => HttpResponse::Ok().json(json!(account_deletion)),
+        Err(error) => account_deletion_error_response(error),
+    }
+}
+
+#[delete("/deletion")]
+pub async fn cancel_deletion(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = reject_api_key(&claims) {
+        return response;
+    }
+
+    match cancel_account_deletion(&data.pg_conn, &claims.id).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(error) => account_deletion_error_response(error),
+    }
 }
 
 #[get("/")]
@@ -66,37 +162,274 @@ pub async fn register_user(
     data: web::Data<AppData>,
     new_user: web::Json<NewUser>,
 ) -> impl Responder {
-    let user = insert_user(&data.pg_conn, &data.data_path, &new_user).await;
+    let user = insert_user(
+        &data.pg_conn,
+        &data.data_path,
+        &new_user,
+        data.registration_mode,
+    )
+    .await;
 
     match user {
-        Ok(user) => HttpResponse::Ok().json(json!(user)),
+        Ok(user) => {
+            // the account exists either way, a failed mail can be sent again
+            let _sent = send_verification_mail(&data.pg_conn, &data.mailer, &user).await;
+
+            HttpResponse::Ok().json(json!(user))
+        }
         Err(error) => {
             println!("{:#?}", error);
             match error {
-                NewUserError::InvalidEmail => HttpResponse::BadRequest().body(stringify!(error)),
+                NewUserError::InvalidEmail => {
+                    HttpResponse::BadRequest().body("Invalid email address.")
+                }
+                NewUserError::WeakPasscode => HttpResponse::BadRequest().body(
+                    "The password needs 10 to 128 characters, with at least three of \
+                    lowercase letters, uppercase letters, digits and symbols.",
+                ),
+                NewUserError::DuplicateEmail => {
+                    HttpResponse::Conflict().body("This email address is already in use.")
+                }
+                NewUserError::DuplicateUserName => {
+                    HttpResponse::Conflict().body("This user name is already taken.")
+                }
+                NewUserError::InvalidInvite => {
+                    HttpResponse::Forbidden().body("A valid invite code is needed to register.")
+                }
+                NewUserError::RegistrationClosed => {
+                    HttpResponse::Forbidden().body("Registration is closed.")
+                }
+                NewUserError::Failed => HttpResponse::InternalServerError().finish(),
             }
         }
     }
 }
 
+fn email_verification_error_response(error: EmailVerificationErrors) -> HttpResponse {
+    match error {
+        EmailVerificationErrors::InvalidToken => {
+            HttpResponse::BadRequest().body("Invalid or expired verification token.")
+        }
+        EmailVerificationErrors::AlreadyVerified => {
+            HttpResponse::Conflict().body("The email address is already verified.")
+        }
+        EmailVerificationErrors::Failed => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[post("/verify")]
+pub async fn verify_email(
+    data: web::Data<AppData>,
+    email_verification: web::Json<EmailVerification>,
+) -> impl Responder {
+    let verified = verify_user_email(&data.pg_conn, &email_verification.token).await;
+
+    match verified {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(error) => email_verification_error_response(error),
+    }
+}
+
+#[post("/verify/resend")]
+pub async fn resend_verification(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = reject_api_key(&claims) {
+        return response;
+    }
+
+    let user_id = claims.id;
+
+    let sent = resend_verification_mail(&data.pg_conn, &data.mailer, &user_id).await;
+
+    match sent {
+        Ok(_) => HttpResponse::Accepted().finish(),
+        Err(error) => email_verification_error_response(error),
+    }
+}
+
+/// The address failed logins are counted for, proxy headers are only
+/// believed when TRUST_PROXY_HEADERS is set.
+fn get_client_ip(req: &HttpRequest, trust_proxy_headers: bool) -> String {
+    let ip_address = match trust_proxy_headers {
+        true => req
+            .connection_info()
+            .realip_remote_addr()
+            .map(|ip_address| ip_address.to_owned()),
+        false => req.peer_addr().map(|peer_addr| peer_addr.ip().to_string()),
+    };
+
+    ip_address.unwrap_or_else(|| "unknown".to_owned())
+}
+
 #[post("/login")]
 pub async fn user_login(
+    req: HttpRequest,
     data: web::Data<AppData>,
     login_user: web::Json<UserLogin>,
 ) -> impl Responder {
-    let token = login_user_by_email(&data.pg_conn, &login_user).await;
+    let ip_address = get_client_ip(&req, data.trust_proxy_headers);
 
-    match token {
-        Ok(token) => HttpResponse::Ok()
-            .append_header(("Authorization", "Bearer ".to_owned() + &token))
-            .json(json!(token)),
+    let login_response = login_user_by_email(
+        &data.pg_conn,
+        &login_user,
+        &ip_address,
+        data.refresh_token_days,
+    )
+    .await;
+
+    match login_response {
+        Ok(LoginResponse::Tokens(auth_tokens)) => HttpResponse::Ok()
+            .append_header((
+                "Authorization",
+                "Bearer ".to_owned() + &auth_tokens.access_token,
+            ))
+            .json(json!(auth_tokens)),
+        Ok(LoginResponse::TotpChallenge(totp_challenge)) => {
+            HttpResponse::Ok().json(json!(totp_challenge))
+        }
         Err(error) => {
             println!("{:#?}", error);
             match error {
+                // the same answer, so it doesn't tell whether the email exists
                 UserError::InvalidEmail | UserError::WrongPasscode => {
-                    HttpResponse::BadRequest().body(stringify!(e))
+                    HttpResponse::Unauthorized().body("Invalid email or password.")
                 }
+                UserError::Disabled => HttpResponse::Forbidden().body("This account is disabled."),
+                UserError::Locked(retry_after) => HttpResponse::TooManyRequests()
+                    .append_header(("Retry-After", retry_after.to_string()))
+                    .body("Too many failed logins, try again later."),
+                UserError::Failed => HttpResponse::InternalServerError().finish(),
             }
         }
     }
 }
+
+#[post("/refresh")]
+pub async fn refresh_token(
+    data: web::Data<AppData>,
+    refresh_token_request: web::Json<RefreshTokenRequest>,
+) -> impl Responder {
+    let auth_tokens = refresh_auth_session(
+        &data.pg_conn,
+        &refresh_token_request.refresh_token,
+        data.refresh_token_days,
+    )
+    .await;
+
+    match auth_tokens {
+        Ok(auth_tokens) => HttpResponse::Ok()
+            .append_header((
+                "Authorization",
+                "Bearer ".to_owned() + &auth_tokens.access_token,
+            ))
+            .json(json!(auth_tokens)),
+        Err(AuthSessionErrors::InvalidToken) | Err(AuthSessionErrors::TokenReused) => {
+            HttpResponse::Unauthorized().body("Invalid refresh token.")
+        }
+        Err(AuthSessionErrors::Failed) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+/// Ends the session of the given access token and/or refresh token, the
+/// access token is also put on the denylist.
+#[post("/logout")]
+pub async fn logout_user(
+    data: web::Data<AppData>,
+    credentials: Option<BearerAuth>,
+    refresh_token_request: Option<web::Json<RefreshTokenRequest>>,
+) -> impl Responder {
+    let claims = credentials.and_then(|credentials| validate_token(credentials.token()).ok());
+
+    if claims.is_none() && refresh_token_request.is_none() {
+        return HttpResponse::Unauthorized().body("A valid access or refresh token is needed.");
+    }
+
+    let mut logged_out = Ok(());
+
+    if let Some(claims) = &claims {
+        logged_out = logged_out.and(revoke_access_token(&data.pg_conn, claims).await);
+        logged_out = logged_out.and(revoke_auth_session(&data.pg_conn, &claims.sid).await);
+    }
+
+    if let Some(refresh_token_request) = &refresh_token_request {
+        logged_out = logged_out.and(
+            revoke_refresh_token_session(&data.pg_conn, &refresh_token_request.refresh_token).await,
+        );
+    }
+
+    match logged_out {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(AuthSessionErrors::InvalidToken) | Err(AuthSessionErrors::TokenReused) => {
+            HttpResponse::Unauthorized().body("Invalid refresh token.")
+        }
+        Err(AuthSessionErrors::Failed) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+fn passcode_error_response(error: PasscodeError) -> HttpResponse {
+    match error {
+        PasscodeError::WrongPasscode => HttpResponse::Forbidden().body("Wrong current password."),
+        PasscodeError::InvalidPasscode => HttpResponse::BadRequest().body("Invalid new password."),
+        PasscodeError::InvalidToken => {
+            HttpResponse::BadRequest().body("Invalid or expired reset token.")
+        }
+        PasscodeError::Failed => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[post("/password")]
+pub async fn change_password(
+    data: web::Data<AppData>,
+    req_user: Option<ReqData<Claims>>,
+    passcode_change: web::Json<PasscodeChange>,
+) -> impl Responder {
+    let claims = req_user.unwrap();
+
+    if let Some(response) = reject_api_key(&claims) {
+        return response;
+    }
+
+    let changed =
+        change_user_passcode(&data.pg_conn, &claims.id, &claims.sid, &passcode_change).await;
+
+    match changed {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(error) => passcode_error_response(error),
+    }
+}
+
+#[post("/password/forgot")]
+pub async fn forgot_password(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    password_reset_request: web::Json<PasswordResetRequest>,
+) -> impl Responder {
+    let pool = data.pg_conn.clone();
+    let mailer = data.mailer.clone();
+    let email = password_reset_request.into_inner().email;
+    let ip_address = get_client_ip(&req, data.trust_proxy_headers);
+
+    // handled after the response, so it is the same for every email
+    actix_web::rt::spawn(async move {
+        let _requested = request_password_reset(&pool, &mailer, &email, &ip_address).await;
+    });
+
+    HttpResponse::Accepted().finish()
+}
+
+#[post("/password/reset")]
+pub async fn reset_password(
+    data: web::Data<AppData>,
+    password_reset: web::Json<PasswordReset>,
+) -> impl Responder {
+    let reset = reset_user_passcode(&data.pg_conn, &password_reset).await;
+
+    match reset {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(error) => passcode_error_response(error),
+    }
+}
@@ -0,0 +1,96 @@
+use actix_web::rt::time::interval;
+use sqlx::PgPool;
+use std::time::Duration;
+
+use crate::models::{
+    account_deletion::run_due_account_deletions, auth_session::purge_expired_tokens,
+    file_version::purge_expired_file_versions, login_attempt::purge_old_login_attempts,
+    trash::purge_expired_trash, upload_session::purge_expired_upload_sessions,
+};
+
+// how often the background jobs wake up to look for work
+const JOB_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+pub async fn purge_expired_upload_sessions_job(pool: PgPool, data_path: String) {
+    let mut interval = interval(JOB_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let purged = purge_expired_upload_sessions(&pool, &data_path).await;
+
+        if purged > 0 {
+            println!("purged {} expired upload sessions", purged);
+        }
+    }
+}
+
+pub async fn purge_expired_file_versions_job(pool: PgPool, data_path: String) {
+    let mut interval = interval(JOB_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let purged = purge_expired_file_versions(&pool, &data_path).await;
+
+        if purged > 0 {
+            println!("purged {} expired file versions", purged);
+        }
+    }
+}
+
+pub async fn purge_expired_trash_job(pool: PgPool, data_path: String, trash_retention_days: i64) {
+    let mut interval = interval(JOB_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let purged = purge_expired_trash(&pool, &data_path, trash_retention_days).await;
+
+        if purged > 0 {
+            println!("purged {} files from the trash", purged);
+        }
+    }
+}
+
+pub async fn purge_expired_tokens_job(pool: PgPool) {
+    let mut interval = interval(JOB_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let purged = purge_expired_tokens(&pool).await;
+
+        if purged > 0 {
+            println!("purged {} expired tokens and sessions", purged);
+        }
+    }
+}
+
+pub async fn purge_old_login_attempts_job(pool: PgPool) {
+    let mut interval = interval(JOB_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let purged = purge_old_login_attempts(&pool).await;
+
+        if purged > 0 {
+            println!("purged {} old login attempts", purged);
+        }
+    }
+}
+
+pub async fn run_account_deletions_job(pool: PgPool, data_path: String) {
+    let mut interval = interval(JOB_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let deleted = run_due_account_deletions(&pool, &data_path).await;
+
+        if deleted > 0 {
+            println!("deleted {} accounts", deleted);
+        }
+    }
+}
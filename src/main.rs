@@ -4,14 +4,29 @@ use actix_web_httpauth::middleware::HttpAuthentication;
 use dotenv::dotenv;
 use sqlx::{self, Pool, Postgres};
 use std::env::var;
+use std::sync::Arc;
 
+use crate::controlers::admin::admin_config;
+use crate::controlers::api_key::api_key_config;
 use crate::controlers::bucket::bucket_config;
+use crate::controlers::folder::folder_config;
+use crate::controlers::public_link::{public_download_config, public_link_config};
+use crate::controlers::public_share::{public_share_config, public_share_page_config};
+use crate::controlers::signed_url::{signed_access_config, signed_url_config};
+use crate::controlers::totp::totp_login;
+use crate::controlers::trash::trash_config;
+use crate::controlers::upload::upload_config;
 use crate::controlers::user_file::user_file_config;
 use crate::controlers::user_info::*;
-use crate::middlewares::auth::jwt_validator;
+use crate::middlewares::auth::{jwt_validator, signed_url_validator};
+use crate::models::admin::make_user_admin;
+use crate::models::blob::migrate_legacy_files;
+use crate::models::invite::RegistrationMode;
+use crate::utility::mailer::{FileMailer, LogMailer, Mailer, SmtpMailer};
 
 mod app_data;
 mod controlers;
+mod jobs;
 mod middlewares;
 mod models;
 mod utility;
@@ -35,6 +50,46 @@ async fn db_connection() -> Pool<Postgres> {
     pool
 }
 
+fn mailer() -> Arc<dyn Mailer> {
+    match var("MAILER").as_deref() {
+        Ok("smtp") => {
+            let host =
+                var("SMTP_HOST").expect("Couldn't find SMTP_HOST from environment variable.");
+            let tls = var("SMTP_TLS").unwrap_or("starttls".to_owned());
+            let port = var("SMTP_PORT")
+                .map(|port| {
+                    port.parse::<u16>()
+                        .expect("SMTP_PORT should be a port number.")
+                })
+                .unwrap_or(if tls == "tls" { 465 } else { 587 });
+            let credentials = match (var("SMTP_USERNAME"), var("SMTP_PASSWORD")) {
+                (Ok(username), Ok(password)) => Some((username, password)),
+                _ => None,
+            };
+            let from =
+                var("MAIL_FROM").expect("Couldn't find MAIL_FROM from environment variable.");
+
+            Arc::new(SmtpMailer::new(&host, port, &tls, credentials, &from))
+        }
+        Ok("file") => Arc::new(FileMailer {
+            mail_dir: var("MAIL_DIR")
+                .expect("Couldn't find MAIL_DIR from environment variable.")
+                .into(),
+        }),
+        Ok("log") | Err(_) => Arc::new(LogMailer),
+        Ok(_) => panic!("MAILER should be one of smtp, file or log."),
+    }
+}
+
+fn registration_mode() -> RegistrationMode {
+    match var("REGISTRATION_MODE").as_deref() {
+        Ok("open") | Err(_) => RegistrationMode::Open,
+        Ok("invite") => RegistrationMode::InviteOnly,
+        Ok("closed") => RegistrationMode::Closed,
+        Ok(_) => panic!("REGISTRATION_MODE should be one of open, invite or closed."),
+    }
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
@@ -42,17 +97,106 @@ async fn main() -> std::io::Result<()> {
     std::env::set_var("RUST_LOG", "actix_web=info");
     env_logger::init();
 
+    // `home-file-server make-admin <email>` promotes a user and exits
+    if let [_, command, email] = &std::env::args().collect::<Vec<String>>()[..] {
+        if command == "make-admin" {
+            match make_user_admin(&db_connection().await, email).await {
+                Ok(user) => println!("{} is now an admin.", user.email),
+                Err(error) => {
+                    println!("Couldn't make {} an admin: {:?}", email, error);
+                    std::process::exit(1);
+                }
+            }
+            return Ok(());
+        }
+    }
+
     let data_path = var("DATA_PATH").expect("Couldn't find DATA_PATH from environment variable.");
+    let max_upload_size = var("MAX_UPLOAD_SIZE").ok().map(|max_upload_size| {
+        max_upload_size
+            .parse::<i64>()
+            .expect("MAX_UPLOAD_SIZE should be a number of bytes.")
+    });
+    let upload_expiry_hours = var("UPLOAD_EXPIRY_HOURS")
+        .map(|upload_expiry_hours| {
+            upload_expiry_hours
+                .parse::<i64>()
+                .expect("UPLOAD_EXPIRY_HOURS should be a number of hours.")
+        })
+        .unwrap_or(24);
+    let trash_retention_days = var("TRASH_RETENTION_DAYS")
+        .map(|trash_retention_days| {
+            trash_retention_days
+                .parse::<i64>()
+                .expect("TRASH_RETENTION_DAYS should be a number of days.")
+        })
+        .unwrap_or(30);
+    let refresh_token_days = var("REFRESH_TOKEN_DAYS")
+        .map(|refresh_token_days| {
+            refresh_token_days
+                .parse::<i64>()
+                .expect("REFRESH_TOKEN_DAYS should be a number of days.")
+        })
+        .unwrap_or(30);
+    let account_deletion_grace_days = var("ACCOUNT_DELETION_GRACE_DAYS")
+        .map(|account_deletion_grace_days| {
+            account_deletion_grace_days
+                .parse::<i64>()
+                .expect("ACCOUNT_DELETION_GRACE_DAYS should be a number of days.")
+        })
+        .unwrap_or(7);
+    // only set behind a reverse proxy, clients could fake their address otherwise
+    let trust_proxy_headers = var("TRUST_PROXY_HEADERS")
+        .map(|trust_proxy_headers| trust_proxy_headers == "true")
+        .unwrap_or(false);
 
     println!("Starting web server.");
 
     let app_data_var = app_data::AppData {
         pg_conn: db_connection().await,
         data_path,
+        max_upload_size,
+        upload_expiry_hours,
+        trash_retention_days,
+        refresh_token_days,
+        account_deletion_grace_days,
+        registration_mode: registration_mode(),
+        trust_proxy_headers,
+        mailer: mailer(),
     };
 
+    migrate_legacy_files(&app_data_var.pg_conn, &app_data_var.data_path).await;
+
+    actix_web::rt::spawn(jobs::purge_expired_upload_sessions_job(
+        app_data_var.pg_conn.clone(),
+        app_data_var.data_path.clone(),
+    ));
+
+    actix_web::rt::spawn(jobs::purge_expired_file_versions_job(
+        app_data_var.pg_conn.clone(),
+        app_data_var.data_path.clone(),
+    ));
+
+    actix_web::rt::spawn(jobs::purge_expired_trash_job(
+        app_data_var.pg_conn.clone(),
+        app_data_var.data_path.clone(),
+        app_data_var.trash_retention_days,
+    ));
+
+    actix_web::rt::spawn(jobs::purge_expired_tokens_job(app_data_var.pg_conn.clone()));
+
+    actix_web::rt::spawn(jobs::purge_old_login_attempts_job(
+        app_data_var.pg_conn.clone(),
+    ));
+
+    actix_web::rt::spawn(jobs::run_account_deletions_job(
+        app_data_var.pg_conn.clone(),
+        app_data_var.data_path.clone(),
+    ));
+
     HttpServer::new(move || {
         let bearer_middleware = HttpAuthentication::bearer(jwt_validator);
+        let signed_url_middleware = HttpAuthentication::with_fn(signed_url_validator);
 
         App::new()
             .app_data(web::Data::new(app_data_var.clone()))
@@ -60,13 +204,34 @@ async fn main() -> std::io::Result<()> {
             .service(
                 web::scope("/api/auth")
                     .service(user_login)
-                    .service(register_user),
+                    .service(register_user)
+                    .service(refresh_token)
+                    .service(logout_user)
+                    .service(forgot_password)
+                    .service(reset_password)
+                    .service(verify_email)
+                    .service(totp_login),
+            )
+            .configure(public_download_config)
+            .configure(public_share_page_config)
+            .service(
+                web::scope("/signed")
+                    .wrap(signed_url_middleware)
+                    .configure(signed_access_config),
             )
             .service(
                 web::scope("/api")
                     .wrap(bearer_middleware)
                     .configure(user_info_config)
+                    .configure(api_key_config)
+                    .configure(admin_config)
                     .configure(user_file_config)
+                    .configure(trash_config)
+                    .configure(folder_config)
+                    .configure(public_link_config)
+                    .configure(public_share_config)
+                    .configure(signed_url_config)
+                    .configure(upload_config)
                     .configure(bucket_config),
             )
             .wrap(Logger::default())
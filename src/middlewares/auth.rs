@@ -1,22 +1,74 @@
-use actix_web::{dev::ServiceRequest, Error, HttpMessage};
+use actix_web::{
+    dev::ServiceRequest,
+    error::{ErrorForbidden, ErrorUnauthorized},
+    web, Error, HttpMessage,
+};
 use actix_web_httpauth::extractors::{
     bearer::{self, BearerAuth},
     AuthenticationError,
 };
+use sqlx::PgPool;
+
+use crate::{
+    app_data::AppData,
+    models::{
+        api_key::{authenticate_api_key, API_KEY_PREFIX},
+        auth_session::is_token_revoked,
+        email_verification::EMAIL_VERIFICATION_RESEND_PATH,
+        signed_url::get_url_signing_generation,
+    },
+    utility::{
+        jwt_token::{get_api_key_claims, validate_token, Claims},
+        signed_url::{verify_signed_url, SignedUrlError, SignedUrlQuery, SignedUrlUser},
+    },
+};
 
-use crate::utility::jwt_token::validate_token;
+async fn validate_access_token(pool: &PgPool, jwt_token: &str) -> Option<Claims> {
+    let claims = validate_token(jwt_token).ok()?;
 
+    // a revoked token is rejected, and so is any token when the denylist
+    // can't be checked
+    match is_token_revoked(pool, &claims).await {
+        Ok(false) => Some(claims),
+        _ => None,
+    }
+}
+
+async fn validate_api_key(pool: &PgPool, api_key: &str) -> Option<Claims> {
+    match authenticate_api_key(pool, api_key).await {
+        Ok(Some((user_info, api_key))) => Some(get_api_key_claims(&user_info, api_key)),
+        Ok(None) => None,
+        Err(error) => {
+            println!("error while checking an api key: {}", error);
+            None
+        }
+    }
+}
+
+/// Accepts an access token or a personal API key as the bearer token.
 pub async fn jwt_validator(
     req: ServiceRequest,
     credentials: BearerAuth,
 ) -> Result<ServiceRequest, (Error, ServiceRequest)> {
-    let jwt_token = credentials.token();
-    match validate_token(jwt_token) {
-        Ok(token) => {
-            req.extensions_mut().insert(token);
+    let token = credentials.token();
+
+    let claims = match req.app_data::<web::Data<AppData>>() {
+        Some(data) if token.starts_with(API_KEY_PREFIX) => {
+            validate_api_key(&data.pg_conn, token).await
+        }
+        Some(data) => validate_access_token(&data.pg_conn, token).await,
+        None => None,
+    };
+
+    match claims {
+        Some(claims) if !claims.email_verified && req.path() != EMAIL_VERIFICATION_RESEND_PATH => {
+            Err((ErrorForbidden("Verify your email address first."), req))
+        }
+        Some(claims) => {
+            req.extensions_mut().insert(claims);
             Ok(req)
         }
-        Err(_) => {
+        _ => {
             let config = req
                 .app_data::<bearer::Config>()
                 .cloned()
@@ -26,3 +78,43 @@ pub async fn jwt_validator(
         }
     }
 }
+
+/// Checks the HMAC of a pre-signed URL against the request method and path
+/// and the URL signing generation of its user, the user it was signed for
+/// is inserted as a `SignedUrlUser`.
+pub async fn signed_url_validator(
+    req: ServiceRequest,
+    signed_url: web::Query<SignedUrlQuery>,
+) -> Result<ServiceRequest, (Error, ServiceRequest)> {
+    let generation = match req.app_data::<web::Data<AppData>>() {
+        Some(data) => get_url_signing_generation(&data.pg_conn, &signed_url.user_id).await,
+        None => Ok(None),
+    };
+
+    let verified = match generation {
+        Ok(Some(generation)) => {
+            verify_signed_url(req.method().as_str(), req.path(), &signed_url, generation)
+        }
+        // unknown and disabled users look like a wrong signature
+        Ok(None) => Err(SignedUrlError::InvalidSignature),
+        Err(error) => {
+            println!("error while checking a signed URL: {}", error);
+            Err(SignedUrlError::InvalidSignature)
+        }
+    };
+
+    match verified {
+        Ok(user_id) => {
+            req.extensions_mut().insert(SignedUrlUser { id: user_id });
+            Ok(req)
+        }
+        Err(error) => {
+            println!("SignedUrlError: {:?}", error);
+            let error = match error {
+                SignedUrlError::Expired => ErrorForbidden("This URL has expired."),
+                SignedUrlError::InvalidSignature => ErrorUnauthorized("Invalid URL signature."),
+            };
+            Err((error, req))
+        }
+    }
+}
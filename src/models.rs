@@ -1,3 +1,21 @@
+pub mod account_deletion;
+pub mod admin;
+pub mod api_key;
+pub mod auth_session;
+pub mod blob;
 pub mod bucket;
+pub mod email_verification;
+pub mod file_user;
+pub mod file_version;
+pub mod folder;
+pub mod invite;
+pub mod login_attempt;
+pub mod password_reset;
+pub mod public_link;
+pub mod public_share;
+pub mod signed_url;
+pub mod totp;
+pub mod trash;
+pub mod upload_session;
 pub mod user_file;
 pub mod user_info;
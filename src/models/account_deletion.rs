@@ -0,0 +1,505 @@
+use ::serde::{Deserialize, Serialize};
+use chrono::{Duration, NaiveDateTime, Utc};
+use sqlx::{self, FromRow, PgPool};
+use std::{fs, sync::Arc};
+use uuid::Uuid;
+
+use crate::utility::mailer::{send_mail, Mail, Mailer};
+
+use super::{
+    auth_session::revoke_user_sessions,
+    bucket::get_bucket_folder_path,
+    upload_session::get_upload_path,
+    user_file::{delete_user_file_record, UserFile, USER_FILE_SELECT},
+    user_info::{check_user_passcode, get_user_by_email, get_user_info_by_user_id, PasscodeError},
+};
+
+/*
+  deleting an account is a job, not a single request. A user asks for it
+  with their passcode, which logs them out everywhere and schedules the
+  deletion after a grace period, logging in again they can still cancel it.
+  When it is due the account is disabled and deleted step by step:
+
+  1. shared buckets go to the user picked for the transfer, if any
+  2. files the user uploaded to other people's buckets go to their owners
+  3. open uploads and every file in the remaining buckets are removed,
+     releasing their blobs
+  4. the buckets and the user row go in one transaction
+
+  every step can run again, so a failed deletion is retried by the job
+  and picks up where it stopped, the error is kept in `last_error`.
+*/
+
+#[derive(Debug, Deserialize)]
+pub struct AccountDeletionRequest {
+    pub passcode: String,
+    // email of the user who gets the buckets shared with others
+    pub transfer_to: Option<String>,
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct AccountDeletion {
+    pub user_id: Uuid,
+    pub transfer_to: Option<Uuid>,
+    pub scheduled_date: NaiveDateTime,
+    pub started_date: Option<NaiveDateTime>,
+    pub completed_date: Option<NaiveDateTime>,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub created_date: NaiveDateTime,
+}
+
+#[derive(Debug)]
+pub enum AccountDeletionErrors {
+    NotFound,
+    WrongPasscode,
+    InvalidTransferUser,
+    // the deletion is already running and can't be changed anymore
+    AlreadyStarted,
+    Failed,
+}
+
+fn map_account_deletion_error(error: sqlx::Error) -> AccountDeletionErrors {
+    println!("error while updating account deletions: {}", error);
+    AccountDeletionErrors::Failed
+}
+
+/// Schedules the deletion, or moves a pending one, and logs the user out
+/// everywhere. Their API keys are removed as well.
+async fn schedule_account_deletion(
+    pool: &PgPool,
+    user_id: &Uuid,
+    transfer_to: Option<&Uuid>,
+    scheduled_date: NaiveDateTime,
+) -> Result<AccountDeletion, AccountDeletionErrors> {
+    let scheduled = async {
+        let mut transaction = pool.begin().await?;
+
+        let query = "INSERT INTO accountdeletion (user_id, transfer_to, scheduled_date) \
+            VALUES($1, $2, $3) ON CONFLICT (user_id) DO UPDATE \
+            SET transfer_to = $2, scheduled_date = $3 \
+            WHERE accountdeletion.started_date IS NULL RETURNING *";
+
+        let account_deletion = sqlx::query_as::<_, AccountDeletion>(query)
+            .bind(user_id)
+            .bind(transfer_to)
+            .bind(scheduled_date)
+            .fetch_optional(&mut *transaction)
+            .await?;
+
+        revoke_user_sessions(&mut transaction, user_id, None).await?;
+
+        let query = "DELETE FROM apikey WHERE user_id = $1";
+
+        sqlx::query(query)
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+
+        Ok::<Option<AccountDeletion>, sqlx::Error>(account_deletion)
+    }
+    .await;
+
+    scheduled
+        .map_err(map_account_deletion_error)?
+        .ok_or(AccountDeletionErrors::AlreadyStarted)
+}
+
+/// Schedules the deletion of the account of a logged in user after
+/// `grace_days`, the user is told by mail.
+pub async fn request_account_deletion(
+    pool: &PgPool,
+    mailer: &Arc<dyn Mailer>,
+    user_id: &Uuid,
+    account_deletion_request: &AccountDeletionRequest,
+    grace_days: i64,
+) -> Result<AccountDeletion, AccountDeletionErrors> {
+    match check_user_passcode(pool, user_id, &account_deletion_request.passcode).await {
+        Ok(_) => (),
+        Err(PasscodeError::WrongPasscode) => return Err(AccountDeletionErrors::WrongPasscode),
+        Err(_) => return Err(AccountDeletionErrors::Failed),
+    }
+
+    let user_info = get_user_info_by_user_id(pool, user_id)
+        .await
+        .ok_or(AccountDeletionErrors::NotFound)?;
+
+    let transfer_to = match &account_deletion_request.transfer_to {
+        Some(email) => match get_user_by_email(pool, email).await {
+            Some(transfer_user)
+                if transfer_user.user_id != user_info.user_id
+                    && transfer_user.disabled_date.is_none() =>
+            {
+                Some(transfer_user.user_id)
+            }
+            _ => return Err(AccountDeletionErrors::InvalidTransferUser),
+        },
+        None => None,
+    };
+
+    let scheduled_date = (Utc::now() + Duration::days(grace_days)).naive_utc();
+
+    let account_deletion =
+        schedule_account_deletion(pool, user_id, transfer_to.as_ref(), scheduled_date).await?;
+
+    let mail = Mail {
+        to: user_info.email,
+        subject: "Your account will be deleted".to_owned(),
+        body: format!(
+            "Hi {},\n\nyour account and all of its files will be deleted on {} UTC. \
+            To keep it, log in and cancel the deletion with a DELETE request to \
+            /api/user/deletion before then.",
+            user_info.user_name,
+            scheduled_date.format("%Y-%m-%d %H:%M")
+        ),
+    };
+
+    // the deletion is scheduled either way, the status can be checked later
+    let _sent = send_mail(mailer, mail).await;
+
+    Ok(account_deletion)
+}
+
+pub async fn get_account_deletion(
+    pool: &PgPool,
+    user_id: &Uuid,
+) -> Result<AccountDeletion, AccountDeletionErrors> {
+    let query = "SELECT * FROM accountdeletion WHERE user_id = $1";
+
+    sqlx::query_as::<_, AccountDeletion>(query)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(map_account_deletion_error)?
+        .ok_or(AccountDeletionErrors::NotFound)
+}
+
+/// Cancels a deletion that didn't start yet.
+pub async fn cancel_account_deletion(
+    pool: &PgPool,
+    user_id: &Uuid,
+) -> Result<(), AccountDeletionErrors> {
+    let account_deletion = get_account_deletion(pool, user_id).await?;
+
+    if account_deletion.started_date.is_some() {
+        return Err(AccountDeletionErrors::AlreadyStarted);
+    }
+
+    let query = "DELETE FROM accountdeletion WHERE user_id = $1 AND started_date IS NULL";
+
+    let deleted = sqlx::query(query)
+        .bind(user_id)
+        .execute(pool)
+        .await
+        .map_err(map_account_deletion_error)?;
+
+    match deleted.rows_affected() {
+        0 => Err(AccountDeletionErrors::AlreadyStarted),
+        _ => Ok(()),
+    }
+}
+
+/// Deletes an account right away, used by admins. A pending deletion the
+/// user asked for is run now, keeping its transfer.
+pub async fn delete_account_now(
+    pool: &PgPool,
+    data_path: &str,
+    user_id: &Uuid,
+) -> Result<AccountDeletion, AccountDeletionErrors> {
+    let transfer_to = match get_account_deletion(pool, user_id).await {
+        Ok(account_deletion) => account_deletion.transfer_to,
+        Err(AccountDeletionErrors::NotFound) => None,
+        Err(error) => return Err(error),
+    };
+
+    let scheduled_date = Utc::now().naive_utc();
+
+    match schedule_account_deletion(pool, user_id, transfer_to.as_ref(), scheduled_date).await {
+        Ok(_) | Err(AccountDeletionErrors::AlreadyStarted) => (),
+        Err(error) => return Err(error),
+    }
+
+    // a failed run is kept with its error and retried by the job
+    let _ = run_account_deletion(pool, data_path, user_id).await;
+
+    get_account_deletion(pool, user_id).await
+}
+
+/// Hands the buckets of the user that are shared with others to
+/// `transfer_to`, who stops being a member of them.
+async fn transfer_shared_buckets(
+    pool: &PgPool,
+    user_id: &Uuid,
+    transfer_to: &Uuid,
+) -> Result<(), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let query = "UPDATE bucket b SET user_id = $2 WHERE b.user_id = $1 \
+        AND EXISTS (SELECT 1 FROM bucketusers bu WHERE bu.bucket_id = b.bucket_id) \
+        RETURNING b.bucket_id";
+
+    let bucket_ids = sqlx::query_scalar::<_, Uuid>(query)
+        .bind(user_id)
+        .bind(transfer_to)
+        .fetch_all(&mut *transaction)
+        .await?;
+
+    let query = "DELETE FROM bucketusers WHERE user_id = $1 AND bucket_id = ANY($2)";
+
+    sqlx::query(query)
+        .bind(transfer_to)
+        .bind(&bucket_ids)
+        .execute(&mut *transaction)
+        .await?;
+
+    let query = "UPDATE bucket b SET is_shared = EXISTS ( \
+        SELECT 1 FROM bucketusers bu WHERE bu.bucket_id = b.bucket_id \
+    ) WHERE b.bucket_id = ANY($1)";
+
+    sqlx::query(query)
+        .bind(&bucket_ids)
+        .execute(&mut *transaction)
+        .await?;
+
+    let query = "UPDATE userfile SET user_id = $2 WHERE user_id = $1 AND bucket_id = ANY($3)";
+
+    sqlx::query(query)
+        .bind(user_id)
+        .bind(transfer_to)
+        .bind(&bucket_ids)
+        .execute(&mut *transaction)
+        .await?;
+
+    transaction.commit().await?;
+
+    println!(
+        "moved {} shared buckets of user {} to user {}",
+        bucket_ids.len(),
+        user_id,
+        transfer_to
+    );
+
+    Ok(())
+}
+
+async fn delete_account_data(
+    pool: &PgPool,
+    data_path: &str,
+    account_deletion: &AccountDeletion,
+) -> Result<(), sqlx::Error> {
+    let user_id = &account_deletion.user_id;
+
+    if let Some(transfer_to) = &account_deletion.transfer_to {
+        transfer_shared_buckets(pool, user_id, transfer_to).await?;
+    }
+
+    let query = "UPDATE userfile uf SET user_id = b.user_id FROM bucket b \
+        WHERE b.bucket_id = uf.bucket_id AND uf.user_id = $1 AND b.user_id <> $1";
+
+    sqlx::query(query).bind(user_id).execute(pool).await?;
+
+    let query = "DELETE FROM uploadsession WHERE user_id = $1 \
+        OR bucket_id IN (SELECT bucket_id FROM bucket WHERE user_id = $1) RETURNING upload_id";
+
+    let upload_ids = sqlx::query_scalar::<_, Uuid>(query)
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+
+    for upload_id in &upload_ids {
+        let _ = fs::remove_file(get_upload_path(data_path, upload_id));
+    }
+
+    let query = format!(
+        "{} WHERE uf.bucket_id IN (SELECT bucket_id FROM bucket WHERE user_id = $1)",
+        USER_FILE_SELECT
+    );
+
+    let files = sqlx::query_as::<_, UserFile>(&query)
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+
+    // each file goes in its own transaction, a retry skips the deleted ones
+    for file_info in &files {
+        delete_user_file_record(pool, data_path, file_info).await?;
+    }
+
+    let mut transaction = pool.begin().await?;
+
+    let query = "DELETE FROM bucket WHERE user_id = $1 RETURNING bucket_name";
+
+    let bucket_names = sqlx::query_scalar::<_, String>(query)
+        .bind(user_id)
+        .fetch_all(&mut *transaction)
+        .await?;
+
+    // the memberships of the user go with it, the buckets and files they
+    // were shared with may not be shared anymore
+    let query = "UPDATE bucket b SET is_shared = EXISTS ( \
+        SELECT 1 FROM bucketusers bu WHERE bu.bucket_id = b.bucket_id AND bu.user_id <> $1 \
+    ) WHERE b.bucket_id IN (SELECT bucket_id FROM bucketusers WHERE user_id = $1)";
+
+    sqlx::query(query)
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    let query = r#"UPDATE userfile uf SET is_shared = EXISTS (
+        SELECT 1 FROM "FileUsers" fu WHERE fu.file_id = uf.file_id AND fu.user_id <> $1
+    ) WHERE uf.file_id IN (SELECT file_id FROM "FileUsers" WHERE user_id = $1)"#;
+
+    sqlx::query(query)
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    // links and shares the user made of files they were shared stop working
+    // with the account, instead of being left without an owner
+    let query = "DELETE FROM publiclink WHERE created_by = $1 RETURNING file_id";
+
+    let linked_file_ids = sqlx::query_scalar::<_, Uuid>(query)
+        .bind(user_id)
+        .fetch_all(&mut *transaction)
+        .await?;
+
+    let query = "UPDATE userfile uf SET is_public = EXISTS ( \
+        SELECT 1 FROM publiclink pl WHERE pl.file_id = uf.file_id \
+    ) WHERE uf.file_id = ANY($1)";
+
+    sqlx::query(query)
+        .bind(&linked_file_ids)
+        .execute(&mut *transaction)
+        .await?;
+
+    let query = "DELETE FROM publicshare WHERE created_by = $1";
+
+    sqlx::query(query)
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    let query = "DELETE FROM userinfo WHERE user_id = $1";
+
+    sqlx::query(query)
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    let query = "UPDATE accountdeletion SET completed_date = now(), last_error = NULL \
+        WHERE user_id = $1";
+
+    sqlx::query(query)
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    transaction.commit().await?;
+
+    for bucket_name in &bucket_names {
+        let bucket_folder_path = get_bucket_folder_path(data_path, bucket_name);
+
+        if bucket_folder_path.exists() {
+            let _ = fs::remove_dir_all(bucket_folder_path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs a due deletion, starting or resuming it. The account is disabled
+/// first so nothing new is added while it is deleted.
+pub async fn run_account_deletion(
+    pool: &PgPool,
+    data_path: &str,
+    user_id: &Uuid,
+) -> Result<(), sqlx::Error> {
+    let started = async {
+        let mut transaction = pool.begin().await?;
+
+        let query = "UPDATE accountdeletion \
+            SET started_date = COALESCE(started_date, now()), attempts = attempts + 1 \
+            WHERE user_id = $1 AND completed_date IS NULL AND scheduled_date <= $2 \
+            RETURNING *";
+
+        let account_deletion = sqlx::query_as::<_, AccountDeletion>(query)
+            .bind(user_id)
+            .bind(Utc::now().naive_utc())
+            .fetch_optional(&mut *transaction)
+            .await?;
+
+        if account_deletion.is_some() {
+            let query = "UPDATE userinfo SET disabled_date = COALESCE(disabled_date, now()) \
+                WHERE user_id = $1";
+
+            sqlx::query(query)
+                .bind(user_id)
+                .execute(&mut *transaction)
+                .await?;
+
+            revoke_user_sessions(&mut transaction, user_id, None).await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok::<Option<AccountDeletion>, sqlx::Error>(account_deletion)
+    }
+    .await?;
+
+    let account_deletion = match started {
+        Some(account_deletion) => account_deletion,
+        None => return Ok(()),
+    };
+
+    match delete_account_data(pool, data_path, &account_deletion).await {
+        Ok(_) => {
+            println!("deleted the account of user {}", user_id);
+            Ok(())
+        }
+        Err(error) => {
+            println!("error while deleting the account of {}: {}", user_id, error);
+
+            let query = "UPDATE accountdeletion SET last_error = $2 WHERE user_id = $1";
+
+            sqlx::query(query)
+                .bind(user_id)
+                .bind(error.to_string())
+                .execute(pool)
+                .await?;
+
+            Err(error)
+        }
+    }
+}
+
+/// Runs every deletion whose grace period is over, failed ones are tried
+/// again.
+pub async fn run_due_account_deletions(pool: &PgPool, data_path: &str) -> usize {
+    let query = "SELECT user_id FROM accountdeletion \
+        WHERE completed_date IS NULL AND scheduled_date <= $1 ORDER BY scheduled_date";
+
+    let user_ids = sqlx::query_scalar::<_, Uuid>(query)
+        .bind(Utc::now().naive_utc())
+        .fetch_all(pool)
+        .await;
+
+    let user_ids = match user_ids {
+        Ok(user_ids) => user_ids,
+        Err(error) => {
+            println!("error while looking for due account deletions: {}", error);
+            return 0;
+        }
+    };
+
+    let mut deleted = 0;
+
+    for user_id in &user_ids {
+        if run_account_deletion(pool, data_path, user_id).await.is_ok() {
+            deleted += 1;
+        }
+    }
+
+    deleted
+}
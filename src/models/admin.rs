@@ -0,0 +1,215 @@
+use sqlx::{self, PgPool};
+use uuid::Uuid;
+
+use super::{
+    account_deletion::{delete_account_now, AccountDeletion},
+    auth_session::{create_impersonation_session, revoke_user_sessions, AuthTokens},
+    bucket::{reset_owned_buckets_max_size, Bucket},
+    login_attempt::unlock_account_logins,
+    user_info::{get_user_by_email, get_user_info_by_user_id, UserInfo, USER_ROLE_ADMIN},
+};
+
+/*
+  admins manage the other accounts through /api/admin. The first user to
+  register is made admin, later ones can be promoted from the command line
+  with `home-file-server make-admin <email>`. The role is checked against
+  the database on every admin request, so a demoted or disabled admin loses
+  access right away. Admins can disable, delete and impersonate only users
+  who are not admins themselves. Every admin action is logged with the
+  admin's id.
+*/
+
+#[derive(Debug)]
+pub enum AdminErrors {
+    NotFound,
+    // admins can't disable, delete or impersonate themselves
+    OwnAccount,
+    // nor other admins
+    AdminAccount,
+    Disabled,
+    Failed,
+}
+
+fn map_admin_error(error: sqlx::Error) -> AdminErrors {
+    println!("error while running an admin action: {}", error);
+    AdminErrors::Failed
+}
+
+async fn get_other_user(
+    pool: &PgPool,
+    admin_id: &Uuid,
+    user_id: &Uuid,
+) -> Result<UserInfo, AdminErrors> {
+    if admin_id == user_id {
+        return Err(AdminErrors::OwnAccount);
+    }
+
+    let user_info = get_user_info_by_user_id(pool, user_id)
+        .await
+        .ok_or(AdminErrors::NotFound)?;
+
+    if user_info.role == USER_ROLE_ADMIN {
+        return Err(AdminErrors::AdminAccount);
+    }
+
+    Ok(user_info)
+}
+
+/// Whether the user is an admin whose account is not disabled.
+pub async fn is_user_admin(pool: &PgPool, user_id: &Uuid) -> Result<bool, sqlx::Error> {
+    let query = "SELECT EXISTS (SELECT 1 FROM userinfo \
+        WHERE user_id = $1 AND role = $2 AND disabled_date IS NULL)";
+
+    sqlx::query_scalar::<_, bool>(query)
+        .bind(user_id)
+        .bind(USER_ROLE_ADMIN)
+        .fetch_one(pool)
+        .await
+}
+
+/// Gives an existing user the admin role, used by the `make-admin` command.
+pub async fn make_user_admin(pool: &PgPool, email: &str) -> Result<UserInfo, AdminErrors> {
+    let user_info = get_user_by_email(pool, email)
+        .await
+        .ok_or(AdminErrors::NotFound)?;
+
+    let query = "UPDATE userinfo SET role = $1 WHERE user_id = $2";
+
+    sqlx::query(query)
+        .bind(USER_ROLE_ADMIN)
+        .bind(user_info.user_id)
+        .execute(pool)
+        .await
+        .map_err(map_admin_error)?;
+
+    get_user_info_by_user_id(pool, &user_info.user_id)
+        .await
+        .ok_or(AdminErrors::Failed)
+}
+
+/// Disables or enables an account. Disabling logs the user out everywhere,
+/// drops their pending 2FA logins and stops their API keys.
+pub async fn set_user_disabled(
+    pool: &PgPool,
+    admin_id: &Uuid,
+    user_id: &Uuid,
+    disabled: bool,
+) -> Result<UserInfo, AdminErrors> {
+    get_other_user(pool, admin_id, user_id).await?;
+
+    let updated = async {
+        let mut transaction = pool.begin().await?;
+
+        let query = "UPDATE userinfo SET disabled_date = \
+            CASE WHEN $2 THEN COALESCE(disabled_date, now()) END WHERE user_id = $1";
+
+        sqlx::query(query)
+            .bind(user_id)
+            .bind(disabled)
+            .execute(&mut *transaction)
+            .await?;
+
+        if disabled {
+            revoke_user_sessions(&mut transaction, user_id, None).await?;
+
+            let query = "DELETE FROM loginchallenge WHERE user_id = $1";
+
+            sqlx::query(query)
+                .bind(user_id)
+                .execute(&mut *transaction)
+                .await?;
+        }
+
+        transaction.commit().await
+    }
+    .await;
+
+    updated.map_err(map_admin_error)?;
+
+    println!(
+        "admin {} {} user {}",
+        admin_id,
+        if disabled { "disabled" } else { "enabled" },
+        user_id
+    );
+
+    get_user_info_by_user_id(pool, user_id)
+        .await
+        .ok_or(AdminErrors::Failed)
+}
+
+/// Deletes an account right away, see `delete_account_now`.
+pub async fn delete_user_account(
+    pool: &PgPool,
+    data_path: &str,
+    admin_id: &Uuid,
+    user_id: &Uuid,
+) -> Result<AccountDeletion, AdminErrors> {
+    get_other_user(pool, admin_id, user_id).await?;
+
+    println!("admin {} is deleting user {}", admin_id, user_id);
+
+    delete_account_now(pool, data_path, user_id)
+        .await
+        .map_err(|_| AdminErrors::Failed)
+}
+
+/// Puts the quota of every bucket the user owns back to the default.
+pub async fn reset_user_quota(
+    pool: &PgPool,
+    admin_id: &Uuid,
+    user_id: &Uuid,
+) -> Result<Vec<Bucket>, AdminErrors> {
+    get_user_info_by_user_id(pool, user_id)
+        .await
+        .ok_or(AdminErrors::NotFound)?;
+
+    let buckets = reset_owned_buckets_max_size(pool, user_id)
+        .await
+        .map_err(map_admin_error)?;
+
+    println!("admin {} reset the quota of user {}", admin_id, user_id);
+
+    Ok(buckets)
+}
+
+/// Lets a user who is locked out after too many failed logins try again.
+pub async fn unlock_user_logins(
+    pool: &PgPool,
+    admin_id: &Uuid,
+    user_id: &Uuid,
+) -> Result<(), AdminErrors> {
+    let user_info = get_user_info_by_user_id(pool, user_id)
+        .await
+        .ok_or(AdminErrors::NotFound)?;
+
+    unlock_account_logins(pool, &user_info.email)
+        .await
+        .map_err(map_admin_error)?;
+
+    println!("admin {} unlocked the logins of user {}", admin_id, user_id);
+
+    Ok(())
+}
+
+/// Logs an admin in as another user for support, see
+/// `create_impersonation_session`.
+pub async fn impersonate_user(
+    pool: &PgPool,
+    admin_id: &Uuid,
+    user_id: &Uuid,
+) -> Result<AuthTokens, AdminErrors> {
+    let user_info = get_other_user(pool, admin_id, user_id).await?;
+
+    if user_info.disabled_date.is_some() {
+        return Err(AdminErrors::Disabled);
+    }
+
+    let auth_tokens = create_impersonation_session(pool, &user_info, admin_id)
+        .await
+        .map_err(|_| AdminErrors::Failed)?;
+
+    println!("admin {} started a session as user {}", admin_id, user_id);
+
+    Ok(auth_tokens)
+}
@@ -0,0 +1,302 @@
+use ::serde::{Deserialize, Serialize};
+use chrono::{Duration, NaiveDateTime, Utc};
+use sqlx::{self, FromRow, PgPool};
+use uuid::Uuid;
+
+use crate::utility::{genarate_salt, passcode::hash_token};
+
+use super::{
+    bucket::{get_bucket_by_id, get_target_bucket, user_can_read_bucket, TargetBucket},
+    user_info::{get_user_info_by_user_id, UserInfo},
+};
+
+/*
+  personal API keys let scripts use the API without logging in. A key is
+  sent as a bearer token like an access token, it carries scopes and can be
+  limited to some buckets, every handler checks these with
+  `check_api_key_access` before doing anything. Only the hash of a key is
+  stored, the key itself is shown once when it is created.
+*/
+
+pub const API_KEY_PREFIX: &str = "hfs_";
+
+// 48 alphanumeric characters, about 285 bits
+const API_KEY_LEN: usize = 48;
+
+// enough of the key to tell keys apart in a list
+const API_KEY_DISPLAY_LEN: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ApiKeyScope {
+    #[serde(rename = "files:read")]
+    FilesRead,
+    #[serde(rename = "files:write")]
+    FilesWrite,
+    #[serde(rename = "buckets:admin")]
+    BucketsAdmin,
+}
+
+impl ApiKeyScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiKeyScope::FilesRead => "files:read",
+            ApiKeyScope::FilesWrite => "files:write",
+            ApiKeyScope::BucketsAdmin => "buckets:admin",
+        }
+    }
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct ApiKeyInfo {
+    pub key_id: Uuid,
+    pub key_name: String,
+    pub key_prefix: String,
+    pub scopes: Vec<String>,
+    pub bucket_ids: Option<Vec<Uuid>>,
+    pub expires_date: Option<NaiveDateTime>,
+    pub last_used_date: Option<NaiveDateTime>,
+    pub created_date: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NewApiKeySecret {
+    #[serde(flatten)]
+    pub api_key: ApiKeyInfo,
+    // only returned once, when the key is created
+    pub key: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewApiKey {
+    pub key_name: String,
+    pub scopes: Vec<ApiKeyScope>,
+    // leave out to allow every bucket of the user
+    pub bucket_ids: Option<Vec<Uuid>>,
+    pub expires_in_days: Option<i64>,
+}
+
+/// The key a request was authenticated with.
+#[derive(Debug, Clone, FromRow)]
+pub struct ApiKeyAccess {
+    pub key_id: Uuid,
+    pub user_id: Uuid,
+    pub scopes: Vec<String>,
+    pub bucket_ids: Option<Vec<Uuid>>,
+}
+
+/// What a request touches, so the buckets of a limited key can be checked.
+#[derive(Debug)]
+pub enum ApiKeyTarget<'a> {
+    // every bucket of the user, like listing all files
+    AllBuckets,
+    Bucket(&'a Uuid),
+    TargetBucket(&'a TargetBucket),
+    File(&'a Uuid),
+    Folder(&'a Uuid),
+    Upload(&'a Uuid),
+    Link(&'a Uuid),
+    Share(&'a Uuid),
+}
+
+#[derive(Debug)]
+pub enum ApiKeyErrors {
+    NotFound,
+    Forbidden,
+    InvalidName,
+    InvalidScopes,
+    InvalidBucket,
+    InvalidExpiry,
+    Failed,
+}
+
+fn map_api_key_error(error: sqlx::Error) -> ApiKeyErrors {
+    println!("error while updating api keys: {}", error);
+    ApiKeyErrors::Failed
+}
+
+pub async fn get_api_keys(pool: &PgPool, user_id: &Uuid) -> Result<Vec<ApiKeyInfo>, ApiKeyErrors> {
+    let query = "SELECT key_id, key_name, key_prefix, scopes, bucket_ids, expires_date, \
+        last_used_date, created_date FROM apikey WHERE user_id = $1 ORDER BY created_date";
+
+    sqlx::query_as::<_, ApiKeyInfo>(query)
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+        .map_err(map_api_key_error)
+}
+
+pub async fn create_api_key(
+    pool: &PgPool,
+    user_id: &Uuid,
+    new_api_key: &NewApiKey,
+) -> Result<NewApiKeySecret, ApiKeyErrors> {
+    let key_name = new_api_key.key_name.trim();
+    if key_name.is_empty() || key_name.len() > 255 {
+        return Err(ApiKeyErrors::InvalidName);
+    }
+
+    if new_api_key.scopes.is_empty() {
+        return Err(ApiKeyErrors::InvalidScopes);
+    }
+
+    if let Some(bucket_ids) = &new_api_key.bucket_ids {
+        if bucket_ids.is_empty() {
+            return Err(ApiKeyErrors::InvalidBucket);
+        }
+
+        for bucket_id in bucket_ids {
+            match get_bucket_by_id(pool, bucket_id).await {
+                Some(bucket) if user_can_read_bucket(pool, &bucket, user_id).await => (),
+                _ => return Err(ApiKeyErrors::InvalidBucket),
+            }
+        }
+    }
+
+    let expires_date = match new_api_key.expires_in_days {
+        Some(expires_in_days) if expires_in_days <= 0 => return Err(ApiKeyErrors::InvalidExpiry),
+        Some(expires_in_days) => Some((Utc::now() + Duration::days(expires_in_days)).naive_utc()),
+        None => None,
+    };
+
+    let key = format!("{}{}", API_KEY_PREFIX, genarate_salt(API_KEY_LEN));
+
+    let mut scopes: Vec<&str> = new_api_key.scopes.iter().map(ApiKeyScope::as_str).collect();
+    scopes.sort_unstable();
+    scopes.dedup();
+
+    let query = "INSERT INTO apikey (user_id, key_name, key_prefix, key_hash, scopes, bucket_ids, expires_date) \
+        VALUES($1, $2, $3, $4, $5, $6, $7) \
+        RETURNING key_id, key_name, key_prefix, scopes, bucket_ids, expires_date, last_used_date, created_date";
+
+    let api_key = sqlx::query_as::<_, ApiKeyInfo>(query)
+        .bind(user_id)
+        .bind(key_name)
+        .bind(&key[..API_KEY_DISPLAY_LEN])
+        .bind(hash_token(&key))
+        .bind(scopes)
+        .bind(&new_api_key.bucket_ids)
+        .bind(expires_date)
+        .fetch_one(pool)
+        .await
+        .map_err(map_api_key_error)?;
+
+    Ok(NewApiKeySecret { api_key, key })
+}
+
+pub async fn delete_api_key(
+    pool: &PgPool,
+    user_id: &Uuid,
+    key_id: &Uuid,
+) -> Result<(), ApiKeyErrors> {
+    let query = "DELETE FROM apikey WHERE key_id = $1 AND user_id = $2";
+
+    let deleted = sqlx::query(query)
+        .bind(key_id)
+        .bind(user_id)
+        .execute(pool)
+        .await
+        .map_err(map_api_key_error)?;
+
+    match deleted.rows_affected() {
+        0 => Err(ApiKeyErrors::NotFound),
+        _ => Ok(()),
+    }
+}
+
+/// Looks up an unexpired key and records that it was used.
+pub async fn authenticate_api_key(
+    pool: &PgPool,
+    key: &str,
+) -> Result<Option<(UserInfo, ApiKeyAccess)>, sqlx::Error> {
+    let query = "UPDATE apikey SET last_used_date = now() \
+        WHERE key_hash = $1 AND (expires_date IS NULL OR expires_date > now()) \
+        RETURNING key_id, user_id, scopes, bucket_ids";
+
+    let api_key = sqlx::query_as::<_, ApiKeyAccess>(query)
+        .bind(hash_token(key))
+        .fetch_optional(pool)
+        .await?;
+
+    let api_key = match api_key {
+        Some(api_key) => api_key,
+        None => return Ok(None),
+    };
+
+    // keys of a disabled user stop working with it
+    Ok(get_user_info_by_user_id(pool, &api_key.user_id)
+        .await
+        .filter(|user_info| user_info.disabled_date.is_none())
+        .map(|user_info| (user_info, api_key)))
+}
+
+async fn get_target_bucket_id(
+    pool: &PgPool,
+    user_id: &Uuid,
+    target: &ApiKeyTarget<'_>,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let (query, id) = match target {
+        ApiKeyTarget::AllBuckets => return Ok(None),
+        ApiKeyTarget::Bucket(bucket_id) => return Ok(Some(**bucket_id)),
+        ApiKeyTarget::TargetBucket(target_bucket) => {
+            return Ok(get_target_bucket(pool, user_id, target_bucket)
+                .await
+                .map(|bucket| bucket.bucket_id))
+        }
+        ApiKeyTarget::File(file_id) => {
+            ("SELECT bucket_id FROM userfile WHERE file_id = $1", file_id)
+        }
+        ApiKeyTarget::Folder(folder_id) => (
+            "SELECT bucket_id FROM folder WHERE folder_id = $1",
+            folder_id,
+        ),
+        ApiKeyTarget::Upload(upload_id) => (
+            "SELECT bucket_id FROM uploadsession WHERE upload_id = $1",
+            upload_id,
+        ),
+        ApiKeyTarget::Link(link_id) => (
+            "SELECT uf.bucket_id FROM publiclink pl JOIN userfile uf ON uf.file_id = pl.file_id \
+            WHERE pl.link_id = $1",
+            link_id,
+        ),
+        ApiKeyTarget::Share(share_id) => (
+            "SELECT bucket_id FROM publicshare WHERE share_id = $1",
+            share_id,
+        ),
+    };
+
+    sqlx::query_scalar::<_, Uuid>(query)
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+}
+
+/// Whether a key has `scope` and, when it is limited to some buckets, the
+/// target is inside one of them.
+pub async fn check_api_key_access(
+    pool: &PgPool,
+    api_key: &ApiKeyAccess,
+    scope: ApiKeyScope,
+    target: ApiKeyTarget<'_>,
+) -> Result<(), ApiKeyErrors> {
+    if !api_key
+        .scopes
+        .iter()
+        .any(|key_scope| key_scope == scope.as_str())
+    {
+        return Err(ApiKeyErrors::Forbidden);
+    }
+
+    let bucket_ids = match &api_key.bucket_ids {
+        Some(bucket_ids) => bucket_ids,
+        None => return Ok(()),
+    };
+
+    let bucket_id = get_target_bucket_id(pool, &api_key.user_id, &target)
+        .await
+        .map_err(map_api_key_error)?;
+
+    match bucket_id {
+        Some(bucket_id) if bucket_ids.contains(&bucket_id) => Ok(()),
+        _ => Err(ApiKeyErrors::Forbidden),
+    }
+}
@@ -0,0 +1,374 @@
+use ::serde::{Deserialize, Serialize};
+use chrono::{Duration, NaiveDateTime, Utc};
+use sqlx::{self, FromRow, PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::utility::{
+    genarate_salt,
+    jwt_token::{generate_token, Claims, ACCESS_TOKEN_MINUTES},
+    passcode::hash_token,
+};
+
+use super::{
+    signed_url::bump_url_signing_generation,
+    user_info::{get_user_info_by_user_id, UserInfo},
+};
+
+/*
+  every login starts an AuthSession, the session hands out short lived
+  access tokens (JWT) and long lived opaque refresh tokens. A refresh
+  token can be used only once, using it again revokes the whole session
+  since it was likely stolen. Access tokens of a revoked session, or
+  whose jti is in RevokedToken, are rejected by `jwt_validator`.
+*/
+
+// 64 alphanumeric characters, about 380 bits
+const REFRESH_TOKEN_LEN: usize = 64;
+
+// sessions an admin starts as another user don't outlive the support case
+const IMPERSONATION_SESSION_DAYS: i64 = 1;
+
+#[derive(Debug, Serialize)]
+pub struct AuthTokens {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub token_type: String,
+    // seconds until the access token expires
+    pub expires_in: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshTokenRequest {
+    pub refresh_token: String,
+}
+
+// a refresh token together with the session it belongs to
+#[derive(Debug, FromRow)]
+struct SessionRefreshToken {
+    token_id: Uuid,
+    session_id: Uuid,
+    user_id: Uuid,
+    expires_date: NaiveDateTime,
+    used_date: Option<NaiveDateTime>,
+    revoked_date: Option<NaiveDateTime>,
+    impersonated_by: Option<Uuid>,
+    session_created_date: NaiveDateTime,
+}
+
+#[derive(Debug)]
+pub enum AuthSessionErrors {
+    InvalidToken,
+    TokenReused,
+    Failed,
+}
+
+fn map_auth_session_error(error: sqlx::Error) -> AuthSessionErrors {
+    println!("error while updating auth sessions: {}", error);
+    AuthSessionErrors::Failed
+}
+
+async fn insert_refresh_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    session_id: &Uuid,
+    refresh_token_days: i64,
+) -> Result<String, sqlx::Error> {
+    let refresh_token = genarate_salt(REFRESH_TOKEN_LEN);
+
+    let query =
+        "INSERT INTO refreshtoken (session_id, token_hash, expires_date) VALUES($1, $2, $3)";
+
+    sqlx::query(query)
+        .bind(session_id)
+        .bind(hash_token(&refresh_token))
+        .bind((Utc::now() + Duration::days(refresh_token_days)).naive_utc())
+        .execute(&mut **transaction)
+        .await?;
+
+    Ok(refresh_token)
+}
+
+fn get_auth_tokens(user_info: &UserInfo, session_id: &Uuid, refresh_token: String) -> AuthTokens {
+    AuthTokens {
+        access_token: generate_token(user_info, session_id),
+        refresh_token,
+        token_type: "Bearer".to_owned(),
+        expires_in: ACCESS_TOKEN_MINUTES * 60,
+    }
+}
+
+async fn start_auth_session(
+    pool: &PgPool,
+    user_info: &UserInfo,
+    impersonated_by: Option<&Uuid>,
+    refresh_token_days: i64,
+) -> Result<AuthTokens, AuthSessionErrors> {
+    let created = async {
+        let mut transaction = pool.begin().await?;
+
+        let query = "INSERT INTO authsession (user_id, impersonated_by) VALUES($1, $2) \
+            RETURNING session_id";
+
+        let session_id = sqlx::query_scalar::<_, Uuid>(query)
+            .bind(user_info.user_id)
+            .bind(impersonated_by)
+            .fetch_one(&mut *transaction)
+            .await?;
+
+        let refresh_token =
+            insert_refresh_token(&mut transaction, &session_id, refresh_token_days).await?;
+
+        transaction.commit().await?;
+
+        Ok::<(Uuid, String), sqlx::Error>((session_id, refresh_token))
+    }
+    .await;
+
+    let (session_id, refresh_token) = created.map_err(map_auth_session_error)?;
+
+    Ok(get_auth_tokens(user_info, &session_id, refresh_token))
+}
+
+/// Starts a new session for a user who just logged in.
+pub async fn create_auth_session(
+    pool: &PgPool,
+    user_info: &UserInfo,
+    refresh_token_days: i64,
+) -> Result<AuthTokens, AuthSessionErrors> {
+    start_auth_session(pool, user_info, None, refresh_token_days).await
+}
+
+/// Starts a session as `user_info` for an admin, the session records who
+/// started it and lasts at most `IMPERSONATION_SESSION_DAYS`.
+pub async fn create_impersonation_session(
+    pool: &PgPool,
+    user_info: &UserInfo,
+    admin_id: &Uuid,
+) -> Result<AuthTokens, AuthSessionErrors> {
+    start_auth_session(pool, user_info, Some(admin_id), IMPERSONATION_SESSION_DAYS).await
+}
+
+/// Trades a refresh token for a new access and refresh token, the used
+/// token can not be used again.
+pub async fn refresh_auth_session(
+    pool: &PgPool,
+    refresh_token: &str,
+    refresh_token_days: i64,
+) -> Result<AuthTokens, AuthSessionErrors> {
+    let mut transaction = pool.begin().await.map_err(map_auth_session_error)?;
+
+    let query = "SELECT rt.token_id, rt.session_id, s.user_id, rt.expires_date, rt.used_date, \
+        s.revoked_date, s.impersonated_by, s.created_date AS session_created_date \
+        FROM refreshtoken rt JOIN authsession s ON s.session_id = rt.session_id \
+        WHERE rt.token_hash = $1 FOR UPDATE OF rt, s";
+
+    let session_token = sqlx::query_as::<_, SessionRefreshToken>(query)
+        .bind(hash_token(refresh_token))
+        .fetch_optional(&mut *transaction)
+        .await
+        .map_err(map_auth_session_error)?;
+
+    let session_token = match session_token {
+        Some(session_token) if session_token.revoked_date.is_none() => session_token,
+        _ => return Err(AuthSessionErrors::InvalidToken),
+    };
+
+    if session_token.used_date.is_some() {
+        println!(
+            "refresh token of session {} was used twice, revoking the session",
+            session_token.session_id
+        );
+
+        let query = "UPDATE authsession SET revoked_date = now() WHERE session_id = $1";
+
+        sqlx::query(query)
+            .bind(session_token.session_id)
+            .execute(&mut *transaction)
+            .await
+            .map_err(map_auth_session_error)?;
+
+        transaction.commit().await.map_err(map_auth_session_error)?;
+
+        return Err(AuthSessionErrors::TokenReused);
+    }
+
+    if session_token.expires_date <= Utc::now().naive_utc() {
+        return Err(AuthSessionErrors::InvalidToken);
+    }
+
+    if session_token.impersonated_by.is_some()
+        && session_token.session_created_date + Duration::days(IMPERSONATION_SESSION_DAYS)
+            <= Utc::now().naive_utc()
+    {
+        return Err(AuthSessionErrors::InvalidToken);
+    }
+
+    let query = "UPDATE refreshtoken SET used_date = now() WHERE token_id = $1";
+
+    sqlx::query(query)
+        .bind(session_token.token_id)
+        .execute(&mut *transaction)
+        .await
+        .map_err(map_auth_session_error)?;
+
+    let new_refresh_token = insert_refresh_token(
+        &mut transaction,
+        &session_token.session_id,
+        refresh_token_days,
+    )
+    .await
+    .map_err(map_auth_session_error)?;
+
+    let user_info = match get_user_info_by_user_id(pool, &session_token.user_id).await {
+        Some(user_info) => user_info,
+        None => return Err(AuthSessionErrors::InvalidToken),
+    };
+
+    transaction.commit().await.map_err(map_auth_session_error)?;
+
+    Ok(get_auth_tokens(
+        &user_info,
+        &session_token.session_id,
+        new_refresh_token,
+    ))
+}
+
+/// Ends a session, the pre-signed URLs of its user stop working as well.
+pub async fn revoke_auth_session(
+    pool: &PgPool,
+    session_id: &Uuid,
+) -> Result<(), AuthSessionErrors> {
+    let revoked = async {
+        let mut transaction = pool.begin().await?;
+
+        let query = "UPDATE authsession SET revoked_date = now() \
+            WHERE session_id = $1 AND revoked_date IS NULL RETURNING user_id";
+
+        let user_id = sqlx::query_scalar::<_, Uuid>(query)
+            .bind(session_id)
+            .fetch_optional(&mut *transaction)
+            .await?;
+
+        if let Some(user_id) = user_id {
+            bump_url_signing_generation(&mut *transaction, &user_id).await?;
+        }
+
+        transaction.commit().await
+    }
+    .await;
+
+    revoked.map_err(map_auth_session_error)
+}
+
+/// Revokes every session of a user, except `except_session_id` when the
+/// user is changing their own password. Their pre-signed URLs stop working
+/// as well.
+pub async fn revoke_user_sessions(
+    transaction: &mut Transaction<'_, Postgres>,
+    user_id: &Uuid,
+    except_session_id: Option<&Uuid>,
+) -> Result<u64, sqlx::Error> {
+    let query = "UPDATE authsession SET revoked_date = now() \
+        WHERE user_id = $1 AND revoked_date IS NULL AND session_id IS DISTINCT FROM $2";
+
+    let revoked = sqlx::query(query)
+        .bind(user_id)
+        .bind(except_session_id)
+        .execute(&mut **transaction)
+        .await?;
+
+    bump_url_signing_generation(&mut **transaction, user_id).await?;
+
+    Ok(revoked.rows_affected())
+}
+
+/// Revokes the session a refresh token belongs to.
+pub async fn revoke_refresh_token_session(
+    pool: &PgPool,
+    refresh_token: &str,
+) -> Result<(), AuthSessionErrors> {
+    let query = "SELECT session_id FROM refreshtoken WHERE token_hash = $1";
+
+    let session_id = sqlx::query_scalar::<_, Uuid>(query)
+        .bind(hash_token(refresh_token))
+        .fetch_optional(pool)
+        .await
+        .map_err(map_auth_session_error)?;
+
+    match session_id {
+        Some(session_id) => revoke_auth_session(pool, &session_id).await,
+        None => Err(AuthSessionErrors::InvalidToken),
+    }
+}
+
+/// Puts an access token on the denylist until it expires.
+pub async fn revoke_access_token(pool: &PgPool, claims: &Claims) -> Result<(), AuthSessionErrors> {
+    let expires_date = NaiveDateTime::from_timestamp_opt(claims.exp as i64, 0)
+        .unwrap_or_else(|| Utc::now().naive_utc());
+
+    let query =
+        "INSERT INTO revokedtoken (jti, expires_date) VALUES($1, $2) ON CONFLICT DO NOTHING";
+
+    sqlx::query(query)
+        .bind(claims.jti)
+        .bind(expires_date)
+        .execute(pool)
+        .await
+        .map_err(map_auth_session_error)?;
+
+    Ok(())
+}
+
+/// Whether an access token was revoked on its own or with its session.
+pub async fn is_token_revoked(pool: &PgPool, claims: &Claims) -> Result<bool, AuthSessionErrors> {
+    let query = "SELECT EXISTS (SELECT 1 FROM revokedtoken WHERE jti = $1) \
+        OR EXISTS (SELECT 1 FROM authsession WHERE session_id = $2 AND revoked_date IS NOT NULL)";
+
+    sqlx::query_scalar::<_, bool>(query)
+        .bind(claims.jti)
+        .bind(claims.sid)
+        .fetch_one(pool)
+        .await
+        .map_err(map_auth_session_error)
+}
+
+/// Removes expired refresh tokens, password reset, email verification and
+/// login challenge tokens, denylist entries of expired access tokens and
+/// sessions that have nothing left.
+pub async fn purge_expired_tokens(pool: &PgPool) -> u64 {
+    let purged = async {
+        let mut purged = 0;
+
+        let query = "DELETE FROM refreshtoken WHERE expires_date <= now()";
+        purged += sqlx::query(query).execute(pool).await?.rows_affected();
+
+        let query = "DELETE FROM revokedtoken WHERE expires_date <= now()";
+        purged += sqlx::query(query).execute(pool).await?.rows_affected();
+
+        let query = "DELETE FROM passwordreset WHERE expires_date <= now()";
+        purged += sqlx::query(query).execute(pool).await?.rows_affected();
+
+        let query = "DELETE FROM emailverification WHERE expires_date <= now()";
+        purged += sqlx::query(query).execute(pool).await?.rows_affected();
+
+        let query = "DELETE FROM loginchallenge WHERE expires_date <= now()";
+        purged += sqlx::query(query).execute(pool).await?.rows_affected();
+
+        // the last access token of a session was issued together with its
+        // last refresh token, so it has long expired by now
+        let query = "DELETE FROM authsession s WHERE NOT EXISTS ( \
+            SELECT 1 FROM refreshtoken rt WHERE rt.session_id = s.session_id \
+        )";
+        purged += sqlx::query(query).execute(pool).await?.rows_affected();
+
+        Ok::<u64, sqlx::Error>(purged)
+    }
+    .await;
+
+    match purged {
+        Ok(purged) => purged,
+        Err(error) => {
+            println!("error while purging expired tokens: {}", error);
+            0
+        }
+    }
+}
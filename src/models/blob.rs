@@ -0,0 +1,238 @@
+use ::serde::{Deserialize, Serialize};
+use chrono::NaiveDateTime;
+use sqlx::{self, FromRow, PgPool, Postgres, Transaction};
+use std::{fs, io, path::PathBuf};
+
+use super::{
+    bucket::get_bucket_folder_path,
+    user_file::{PartialFile, UserFileErrors},
+};
+
+/*
+  file contents are stored once per SHA-256 under DATA_PATH/.blobs,
+  sharded by the first two bytes of the hash:
+
+  .blobs/AB/CD/ABCD...
+
+  every FileMetadata row holds a reference, the blob is removed from
+  disk when the last reference goes away. Removing it waits until the
+  transaction that dropped the reference is committed, a rollback keeps
+  the content. Adding and removing a blob take a lock on its hash, so an
+  upload of the same content never reuses a file that is being removed.
+*/
+
+// advisory lock class of blob hashes, the key is the hash of the file hash
+const BLOB_LOCK: i32 = 1;
+
+#[derive(Debug, FromRow, Serialize, Deserialize)]
+pub struct Blob {
+    pub file_hash: String,
+    pub blob_size: i64,
+    pub ref_count: i32,
+    pub created_date: NaiveDateTime,
+}
+
+#[derive(Debug, FromRow)]
+struct LegacyFile {
+    file_id: uuid::Uuid,
+    file_hash: String,
+    bucket_name: String,
+}
+
+pub fn get_blob_path(data_path: &str, file_hash: &str) -> PathBuf {
+    let mut blob_path = PathBuf::from(data_path);
+    blob_path.push(".blobs");
+    blob_path.push(&file_hash[0..2]);
+    blob_path.push(&file_hash[2..4]);
+    blob_path.push(file_hash);
+
+    blob_path
+}
+
+async fn lock_blob_hash(
+    transaction: &mut Transaction<'_, Postgres>,
+    file_hash: &str,
+) -> Result<(), sqlx::Error> {
+    let query = "SELECT pg_advisory_xact_lock($1, hashtext($2))";
+
+    sqlx::query(query)
+        .bind(BLOB_LOCK)
+        .bind(file_hash)
+        .execute(&mut **transaction)
+        .await?;
+
+    Ok(())
+}
+
+/// Adds a reference to the blob of `file_hash`, moving the uploaded
+/// content into the blob store when no copy of it exists yet.
+///
+/// Returns `true` when the content was moved into the store, so the
+/// caller can remove it again if the transaction is not committed.
+pub async fn add_blob_reference(
+    transaction: &mut Transaction<'_, Postgres>,
+    data_path: &str,
+    file_hash: &str,
+    blob_size: i64,
+    partial_file: PartialFile,
+) -> Result<bool, UserFileErrors> {
+    // held until commit, the stored content can't be removed before then
+    let query = async {
+        lock_blob_hash(transaction, file_hash).await?;
+
+        let query = "INSERT INTO blob (file_hash, blob_size) VALUES($1, $2) \
+            ON CONFLICT (file_hash) DO UPDATE SET ref_count = blob.ref_count + 1";
+
+        sqlx::query(query)
+            .bind(file_hash)
+            .bind(blob_size)
+            .execute(&mut **transaction)
+            .await
+    }
+    .await;
+
+    if let Err(error) = query {
+        println!("error while adding blob reference {}: {}", file_hash, error);
+        return Err(UserFileErrors::FailedToSave);
+    }
+
+    let blob_path = get_blob_path(data_path, file_hash);
+
+    if blob_path.exists() {
+        // same content is already stored, the upload is dropped here
+        return Ok(false);
+    }
+
+    if let Some(blob_folder_path) = blob_path.parent() {
+        let _ = fs::create_dir_all(blob_folder_path);
+    }
+
+    partial_file.persist(&blob_path)?;
+
+    Ok(true)
+}
+
+/// Adds a reference to a blob that is already stored.
+pub async fn copy_blob_reference(
+    transaction: &mut Transaction<'_, Postgres>,
+    file_hash: &str,
+) -> Result<(), sqlx::Error> {
+    let query = "UPDATE blob SET ref_count = ref_count + 1 WHERE file_hash = $1";
+
+    sqlx::query(query)
+        .bind(file_hash)
+        .execute(&mut **transaction)
+        .await?;
+
+    Ok(())
+}
+
+/// Drops a reference to the blob of `file_hash`, the blob row is removed
+/// once nothing references it anymore.
+///
+/// Returns `true` when the row was removed, the caller passes the hash to
+/// `remove_unused_blobs` once the transaction is committed.
+pub async fn release_blob_reference(
+    transaction: &mut Transaction<'_, Postgres>,
+    file_hash: &str,
+) -> Result<bool, sqlx::Error> {
+    let query = "UPDATE blob SET ref_count = ref_count - 1 WHERE file_hash = $1 RETURNING *";
+
+    let blob = sqlx::query_as::<_, Blob>(query)
+        .bind(file_hash)
+        .fetch_optional(&mut **transaction)
+        .await?;
+
+    if let Some(blob) = blob {
+        if blob.ref_count <= 0 {
+            let query = "DELETE FROM blob WHERE file_hash = $1";
+
+            sqlx::query(query)
+                .bind(file_hash)
+                .execute(&mut **transaction)
+                .await?;
+
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Removes the content of blobs that lost their last reference, blobs
+/// that got a new one in the meantime are kept.
+pub async fn remove_unused_blobs(pool: &PgPool, data_path: &str, file_hashes: &[String]) {
+    for file_hash in file_hashes {
+        let removed = async {
+            let mut transaction = pool.begin().await?;
+
+            lock_blob_hash(&mut transaction, file_hash).await?;
+
+            let query = "SELECT file_hash FROM blob WHERE file_hash = $1 FOR UPDATE";
+
+            let blob = sqlx::query_scalar::<_, String>(query)
+                .bind(file_hash)
+                .fetch_optional(&mut *transaction)
+                .await?;
+
+            if blob.is_none() {
+                if let Err(error) = fs::remove_file(get_blob_path(data_path, file_hash)) {
+                    if error.kind() != io::ErrorKind::NotFound {
+                        println!("error while removing blob {}: {}", file_hash, error);
+                    }
+                }
+            }
+
+            transaction.commit().await
+        }
+        .await;
+
+        if let Err(error) = removed {
+            println!("error while removing blob {}: {}", file_hash, error);
+        }
+    }
+}
+
+/// Moves files stored as `<bucket>/<file_id>` before the blob store existed
+/// into the blob store.
+pub async fn migrate_legacy_files(pool: &PgPool, data_path: &str) {
+    let query = "SELECT uf.file_id, fm.file_hash, b.bucket_name FROM userfile uf \
+        JOIN filemetadata fm ON fm.file_id = uf.file_id \
+        JOIN bucket b ON b.bucket_id = uf.bucket_id";
+
+    let legacy_files = sqlx::query_as::<_, LegacyFile>(query).fetch_all(pool).await;
+
+    let legacy_files = match legacy_files {
+        Ok(legacy_files) => legacy_files,
+        Err(error) => {
+            println!("error while looking up legacy files: {}", error);
+            return;
+        }
+    };
+
+    for legacy_file in legacy_files {
+        let mut legacy_path = get_bucket_folder_path(data_path, &legacy_file.bucket_name);
+        legacy_path.push(legacy_file.file_id.to_string());
+
+        if !legacy_path.exists() {
+            continue;
+        }
+
+        let blob_path = get_blob_path(data_path, &legacy_file.file_hash);
+
+        if blob_path.exists() {
+            let _ = fs::remove_file(legacy_path);
+        } else {
+            if let Some(blob_folder_path) = blob_path.parent() {
+                let _ = fs::create_dir_all(blob_folder_path);
+            }
+            if let Err(error) = fs::rename(&legacy_path, &blob_path) {
+                println!(
+                    "error while moving {} into the blob store: {}",
+                    legacy_path.display(),
+                    error
+                );
+            }
+        }
+    }
+}
@@ -2,11 +2,13 @@ use std::{fs, path::PathBuf};
 
 use ::serde::{Deserialize, Serialize};
 use chrono::NaiveDateTime;
-use sqlx::{self, postgres::PgPool, FromRow};
+use sqlx::{self, postgres::PgPool, FromRow, Postgres, Transaction};
 use uuid::Uuid;
 
 use crate::utility::get_vec_to_sql_str;
 
+use super::user_info::get_user_by_email;
+
 #[derive(Debug, FromRow, Serialize, Deserialize)]
 pub struct Bucket {
     pub bucket_id: Uuid,
@@ -16,6 +18,8 @@ pub struct Bucket {
     pub max_bucket_size: i64,
     pub created_date: NaiveDateTime,
     pub is_shared: bool,
+    pub max_versions: i32,
+    pub version_retention_days: Option<i32>,
 }
 
 #[derive(Debug, FromRow, Serialize, Deserialize, PartialEq)]
@@ -28,6 +32,130 @@ pub struct NewBucket {
     pub bucket_name: String,
 }
 
+#[derive(Debug, FromRow, Serialize, Deserialize)]
+pub struct BucketUsage {
+    pub bucket_id: Uuid,
+    pub bucket_name: String,
+    pub used_bytes: i64,
+    pub free_bytes: i64,
+    pub max_bucket_size: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BucketQuota {
+    pub max_bucket_size: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BucketVersioning {
+    pub max_versions: i32,
+    pub version_retention_days: Option<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TargetBucket {
+    pub bucket_id: Option<Uuid>,
+    pub bucket_name: Option<String>,
+    // folder path inside the bucket, like `docs/2023`
+    pub path: Option<String>,
+}
+
+#[derive(Debug, FromRow, Serialize, Deserialize)]
+pub struct BucketUser {
+    pub bucket_id: Uuid,
+    pub user_id: Uuid,
+    #[sqlx(rename = "Permissions")]
+    pub permissions: i32,
+}
+
+/*
+  BucketUsers."Permissions" uses the same bits as "FileUsers" and applies
+  them to every file in the bucket:
+
+  1 read    list the bucket and download its files
+  2 write   upload, create folders and change files
+  4 delete  delete files and folders
+  8 share   share files and manage the users of the bucket
+
+  the owner of a bucket can do everything, the bits are handed out
+  through roles.
+*/
+
+pub const BUCKET_READ: i32 = 1;
+pub const BUCKET_WRITE: i32 = 2;
+pub const BUCKET_DELETE: i32 = 4;
+pub const BUCKET_SHARE: i32 = 8;
+pub const BUCKET_ALL: i32 = BUCKET_READ | BUCKET_WRITE | BUCKET_DELETE | BUCKET_SHARE;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BucketRole {
+    Owner,
+    Manager,
+    Contributor,
+    Viewer,
+}
+
+impl BucketRole {
+    pub fn permissions(&self) -> i32 {
+        match self {
+            BucketRole::Owner | BucketRole::Manager => BUCKET_ALL,
+            BucketRole::Contributor => BUCKET_READ | BUCKET_WRITE,
+            BucketRole::Viewer => BUCKET_READ,
+        }
+    }
+
+    pub fn from_permissions(permissions: i32) -> Self {
+        if permissions & (BUCKET_DELETE | BUCKET_SHARE) != 0 {
+            BucketRole::Manager
+        } else if permissions & BUCKET_WRITE != 0 {
+            BucketRole::Contributor
+        } else {
+            BucketRole::Viewer
+        }
+    }
+}
+
+// a bucket the user owns or was invited to, with the user's role on it
+#[derive(Debug, FromRow, Serialize)]
+pub struct UserBucket {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub bucket: Bucket,
+    #[sqlx(rename = "Permissions")]
+    #[serde(skip)]
+    pub permissions: i32,
+    #[sqlx(skip)]
+    pub role: Option<BucketRole>,
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct BucketMember {
+    pub user_id: Uuid,
+    pub user_name: String,
+    pub email: String,
+    #[sqlx(rename = "Permissions")]
+    #[serde(skip)]
+    pub permissions: i32,
+    #[sqlx(skip)]
+    pub role: Option<BucketRole>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewBucketMember {
+    pub email: String,
+    pub role: BucketRole,
+}
+
+#[derive(Debug)]
+pub enum BucketMemberErrors {
+    NotFound,
+    Forbidden,
+    InvalidRole,
+    InvalidUser,
+    Failed,
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub enum BucketDeletionError {
     InvalidBucket,
@@ -36,16 +164,29 @@ pub enum BucketDeletionError {
     FailedToDeleteBucket,
 }
 
-pub async fn get_all_user_bucket_info(pool: &PgPool, user_id: &Uuid) -> Option<Vec<Bucket>> {
-    let query = "SELECT * FROM bucket WHERE user_id = $1";
+/// Lists the buckets a user owns or was invited to, with the role of the
+/// user on each of them.
+pub async fn get_all_user_bucket_info(pool: &PgPool, user_id: &Uuid) -> Option<Vec<UserBucket>> {
+    let query = format!(
+        r#"SELECT b.*, CASE WHEN b.user_id = $1 THEN {} ELSE bu."Permissions" END AS "Permissions"
+        FROM bucket b LEFT JOIN bucketusers bu ON bu.bucket_id = b.bucket_id AND bu.user_id = $1
+        WHERE b.user_id = $1 OR bu.user_id = $1 ORDER BY b.created_date"#,
+        BUCKET_ALL
+    );
 
-    let query = sqlx::query_as::<_, Bucket>(query).bind(user_id);
+    let query = sqlx::query_as::<_, UserBucket>(&query).bind(user_id);
 
     let buckets = query.fetch_all(pool).await;
 
     match buckets {
-        Ok(buckets) => {
-            println!("{:#?}", buckets);
+        Ok(mut buckets) => {
+            for user_bucket in &mut buckets {
+                user_bucket.role = Some(if &user_bucket.bucket.user_id == user_id {
+                    BucketRole::Owner
+                } else {
+                    BucketRole::from_permissions(user_bucket.permissions)
+                });
+            }
             Some(buckets)
         }
         Err(error) => {
@@ -58,6 +199,46 @@ pub async fn get_all_user_bucket_info(pool: &PgPool, user_id: &Uuid) -> Option<V
     }
 }
 
+pub async fn get_owned_buckets(pool: &PgPool, user_id: &Uuid) -> Option<Vec<Bucket>> {
+    let query = "SELECT * FROM bucket WHERE user_id = $1";
+
+    let query = sqlx::query_as::<_, Bucket>(query).bind(user_id);
+
+    let buckets = query.fetch_all(pool).await;
+
+    match buckets {
+        Ok(buckets) => Some(buckets),
+        Err(error) => {
+            println!(
+                "Error occurred while fetching buckets for user {:?}: {}",
+                user_id, error
+            );
+            None
+        }
+    }
+}
+
+pub async fn get_user_bucket_usage(pool: &PgPool, user_id: &Uuid) -> Option<Vec<BucketUsage>> {
+    let query = "SELECT bucket_id, bucket_name, bucket_size AS used_bytes, \
+        GREATEST(max_bucket_size - bucket_size, 0) AS free_bytes, max_bucket_size \
+        FROM bucket WHERE user_id = $1";
+
+    let query = sqlx::query_as::<_, BucketUsage>(query).bind(user_id);
+
+    let usage = query.fetch_all(pool).await;
+
+    match usage {
+        Ok(usage) => Some(usage),
+        Err(error) => {
+            println!(
+                "Error occurred while fetching bucket usage for user {}: {}",
+                user_id, error
+            );
+            None
+        }
+    }
+}
+
 pub async fn get_bucket_by_name(pool: &PgPool, bucket_name: &str) -> Option<Bucket> {
     let query = "SELECT * FROM bucket WHERE bucket_name = $1";
 
@@ -77,7 +258,7 @@ pub async fn get_bucket_by_name(pool: &PgPool, bucket_name: &str) -> Option<Buck
     }
 }
 
-pub async fn _get_bucket_by_id(pool: &PgPool, bucket_id: &Uuid) -> Option<Bucket> {
+pub async fn get_bucket_by_id(pool: &PgPool, bucket_id: &Uuid) -> Option<Bucket> {
     let query = "SELECT * FROM bucket WHERE bucket_id = $1";
 
     let query = sqlx::query_as::<_, Bucket>(query).bind(bucket_id);
@@ -96,14 +277,338 @@ pub async fn _get_bucket_by_id(pool: &PgPool, bucket_id: &Uuid) -> Option<Bucket
     }
 }
 
+pub async fn get_user_default_bucket(pool: &PgPool, user_id: &Uuid) -> Option<Bucket> {
+    let query = "SELECT * FROM bucket WHERE user_id = $1 ORDER BY created_date LIMIT 1";
+
+    let query = sqlx::query_as::<_, Bucket>(query).bind(user_id);
+
+    let bucket = query.fetch_one(pool).await;
+
+    match bucket {
+        Ok(bucket) => Some(bucket),
+        Err(error) => {
+            println!(
+                "Error occurred while fetching default bucket for user {}: {}",
+                user_id, error
+            );
+            None
+        }
+    }
+}
+
+pub async fn get_bucket_user(
+    pool: &PgPool,
+    bucket_id: &Uuid,
+    user_id: &Uuid,
+) -> Option<BucketUser> {
+    let query = "SELECT * FROM bucketusers WHERE bucket_id = $1 AND user_id = $2";
+
+    let query = sqlx::query_as::<_, BucketUser>(query)
+        .bind(bucket_id)
+        .bind(user_id);
+
+    let bucket_user = query.fetch_optional(pool).await;
+
+    match bucket_user {
+        Ok(bucket_user) => bucket_user,
+        Err(error) => {
+            println!(
+                "Error occurred while fetching bucket user for bucket {} and user {}: {}",
+                bucket_id, user_id, error
+            );
+            None
+        }
+    }
+}
+
+/// Looks up the bucket named by `target_bucket`, the default bucket of the
+/// user when neither an id nor a name is given.
+pub async fn get_target_bucket(
+    pool: &PgPool,
+    user_id: &Uuid,
+    target_bucket: &TargetBucket,
+) -> Option<Bucket> {
+    match (&target_bucket.bucket_id, &target_bucket.bucket_name) {
+        (Some(bucket_id), _) => get_bucket_by_id(pool, bucket_id).await,
+        (None, Some(bucket_name)) => get_bucket_by_name(pool, bucket_name).await,
+        (None, None) => get_user_default_bucket(pool, user_id).await,
+    }
+}
+
+/// The permission bits `user_id` has on a bucket.
+pub async fn get_bucket_permissions(pool: &PgPool, bucket: &Bucket, user_id: &Uuid) -> i32 {
+    if &bucket.user_id == user_id {
+        return BUCKET_ALL;
+    }
+
+    match get_bucket_user(pool, &bucket.bucket_id, user_id).await {
+        Some(bucket_user) => bucket_user.permissions,
+        None => 0,
+    }
+}
+
+pub async fn user_can_read_bucket(pool: &PgPool, bucket: &Bucket, user_id: &Uuid) -> bool {
+    get_bucket_permissions(pool, bucket, user_id).await & BUCKET_READ != 0
+}
+
+pub async fn user_can_write_bucket(pool: &PgPool, bucket: &Bucket, user_id: &Uuid) -> bool {
+    get_bucket_permissions(pool, bucket, user_id).await & BUCKET_WRITE != 0
+}
+
+async fn get_managed_bucket(
+    pool: &PgPool,
+    user_id: &Uuid,
+    bucket_id: &Uuid,
+) -> Result<Bucket, BucketMemberErrors> {
+    let bucket = match get_bucket_by_id(pool, bucket_id).await {
+        Some(bucket) => bucket,
+        None => return Err(BucketMemberErrors::NotFound),
+    };
+
+    if get_bucket_permissions(pool, &bucket, user_id).await & BUCKET_SHARE != 0 {
+        Ok(bucket)
+    } else {
+        Err(BucketMemberErrors::Forbidden)
+    }
+}
+
+/// Lists the members of a bucket, only to users who manage them like
+/// `get_file_shares` does for files.
+pub async fn get_bucket_members(
+    pool: &PgPool,
+    user_id: &Uuid,
+    bucket_id: &Uuid,
+) -> Result<Vec<BucketMember>, BucketMemberErrors> {
+    get_managed_bucket(pool, user_id, bucket_id).await?;
+
+    let query = r#"SELECT ui.user_id, ui.user_name, ui.email, bu."Permissions" FROM bucketusers bu
+        JOIN userinfo ui ON ui.user_id = bu.user_id WHERE bu.bucket_id = $1 ORDER BY ui.user_name"#;
+
+    let query = sqlx::query_as::<_, BucketMember>(query).bind(bucket_id);
+
+    let members = query.fetch_all(pool).await;
+
+    match members {
+        Ok(mut members) => {
+            for member in &mut members {
+                member.role = Some(BucketRole::from_permissions(member.permissions));
+            }
+            Ok(members)
+        }
+        Err(error) => {
+            println!(
+                "Error occurred while fetching users of bucket {}: {}",
+                bucket_id, error
+            );
+            Err(BucketMemberErrors::Failed)
+        }
+    }
+}
+
+async fn update_bucket_is_shared(pool: &PgPool, bucket_id: &Uuid) {
+    let query = "UPDATE bucket SET is_shared = EXISTS ( \
+        SELECT 1 FROM bucketusers WHERE bucket_id = $1 \
+    ) WHERE bucket_id = $1";
+
+    let query = sqlx::query(query).bind(bucket_id).execute(pool).await;
+
+    if let Err(error) = query {
+        println!(
+            "error while updating is_shared of bucket {}: {}",
+            bucket_id, error
+        );
+    }
+}
+
+/// Invites the user registered under `email` to a bucket, inviting an
+/// existing member again changes their role.
+///
+/// Unknown emails are not reported, so this can't be used to find out who
+/// has an account.
+pub async fn add_bucket_member(
+    pool: &PgPool,
+    user_id: &Uuid,
+    bucket_id: &Uuid,
+    new_member: &NewBucketMember,
+) -> Result<(), BucketMemberErrors> {
+    let bucket = get_managed_bucket(pool, user_id, bucket_id).await?;
+
+    if new_member.role == BucketRole::Owner {
+        return Err(BucketMemberErrors::InvalidRole);
+    }
+
+    let member = match get_user_by_email(pool, &new_member.email).await {
+        Some(member) if member.user_id != bucket.user_id => member,
+        _ => return Ok(()),
+    };
+
+    if &member.user_id == user_id {
+        return Err(BucketMemberErrors::InvalidUser);
+    }
+
+    let query = r#"INSERT INTO bucketusers (bucket_id, user_id, "Permissions") VALUES($1, $2, $3)
+        ON CONFLICT (bucket_id, user_id) DO UPDATE SET "Permissions" = EXCLUDED."Permissions""#;
+
+    let query = sqlx::query(query)
+        .bind(bucket_id)
+        .bind(member.user_id)
+        .bind(new_member.role.permissions())
+        .execute(pool)
+        .await;
+
+    match query {
+        Ok(_) => {
+            update_bucket_is_shared(pool, bucket_id).await;
+            Ok(())
+        }
+        Err(error) => {
+            println!("error while adding user to bucket {}: {}", bucket_id, error);
+            Err(BucketMemberErrors::Failed)
+        }
+    }
+}
+
+/// Removes a user from a bucket, users can always leave a bucket
+/// themselves.
+pub async fn remove_bucket_member(
+    pool: &PgPool,
+    user_id: &Uuid,
+    bucket_id: &Uuid,
+    member_id: &Uuid,
+) -> Result<(), BucketMemberErrors> {
+    if user_id != member_id {
+        get_managed_bucket(pool, user_id, bucket_id).await?;
+    }
+
+    let query = "DELETE FROM bucketusers WHERE bucket_id = $1 AND user_id = $2";
+
+    let query = sqlx::query(query)
+        .bind(bucket_id)
+        .bind(member_id)
+        .execute(pool)
+        .await;
+
+    match query {
+        Ok(result) if result.rows_affected() == 0 => Err(BucketMemberErrors::NotFound),
+        Ok(_) => {
+            update_bucket_is_shared(pool, bucket_id).await;
+            Ok(())
+        }
+        Err(error) => {
+            println!(
+                "error while removing user from bucket {}: {}",
+                bucket_id, error
+            );
+            Err(BucketMemberErrors::Failed)
+        }
+    }
+}
+
+/// Adds `delta` bytes to the bucket size, returns `false` when a positive
+/// delta would push the bucket over its `max_bucket_size`.
+pub async fn add_to_bucket_size(
+    transaction: &mut Transaction<'_, Postgres>,
+    bucket_id: &Uuid,
+    delta: i64,
+) -> Result<bool, sqlx::Error> {
+    let query = "UPDATE bucket SET bucket_size = GREATEST(bucket_size + $1, 0) \
+        WHERE bucket_id = $2 AND ($1 <= 0 OR bucket_size + $1 <= max_bucket_size)";
+
+    let query = sqlx::query(query)
+        .bind(delta)
+        .bind(bucket_id)
+        .execute(&mut **transaction)
+        .await?;
+
+    Ok(query.rows_affected() == 1)
+}
+
+pub async fn set_bucket_max_size(
+    pool: &PgPool,
+    bucket_id: &Uuid,
+    max_bucket_size: i64,
+) -> Option<Bucket> {
+    let query = "UPDATE bucket SET max_bucket_size = $1 WHERE bucket_id = $2 RETURNING *";
+
+    let query = sqlx::query_as::<_, Bucket>(query)
+        .bind(max_bucket_size)
+        .bind(bucket_id);
+
+    let bucket = query.fetch_optional(pool).await;
+
+    match bucket {
+        Ok(bucket) => bucket,
+        Err(error) => {
+            println!(
+                "Error occurred while updating max size of bucket {}: {}",
+                bucket_id, error
+            );
+            None
+        }
+    }
+}
+
+/// Puts the max size of every bucket a user owns back to the default.
+pub async fn reset_owned_buckets_max_size(
+    pool: &PgPool,
+    user_id: &Uuid,
+) -> Result<Vec<Bucket>, sqlx::Error> {
+    let query = "UPDATE bucket SET max_bucket_size = DEFAULT WHERE user_id = $1 RETURNING *";
+
+    sqlx::query_as::<_, Bucket>(query)
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn set_bucket_versioning(
+    pool: &PgPool,
+    bucket_id: &Uuid,
+    user_id: &Uuid,
+    versioning: &BucketVersioning,
+) -> Option<Bucket> {
+    let query = "UPDATE bucket SET max_versions = $1, version_retention_days = $2 \
+        WHERE bucket_id = $3 AND user_id = $4 RETURNING *";
+
+    let query = sqlx::query_as::<_, Bucket>(query)
+        .bind(versioning.max_versions)
+        .bind(versioning.version_retention_days)
+        .bind(bucket_id)
+        .bind(user_id);
+
+    let bucket = query.fetch_optional(pool).await;
+
+    match bucket {
+        Ok(bucket) => bucket,
+        Err(error) => {
+            println!(
+                "Error occurred while updating versioning of bucket {}: {}",
+                bucket_id, error
+            );
+            None
+        }
+    }
+}
+
+pub fn get_bucket_folder_path(data_path: &str, bucket_name: &str) -> PathBuf {
+    let mut bucket_folder_path = PathBuf::from(data_path);
+    bucket_folder_path.push(bucket_name);
+
+    bucket_folder_path
+}
+
 pub async fn create_user_bucket(
     pool: &PgPool,
     data_path: &str,
     user_id: &Uuid,
     bucket_name: &str,
 ) -> Option<Bucket> {
-    let mut bucket_folder_path = PathBuf::from(data_path);
-    bucket_folder_path.push(bucket_name);
+    // names starting with a dot are reserved for server folders like `.uploads`
+    if bucket_name.is_empty() || bucket_name.starts_with('.') || bucket_name.contains(['/', '\\']) {
+        return None;
+    }
+
+    let bucket_folder_path = get_bucket_folder_path(data_path, bucket_name);
 
     if !bucket_folder_path.exists() {
         let _ = fs::create_dir_all(bucket_folder_path);
@@ -120,14 +625,7 @@ pub async fn create_user_bucket(
         .await;
 
     match query {
-        Ok(_) => {
-            let bucket = get_bucket_by_name(pool, &bucket_name).await;
-
-            match bucket {
-                Some(bucket) => Some(bucket),
-                None => None,
-            }
-        }
+        Ok(_) => get_bucket_by_name(pool, bucket_name).await,
         Err(error) => {
             println!("error: {}", error);
             None
@@ -180,8 +678,7 @@ async fn delete_buckets_with_out_check(
     match query {
         Ok(_) => {
             for bucket in buckets {
-                let mut bucket_folder_path = PathBuf::from(data_path);
-                bucket_folder_path.push(&bucket.bucket_name);
+                let bucket_folder_path = get_bucket_folder_path(data_path, &bucket.bucket_name);
 
                 if bucket_folder_path.exists() {
                     let _ = fs::remove_dir_all(bucket_folder_path);
@@ -202,7 +699,7 @@ pub async fn delete_user_buckets(
     data_path: &str,
     user_id: &Uuid,
 ) -> Result<(), BucketDeletionError> {
-    let user_buckets = get_all_user_bucket_info(pool, user_id).await;
+    let user_buckets = get_owned_buckets(pool, user_id).await;
 
     if user_buckets.is_none() {
         return Err(BucketDeletionError::InvalidBucket);
@@ -0,0 +1,175 @@
+use ::serde::Deserialize;
+use chrono::{Duration, NaiveDateTime, Utc};
+use sqlx::{self, FromRow, PgPool};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::utility::{
+    genarate_salt,
+    mailer::{send_mail, Mail, Mailer},
+    passcode::hash_token,
+};
+
+use super::user_info::{get_user_info_by_user_id, UserInfo};
+
+/*
+  a new account has to confirm its email address with a single use token
+  mailed to it, only its hash is stored. Until then the user can log in,
+  but `jwt_validator` only lets them through to
+  `EMAIL_VERIFICATION_RESEND_PATH` to ask for a new mail.
+*/
+
+pub const EMAIL_VERIFICATION_RESEND_PATH: &str = "/api/user/verify/resend";
+
+// 64 alphanumeric characters, about 380 bits
+const EMAIL_VERIFICATION_TOKEN_LEN: usize = 64;
+
+const EMAIL_VERIFICATION_HOURS: i64 = 24;
+
+#[derive(Debug, Deserialize)]
+pub struct EmailVerification {
+    pub token: String,
+}
+
+#[derive(Debug, FromRow)]
+struct EmailVerificationToken {
+    verification_id: Uuid,
+    user_id: Uuid,
+    expires_date: NaiveDateTime,
+    used_date: Option<NaiveDateTime>,
+}
+
+#[derive(Debug)]
+pub enum EmailVerificationErrors {
+    InvalidToken,
+    AlreadyVerified,
+    Failed,
+}
+
+/// Mails a new verification token to the user, older ones stop working.
+pub async fn send_verification_mail(
+    pool: &PgPool,
+    mailer: &Arc<dyn Mailer>,
+    user_info: &UserInfo,
+) -> Result<(), EmailVerificationErrors> {
+    if user_info.email_verified_date.is_some() {
+        return Err(EmailVerificationErrors::AlreadyVerified);
+    }
+
+    let token = genarate_salt(EMAIL_VERIFICATION_TOKEN_LEN);
+
+    let created = async {
+        let mut transaction = pool.begin().await?;
+
+        let query = "UPDATE emailverification SET used_date = now() \
+            WHERE user_id = $1 AND used_date IS NULL";
+
+        sqlx::query(query)
+            .bind(user_info.user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        let query = "INSERT INTO emailverification (user_id, token_hash, expires_date) \
+            VALUES($1, $2, $3)";
+
+        sqlx::query(query)
+            .bind(user_info.user_id)
+            .bind(hash_token(&token))
+            .bind((Utc::now() + Duration::hours(EMAIL_VERIFICATION_HOURS)).naive_utc())
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await
+    }
+    .await;
+
+    if let Err(error) = created {
+        println!(
+            "error while creating an email verification for {}: {}",
+            user_info.user_id, error
+        );
+        return Err(EmailVerificationErrors::Failed);
+    }
+
+    let mail = Mail {
+        to: user_info.email.to_owned(),
+        subject: "Verify your email address".to_owned(),
+        body: format!(
+            "Hi {},\n\nto finish setting up your account, send this token to \
+            /api/auth/verify within {} hours:\n\n{}\n\n\
+            If you didn't make an account, you can ignore this mail.",
+            user_info.user_name, EMAIL_VERIFICATION_HOURS, token
+        ),
+    };
+
+    match send_mail(mailer, mail).await {
+        Ok(_) => Ok(()),
+        Err(_) => Err(EmailVerificationErrors::Failed),
+    }
+}
+
+pub async fn resend_verification_mail(
+    pool: &PgPool,
+    mailer: &Arc<dyn Mailer>,
+    user_id: &Uuid,
+) -> Result<(), EmailVerificationErrors> {
+    match get_user_info_by_user_id(pool, user_id).await {
+        Some(user_info) => send_verification_mail(pool, mailer, &user_info).await,
+        None => Err(EmailVerificationErrors::Failed),
+    }
+}
+
+/// Marks the email address of the user a verification token was sent to
+/// as verified.
+pub async fn verify_user_email(pool: &PgPool, token: &str) -> Result<(), EmailVerificationErrors> {
+    let verified = async {
+        let mut transaction = pool.begin().await?;
+
+        let query = "SELECT verification_id, user_id, expires_date, used_date \
+            FROM emailverification WHERE token_hash = $1 FOR UPDATE";
+
+        let verification_token = sqlx::query_as::<_, EmailVerificationToken>(query)
+            .bind(hash_token(token))
+            .fetch_optional(&mut *transaction)
+            .await?;
+
+        let verification_token = match verification_token {
+            Some(verification_token)
+                if verification_token.used_date.is_none()
+                    && verification_token.expires_date > Utc::now().naive_utc() =>
+            {
+                verification_token
+            }
+            _ => return Ok(false),
+        };
+
+        let query = "UPDATE emailverification SET used_date = now() WHERE verification_id = $1";
+
+        sqlx::query(query)
+            .bind(verification_token.verification_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        let query = "UPDATE userinfo SET email_verified_date = now() \
+            WHERE user_id = $1 AND email_verified_date IS NULL";
+
+        sqlx::query(query)
+            .bind(verification_token.user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+
+        Ok::<bool, sqlx::Error>(true)
+    }
+    .await;
+
+    match verified {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(EmailVerificationErrors::InvalidToken),
+        Err(error) => {
+            println!("error while verifying an email address: {}", error);
+            Err(EmailVerificationErrors::Failed)
+        }
+    }
+}
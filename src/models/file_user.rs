@@ -0,0 +1,279 @@
+use ::serde::{Deserialize, Serialize};
+use sqlx::{self, FromRow, PgPool};
+use uuid::Uuid;
+
+use super::{
+    bucket::{get_bucket_by_id, get_bucket_permissions, BUCKET_SHARE},
+    user_file::{get_file_info_by_id, UserFile, UserFileErrors},
+    user_info::get_user_by_email,
+};
+
+/*
+  "FileUsers"."Permissions" is a bitmask of what a user the file is
+  shared with may do, the owner of a file can always do everything:
+
+  1 read     download the file and its versions, copy it
+  2 write    upload new versions, restore, rename and move
+  4 delete   move the file to the trash of its owner
+  8 reshare  share the file with others, with at most their own bits
+
+  a role on the bucket of the file grants the same bits.
+
+  the owner of the file and users allowed to share through the bucket
+  manage all of its shares. Users who only got the reshare bit can share
+  it with new users, but not change an existing share, and can only stop
+  the shares they made themselves.
+*/
+
+pub const FILE_READ: i32 = 1;
+pub const FILE_WRITE: i32 = 2;
+pub const FILE_DELETE: i32 = 4;
+pub const FILE_RESHARE: i32 = 8;
+pub const FILE_ALL: i32 = FILE_READ | FILE_WRITE | FILE_DELETE | FILE_RESHARE;
+
+#[derive(Debug, FromRow, Serialize, Deserialize)]
+pub struct FileUser {
+    pub file_id: Uuid,
+    pub user_id: Uuid,
+    #[sqlx(rename = "Permissions")]
+    pub permissions: i32,
+    pub granted_by: Option<Uuid>,
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct FileShare {
+    pub user_id: Uuid,
+    pub user_name: String,
+    pub email: String,
+    #[sqlx(rename = "Permissions")]
+    pub permissions: i32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewFileShare {
+    pub email: String,
+    pub permissions: i32,
+}
+
+pub async fn get_file_user(pool: &PgPool, file_id: &Uuid, user_id: &Uuid) -> Option<FileUser> {
+    let query = r#"SELECT * FROM "FileUsers" WHERE file_id = $1 AND user_id = $2"#;
+
+    let query = sqlx::query_as::<_, FileUser>(query)
+        .bind(file_id)
+        .bind(user_id);
+
+    let file_user = query.fetch_optional(pool).await;
+
+    match file_user {
+        Ok(file_user) => file_user,
+        Err(error) => {
+            println!(
+                "Error occurred while fetching file user for file {} and user {}: {}",
+                file_id, user_id, error
+            );
+            None
+        }
+    }
+}
+
+/// The permission bits `user_id` has on a file, either shared with them
+/// directly or through their role on the bucket of the file.
+pub async fn get_file_permissions(pool: &PgPool, file_info: &UserFile, user_id: &Uuid) -> i32 {
+    if &file_info.user_id == user_id {
+        return FILE_ALL;
+    }
+
+    let mut permissions = match get_file_user(pool, &file_info.file_id, user_id).await {
+        Some(file_user) => file_user.permissions,
+        None => 0,
+    };
+
+    // bucket permissions use the same bits as file permissions
+    if let Some(bucket) = get_bucket_by_id(pool, &file_info.bucket_id).await {
+        permissions |= get_bucket_permissions(pool, &bucket, user_id).await;
+    }
+
+    permissions
+}
+
+/// Whether `user_id` manages every share of the file, not only their own.
+async fn can_manage_file_shares(pool: &PgPool, file_info: &UserFile, user_id: &Uuid) -> bool {
+    if &file_info.user_id == user_id {
+        return true;
+    }
+
+    match get_bucket_by_id(pool, &file_info.bucket_id).await {
+        Some(bucket) => get_bucket_permissions(pool, &bucket, user_id).await & BUCKET_SHARE != 0,
+        None => false,
+    }
+}
+
+pub async fn user_has_file_permission(
+    pool: &PgPool,
+    file_info: &UserFile,
+    user_id: &Uuid,
+    permission: i32,
+) -> bool {
+    get_file_permissions(pool, file_info, user_id).await & permission == permission
+}
+
+pub async fn get_file_shares(
+    pool: &PgPool,
+    user_id: &Uuid,
+    file_id: &Uuid,
+) -> Result<Vec<FileShare>, UserFileErrors> {
+    let file_info = get_file_info_by_id(pool, file_id).await?;
+
+    if !user_has_file_permission(pool, &file_info, user_id, FILE_RESHARE).await {
+        return Err(UserFileErrors::Forbidden);
+    }
+
+    let query = r#"SELECT ui.user_id, ui.user_name, ui.email, fu."Permissions" FROM "FileUsers" fu
+        JOIN userinfo ui ON ui.user_id = fu.user_id WHERE fu.file_id = $1 ORDER BY ui.user_name"#;
+
+    let query = sqlx::query_as::<_, FileShare>(query).bind(file_id);
+
+    let file_shares = query.fetch_all(pool).await;
+
+    match file_shares {
+        Ok(file_shares) => Ok(file_shares),
+        Err(error) => {
+            println!(
+                "Error occurred while fetching shares of file {}: {}",
+                file_id, error
+            );
+            Err(UserFileErrors::NotFound)
+        }
+    }
+}
+
+async fn update_file_is_shared(pool: &PgPool, file_id: &Uuid) {
+    let query = r#"UPDATE userfile SET is_shared = EXISTS (
+        SELECT 1 FROM "FileUsers" WHERE file_id = $1
+    ) WHERE file_id = $1"#;
+
+    let query = sqlx::query(query).bind(file_id).execute(pool).await;
+
+    if let Err(error) = query {
+        println!(
+            "error while updating is_shared of file {}: {}",
+            file_id, error
+        );
+    }
+}
+
+/// Shares a file with the user registered under `email`, sharing again
+/// replaces the permissions. A user resharing a file can only hand out
+/// the bits they have themselves and can't change an existing share.
+///
+/// Unknown emails and shares that are left as they were are not reported,
+/// so this can't be used to find out who has an account.
+pub async fn share_user_file(
+    pool: &PgPool,
+    user_id: &Uuid,
+    file_id: &Uuid,
+    new_file_share: &NewFileShare,
+) -> Result<(), UserFileErrors> {
+    let file_info = get_file_info_by_id(pool, file_id).await?;
+
+    if file_info.deleted_date.is_some() {
+        return Err(UserFileErrors::Deleted);
+    }
+
+    let permissions = get_file_permissions(pool, &file_info, user_id).await;
+
+    if permissions & FILE_RESHARE == 0 {
+        return Err(UserFileErrors::Forbidden);
+    }
+
+    if new_file_share.permissions & FILE_READ == 0
+        || new_file_share.permissions & !FILE_ALL != 0
+        || new_file_share.permissions & !permissions != 0
+    {
+        return Err(UserFileErrors::InvalidPermissions);
+    }
+
+    let share_user = match get_user_by_email(pool, &new_file_share.email).await {
+        Some(share_user) if share_user.user_id != file_info.user_id => share_user,
+        _ => return Ok(()),
+    };
+
+    if &share_user.user_id == user_id {
+        return Err(UserFileErrors::InvalidShareUser);
+    }
+
+    let query = match can_manage_file_shares(pool, &file_info, user_id).await {
+        true => {
+            r#"INSERT INTO "FileUsers" (file_id, user_id, "Permissions", granted_by)
+            VALUES($1, $2, $3, $4) ON CONFLICT (file_id, user_id)
+            DO UPDATE SET "Permissions" = EXCLUDED."Permissions", granted_by = EXCLUDED.granted_by"#
+        }
+        false => {
+            r#"INSERT INTO "FileUsers" (file_id, user_id, "Permissions", granted_by)
+            VALUES($1, $2, $3, $4) ON CONFLICT (file_id, user_id) DO NOTHING"#
+        }
+    };
+
+    let query = sqlx::query(query)
+        .bind(file_id)
+        .bind(share_user.user_id)
+        .bind(new_file_share.permissions)
+        .bind(user_id)
+        .execute(pool)
+        .await;
+
+    match query {
+        Ok(_) => {
+            update_file_is_shared(pool, file_id).await;
+            Ok(())
+        }
+        Err(error) => {
+            println!("error while sharing file {}: {}", file_id, error);
+            Err(UserFileErrors::FailedToSave)
+        }
+    }
+}
+
+/// Stops sharing a file with `share_user_id`, users can always remove
+/// themselves from a file shared with them. Users who only reshare the
+/// file can stop only the shares they made.
+pub async fn unshare_user_file(
+    pool: &PgPool,
+    user_id: &Uuid,
+    file_id: &Uuid,
+    share_user_id: &Uuid,
+) -> Result<(), UserFileErrors> {
+    let file_info = get_file_info_by_id(pool, file_id).await?;
+
+    // the user the shares have to be made by, none when any share can go
+    let granted_by =
+        if user_id == share_user_id || can_manage_file_shares(pool, &file_info, user_id).await {
+            None
+        } else if user_has_file_permission(pool, &file_info, user_id, FILE_RESHARE).await {
+            Some(user_id)
+        } else {
+            return Err(UserFileErrors::Forbidden);
+        };
+
+    let query = r#"DELETE FROM "FileUsers" WHERE file_id = $1 AND user_id = $2
+        AND ($3::UUID IS NULL OR granted_by = $3)"#;
+
+    let query = sqlx::query(query)
+        .bind(file_id)
+        .bind(share_user_id)
+        .bind(granted_by)
+        .execute(pool)
+        .await;
+
+    match query {
+        Ok(result) if result.rows_affected() == 0 => Err(UserFileErrors::NotFound),
+        Ok(_) => {
+            update_file_is_shared(pool, file_id).await;
+            Ok(())
+        }
+        Err(error) => {
+            println!("error while unsharing file {}: {}", file_id, error);
+            Err(UserFileErrors::FailedToDelete)
+        }
+    }
+}
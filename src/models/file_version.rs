@@ -0,0 +1,509 @@
+use ::serde::{Deserialize, Serialize};
+use actix_multipart::Multipart;
+use chrono::NaiveDateTime;
+use sqlx::{self, FromRow, PgPool, Postgres, Transaction};
+use std::{path::PathBuf, slice};
+use uuid::Uuid;
+
+use super::{
+    blob::{
+        add_blob_reference, copy_blob_reference, get_blob_path, release_blob_reference,
+        remove_unused_blobs,
+    },
+    bucket::{add_to_bucket_size, get_bucket_by_id},
+    user_file::{
+        check_health_and_reterive_file, get_file_info_by_id, receive_upload, user_can_read,
+        user_can_write, NewFileMetadata, PartialFile, UserFile, UserFileErrors,
+    },
+};
+
+/*
+  FileMetadata always holds the current version of a file, earlier
+  versions are kept in FileVersion, each with its own blob reference
+  and charged to the bucket like any other file.
+*/
+
+#[derive(Debug, FromRow, Serialize, Deserialize)]
+pub struct FileVersion {
+    pub version_id: Uuid,
+    pub file_id: Uuid,
+    pub version_number: i32,
+    pub file_name: String,
+    pub file_size: i64,
+    pub file_type: String,
+    pub file_hash: String,
+    pub uploaded_by: Option<Uuid>,
+    pub created_date: NaiveDateTime,
+}
+
+#[derive(Debug, FromRow)]
+struct ExpiredFileVersion {
+    version_id: Uuid,
+    bucket_id: Uuid,
+}
+
+async fn get_readable_file(
+    pool: &PgPool,
+    user_id: &Uuid,
+    file_id: &Uuid,
+) -> Result<UserFile, UserFileErrors> {
+    let file_info = get_file_info_by_id(pool, file_id).await?;
+
+    if file_info.deleted_date.is_some() {
+        return Err(UserFileErrors::Deleted);
+    }
+
+    if user_can_read(pool, &file_info, user_id).await {
+        Ok(file_info)
+    } else {
+        Err(UserFileErrors::Forbidden)
+    }
+}
+
+async fn get_writable_file(
+    pool: &PgPool,
+    user_id: &Uuid,
+    file_id: &Uuid,
+) -> Result<UserFile, UserFileErrors> {
+    let file_info = get_file_info_by_id(pool, file_id).await?;
+
+    if file_info.deleted_date.is_some() {
+        return Err(UserFileErrors::Deleted);
+    }
+
+    if user_can_write(pool, &file_info, user_id).await {
+        Ok(file_info)
+    } else {
+        Err(UserFileErrors::Forbidden)
+    }
+}
+
+async fn get_file_version_by_id(
+    pool: &PgPool,
+    file_id: &Uuid,
+    version_id: &Uuid,
+) -> Result<FileVersion, UserFileErrors> {
+    let query = "SELECT * FROM fileversion WHERE version_id = $1 AND file_id = $2";
+
+    let query = sqlx::query_as::<_, FileVersion>(query)
+        .bind(version_id)
+        .bind(file_id);
+
+    let file_version = query.fetch_optional(pool).await;
+
+    match file_version {
+        Ok(Some(file_version)) => Ok(file_version),
+        Ok(None) => Err(UserFileErrors::NotFound),
+        Err(error) => {
+            println!(
+                "Error occurred while fetching version {} of file {}: {}",
+                version_id, file_id, error
+            );
+            Err(UserFileErrors::NotFound)
+        }
+    }
+}
+
+pub async fn get_file_versions(
+    pool: &PgPool,
+    user_id: &Uuid,
+    file_id: &Uuid,
+) -> Result<Vec<FileVersion>, UserFileErrors> {
+    let file_info = get_readable_file(pool, user_id, file_id).await?;
+
+    let query = "SELECT * FROM fileversion WHERE file_id = $1 ORDER BY version_number DESC";
+
+    let query = sqlx::query_as::<_, FileVersion>(query).bind(file_info.file_id);
+
+    let file_versions = query.fetch_all(pool).await;
+
+    match file_versions {
+        Ok(file_versions) => Ok(file_versions),
+        Err(error) => {
+            println!(
+                "Error occurred while fetching versions of file {}: {}",
+                file_id, error
+            );
+            Err(UserFileErrors::NotFound)
+        }
+    }
+}
+
+/// Returns the file as it was at `version_id`, with the path of its content.
+pub async fn get_file_version(
+    pool: &PgPool,
+    data_path: &str,
+    user_id: &Uuid,
+    file_id: &Uuid,
+    version_id: &Uuid,
+) -> Result<(UserFile, PathBuf), UserFileErrors> {
+    let file_info = get_readable_file(pool, user_id, file_id).await?;
+    let file_version = get_file_version_by_id(pool, file_id, version_id).await?;
+
+    let file_path = get_blob_path(data_path, &file_version.file_hash);
+
+    if !file_path.exists() {
+        return Err(UserFileErrors::Deleted);
+    }
+
+    let version_info = UserFile {
+        file_name: file_version.file_name,
+        file_size: file_version.file_size,
+        file_type: file_version.file_type,
+        file_hash: file_version.file_hash,
+        version_number: file_version.version_number,
+        uploaded_by: file_version.uploaded_by,
+        created_date: file_version.created_date,
+        ..file_info
+    };
+
+    Ok((version_info, file_path))
+}
+
+/// Locks the current version of a file and copies it into the history.
+async fn push_current_version(
+    transaction: &mut Transaction<'_, Postgres>,
+    file_id: &Uuid,
+) -> Result<(), sqlx::Error> {
+    let query = "SELECT file_id FROM filemetadata WHERE file_id = $1 FOR UPDATE";
+
+    sqlx::query(query)
+        .bind(file_id)
+        .execute(&mut **transaction)
+        .await?;
+
+    let query = "INSERT INTO fileversion \
+        (file_id, version_number, file_name, file_size, file_type, file_hash, uploaded_by, created_date) \
+        SELECT file_id, version_number, file_name, file_size, file_type, file_hash, uploaded_by, created_date \
+        FROM filemetadata WHERE file_id = $1";
+
+    sqlx::query(query)
+        .bind(file_id)
+        .execute(&mut **transaction)
+        .await?;
+
+    Ok(())
+}
+
+async fn set_current_version(
+    transaction: &mut Transaction<'_, Postgres>,
+    file_id: &Uuid,
+    user_id: &Uuid,
+    file_size: i64,
+    file_type: &str,
+    file_hash: &str,
+) -> Result<(), sqlx::Error> {
+    let query = "UPDATE filemetadata SET file_size = $1, file_type = $2, file_hash = $3, \
+        uploaded_by = $4, version_number = version_number + 1, created_date = now() \
+        WHERE file_id = $5";
+
+    sqlx::query(query)
+        .bind(file_size)
+        .bind(file_type)
+        .bind(file_hash)
+        .bind(user_id)
+        .bind(file_id)
+        .execute(&mut **transaction)
+        .await?;
+
+    Ok(())
+}
+
+async fn store_file_version(
+    pool: &PgPool,
+    data_path: &str,
+    user_id: &Uuid,
+    file_info: &UserFile,
+    new_file: &NewFileMetadata,
+    partial_file: PartialFile,
+) -> Result<(), UserFileErrors> {
+    let mut transaction = match pool.begin().await {
+        Ok(transaction) => transaction,
+        Err(error) => {
+            println!("error while starting transaction: {}", error);
+            return Err(UserFileErrors::FailedToSave);
+        }
+    };
+
+    match add_to_bucket_size(&mut transaction, &file_info.bucket_id, new_file.file_size).await {
+        Ok(true) => {}
+        Ok(false) => return Err(UserFileErrors::QuotaExceeded),
+        Err(error) => {
+            println!("error while updating bucket size: {}", error);
+            return Err(UserFileErrors::FailedToSave);
+        }
+    }
+
+    if let Err(error) = push_current_version(&mut transaction, &file_info.file_id).await {
+        println!("error while keeping the current file version: {}", error);
+        return Err(UserFileErrors::FailedToSave);
+    }
+
+    let stored_blob = add_blob_reference(
+        &mut transaction,
+        data_path,
+        &new_file.file_hash,
+        new_file.file_size,
+        partial_file,
+    )
+    .await?;
+
+    let updated = set_current_version(
+        &mut transaction,
+        &file_info.file_id,
+        user_id,
+        new_file.file_size,
+        &new_file.file_type,
+        &new_file.file_hash,
+    )
+    .await;
+
+    let committed = match updated {
+        Ok(_) => transaction.commit().await,
+        Err(error) => Err(error),
+    };
+
+    match committed {
+        Ok(_) => Ok(()),
+        Err(error) => {
+            println!("error while saving the new file version: {}", error);
+            if stored_blob {
+                remove_unused_blobs(pool, data_path, slice::from_ref(&new_file.file_hash)).await;
+            }
+            Err(UserFileErrors::FailedToSave)
+        }
+    }
+}
+
+/// Uploads a new version of an existing file, the current one moves into
+/// the history.
+pub async fn save_file_version(
+    pool: &PgPool,
+    data_path: &str,
+    user_id: &Uuid,
+    file_id: &Uuid,
+    max_upload_size: Option<i64>,
+    payload: Multipart,
+) -> Result<UserFile, UserFileErrors> {
+    let file_info = get_writable_file(pool, user_id, file_id).await?;
+
+    let bucket = match get_bucket_by_id(pool, &file_info.bucket_id).await {
+        Some(bucket) => bucket,
+        None => return Err(UserFileErrors::InvalidBucket),
+    };
+
+    let received_file = receive_upload(payload, data_path, &bucket, max_upload_size).await?;
+    let (mut new_file, partial_file) = received_file.into_metadata(file_id);
+    new_file.file_name = file_info.file_name.to_owned();
+
+    store_file_version(
+        pool,
+        data_path,
+        user_id,
+        &file_info,
+        &new_file,
+        partial_file,
+    )
+    .await?;
+
+    apply_version_retention(pool, data_path, file_id).await;
+
+    get_file_info_by_id(pool, file_id).await
+}
+
+/// Makes an earlier version current again, by adding it as a new version.
+pub async fn restore_file_version(
+    pool: &PgPool,
+    data_path: &str,
+    user_id: &Uuid,
+    file_id: &Uuid,
+    version_id: &Uuid,
+) -> Result<UserFile, UserFileErrors> {
+    let (file_info, _) = check_health_and_reterive_file(pool, data_path, file_id).await?;
+
+    if !user_can_write(pool, &file_info, user_id).await {
+        return Err(UserFileErrors::Forbidden);
+    }
+
+    let file_version = get_file_version_by_id(pool, file_id, version_id).await?;
+
+    let restored = async {
+        let mut transaction = pool.begin().await?;
+
+        if !add_to_bucket_size(
+            &mut transaction,
+            &file_info.bucket_id,
+            file_version.file_size,
+        )
+        .await?
+        {
+            return Ok(false);
+        }
+
+        push_current_version(&mut transaction, file_id).await?;
+        copy_blob_reference(&mut transaction, &file_version.file_hash).await?;
+        set_current_version(
+            &mut transaction,
+            file_id,
+            user_id,
+            file_version.file_size,
+            &file_version.file_type,
+            &file_version.file_hash,
+        )
+        .await?;
+
+        transaction.commit().await?;
+
+        Ok::<bool, sqlx::Error>(true)
+    }
+    .await;
+
+    match restored {
+        Ok(true) => {}
+        Ok(false) => return Err(UserFileErrors::QuotaExceeded),
+        Err(error) => {
+            println!(
+                "error while restoring version {} of file {}: {}",
+                version_id, file_id, error
+            );
+            return Err(UserFileErrors::FailedToSave);
+        }
+    }
+
+    apply_version_retention(pool, data_path, file_id).await;
+
+    get_file_info_by_id(pool, file_id).await
+}
+
+/// Returns the hash of the blob when the version held its last reference.
+async fn delete_file_version(
+    transaction: &mut Transaction<'_, Postgres>,
+    bucket_id: &Uuid,
+    version_id: &Uuid,
+) -> Result<Option<String>, sqlx::Error> {
+    let query = "DELETE FROM fileversion WHERE version_id = $1 RETURNING *";
+
+    let file_version = sqlx::query_as::<_, FileVersion>(query)
+        .bind(version_id)
+        .fetch_optional(&mut **transaction)
+        .await?;
+
+    if let Some(file_version) = file_version {
+        add_to_bucket_size(transaction, bucket_id, -file_version.file_size).await?;
+        if release_blob_reference(transaction, &file_version.file_hash).await? {
+            return Ok(Some(file_version.file_hash));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Releases the history of a file that is about to be deleted, returns
+/// the hashes of the blobs that are no longer used.
+pub async fn release_file_versions(
+    transaction: &mut Transaction<'_, Postgres>,
+    file_info: &UserFile,
+) -> Result<Vec<String>, sqlx::Error> {
+    let query = "SELECT version_id FROM fileversion WHERE file_id = $1";
+
+    let version_ids = sqlx::query_scalar::<_, Uuid>(query)
+        .bind(file_info.file_id)
+        .fetch_all(&mut **transaction)
+        .await?;
+
+    let mut unused_blobs = Vec::new();
+
+    for version_id in version_ids {
+        if let Some(file_hash) =
+            delete_file_version(transaction, &file_info.bucket_id, &version_id).await?
+        {
+            unused_blobs.push(file_hash);
+        }
+    }
+
+    Ok(unused_blobs)
+}
+
+async fn delete_expired_file_versions(
+    pool: &PgPool,
+    data_path: &str,
+    expired_versions: &Vec<ExpiredFileVersion>,
+) -> usize {
+    let mut deleted = 0;
+
+    for expired_version in expired_versions {
+        let result = async {
+            let mut transaction = pool.begin().await?;
+            let unused_blob = delete_file_version(
+                &mut transaction,
+                &expired_version.bucket_id,
+                &expired_version.version_id,
+            )
+            .await?;
+            transaction.commit().await?;
+            Ok::<Option<String>, sqlx::Error>(unused_blob)
+        }
+        .await;
+
+        match result {
+            Ok(unused_blob) => {
+                deleted += 1;
+                if let Some(file_hash) = unused_blob {
+                    remove_unused_blobs(pool, data_path, &[file_hash]).await;
+                }
+            }
+            Err(error) => println!(
+                "error while deleting file version {}: {}",
+                expired_version.version_id, error
+            ),
+        }
+    }
+
+    deleted
+}
+
+// versions beyond the bucket's max_versions or older than its retention period
+const EXPIRED_FILE_VERSIONS_SELECT: &str = "SELECT fv.version_id, b.bucket_id FROM fileversion fv \
+    JOIN userfile uf ON uf.file_id = fv.file_id \
+    JOIN bucket b ON b.bucket_id = uf.bucket_id \
+    WHERE (fv.version_number <= ( \
+            SELECT MAX(version_number) FROM fileversion WHERE file_id = fv.file_id \
+        ) - b.max_versions \
+        OR fv.created_date < now() - make_interval(days => b.version_retention_days))";
+
+/// Drops the versions of a file its bucket no longer wants to keep.
+pub async fn apply_version_retention(pool: &PgPool, data_path: &str, file_id: &Uuid) {
+    let query = format!("{} AND fv.file_id = $1", EXPIRED_FILE_VERSIONS_SELECT);
+
+    let expired_versions = sqlx::query_as::<_, ExpiredFileVersion>(&query)
+        .bind(file_id)
+        .fetch_all(pool)
+        .await;
+
+    match expired_versions {
+        Ok(expired_versions) => {
+            delete_expired_file_versions(pool, data_path, &expired_versions).await;
+        }
+        Err(error) => println!(
+            "error while applying version retention to file {}: {}",
+            file_id, error
+        ),
+    }
+}
+
+/// Drops every version its bucket no longer wants to keep.
+pub async fn purge_expired_file_versions(pool: &PgPool, data_path: &str) -> usize {
+    let expired_versions = sqlx::query_as::<_, ExpiredFileVersion>(EXPIRED_FILE_VERSIONS_SELECT)
+        .fetch_all(pool)
+        .await;
+
+    match expired_versions {
+        Ok(expired_versions) => {
+            delete_expired_file_versions(pool, data_path, &expired_versions).await
+        }
+        Err(error) => {
+            println!("error while purging expired file versions: {}", error);
+            0
+        }
+    }
+}
@@ -0,0 +1,549 @@
+use ::serde::{Deserialize, Serialize};
+use chrono::NaiveDateTime;
+use sqlx::{self, FromRow, PgExecutor, PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::utility::deserialize_some;
+
+use super::{
+    bucket::{
+        get_bucket_by_id, get_bucket_permissions, get_target_bucket, user_can_read_bucket,
+        user_can_write_bucket, Bucket, TargetBucket, BUCKET_DELETE, BUCKET_READ, BUCKET_WRITE,
+    },
+    user_file::{UserFile, UserFileErrors, USER_FILE_SELECT},
+};
+
+/*
+  the folder tree of a bucket lives only in Postgres, a file points to
+  its folder through UserFile.folder_id and a NULL folder is the root
+  of the bucket, so renaming or moving a folder never touches the blobs.
+*/
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct Folder {
+    pub folder_id: Uuid,
+    pub bucket_id: Uuid,
+    pub parent_id: Option<Uuid>,
+    pub folder_name: String,
+    pub created_date: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewFolder {
+    pub bucket_id: Uuid,
+    pub parent_id: Option<Uuid>,
+    pub folder_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FolderUpdate {
+    pub folder_name: Option<String>,
+    // absent keeps the parent, null moves the folder to the root of the bucket
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub parent_id: Option<Option<Uuid>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FolderContents {
+    pub bucket_id: Uuid,
+    pub folder: Option<Folder>,
+    pub folders: Vec<Folder>,
+    pub files: Vec<UserFile>,
+}
+
+// a file below a folder, with its folder path relative to that folder
+#[derive(Debug, FromRow)]
+pub struct FolderTreeFile {
+    #[sqlx(flatten)]
+    pub file: UserFile,
+    pub folder_path: String,
+}
+
+// where an upload goes inside its bucket
+#[derive(Debug)]
+pub enum TargetFolder {
+    // a folder that exists already, `None` is the root of the bucket
+    Existing(Option<Uuid>),
+    // a path with missing folders, created when the file is stored
+    Missing(String),
+}
+
+#[derive(Debug)]
+pub enum FolderErrors {
+    NotFound,
+    Forbidden,
+    InvalidName,
+    AlreadyExists,
+    InvalidMove,
+    Failed,
+}
+
+const UNIQUE_VIOLATION: &str = "23505";
+
+pub fn is_valid_folder_name(folder_name: &str) -> bool {
+    !folder_name.is_empty()
+        && folder_name.len() <= 255
+        && folder_name != "."
+        && folder_name != ".."
+        && !folder_name.contains(['/', '\\'])
+}
+
+/// Splits a path like `docs/2023/taxes` into folder names, empty segments
+/// from leading, trailing or repeated slashes are ignored.
+pub fn split_folder_path(path: &str) -> Result<Vec<&str>, FolderErrors> {
+    let folder_names = path
+        .split('/')
+        .filter(|folder_name| !folder_name.is_empty())
+        .collect::<Vec<&str>>();
+
+    if folder_names
+        .iter()
+        .all(|folder_name| is_valid_folder_name(folder_name))
+    {
+        Ok(folder_names)
+    } else {
+        Err(FolderErrors::InvalidName)
+    }
+}
+
+fn map_folder_error(error: sqlx::Error) -> FolderErrors {
+    match &error {
+        sqlx::Error::Database(database_error)
+            if database_error.code().as_deref() == Some(UNIQUE_VIOLATION) =>
+        {
+            FolderErrors::AlreadyExists
+        }
+        _ => {
+            println!("error while updating folders: {}", error);
+            FolderErrors::Failed
+        }
+    }
+}
+
+pub async fn get_folder_by_id(pool: &PgPool, folder_id: &Uuid) -> Option<Folder> {
+    let query = "SELECT * FROM folder WHERE folder_id = $1";
+
+    let query = sqlx::query_as::<_, Folder>(query).bind(folder_id);
+
+    let folder = query.fetch_optional(pool).await;
+
+    match folder {
+        Ok(folder) => folder,
+        Err(error) => {
+            println!(
+                "Error occurred while fetching folder {}: {}",
+                folder_id, error
+            );
+            None
+        }
+    }
+}
+
+async fn get_child_folder(
+    executor: impl PgExecutor<'_>,
+    bucket_id: &Uuid,
+    parent_id: Option<&Uuid>,
+    folder_name: &str,
+) -> Result<Option<Folder>, sqlx::Error> {
+    let query = "SELECT * FROM folder WHERE bucket_id = $1 \
+        AND parent_id IS NOT DISTINCT FROM $2 AND folder_name = $3";
+
+    sqlx::query_as::<_, Folder>(query)
+        .bind(bucket_id)
+        .bind(parent_id)
+        .bind(folder_name)
+        .fetch_optional(executor)
+        .await
+}
+
+/// Looks up the folder at `path`, `None` is the root of the bucket.
+pub async fn find_folder_by_path(
+    pool: &PgPool,
+    bucket_id: &Uuid,
+    path: &str,
+) -> Result<Option<Folder>, FolderErrors> {
+    find_folder_by_relative_path(pool, bucket_id, None, path).await
+}
+
+/// Looks up the folder at `path` below `root_folder`.
+pub async fn find_folder_by_relative_path(
+    pool: &PgPool,
+    bucket_id: &Uuid,
+    root_folder: Option<Folder>,
+    path: &str,
+) -> Result<Option<Folder>, FolderErrors> {
+    let mut folder = root_folder;
+
+    for folder_name in split_folder_path(path)? {
+        let parent_id = folder.as_ref().map(|folder| folder.folder_id);
+
+        folder = match get_child_folder(pool, bucket_id, parent_id.as_ref(), folder_name).await {
+            Ok(Some(child_folder)) => Some(child_folder),
+            Ok(None) => return Err(FolderErrors::NotFound),
+            Err(error) => return Err(map_folder_error(error)),
+        };
+    }
+
+    Ok(folder)
+}
+
+/// Looks up the folder at `path`, creating the missing folders on the way.
+async fn create_folder_path(
+    transaction: &mut Transaction<'_, Postgres>,
+    bucket_id: &Uuid,
+    path: &str,
+) -> Result<Option<Folder>, FolderErrors> {
+    let mut folder: Option<Folder> = None;
+
+    for folder_name in split_folder_path(path)? {
+        let parent_id = folder.as_ref().map(|folder| folder.folder_id);
+
+        let query = "INSERT INTO folder (bucket_id, parent_id, folder_name) VALUES($1, $2, $3) \
+            ON CONFLICT DO NOTHING";
+
+        sqlx::query(query)
+            .bind(bucket_id)
+            .bind(parent_id)
+            .bind(folder_name)
+            .execute(&mut **transaction)
+            .await
+            .map_err(map_folder_error)?;
+
+        folder = match get_child_folder(
+            &mut **transaction,
+            bucket_id,
+            parent_id.as_ref(),
+            folder_name,
+        )
+        .await
+        {
+            Ok(Some(child_folder)) => Some(child_folder),
+            // the folder was removed right after it was created
+            Ok(None) => return Err(FolderErrors::NotFound),
+            Err(error) => return Err(map_folder_error(error)),
+        };
+    }
+
+    Ok(folder)
+}
+
+/// Resolves the `path` of an upload target inside `bucket`. Missing
+/// folders are only created with the file, so a refused or aborted upload
+/// leaves none behind.
+pub async fn resolve_target_folder(
+    pool: &PgPool,
+    bucket: &Bucket,
+    target_bucket: &TargetBucket,
+) -> Result<TargetFolder, UserFileErrors> {
+    let path = match &target_bucket.path {
+        Some(path) => path,
+        None => return Ok(TargetFolder::Existing(None)),
+    };
+
+    match find_folder_by_path(pool, &bucket.bucket_id, path).await {
+        Ok(folder) => Ok(TargetFolder::Existing(
+            folder.map(|folder| folder.folder_id),
+        )),
+        Err(FolderErrors::NotFound) => Ok(TargetFolder::Missing(path.to_owned())),
+        Err(FolderErrors::InvalidName) => Err(UserFileErrors::InvalidPath),
+        Err(_) => Err(UserFileErrors::FailedToSave),
+    }
+}
+
+/// The folder of a file being stored in `transaction`, the missing
+/// folders of its target are created now.
+pub async fn create_target_folder(
+    transaction: &mut Transaction<'_, Postgres>,
+    bucket_id: &Uuid,
+    target_folder: &TargetFolder,
+) -> Result<Option<Uuid>, UserFileErrors> {
+    let path = match target_folder {
+        TargetFolder::Existing(folder_id) => return Ok(folder_id.to_owned()),
+        TargetFolder::Missing(path) => path,
+    };
+
+    match create_folder_path(transaction, bucket_id, path).await {
+        Ok(folder) => Ok(folder.map(|folder| folder.folder_id)),
+        Err(FolderErrors::InvalidName) => Err(UserFileErrors::InvalidPath),
+        Err(_) => Err(UserFileErrors::FailedToSave),
+    }
+}
+
+async fn get_user_folder(
+    pool: &PgPool,
+    user_id: &Uuid,
+    folder_id: &Uuid,
+    permission: i32,
+) -> Result<(Folder, Bucket), FolderErrors> {
+    let folder = match get_folder_by_id(pool, folder_id).await {
+        Some(folder) => folder,
+        None => return Err(FolderErrors::NotFound),
+    };
+
+    let bucket = match get_bucket_by_id(pool, &folder.bucket_id).await {
+        Some(bucket) => bucket,
+        None => return Err(FolderErrors::NotFound),
+    };
+
+    if get_bucket_permissions(pool, &bucket, user_id).await & permission != 0 {
+        Ok((folder, bucket))
+    } else {
+        Err(FolderErrors::Forbidden)
+    }
+}
+
+pub async fn create_folder(
+    pool: &PgPool,
+    user_id: &Uuid,
+    new_folder: &NewFolder,
+) -> Result<Folder, FolderErrors> {
+    if !is_valid_folder_name(&new_folder.folder_name) {
+        return Err(FolderErrors::InvalidName);
+    }
+
+    let bucket_id = match &new_folder.parent_id {
+        Some(parent_id) => {
+            let (parent_folder, _) =
+                get_user_folder(pool, user_id, parent_id, BUCKET_WRITE).await?;
+
+            if parent_folder.bucket_id != new_folder.bucket_id {
+                return Err(FolderErrors::InvalidMove);
+            }
+
+            parent_folder.bucket_id
+        }
+        None => {
+            let bucket = match get_bucket_by_id(pool, &new_folder.bucket_id).await {
+                Some(bucket) => bucket,
+                None => return Err(FolderErrors::NotFound),
+            };
+
+            if !user_can_write_bucket(pool, &bucket, user_id).await {
+                return Err(FolderErrors::Forbidden);
+            }
+
+            bucket.bucket_id
+        }
+    };
+
+    let query =
+        "INSERT INTO folder (bucket_id, parent_id, folder_name) VALUES($1, $2, $3) RETURNING *";
+
+    sqlx::query_as::<_, Folder>(query)
+        .bind(bucket_id)
+        .bind(new_folder.parent_id)
+        .bind(&new_folder.folder_name)
+        .fetch_one(pool)
+        .await
+        .map_err(map_folder_error)
+}
+
+pub async fn list_folder(
+    pool: &PgPool,
+    bucket_id: &Uuid,
+    folder: Option<Folder>,
+) -> Result<FolderContents, FolderErrors> {
+    let folder_id = folder.as_ref().map(|folder| folder.folder_id);
+
+    let query = "SELECT * FROM folder WHERE bucket_id = $1 \
+        AND parent_id IS NOT DISTINCT FROM $2 ORDER BY folder_name";
+
+    let folders = sqlx::query_as::<_, Folder>(query)
+        .bind(bucket_id)
+        .bind(folder_id)
+        .fetch_all(pool)
+        .await
+        .map_err(map_folder_error)?;
+
+    let query = format!(
+        "{} WHERE uf.bucket_id = $1 AND uf.folder_id IS NOT DISTINCT FROM $2 \
+        AND uf.deleted_date IS NULL ORDER BY fm.file_name",
+        USER_FILE_SELECT
+    );
+
+    let files = sqlx::query_as::<_, UserFile>(&query)
+        .bind(bucket_id)
+        .bind(folder_id)
+        .fetch_all(pool)
+        .await
+        .map_err(map_folder_error)?;
+
+    Ok(FolderContents {
+        bucket_id: bucket_id.to_owned(),
+        folder,
+        folders,
+        files,
+    })
+}
+
+pub async fn get_folder_contents(
+    pool: &PgPool,
+    user_id: &Uuid,
+    folder_id: &Uuid,
+) -> Result<FolderContents, FolderErrors> {
+    let (folder, bucket) = get_user_folder(pool, user_id, folder_id, BUCKET_READ).await?;
+
+    list_folder(pool, &bucket.bucket_id, Some(folder)).await
+}
+
+/// Lists the folder at `path` of the target bucket, the root when no path
+/// is given.
+pub async fn get_folder_contents_by_path(
+    pool: &PgPool,
+    user_id: &Uuid,
+    target_bucket: &TargetBucket,
+) -> Result<FolderContents, FolderErrors> {
+    let bucket = match get_target_bucket(pool, user_id, target_bucket).await {
+        Some(bucket) => bucket,
+        None => return Err(FolderErrors::NotFound),
+    };
+
+    if !user_can_read_bucket(pool, &bucket, user_id).await {
+        return Err(FolderErrors::Forbidden);
+    }
+
+    let folder = match &target_bucket.path {
+        Some(path) => find_folder_by_path(pool, &bucket.bucket_id, path).await?,
+        None => None,
+    };
+
+    list_folder(pool, &bucket.bucket_id, folder).await
+}
+
+pub async fn is_descendant_folder(
+    pool: &PgPool,
+    folder_id: &Uuid,
+    descendant_id: &Uuid,
+) -> Result<bool, FolderErrors> {
+    let query = "WITH RECURSIVE subtree AS ( \
+            SELECT folder_id FROM folder WHERE folder_id = $1 \
+            UNION ALL \
+            SELECT f.folder_id FROM folder f JOIN subtree s ON f.parent_id = s.folder_id \
+        ) SELECT EXISTS (SELECT 1 FROM subtree WHERE folder_id = $2)";
+
+    sqlx::query_scalar::<_, bool>(query)
+        .bind(folder_id)
+        .bind(descendant_id)
+        .fetch_one(pool)
+        .await
+        .map_err(map_folder_error)
+}
+
+/// Lists every file below `folder_id`, or below the root of the bucket,
+/// with the path of its folder relative to it.
+pub async fn get_folder_tree_files(
+    pool: &PgPool,
+    bucket_id: &Uuid,
+    folder_id: Option<&Uuid>,
+) -> Result<Vec<FolderTreeFile>, FolderErrors> {
+    let query = format!(
+        "WITH RECURSIVE tree AS ( \
+                SELECT folder_id, folder_name::TEXT AS folder_path FROM folder \
+                WHERE bucket_id = $1 AND parent_id IS NOT DISTINCT FROM $2 \
+                UNION ALL \
+                SELECT f.folder_id, t.folder_path || '/' || f.folder_name \
+                FROM folder f JOIN tree t ON f.parent_id = t.folder_id \
+            ) SELECT tree_file.*, COALESCE(tree.folder_path, '') AS folder_path FROM ({}) tree_file \
+            LEFT JOIN tree ON tree.folder_id = tree_file.folder_id \
+            WHERE tree_file.bucket_id = $1 AND tree_file.deleted_date IS NULL \
+            AND (tree_file.folder_id IS NOT DISTINCT FROM $2 OR tree.folder_id IS NOT NULL) \
+            ORDER BY folder_path, tree_file.file_name",
+        USER_FILE_SELECT
+    );
+
+    sqlx::query_as::<_, FolderTreeFile>(&query)
+        .bind(bucket_id)
+        .bind(folder_id)
+        .fetch_all(pool)
+        .await
+        .map_err(map_folder_error)
+}
+
+/// Renames and/or moves a folder within its bucket.
+pub async fn update_folder(
+    pool: &PgPool,
+    user_id: &Uuid,
+    folder_id: &Uuid,
+    folder_update: &FolderUpdate,
+) -> Result<Folder, FolderErrors> {
+    let (folder, _) = get_user_folder(pool, user_id, folder_id, BUCKET_WRITE).await?;
+
+    let folder_name = match &folder_update.folder_name {
+        Some(folder_name) if !is_valid_folder_name(folder_name) => {
+            return Err(FolderErrors::InvalidName)
+        }
+        Some(folder_name) => folder_name.to_owned(),
+        None => folder.folder_name,
+    };
+
+    let parent_id = match folder_update.parent_id {
+        Some(Some(parent_id)) => {
+            let (parent_folder, _) =
+                get_user_folder(pool, user_id, &parent_id, BUCKET_WRITE).await?;
+
+            if parent_folder.bucket_id != folder.bucket_id
+                || is_descendant_folder(pool, folder_id, &parent_id).await?
+            {
+                return Err(FolderErrors::InvalidMove);
+            }
+
+            Some(parent_id)
+        }
+        Some(None) => None,
+        None => folder.parent_id,
+    };
+
+    let query =
+        "UPDATE folder SET folder_name = $1, parent_id = $2 WHERE folder_id = $3 RETURNING *";
+
+    sqlx::query_as::<_, Folder>(query)
+        .bind(folder_name)
+        .bind(parent_id)
+        .bind(folder_id)
+        .fetch_one(pool)
+        .await
+        .map_err(map_folder_error)
+}
+
+/// Deletes a folder with all of its subfolders, the files inside are moved
+/// to the trash and restored to the root of the bucket.
+pub async fn delete_folder(
+    pool: &PgPool,
+    user_id: &Uuid,
+    folder_id: &Uuid,
+) -> Result<u64, FolderErrors> {
+    get_user_folder(pool, user_id, folder_id, BUCKET_DELETE).await?;
+
+    let deleted = async {
+        let mut transaction = pool.begin().await?;
+
+        let query = "WITH RECURSIVE subtree AS ( \
+                SELECT folder_id FROM folder WHERE folder_id = $1 \
+                UNION ALL \
+                SELECT f.folder_id FROM folder f JOIN subtree s ON f.parent_id = s.folder_id \
+            ) UPDATE userfile SET deleted_date = now() \
+            WHERE folder_id IN (SELECT folder_id FROM subtree) AND deleted_date IS NULL";
+
+        let trashed = sqlx::query(query)
+            .bind(folder_id)
+            .execute(&mut *transaction)
+            .await?
+            .rows_affected();
+
+        // subfolders are removed by the parent_id cascade and the files
+        // lose their folder_id
+        let query = "DELETE FROM folder WHERE folder_id = $1";
+
+        sqlx::query(query)
+            .bind(folder_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+
+        Ok::<u64, sqlx::Error>(trashed)
+    }
+    .await;
+
+    deleted.map_err(map_folder_error)
+}
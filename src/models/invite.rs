@@ -0,0 +1,164 @@
+use ::serde::{Deserialize, Serialize};
+use chrono::{Duration, NaiveDateTime, Utc};
+use sqlx::{self, FromRow, PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::utility::{genarate_salt, passcode::hash_token};
+
+/*
+  REGISTRATION_MODE decides who can register: anyone (`open`), only users
+  with an invite code (`invite`) or nobody (`closed`). The very first user
+  can always register, so a new install gets its admin. Admins create the
+  invite codes, a code can be used `max_uses` times until it expires and
+  can set the quota of the first bucket of the users it invites. Only the
+  hash of a code is stored, the code is shown once when it is created.
+*/
+
+// 24 alphanumeric characters, about 140 bits
+const INVITE_CODE_LEN: usize = 24;
+
+// enough of the code to tell invites apart in a list
+const INVITE_CODE_DISPLAY_LEN: usize = 6;
+
+const DEFAULT_INVITE_DAYS: i64 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegistrationMode {
+    Open,
+    InviteOnly,
+    Closed,
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct Invite {
+    pub invite_id: Uuid,
+    pub code_prefix: String,
+    pub max_uses: i32,
+    pub use_count: i32,
+    pub max_bucket_size: Option<i64>,
+    pub expires_date: NaiveDateTime,
+    pub created_by: Option<Uuid>,
+    pub created_date: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NewInviteCode {
+    #[serde(flatten)]
+    pub invite: Invite,
+    // only returned once, when the invite is created
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewInvite {
+    // 1 when left out, a single use code
+    pub max_uses: Option<i32>,
+    pub expires_in_days: Option<i64>,
+    pub max_bucket_size: Option<i64>,
+}
+
+#[derive(Debug)]
+pub enum InviteErrors {
+    NotFound,
+    InvalidUses,
+    InvalidExpiry,
+    InvalidQuota,
+    Failed,
+}
+
+const INVITE_COLUMNS: &str = "invite_id, code_prefix, max_uses, use_count, max_bucket_size, \
+    expires_date, created_by, created_date";
+
+fn map_invite_error(error: sqlx::Error) -> InviteErrors {
+    println!("error while updating invites: {}", error);
+    InviteErrors::Failed
+}
+
+pub async fn get_invites(pool: &PgPool) -> Result<Vec<Invite>, InviteErrors> {
+    let query = format!(
+        "SELECT {} FROM invite ORDER BY created_date DESC",
+        INVITE_COLUMNS
+    );
+
+    sqlx::query_as::<_, Invite>(&query)
+        .fetch_all(pool)
+        .await
+        .map_err(map_invite_error)
+}
+
+pub async fn create_invite(
+    pool: &PgPool,
+    admin_id: &Uuid,
+    new_invite: &NewInvite,
+) -> Result<NewInviteCode, InviteErrors> {
+    let max_uses = new_invite.max_uses.unwrap_or(1);
+    if max_uses <= 0 {
+        return Err(InviteErrors::InvalidUses);
+    }
+
+    let expires_in_days = new_invite.expires_in_days.unwrap_or(DEFAULT_INVITE_DAYS);
+    if expires_in_days <= 0 {
+        return Err(InviteErrors::InvalidExpiry);
+    }
+
+    if new_invite.max_bucket_size.unwrap_or_default() < 0 {
+        return Err(InviteErrors::InvalidQuota);
+    }
+
+    let code = genarate_salt(INVITE_CODE_LEN);
+
+    let query = format!(
+        "INSERT INTO invite (code_prefix, code_hash, max_uses, max_bucket_size, expires_date, created_by) \
+        VALUES($1, $2, $3, $4, $5, $6) RETURNING {}",
+        INVITE_COLUMNS
+    );
+
+    let invite = sqlx::query_as::<_, Invite>(&query)
+        .bind(&code[..INVITE_CODE_DISPLAY_LEN])
+        .bind(hash_token(&code))
+        .bind(max_uses)
+        .bind(new_invite.max_bucket_size)
+        .bind((Utc::now() + Duration::days(expires_in_days)).naive_utc())
+        .bind(admin_id)
+        .fetch_one(pool)
+        .await
+        .map_err(map_invite_error)?;
+
+    println!("admin {} created invite {}", admin_id, invite.invite_id);
+
+    Ok(NewInviteCode { invite, code })
+}
+
+pub async fn delete_invite(pool: &PgPool, invite_id: &Uuid) -> Result<(), InviteErrors> {
+    let query = "DELETE FROM invite WHERE invite_id = $1";
+
+    let deleted = sqlx::query(query)
+        .bind(invite_id)
+        .execute(pool)
+        .await
+        .map_err(map_invite_error)?;
+
+    match deleted.rows_affected() {
+        0 => Err(InviteErrors::NotFound),
+        _ => Ok(()),
+    }
+}
+
+/// Uses up one use of an unexpired invite code, nothing is used when the
+/// registration is rolled back.
+pub async fn claim_invite(
+    transaction: &mut Transaction<'_, Postgres>,
+    code: &str,
+) -> Result<Option<Invite>, sqlx::Error> {
+    let query = format!(
+        "UPDATE invite SET use_count = use_count + 1 \
+        WHERE code_hash = $1 AND use_count < max_uses AND expires_date > $2 RETURNING {}",
+        INVITE_COLUMNS
+    );
+
+    sqlx::query_as::<_, Invite>(&query)
+        .bind(hash_token(code))
+        .bind(Utc::now().naive_utc())
+        .fetch_optional(&mut **transaction)
+        .await
+}
@@ -0,0 +1,346 @@
+use ::serde::{Deserialize, Serialize};
+use chrono::{Duration, NaiveDateTime, Utc};
+use sqlx::{self, FromRow, PgPool};
+use uuid::Uuid;
+
+/*
+  failed logins are counted per account, by the email that was tried, and
+  per IP address. After a few failures every further one makes the caller
+  wait twice as long before the next try, after many the key is locked for
+  a while. Unknown emails are counted the same way, so a lockout says
+  nothing about whether an account exists. A successful login clears the
+  count of the account, an admin can clear it too. Every attempt is kept
+  in LoginAttempt for `LOGIN_ATTEMPT_RETENTION_DAYS` as an audit trail.
+
+  an attempt is counted as a failure before it is checked and given back
+  when it succeeds, so a burst of parallel guesses can't all get past the
+  limits before the first failure is counted. Other guessable secrets, like
+  link passwords and TOTP codes, are throttled the same way under their
+  own keys.
+*/
+
+pub struct ThrottleLimits {
+    // failures before the backoff starts
+    pub backoff_after: i32,
+    // failures before the key is locked for `LOCKOUT_MINUTES`
+    pub lockout_after: i32,
+}
+
+const ACCOUNT_LIMITS: ThrottleLimits = ThrottleLimits {
+    backoff_after: 3,
+    lockout_after: 10,
+};
+
+// many users can share an address, so it gets more room
+const IP_LIMITS: ThrottleLimits = ThrottleLimits {
+    backoff_after: 20,
+    lockout_after: 100,
+};
+
+const MAX_BACKOFF_SECONDS: i64 = 5 * 60;
+
+const LOCKOUT_MINUTES: i64 = 15;
+
+// failures older than this are forgotten
+const FAILURE_WINDOW_MINUTES: i64 = 60;
+
+const LOGIN_ATTEMPT_RETENTION_DAYS: i64 = 90;
+
+// most recent attempts returned to admins
+const LOGIN_ATTEMPT_LIST_LIMIT: i64 = 100;
+
+#[derive(Debug, Clone, Copy)]
+pub enum LoginResult {
+    Success,
+    Failure,
+    // tried while the account or address was locked
+    Locked,
+}
+
+impl LoginResult {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LoginResult::Success => "success",
+            LoginResult::Failure => "failure",
+            LoginResult::Locked => "locked",
+        }
+    }
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct LoginAttempt {
+    pub attempt_id: Uuid,
+    pub email: String,
+    pub user_id: Option<Uuid>,
+    pub ip_address: String,
+    pub result: String,
+    pub created_date: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginAttemptQuery {
+    pub user_id: Option<Uuid>,
+}
+
+#[derive(Debug, FromRow)]
+struct LoginThrottle {
+    failures: i32,
+    last_failure_date: NaiveDateTime,
+    locked_until: Option<NaiveDateTime>,
+}
+
+// emails are kept as long as a valid address could be
+fn truncate_email(email: &str) -> String {
+    email.chars().take(255).collect()
+}
+
+fn get_account_key(email: &str) -> String {
+    format!("account:{}", truncate_email(email).to_lowercase())
+}
+
+fn get_ip_key(ip_address: &str) -> String {
+    format!("ip:{}", ip_address)
+}
+
+/// Seconds to wait after `failures` failed logins, 0 when there is no need.
+fn get_backoff_seconds(limits: &ThrottleLimits, failures: i32) -> i64 {
+    if failures >= limits.lockout_after {
+        LOCKOUT_MINUTES * 60
+    } else if failures >= limits.backoff_after {
+        let exponent = (failures - limits.backoff_after).min(16) as u32;
+        2_i64.pow(exponent).min(MAX_BACKOFF_SECONDS)
+    } else {
+        0
+    }
+}
+
+fn get_locked_until(
+    limits: &ThrottleLimits,
+    failures: i32,
+    last_failure_date: NaiveDateTime,
+) -> Option<NaiveDateTime> {
+    match get_backoff_seconds(limits, failures) {
+        0 => None,
+        backoff_seconds => Some(last_failure_date + Duration::seconds(backoff_seconds)),
+    }
+}
+
+/// Counts an attempt against `throttle_key` as a failure before it is
+/// checked. Returns the seconds to wait instead when the key is locked,
+/// nothing is counted then.
+pub async fn reserve_attempt(
+    pool: &PgPool,
+    throttle_key: &str,
+    limits: &ThrottleLimits,
+) -> Result<Option<i64>, sqlx::Error> {
+    let now = Utc::now().naive_utc();
+
+    let mut transaction = pool.begin().await?;
+
+    let query = "INSERT INTO loginthrottle (throttle_key, failures, last_failure_date) \
+        VALUES($1, 0, $2) ON CONFLICT (throttle_key) DO NOTHING";
+
+    sqlx::query(query)
+        .bind(throttle_key)
+        .bind(now)
+        .execute(&mut *transaction)
+        .await?;
+
+    // parallel attempts on the same key wait here for each other
+    let query = "SELECT failures, last_failure_date, locked_until FROM loginthrottle \
+        WHERE throttle_key = $1 FOR UPDATE";
+
+    let login_throttle = sqlx::query_as::<_, LoginThrottle>(query)
+        .bind(throttle_key)
+        .fetch_one(&mut *transaction)
+        .await?;
+
+    if let Some(locked_until) = login_throttle.locked_until {
+        if locked_until > now {
+            // waiting out a lockout doesn't make it longer
+            transaction.commit().await?;
+            return Ok(Some((locked_until - now).num_seconds().max(1)));
+        }
+    }
+
+    let failures =
+        if login_throttle.last_failure_date < now - Duration::minutes(FAILURE_WINDOW_MINUTES) {
+            1
+        } else {
+            login_throttle.failures + 1
+        };
+
+    let query = "UPDATE loginthrottle SET failures = $2, last_failure_date = $3, \
+        locked_until = $4 WHERE throttle_key = $1";
+
+    sqlx::query(query)
+        .bind(throttle_key)
+        .bind(failures)
+        .bind(now)
+        .bind(get_locked_until(limits, failures, now))
+        .execute(&mut *transaction)
+        .await?;
+
+    transaction.commit().await?;
+
+    if failures == limits.lockout_after {
+        println!(
+            "{} is locked out after {} failed attempts",
+            throttle_key, failures
+        );
+    }
+
+    Ok(None)
+}
+
+/// Gives back an attempt reserved with `reserve_attempt` that succeeded.
+pub async fn refund_attempt(
+    pool: &PgPool,
+    throttle_key: &str,
+    limits: &ThrottleLimits,
+) -> Result<(), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let query = "SELECT failures, last_failure_date, locked_until FROM loginthrottle \
+        WHERE throttle_key = $1 FOR UPDATE";
+
+    let login_throttle = sqlx::query_as::<_, LoginThrottle>(query)
+        .bind(throttle_key)
+        .fetch_optional(&mut *transaction)
+        .await?;
+
+    if let Some(login_throttle) = login_throttle {
+        let failures = (login_throttle.failures - 1).max(0);
+
+        let query =
+            "UPDATE loginthrottle SET failures = $2, locked_until = $3 WHERE throttle_key = $1";
+
+        sqlx::query(query)
+            .bind(throttle_key)
+            .bind(failures)
+            .bind(get_locked_until(
+                limits,
+                failures,
+                login_throttle.last_failure_date,
+            ))
+            .execute(&mut *transaction)
+            .await?;
+    }
+
+    transaction.commit().await
+}
+
+/// Forgets every failure counted against `throttle_key`.
+pub async fn clear_attempts(pool: &PgPool, throttle_key: &str) -> Result<(), sqlx::Error> {
+    let query = "DELETE FROM loginthrottle WHERE throttle_key = $1";
+
+    sqlx::query(query).bind(throttle_key).execute(pool).await?;
+
+    Ok(())
+}
+
+/// Reserves a login attempt for the account and the address. Returns the
+/// seconds to wait when either of them is locked.
+pub async fn reserve_login_attempt(
+    pool: &PgPool,
+    email: &str,
+    ip_address: &str,
+) -> Result<Option<i64>, sqlx::Error> {
+    let account_key = get_account_key(email);
+
+    if let Some(retry_after) = reserve_attempt(pool, &account_key, &ACCOUNT_LIMITS).await? {
+        return Ok(Some(retry_after));
+    }
+
+    match reserve_attempt(pool, &get_ip_key(ip_address), &IP_LIMITS).await? {
+        Some(retry_after) => {
+            // the password isn't checked, so the account attempt doesn't count
+            refund_attempt(pool, &account_key, &ACCOUNT_LIMITS).await?;
+            Ok(Some(retry_after))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Keeps an attempt reserved with `reserve_login_attempt` in the audit
+/// trail, a successful one clears the failures of the account.
+pub async fn record_login_attempt(
+    pool: &PgPool,
+    email: &str,
+    ip_address: &str,
+    login_result: LoginResult,
+) -> Result<(), sqlx::Error> {
+    // the user is looked up here the same way for known and unknown emails
+    let query = "INSERT INTO loginattempt (email, user_id, ip_address, result) \
+        VALUES($1, (SELECT user_id FROM userinfo WHERE lower(email) = lower($1)), $2, $3)";
+
+    sqlx::query(query)
+        .bind(truncate_email(email))
+        .bind(ip_address)
+        .bind(login_result.as_str())
+        .execute(pool)
+        .await?;
+
+    if let LoginResult::Success = login_result {
+        clear_attempts(pool, &get_account_key(email)).await?;
+        refund_attempt(pool, &get_ip_key(ip_address), &IP_LIMITS).await?;
+    }
+
+    Ok(())
+}
+
+/// Clears the failed logins of an account, used by admins.
+pub async fn unlock_account_logins(pool: &PgPool, email: &str) -> Result<(), sqlx::Error> {
+    clear_attempts(pool, &get_account_key(email)).await
+}
+
+/// The most recent login attempts, of one user or of everyone.
+pub async fn get_login_attempts(
+    pool: &PgPool,
+    user_id: Option<&Uuid>,
+) -> Result<Vec<LoginAttempt>, sqlx::Error> {
+    let query = "SELECT * FROM loginattempt WHERE $1::UUID IS NULL OR user_id = $1 \
+        ORDER BY created_date DESC LIMIT $2";
+
+    sqlx::query_as::<_, LoginAttempt>(query)
+        .bind(user_id)
+        .bind(LOGIN_ATTEMPT_LIST_LIMIT)
+        .fetch_all(pool)
+        .await
+}
+
+/// Forgets old attempts and failure counts that no longer matter.
+pub async fn purge_old_login_attempts(pool: &PgPool) -> u64 {
+    let purged = async {
+        let now = Utc::now().naive_utc();
+        let mut purged = 0;
+
+        let query =
+            "DELETE FROM loginattempt WHERE created_date < now() - make_interval(days => $1)";
+        purged += sqlx::query(query)
+            .bind(LOGIN_ATTEMPT_RETENTION_DAYS as i32)
+            .execute(pool)
+            .await?
+            .rows_affected();
+
+        let query = "DELETE FROM loginthrottle WHERE last_failure_date < $1 \
+            AND (locked_until IS NULL OR locked_until < $2)";
+        purged += sqlx::query(query)
+            .bind(now - Duration::minutes(FAILURE_WINDOW_MINUTES))
+            .bind(now)
+            .execute(pool)
+            .await?
+            .rows_affected();
+
+        Ok::<u64, sqlx::Error>(purged)
+    }
+    .await;
+
+    match purged {
+        Ok(purged) => purged,
+        Err(error) => {
+            println!("error while purging old login attempts: {}", error);
+            0
+        }
+    }
+}
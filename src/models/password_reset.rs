@@ -0,0 +1,237 @@
+use ::serde::Deserialize;
+use chrono::{Duration, NaiveDateTime, Utc};
+use sqlx::{self, FromRow, PgPool};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::utility::{
+    genarate_salt,
+    mailer::{send_mail, Mail, Mailer},
+    passcode::hash_token,
+};
+
+use super::{
+    login_attempt::{refund_attempt, reserve_attempt, ThrottleLimits},
+    user_info::{get_user_by_email, is_valid_passcode, set_user_passcode, PasscodeError},
+};
+
+/*
+  a forgotten passcode is reset with a single use token mailed to the
+  user, only its hash is stored. Asking for a new token invalidates the
+  older ones, and a reset logs the user out everywhere.
+
+  requests are handled after the response is sent, so it looks and takes
+  the same for every email. They are counted per email and per address,
+  past the limits further requests are dropped so nobody can flood a mail
+  box.
+*/
+
+// 64 alphanumeric characters, about 380 bits
+const PASSWORD_RESET_TOKEN_LEN: usize = 64;
+
+const PASSWORD_RESET_MINUTES: i64 = 60;
+
+const RESET_EMAIL_LIMITS: ThrottleLimits = ThrottleLimits {
+    backoff_after: 3,
+    lockout_after: 5,
+};
+
+const RESET_IP_LIMITS: ThrottleLimits = ThrottleLimits {
+    backoff_after: 10,
+    lockout_after: 30,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct PasswordResetRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasswordReset {
+    pub token: String,
+    pub new_passcode: String,
+}
+
+#[derive(Debug, FromRow)]
+struct PasswordResetToken {
+    reset_id: Uuid,
+    user_id: Uuid,
+    expires_date: NaiveDateTime,
+    used_date: Option<NaiveDateTime>,
+}
+
+/// Counts a reset request against the email and the address, returns
+/// whether it is within the limits of both.
+async fn reserve_password_reset(
+    pool: &PgPool,
+    email: &str,
+    ip_address: &str,
+) -> Result<bool, sqlx::Error> {
+    let email_key = format!("reset:{}", email.to_lowercase());
+
+    if reserve_attempt(pool, &email_key, &RESET_EMAIL_LIMITS)
+        .await?
+        .is_some()
+    {
+        return Ok(false);
+    }
+
+    let ip_key = format!("reset-ip:{}", ip_address);
+
+    if reserve_attempt(pool, &ip_key, &RESET_IP_LIMITS)
+        .await?
+        .is_some()
+    {
+        // nothing is sent, so the request doesn't count for the email
+        refund_attempt(pool, &email_key, &RESET_EMAIL_LIMITS).await?;
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+/// Mails a reset token to the user with `email`. Unknown addresses are
+/// not reported, so this can't be used to find out who has an account.
+pub async fn request_password_reset(
+    pool: &PgPool,
+    mailer: &Arc<dyn Mailer>,
+    email: &str,
+    ip_address: &str,
+) -> Result<(), PasscodeError> {
+    match reserve_password_reset(pool, email, ip_address).await {
+        Ok(true) => (),
+        Ok(false) => {
+            println!(
+                "password reset for {} from {} dropped, too many requests",
+                email, ip_address
+            );
+            return Ok(());
+        }
+        Err(error) => {
+            println!("error while counting a password reset request: {}", error);
+            return Err(PasscodeError::Failed);
+        }
+    }
+
+    let user_info = match get_user_by_email(pool, email).await {
+        Some(user_info) => user_info,
+        None => {
+            println!("password reset asked for unknown email {}", email);
+            return Ok(());
+        }
+    };
+
+    let token = genarate_salt(PASSWORD_RESET_TOKEN_LEN);
+
+    let created = async {
+        let mut transaction = pool.begin().await?;
+
+        let query = "UPDATE passwordreset SET used_date = now() \
+            WHERE user_id = $1 AND used_date IS NULL";
+
+        sqlx::query(query)
+            .bind(user_info.user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        let query =
+            "INSERT INTO passwordreset (user_id, token_hash, expires_date) VALUES($1, $2, $3)";
+
+        sqlx::query(query)
+            .bind(user_info.user_id)
+            .bind(hash_token(&token))
+            .bind((Utc::now() + Duration::minutes(PASSWORD_RESET_MINUTES)).naive_utc())
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await
+    }
+    .await;
+
+    if let Err(error) = created {
+        println!(
+            "error while creating a password reset for {}: {}",
+            user_info.user_id, error
+        );
+        return Err(PasscodeError::Failed);
+    }
+
+    let mail = Mail {
+        to: user_info.email,
+        subject: "Reset your password".to_owned(),
+        body: format!(
+            "Hi {},\n\nsomeone asked to reset the password of your account. \
+            To choose a new one, send this token with your new password to \
+            /api/auth/password/reset within {} minutes:\n\n{}\n\n\
+            If it wasn't you, you can ignore this mail.",
+            user_info.user_name, PASSWORD_RESET_MINUTES, token
+        ),
+    };
+
+    match send_mail(mailer, mail).await {
+        Ok(_) => Ok(()),
+        Err(_) => Err(PasscodeError::Failed),
+    }
+}
+
+/// Sets a new passcode with a reset token and revokes all sessions of
+/// the user.
+pub async fn reset_user_passcode(
+    pool: &PgPool,
+    password_reset: &PasswordReset,
+) -> Result<(), PasscodeError> {
+    if !is_valid_passcode(&password_reset.new_passcode) {
+        return Err(PasscodeError::InvalidPasscode);
+    }
+
+    let reset = async {
+        let mut transaction = pool.begin().await?;
+
+        let query = "SELECT reset_id, user_id, expires_date, used_date FROM passwordreset \
+            WHERE token_hash = $1 FOR UPDATE";
+
+        let reset_token = sqlx::query_as::<_, PasswordResetToken>(query)
+            .bind(hash_token(&password_reset.token))
+            .fetch_optional(&mut *transaction)
+            .await?;
+
+        let reset_token = match reset_token {
+            Some(reset_token)
+                if reset_token.used_date.is_none()
+                    && reset_token.expires_date > Utc::now().naive_utc() =>
+            {
+                reset_token
+            }
+            _ => return Ok(false),
+        };
+
+        let query = "UPDATE passwordreset SET used_date = now() WHERE reset_id = $1";
+
+        sqlx::query(query)
+            .bind(reset_token.reset_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        set_user_passcode(
+            &mut transaction,
+            &reset_token.user_id,
+            &password_reset.new_passcode,
+            None,
+        )
+        .await?;
+
+        transaction.commit().await?;
+
+        Ok::<bool, sqlx::Error>(true)
+    }
+    .await;
+
+    match reset {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(PasscodeError::InvalidToken),
+        Err(error) => {
+            println!("error while resetting a passcode: {}", error);
+            Err(PasscodeError::Failed)
+        }
+    }
+}
@@ -0,0 +1,424 @@
+use ::serde::{Deserialize, Serialize};
+use actix_files::HttpRange;
+use chrono::{Duration, NaiveDateTime, Utc};
+use sqlx::{self, FromRow, PgPool};
+use std::path::PathBuf;
+use uuid::Uuid;
+
+use crate::utility::{
+    genarate_salt,
+    passcode::{hash_passcode, verify_passcode},
+};
+
+use super::{
+    file_user::{user_has_file_permission, FILE_RESHARE},
+    login_attempt::{refund_attempt, reserve_attempt, ThrottleLimits},
+    user_file::{check_health_and_reterive_file, get_file_info_by_id, UserFile, UserFileErrors},
+};
+
+/*
+  a public link lets anyone holding its token download a file without an
+  account through `/public/{token}`, a file with at least one link has
+  UserFile.is_public set. Wrong link passwords are throttled per link like
+  failed logins.
+*/
+
+pub const PUBLIC_LINK_PATH: &str = "/public";
+
+// 32 alphanumeric characters, about 190 bits
+const PUBLIC_LINK_TOKEN_LEN: usize = 32;
+
+const LINK_PASSWORD_LIMITS: ThrottleLimits = ThrottleLimits {
+    backoff_after: 5,
+    lockout_after: 20,
+};
+
+#[derive(Debug, FromRow, Serialize, Deserialize)]
+pub struct PublicLink {
+    pub link_id: Uuid,
+    pub file_id: Uuid,
+    pub token: String,
+    pub created_by: Option<Uuid>,
+    #[serde(skip)]
+    pub password_hash: Option<String>,
+    pub expires_date: Option<NaiveDateTime>,
+    pub max_downloads: Option<i32>,
+    pub download_count: i32,
+    pub created_date: NaiveDateTime,
+}
+
+// what owners get to see of a link
+#[derive(Debug, Serialize)]
+pub struct PublicLinkInfo {
+    #[serde(flatten)]
+    pub link: PublicLink,
+    pub url: String,
+    pub has_password: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewPublicLink {
+    pub file_id: Uuid,
+    pub expires_in_hours: Option<i64>,
+    pub password: Option<String>,
+    pub max_downloads: Option<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PublicLinkFilter {
+    pub file_id: Uuid,
+}
+
+#[derive(Debug)]
+pub enum PublicLinkErrors {
+    NotFound,
+    Forbidden,
+    Expired,
+    PasswordRequired,
+    WrongPassword,
+    // too many wrong passwords, seconds until the next try
+    Locked(i64),
+    InvalidLink,
+    Failed,
+    File(UserFileErrors),
+}
+
+impl From<PublicLink> for PublicLinkInfo {
+    fn from(link: PublicLink) -> Self {
+        PublicLinkInfo {
+            url: format!("{}/{}", PUBLIC_LINK_PATH, link.token),
+            has_password: link.password_hash.is_some(),
+            link,
+        }
+    }
+}
+
+impl PublicLink {
+    fn is_expired(&self) -> bool {
+        match self.expires_date {
+            Some(expires_date) => expires_date <= Utc::now().naive_utc(),
+            None => false,
+        }
+    }
+
+    // checked for every request, ranged ones included
+    fn is_used_up(&self) -> bool {
+        match self.max_downloads {
+            Some(max_downloads) => self.download_count >= max_downloads,
+            None => false,
+        }
+    }
+}
+
+async fn get_shareable_file(
+    pool: &PgPool,
+    user_id: &Uuid,
+    file_id: &Uuid,
+) -> Result<UserFile, PublicLinkErrors> {
+    let file_info = get_file_info_by_id(pool, file_id)
+        .await
+        .map_err(PublicLinkErrors::File)?;
+
+    if user_has_file_permission(pool, &file_info, user_id, FILE_RESHARE).await {
+        Ok(file_info)
+    } else {
+        Err(PublicLinkErrors::Forbidden)
+    }
+}
+
+async fn update_file_is_public(pool: &PgPool, file_id: &Uuid) {
+    let query = "UPDATE userfile SET is_public = EXISTS ( \
+        SELECT 1 FROM publiclink WHERE file_id = $1 \
+    ) WHERE file_id = $1";
+
+    let query = sqlx::query(query).bind(file_id).execute(pool).await;
+
+    if let Err(error) = query {
+        println!(
+            "error while updating is_public of file {}: {}",
+            file_id, error
+        );
+    }
+}
+
+pub async fn create_public_link(
+    pool: &PgPool,
+    user_id: &Uuid,
+    new_public_link: &NewPublicLink,
+) -> Result<PublicLinkInfo, PublicLinkErrors> {
+    let file_info = get_shareable_file(pool, user_id, &new_public_link.file_id).await?;
+
+    if file_info.deleted_date.is_some() {
+        return Err(PublicLinkErrors::File(UserFileErrors::Deleted));
+    }
+
+    if new_public_link.expires_in_hours.unwrap_or(1) <= 0
+        || new_public_link.max_downloads.unwrap_or(1) <= 0
+        || new_public_link
+            .password
+            .as_ref()
+            .is_some_and(|password| password.is_empty())
+    {
+        return Err(PublicLinkErrors::InvalidLink);
+    }
+
+    let expires_date = new_public_link
+        .expires_in_hours
+        .map(|expires_in_hours| (Utc::now() + Duration::hours(expires_in_hours)).naive_utc());
+    let password_hash = new_public_link
+        .password
+        .as_ref()
+        .map(|password| hash_passcode(password));
+
+    let query = "INSERT INTO publiclink (file_id, token, created_by, password_hash, expires_date, max_downloads) \
+        VALUES($1, $2, $3, $4, $5, $6) RETURNING *";
+
+    let query = sqlx::query_as::<_, PublicLink>(query)
+        .bind(file_info.file_id)
+        .bind(genarate_salt(PUBLIC_LINK_TOKEN_LEN))
+        .bind(user_id)
+        .bind(password_hash)
+        .bind(expires_date)
+        .bind(new_public_link.max_downloads);
+
+    let public_link = query.fetch_one(pool).await;
+
+    match public_link {
+        Ok(public_link) => {
+            update_file_is_public(pool, &file_info.file_id).await;
+            Ok(PublicLinkInfo::from(public_link))
+        }
+        Err(error) => {
+            println!(
+                "error while creating public link for file {}: {}",
+                file_info.file_id, error
+            );
+            Err(PublicLinkErrors::Failed)
+        }
+    }
+}
+
+pub async fn get_public_links(
+    pool: &PgPool,
+    user_id: &Uuid,
+    file_id: &Uuid,
+) -> Result<Vec<PublicLinkInfo>, PublicLinkErrors> {
+    get_shareable_file(pool, user_id, file_id).await?;
+
+    let query = "SELECT * FROM publiclink WHERE file_id = $1 ORDER BY created_date";
+
+    let query = sqlx::query_as::<_, PublicLink>(query).bind(file_id);
+
+    let public_links = query.fetch_all(pool).await;
+
+    match public_links {
+        Ok(public_links) => Ok(public_links.into_iter().map(PublicLinkInfo::from).collect()),
+        Err(error) => {
+            println!(
+                "Error occurred while fetching public links of file {}: {}",
+                file_id, error
+            );
+            Err(PublicLinkErrors::Failed)
+        }
+    }
+}
+
+async fn get_public_link_by_id(pool: &PgPool, link_id: &Uuid) -> Option<PublicLink> {
+    let query = "SELECT * FROM publiclink WHERE link_id = $1";
+
+    let query = sqlx::query_as::<_, PublicLink>(query).bind(link_id);
+
+    match query.fetch_optional(pool).await {
+        Ok(public_link) => public_link,
+        Err(error) => {
+            println!(
+                "Error occurred while fetching public link {}: {}",
+                link_id, error
+            );
+            None
+        }
+    }
+}
+
+pub async fn revoke_public_link(
+    pool: &PgPool,
+    user_id: &Uuid,
+    link_id: &Uuid,
+) -> Result<(), PublicLinkErrors> {
+    let public_link = match get_public_link_by_id(pool, link_id).await {
+        Some(public_link) => public_link,
+        None => return Err(PublicLinkErrors::NotFound),
+    };
+
+    get_shareable_file(pool, user_id, &public_link.file_id).await?;
+
+    let query = "DELETE FROM publiclink WHERE link_id = $1";
+
+    let query = sqlx::query(query).bind(link_id).execute(pool).await;
+
+    match query {
+        Ok(_) => {
+            update_file_is_public(pool, &public_link.file_id).await;
+            Ok(())
+        }
+        Err(error) => {
+            println!("error while revoking public link {}: {}", link_id, error);
+            Err(PublicLinkErrors::Failed)
+        }
+    }
+}
+
+/// Counts a download of the link, fails once `max_downloads` is reached.
+async fn count_public_link_download(pool: &PgPool, link_id: &Uuid) -> Result<(), PublicLinkErrors> {
+    let query = "UPDATE publiclink SET download_count = download_count + 1 \
+        WHERE link_id = $1 AND (max_downloads IS NULL OR download_count < max_downloads)";
+
+    let query = sqlx::query(query).bind(link_id).execute(pool).await;
+
+    match query {
+        Ok(result) if result.rows_affected() == 0 => Err(PublicLinkErrors::Expired),
+        Ok(_) => Ok(()),
+        Err(error) => {
+            println!(
+                "error while counting download of public link {}: {}",
+                link_id, error
+            );
+            Err(PublicLinkErrors::Failed)
+        }
+    }
+}
+
+/// A request starts a new download unless it asks for a range that leaves
+/// out the first byte, like a player seeking in a video or a resumed
+/// download. Ranges that can't be parsed are refused when served.
+fn is_new_download(range: Option<&str>, file_size: i64) -> bool {
+    match range {
+        Some(range) => match HttpRange::parse(range, file_size as u64) {
+            Ok(ranges) => ranges.is_empty() || ranges.iter().any(|range| range.start == 0),
+            Err(_) => false,
+        },
+        None => true,
+    }
+}
+
+async fn check_public_link_password(
+    pool: &PgPool,
+    public_link: &PublicLink,
+    password: Option<&str>,
+) -> Result<(), PublicLinkErrors> {
+    let (password_hash, password) = match (&public_link.password_hash, password) {
+        (None, _) => return Ok(()),
+        (Some(_), None) => return Err(PublicLinkErrors::PasswordRequired),
+        (Some(password_hash), Some(password)) => (password_hash, password),
+    };
+
+    let throttle_key = format!("link:{}", public_link.link_id);
+
+    match reserve_attempt(pool, &throttle_key, &LINK_PASSWORD_LIMITS).await {
+        Ok(None) => (),
+        Ok(Some(retry_after)) => return Err(PublicLinkErrors::Locked(retry_after)),
+        Err(error) => {
+            println!("error while throttling public link passwords: {}", error);
+            return Err(PublicLinkErrors::Failed);
+        }
+    }
+
+    if !verify_passcode(password, password_hash) {
+        return Err(PublicLinkErrors::WrongPassword);
+    }
+
+    if let Err(error) = refund_attempt(pool, &throttle_key, &LINK_PASSWORD_LIMITS).await {
+        println!("error while throttling public link passwords: {}", error);
+    }
+
+    Ok(())
+}
+
+/// Resolves a public link to the file it points to. Every request is
+/// refused once the downloads are used up, but only requests starting a
+/// download are counted, so range requests resuming or seeking in a video
+/// are not.
+pub async fn open_public_link(
+    pool: &PgPool,
+    data_path: &str,
+    token: &str,
+    password: Option<&str>,
+    range: Option<&str>,
+) -> Result<(UserFile, PathBuf), PublicLinkErrors> {
+    let query = "SELECT * FROM publiclink WHERE token = $1";
+
+    let query = sqlx::query_as::<_, PublicLink>(query).bind(token);
+
+    let public_link = match query.fetch_optional(pool).await {
+        Ok(Some(public_link)) => public_link,
+        Ok(None) => return Err(PublicLinkErrors::NotFound),
+        Err(error) => {
+            println!("Error occurred while fetching public link: {}", error);
+            return Err(PublicLinkErrors::Failed);
+        }
+    };
+
+    if public_link.is_expired() || public_link.is_used_up() {
+        return Err(PublicLinkErrors::Expired);
+    }
+
+    check_public_link_password(pool, &public_link, password).await?;
+
+    let (file_info, file_path) =
+        check_health_and_reterive_file(pool, data_path, &public_link.file_id)
+            .await
+            .map_err(PublicLinkErrors::File)?;
+
+    if is_new_download(range, file_info.file_size) {
+        count_public_link_download(pool, &public_link.link_id).await?;
+    }
+
+    Ok((file_info, file_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_public_link(max_downloads: Option<i32>, download_count: i32) -> PublicLink {
+        PublicLink {
+            link_id: Uuid::new_v4(),
+            file_id: Uuid::new_v4(),
+            token: genarate_salt(PUBLIC_LINK_TOKEN_LEN),
+            created_by: None,
+            password_hash: None,
+            expires_date: None,
+            max_downloads,
+            download_count,
+            created_date: Utc::now().naive_utc(),
+        }
+    }
+
+    #[test]
+    fn counts_requests_that_fetch_the_first_byte() {
+        assert!(is_new_download(None, 100));
+        assert!(is_new_download(Some("bytes=0-"), 100));
+        assert!(is_new_download(Some("bytes=00-"), 100));
+        assert!(is_new_download(Some("bytes=0-0"), 100));
+        assert!(is_new_download(Some("bytes=50-60, 0-9"), 100));
+        // the last 100 bytes of a 100 byte file
+        assert!(is_new_download(Some("bytes=-100"), 100));
+    }
+
+    #[test]
+    fn does_not_count_resumed_downloads() {
+        assert!(!is_new_download(Some("bytes=1-"), 100));
+        assert!(!is_new_download(Some("bytes=50-99"), 100));
+        assert!(!is_new_download(Some("bytes=-10"), 100));
+        assert!(!is_new_download(Some("bytes=abc"), 100));
+        assert!(!is_new_download(Some("items=0-"), 100));
+    }
+
+    #[test]
+    fn refuses_used_up_links() {
+        assert!(!get_public_link(None, 1000).is_used_up());
+        assert!(!get_public_link(Some(2), 1).is_used_up());
+        assert!(get_public_link(Some(2), 2).is_used_up());
+        assert!(get_public_link(Some(0), 0).is_used_up());
+    }
+}
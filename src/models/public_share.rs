@@ -0,0 +1,608 @@
+use ::serde::{Deserialize, Serialize};
+use chrono::{Duration, NaiveDateTime, Utc};
+use sqlx::{self, FromRow, PgPool};
+use std::{collections::HashSet, path::PathBuf};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+use crate::utility::{
+    genarate_salt,
+    passcode::{hash_passcode, hash_token, verify_passcode},
+    zip_stream::ZipEntry,
+};
+
+use super::{
+    blob::get_blob_path,
+    bucket::{get_bucket_by_id, get_bucket_permissions, Bucket, BUCKET_SHARE},
+    folder::{
+        find_folder_by_relative_path, get_folder_by_id, get_folder_tree_files,
+        is_descendant_folder, list_folder, FolderErrors,
+    },
+    login_attempt::{refund_attempt, reserve_attempt, ThrottleLimits},
+    user_file::{check_health_and_reterive_file, sanitize_file_name, UserFile, UserFileErrors},
+};
+
+/*
+  a public share is a read-only public link to a whole bucket, or to a
+  folder and everything below it. Anyone holding its token can browse it
+  through `/public/share/{token}`, download single files from it or all
+  of it as one zip.
+
+  the password of a share is sent once, in a header or a form. The form
+  answers with an access key for a cookie, derived from the password hash,
+  so the password never ends up in links. Wrong passwords are throttled
+  per share like failed logins.
+*/
+
+pub const PUBLIC_SHARE_PATH: &str = "/public/share";
+
+// 32 alphanumeric characters, about 190 bits
+const PUBLIC_SHARE_TOKEN_LEN: usize = 32;
+
+const SHARE_PASSWORD_LIMITS: ThrottleLimits = ThrottleLimits {
+    backoff_after: 5,
+    lockout_after: 20,
+};
+
+#[derive(Debug, FromRow, Serialize, Deserialize)]
+pub struct PublicShare {
+    pub share_id: Uuid,
+    pub bucket_id: Uuid,
+    pub folder_id: Option<Uuid>,
+    pub token: String,
+    pub created_by: Option<Uuid>,
+    #[serde(skip)]
+    pub password_hash: Option<String>,
+    pub expires_date: Option<NaiveDateTime>,
+    pub created_date: NaiveDateTime,
+}
+
+// what owners get to see of a share
+#[derive(Debug, Serialize)]
+pub struct PublicShareInfo {
+    #[serde(flatten)]
+    pub share: PublicShare,
+    pub url: String,
+    pub has_password: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewPublicShare {
+    pub bucket_id: Uuid,
+    pub folder_id: Option<Uuid>,
+    pub expires_in_hours: Option<i64>,
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PublicShareFilter {
+    pub bucket_id: Uuid,
+}
+
+// what visitors get to see of a file, without owner ids or hashes
+#[derive(Debug, Serialize)]
+pub struct PublicShareFile {
+    pub file_id: Uuid,
+    pub file_name: String,
+    pub file_size: i64,
+    pub file_type: String,
+    pub created_date: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PublicShareContents {
+    // name of the shared bucket or folder
+    pub name: String,
+    // path of the listed folder, relative to the shared one
+    pub path: String,
+    pub folders: Vec<String>,
+    pub files: Vec<PublicShareFile>,
+}
+
+/// What a visitor proves they know the password of a share with.
+#[derive(Debug, Clone, Copy)]
+pub enum ShareCredentials<'a> {
+    None,
+    Password(&'a str),
+    // from `unlock_public_share`
+    AccessKey(&'a str),
+}
+
+#[derive(Debug)]
+pub enum PublicShareErrors {
+    NotFound,
+    Forbidden,
+    Expired,
+    PasswordRequired,
+    WrongPassword,
+    // too many wrong passwords, seconds until the next try
+    Locked(i64),
+    InvalidShare,
+    Failed,
+    Folder(FolderErrors),
+    File(UserFileErrors),
+}
+
+impl From<PublicShare> for PublicShareInfo {
+    fn from(share: PublicShare) -> Self {
+        PublicShareInfo {
+            url: format!("{}/{}", PUBLIC_SHARE_PATH, share.token),
+            has_password: share.password_hash.is_some(),
+            share,
+        }
+    }
+}
+
+impl From<UserFile> for PublicShareFile {
+    fn from(file: UserFile) -> Self {
+        PublicShareFile {
+            file_id: file.file_id,
+            file_name: file.file_name,
+            file_size: file.file_size,
+            file_type: file.file_type,
+            created_date: file.created_date,
+        }
+    }
+}
+
+impl PublicShare {
+    fn is_expired(&self) -> bool {
+        match self.expires_date {
+            Some(expires_date) => expires_date <= Utc::now().naive_utc(),
+            None => false,
+        }
+    }
+}
+
+async fn get_shareable_bucket(
+    pool: &PgPool,
+    user_id: &Uuid,
+    bucket_id: &Uuid,
+) -> Result<Bucket, PublicShareErrors> {
+    let bucket = match get_bucket_by_id(pool, bucket_id).await {
+        Some(bucket) => bucket,
+        None => return Err(PublicShareErrors::NotFound),
+    };
+
+    if get_bucket_permissions(pool, &bucket, user_id).await & BUCKET_SHARE != 0 {
+        Ok(bucket)
+    } else {
+        Err(PublicShareErrors::Forbidden)
+    }
+}
+
+pub async fn create_public_share(
+    pool: &PgPool,
+    user_id: &Uuid,
+    new_public_share: &NewPublicShare,
+) -> Result<PublicShareInfo, PublicShareErrors> {
+    let bucket = get_shareable_bucket(pool, user_id, &new_public_share.bucket_id).await?;
+
+    if let Some(folder_id) = &new_public_share.folder_id {
+        match get_folder_by_id(pool, folder_id).await {
+            Some(folder) if folder.bucket_id == bucket.bucket_id => {}
+            _ => return Err(PublicShareErrors::Folder(FolderErrors::NotFound)),
+        }
+    }
+
+    if new_public_share.expires_in_hours.unwrap_or(1) <= 0
+        || new_public_share
+            .password
+            .as_ref()
+            .is_some_and(|password| password.is_empty())
+    {
+        return Err(PublicShareErrors::InvalidShare);
+    }
+
+    let expires_date = new_public_share
+        .expires_in_hours
+        .map(|expires_in_hours| (Utc::now() + Duration::hours(expires_in_hours)).naive_utc());
+    let password_hash = new_public_share
+        .password
+        .as_ref()
+        .map(|password| hash_passcode(password));
+
+    let query = "INSERT INTO publicshare (bucket_id, folder_id, token, created_by, password_hash, expires_date) \
+        VALUES($1, $2, $3, $4, $5, $6) RETURNING *";
+
+    let query = sqlx::query_as::<_, PublicShare>(query)
+        .bind(bucket.bucket_id)
+        .bind(new_public_share.folder_id)
+        .bind(genarate_salt(PUBLIC_SHARE_TOKEN_LEN))
+        .bind(user_id)
+        .bind(password_hash)
+        .bind(expires_date);
+
+    match query.fetch_one(pool).await {
+        Ok(public_share) => Ok(PublicShareInfo::from(public_share)),
+        Err(error) => {
+            println!(
+                "error while creating public share for bucket {}: {}",
+                bucket.bucket_id, error
+            );
+            Err(PublicShareErrors::Failed)
+        }
+    }
+}
+
+pub async fn get_public_shares(
+    pool: &PgPool,
+    user_id: &Uuid,
+    bucket_id: &Uuid,
+) -> Result<Vec<PublicShareInfo>, PublicShareErrors> {
+    get_shareable_bucket(pool, user_id, bucket_id).await?;
+
+    let query = "SELECT * FROM publicshare WHERE bucket_id = $1 ORDER BY created_date";
+
+    let query = sqlx::query_as::<_, PublicShare>(query).bind(bucket_id);
+
+    match query.fetch_all(pool).await {
+        Ok(public_shares) => Ok(public_shares
+            .into_iter()
+            .map(PublicShareInfo::from)
+            .collect()),
+        Err(error) => {
+            println!(
+                "Error occurred while fetching public shares of bucket {}: {}",
+                bucket_id, error
+            );
+            Err(PublicShareErrors::Failed)
+        }
+    }
+}
+
+pub async fn revoke_public_share(
+    pool: &PgPool,
+    user_id: &Uuid,
+    share_id: &Uuid,
+) -> Result<(), PublicShareErrors> {
+    let query = "SELECT * FROM publicshare WHERE share_id = $1";
+
+    let public_share = match sqlx::query_as::<_, PublicShare>(query)
+        .bind(share_id)
+        .fetch_optional(pool)
+        .await
+    {
+        Ok(Some(public_share)) => public_share,
+        Ok(None) => return Err(PublicShareErrors::NotFound),
+        Err(error) => {
+            println!(
+                "Error occurred while fetching public share {}: {}",
+                share_id, error
+            );
+            return Err(PublicShareErrors::Failed);
+        }
+    };
+
+    get_shareable_bucket(pool, user_id, &public_share.bucket_id).await?;
+
+    let query = "DELETE FROM publicshare WHERE share_id = $1";
+
+    match sqlx::query(query).bind(share_id).execute(pool).await {
+        Ok(_) => Ok(()),
+        Err(error) => {
+            println!("error while revoking public share {}: {}", share_id, error);
+            Err(PublicShareErrors::Failed)
+        }
+    }
+}
+
+fn get_share_access_key(public_share: &PublicShare, password_hash: &str) -> String {
+    hash_token(&format!("{}:{}", public_share.share_id, password_hash))
+}
+
+async fn check_public_share_password(
+    pool: &PgPool,
+    public_share: &PublicShare,
+    password_hash: &str,
+    password: &str,
+) -> Result<(), PublicShareErrors> {
+    let throttle_key = format!("share:{}", public_share.share_id);
+
+    match reserve_attempt(pool, &throttle_key, &SHARE_PASSWORD_LIMITS).await {
+        Ok(None) => (),
+        Ok(Some(retry_after)) => return Err(PublicShareErrors::Locked(retry_after)),
+        Err(error) => {
+            println!("error while throttling public share passwords: {}", error);
+            return Err(PublicShareErrors::Failed);
+        }
+    }
+
+    if !verify_passcode(password, password_hash) {
+        return Err(PublicShareErrors::WrongPassword);
+    }
+
+    if let Err(error) = refund_attempt(pool, &throttle_key, &SHARE_PASSWORD_LIMITS).await {
+        println!("error while throttling public share passwords: {}", error);
+    }
+
+    Ok(())
+}
+
+/// Resolves a share token, checking its expiry and password.
+async fn open_public_share(
+    pool: &PgPool,
+    token: &str,
+    credentials: ShareCredentials<'_>,
+) -> Result<PublicShare, PublicShareErrors> {
+    let query = "SELECT * FROM publicshare WHERE token = $1";
+
+    let query = sqlx::query_as::<_, PublicShare>(query).bind(token);
+
+    let public_share = match query.fetch_optional(pool).await {
+        Ok(Some(public_share)) => public_share,
+        Ok(None) => return Err(PublicShareErrors::NotFound),
+        Err(error) => {
+            println!("Error occurred while fetching public share: {}", error);
+            return Err(PublicShareErrors::Failed);
+        }
+    };
+
+    if public_share.is_expired() {
+        return Err(PublicShareErrors::Expired);
+    }
+
+    if let Some(password_hash) = &public_share.password_hash {
+        match credentials {
+            ShareCredentials::Password(password) => {
+                check_public_share_password(pool, &public_share, password_hash, password).await?
+            }
+            ShareCredentials::AccessKey(access_key) => {
+                let expected_key = get_share_access_key(&public_share, password_hash);
+                if !bool::from(expected_key.as_bytes().ct_eq(access_key.as_bytes())) {
+                    return Err(PublicShareErrors::PasswordRequired);
+                }
+            }
+            ShareCredentials::None => return Err(PublicShareErrors::PasswordRequired),
+        }
+    }
+
+    Ok(public_share)
+}
+
+/// Checks the password of a share and returns the access key a browser
+/// keeps in a cookie instead of it, `None` when the share has no password.
+pub async fn unlock_public_share(
+    pool: &PgPool,
+    token: &str,
+    password: &str,
+) -> Result<Option<String>, PublicShareErrors> {
+    let public_share = open_public_share(pool, token, ShareCredentials::Password(password)).await?;
+
+    Ok(public_share
+        .password_hash
+        .as_ref()
+        .map(|password_hash| get_share_access_key(&public_share, password_hash)))
+}
+
+/// Name of the shared folder, or of the bucket when all of it is shared.
+async fn get_public_share_name(
+    pool: &PgPool,
+    public_share: &PublicShare,
+) -> Result<String, PublicShareErrors> {
+    match &public_share.folder_id {
+        Some(folder_id) => match get_folder_by_id(pool, folder_id).await {
+            Some(folder) => Ok(folder.folder_name),
+            None => Err(PublicShareErrors::NotFound),
+        },
+        None => match get_bucket_by_id(pool, &public_share.bucket_id).await {
+            Some(bucket) => Ok(bucket.bucket_name),
+            None => Err(PublicShareErrors::NotFound),
+        },
+    }
+}
+
+/// Lists the folder at `path` inside a share, its root when no path is
+/// given.
+pub async fn get_public_share_contents(
+    pool: &PgPool,
+    token: &str,
+    credentials: ShareCredentials<'_>,
+    path: Option<&str>,
+) -> Result<PublicShareContents, PublicShareErrors> {
+    let public_share = open_public_share(pool, token, credentials).await?;
+
+    let root_folder = match &public_share.folder_id {
+        Some(folder_id) => match get_folder_by_id(pool, folder_id).await {
+            Some(folder) => Some(folder),
+            None => return Err(PublicShareErrors::NotFound),
+        },
+        None => None,
+    };
+
+    let folder = find_folder_by_relative_path(
+        pool,
+        &public_share.bucket_id,
+        root_folder,
+        path.unwrap_or_default(),
+    )
+    .await
+    .map_err(PublicShareErrors::Folder)?;
+
+    let folder_contents = list_folder(pool, &public_share.bucket_id, folder)
+        .await
+        .map_err(PublicShareErrors::Folder)?;
+
+    Ok(PublicShareContents {
+        name: get_public_share_name(pool, &public_share).await?,
+        path: path
+            .unwrap_or_default()
+            .split('/')
+            .filter(|folder_name| !folder_name.is_empty())
+            .collect::<Vec<&str>>()
+            .join("/"),
+        folders: folder_contents
+            .folders
+            .into_iter()
+            .map(|folder| folder.folder_name)
+            .collect(),
+        files: folder_contents
+            .files
+            .into_iter()
+            .map(PublicShareFile::from)
+            .collect(),
+    })
+}
+
+/// Resolves a file of a share, files outside of the shared folder are
+/// not found.
+pub async fn get_public_share_file(
+    pool: &PgPool,
+    data_path: &str,
+    token: &str,
+    credentials: ShareCredentials<'_>,
+    file_id: &Uuid,
+) -> Result<(UserFile, PathBuf), PublicShareErrors> {
+    let public_share = open_public_share(pool, token, credentials).await?;
+
+    let (file_info, file_path) = check_health_and_reterive_file(pool, data_path, file_id)
+        .await
+        .map_err(PublicShareErrors::File)?;
+
+    if file_info.bucket_id != public_share.bucket_id {
+        return Err(PublicShareErrors::NotFound);
+    }
+
+    let is_shared = match (&public_share.folder_id, &file_info.folder_id) {
+        (None, _) => true,
+        (Some(_), None) => false,
+        (Some(share_folder_id), Some(folder_id)) => {
+            is_descendant_folder(pool, share_folder_id, folder_id)
+                .await
+                .map_err(PublicShareErrors::Folder)?
+        }
+    };
+
+    if is_shared {
+        Ok((file_info, file_path))
+    } else {
+        Err(PublicShareErrors::NotFound)
+    }
+}
+
+/// Names a zip entry after its folder path and file name, a number is added
+/// to repeated names.
+fn get_archive_entry_name(
+    entry_names: &mut HashSet<String>,
+    folder_path: &str,
+    file_name: &str,
+) -> String {
+    // names stored before they were checked can't leave the archive's root
+    let file_name = match sanitize_file_name(file_name) {
+        file_name if file_name.is_empty() => "unnamed".to_owned(),
+        file_name => file_name,
+    };
+    let file_name = file_name.as_str();
+
+    let join = |file_name: &str| match folder_path {
+        "" => file_name.to_owned(),
+        _ => format!("{}/{}", folder_path, file_name),
+    };
+
+    let mut entry_name = join(file_name);
+    let mut copy_number = 1;
+
+    while entry_names.contains(&entry_name) {
+        entry_name = match file_name.rsplit_once('.') {
+            Some((stem, extension)) if !stem.is_empty() => {
+                join(&format!("{} ({}).{}", stem, copy_number, extension))
+            }
+            _ => join(&format!("{} ({})", file_name, copy_number)),
+        };
+        copy_number += 1;
+    }
+
+    entry_names.insert(entry_name.to_owned());
+    entry_name
+}
+
+/// Lists every file of a share as the entries of a zip, which is written
+/// while it is sent.
+pub async fn get_public_share_archive(
+    pool: &PgPool,
+    data_path: &str,
+    token: &str,
+    credentials: ShareCredentials<'_>,
+) -> Result<(String, Vec<ZipEntry>), PublicShareErrors> {
+    let public_share = open_public_share(pool, token, credentials).await?;
+
+    let tree_files = get_folder_tree_files(
+        pool,
+        &public_share.bucket_id,
+        public_share.folder_id.as_ref(),
+    )
+    .await
+    .map_err(PublicShareErrors::Folder)?;
+
+    let mut entry_names = HashSet::new();
+    let entries = tree_files
+        .into_iter()
+        .map(|tree_file| ZipEntry {
+            name: get_archive_entry_name(
+                &mut entry_names,
+                &tree_file.folder_path,
+                &tree_file.file.file_name,
+            ),
+            path: get_blob_path(data_path, &tree_file.file.file_hash),
+            modified_date: tree_file.file.created_date,
+        })
+        .collect::<Vec<ZipEntry>>();
+
+    let archive_name = format!("{}.zip", get_public_share_name(pool, &public_share).await?);
+
+    Ok((archive_name, entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_entries_inside_the_archive() {
+        let mut entry_names = HashSet::new();
+
+        assert_eq!(
+            get_archive_entry_name(&mut entry_names, "", "../../etc/passwd"),
+            "passwd"
+        );
+        assert_eq!(
+            get_archive_entry_name(&mut entry_names, "docs", "..\\..\\boot.ini"),
+            "docs/boot.ini"
+        );
+        assert_eq!(
+            get_archive_entry_name(&mut entry_names, "docs", ".."),
+            "docs/unnamed"
+        );
+        assert_eq!(get_archive_entry_name(&mut entry_names, "", "/"), "unnamed");
+    }
+
+    #[test]
+    fn numbers_repeated_names() {
+        let mut entry_names = HashSet::new();
+
+        assert_eq!(
+            get_archive_entry_name(&mut entry_names, "photos", "cat.jpg"),
+            "photos/cat.jpg"
+        );
+        assert_eq!(
+            get_archive_entry_name(&mut entry_names, "photos", "cat.jpg"),
+            "photos/cat (1).jpg"
+        );
+        assert_eq!(
+            get_archive_entry_name(&mut entry_names, "photos", "cat.jpg"),
+            "photos/cat (2).jpg"
+        );
+        assert_eq!(
+            get_archive_entry_name(&mut entry_names, "", ".bashrc"),
+            ".bashrc"
+        );
+        assert_eq!(
+            get_archive_entry_name(&mut entry_names, "", ".bashrc"),
+            ".bashrc (1)"
+        );
+        assert_eq!(
+            get_archive_entry_name(&mut entry_names, "other", "cat.jpg"),
+            "other/cat.jpg"
+        );
+    }
+}
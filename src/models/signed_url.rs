@@ -0,0 +1,179 @@
+use ::serde::{Deserialize, Serialize};
+use chrono::{Duration, NaiveDateTime, Utc};
+use sqlx::{PgExecutor, PgPool};
+use uuid::Uuid;
+
+use crate::utility::signed_url::sign_url;
+
+use super::{
+    bucket::{get_bucket_by_id, user_can_write_bucket},
+    file_user::{user_has_file_permission, FILE_READ},
+    user_file::{get_file_info_by_id, UserFileErrors},
+};
+
+/*
+  a pre-signed URL lets a script or a media player download a file (GET)
+  or push a new one into a bucket (PUT) without holding a JWT, the URL
+  carries its expiry and an HMAC over method, path, expiry and the user
+  it acts as. Permissions are checked again when the URL is used.
+
+  the HMAC also covers the user's URL signing generation, which is bumped
+  when the user logs out, changes their passcode or has their sessions
+  revoked, so the URLs stop working with the sessions they came from. URLs
+  of disabled users are refused.
+*/
+
+pub const SIGNED_URL_PATH: &str = "/signed";
+
+const DEFAULT_EXPIRY_SECONDS: i64 = 60 * 60;
+const MAX_EXPIRY_SECONDS: i64 = 7 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum SignedUrlMethod {
+    Get,
+    Put,
+}
+
+impl SignedUrlMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SignedUrlMethod::Get => "GET",
+            SignedUrlMethod::Put => "PUT",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewSignedUrl {
+    pub method: SignedUrlMethod,
+    // the file to download, for GET
+    pub file_id: Option<Uuid>,
+    // the bucket to upload into, for PUT
+    pub bucket_id: Option<Uuid>,
+    pub expires_in_seconds: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SignedUrl {
+    pub method: SignedUrlMethod,
+    // uploads add `&file_name=` to it
+    pub url: String,
+    pub expires_date: NaiveDateTime,
+}
+
+#[derive(Debug)]
+pub enum SignedUrlErrors {
+    NotFound,
+    Forbidden,
+    InvalidRequest,
+    Failed,
+    File(UserFileErrors),
+}
+
+/// The URL signing generation of a user who may use pre-signed URLs,
+/// `None` for unknown and disabled users.
+pub async fn get_url_signing_generation(
+    pool: &PgPool,
+    user_id: &Uuid,
+) -> Result<Option<i32>, sqlx::Error> {
+    let query = "SELECT url_signing_generation FROM userinfo \
+        WHERE user_id = $1 AND disabled_date IS NULL";
+
+    sqlx::query_scalar::<_, i32>(query)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+}
+
+/// Invalidates every pre-signed URL of a user.
+pub async fn bump_url_signing_generation(
+    executor: impl PgExecutor<'_>,
+    user_id: &Uuid,
+) -> Result<(), sqlx::Error> {
+    let query = "UPDATE userinfo SET url_signing_generation = url_signing_generation + 1 \
+        WHERE user_id = $1";
+
+    sqlx::query(query).bind(user_id).execute(executor).await?;
+
+    Ok(())
+}
+
+async fn get_signed_path(
+    pool: &PgPool,
+    user_id: &Uuid,
+    new_signed_url: &NewSignedUrl,
+) -> Result<String, SignedUrlErrors> {
+    match (
+        new_signed_url.method,
+        new_signed_url.file_id,
+        new_signed_url.bucket_id,
+    ) {
+        (SignedUrlMethod::Get, Some(file_id), None) => {
+            let file_info = get_file_info_by_id(pool, &file_id)
+                .await
+                .map_err(SignedUrlErrors::File)?;
+
+            if file_info.deleted_date.is_some() {
+                return Err(SignedUrlErrors::File(UserFileErrors::Deleted));
+            }
+
+            if !user_has_file_permission(pool, &file_info, user_id, FILE_READ).await {
+                return Err(SignedUrlErrors::Forbidden);
+            }
+
+            Ok(format!("{}/file/{}", SIGNED_URL_PATH, file_id))
+        }
+        (SignedUrlMethod::Put, None, Some(bucket_id)) => {
+            let bucket = match get_bucket_by_id(pool, &bucket_id).await {
+                Some(bucket) => bucket,
+                None => return Err(SignedUrlErrors::NotFound),
+            };
+
+            if !user_can_write_bucket(pool, &bucket, user_id).await {
+                return Err(SignedUrlErrors::Forbidden);
+            }
+
+            Ok(format!("{}/upload/{}", SIGNED_URL_PATH, bucket_id))
+        }
+        _ => Err(SignedUrlErrors::InvalidRequest),
+    }
+}
+
+pub async fn create_signed_url(
+    pool: &PgPool,
+    user_id: &Uuid,
+    new_signed_url: &NewSignedUrl,
+) -> Result<SignedUrl, SignedUrlErrors> {
+    let expires_in_seconds = new_signed_url
+        .expires_in_seconds
+        .unwrap_or(DEFAULT_EXPIRY_SECONDS);
+
+    if expires_in_seconds <= 0 || expires_in_seconds > MAX_EXPIRY_SECONDS {
+        return Err(SignedUrlErrors::InvalidRequest);
+    }
+
+    let signed_path = get_signed_path(pool, user_id, new_signed_url).await?;
+
+    let generation = match get_url_signing_generation(pool, user_id).await {
+        Ok(Some(generation)) => generation,
+        Ok(None) => return Err(SignedUrlErrors::Forbidden),
+        Err(error) => {
+            println!("error while signing a URL: {}", error);
+            return Err(SignedUrlErrors::Failed);
+        }
+    };
+    let expires_date = Utc::now() + Duration::seconds(expires_in_seconds);
+
+    Ok(SignedUrl {
+        method: new_signed_url.method,
+        url: sign_url(
+            new_signed_url.method.as_str(),
+            &signed_path,
+            expires_date.timestamp(),
+            user_id,
+            generation,
+        ),
+        expires_date: expires_date.naive_utc(),
+    })
+}
@@ -0,0 +1,487 @@
+use ::serde::{Deserialize, Serialize};
+use chrono::{Duration, NaiveDateTime, Utc};
+use sqlx::{self, FromRow, PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::utility::{
+    genarate_salt,
+    passcode::hash_token,
+    totp::{generate_totp_secret, get_totp_uri, verify_totp_code},
+};
+
+use super::{
+    auth_session::{create_auth_session, AuthTokens},
+    login_attempt::{clear_attempts, reserve_attempt, ThrottleLimits},
+    user_info::get_user_info_by_user_id,
+};
+
+/*
+  two factor authentication is optional. A user enrolls by asking for a
+  secret and confirming it with a first code, which hands out one time
+  recovery codes, only their hashes are stored. Once enabled, a login with
+  the right passcode only returns a short lived challenge token, which is
+  traded for the session tokens together with a TOTP or recovery code.
+  Wrong codes are counted per user across challenges, so logging in again
+  with the passcode doesn't give a new round of guesses. Codes asked for
+  to disable 2FA or replace the recovery codes count against the same
+  limit.
+*/
+
+const TOTP_ISSUER: &str = "Home File Server";
+
+// 64 alphanumeric characters, about 380 bits
+const LOGIN_CHALLENGE_TOKEN_LEN: usize = 64;
+
+const LOGIN_CHALLENGE_MINUTES: i64 = 5;
+
+// wrong codes allowed per challenge, the user has to log in again after
+const LOGIN_CHALLENGE_ATTEMPTS: i32 = 5;
+
+const SECOND_FACTOR_LIMITS: ThrottleLimits = ThrottleLimits {
+    backoff_after: 5,
+    lockout_after: 10,
+};
+
+const RECOVERY_CODE_COUNT: usize = 10;
+
+// 12 alphanumeric characters, about 71 bits
+const RECOVERY_CODE_LEN: usize = 12;
+
+#[derive(Debug, Serialize)]
+pub struct TotpEnrollment {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TotpCode {
+    pub code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TotpChallenge {
+    pub challenge_token: String,
+    // seconds until the challenge expires
+    pub expires_in: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TotpLogin {
+    pub challenge_token: String,
+    // a TOTP code or one of the recovery codes
+    pub code: String,
+}
+
+#[derive(Debug, FromRow)]
+struct UserTotp {
+    secret: String,
+    last_used_step: Option<i64>,
+    enabled_date: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, FromRow)]
+struct LoginChallenge {
+    challenge_id: Uuid,
+    user_id: Uuid,
+    attempts: i32,
+    expires_date: NaiveDateTime,
+    used_date: Option<NaiveDateTime>,
+}
+
+#[derive(Debug)]
+pub enum TotpErrors {
+    AlreadyEnabled,
+    NotEnabled,
+    InvalidCode,
+    InvalidChallenge,
+    // too many wrong codes, seconds until the next try
+    Locked(i64),
+    // the account was disabled before the login was finished
+    Disabled,
+    Failed,
+}
+
+fn map_totp_error(error: sqlx::Error) -> TotpErrors {
+    println!("error while updating two factor authentication: {}", error);
+    TotpErrors::Failed
+}
+
+/// Counts a second factor code of the user before it is checked, returns
+/// the throttle key to clear when the code is right.
+async fn reserve_second_factor(pool: &PgPool, user_id: &Uuid) -> Result<String, TotpErrors> {
+    let throttle_key = format!("totp:{}", user_id);
+
+    match reserve_attempt(pool, &throttle_key, &SECOND_FACTOR_LIMITS)
+        .await
+        .map_err(map_totp_error)?
+    {
+        Some(retry_after) => Err(TotpErrors::Locked(retry_after)),
+        None => Ok(throttle_key),
+    }
+}
+
+async fn get_user_totp(
+    transaction: &mut Transaction<'_, Postgres>,
+    user_id: &Uuid,
+) -> Result<Option<UserTotp>, sqlx::Error> {
+    let query = "SELECT secret, last_used_step, enabled_date FROM usertotp \
+        WHERE user_id = $1 FOR UPDATE";
+
+    sqlx::query_as::<_, UserTotp>(query)
+        .bind(user_id)
+        .fetch_optional(&mut **transaction)
+        .await
+}
+
+/// Checks a TOTP code, refusing one that was already used, or else uses up
+/// a recovery code.
+async fn check_second_factor(
+    transaction: &mut Transaction<'_, Postgres>,
+    user_id: &Uuid,
+    user_totp: &UserTotp,
+    code: &str,
+) -> Result<bool, sqlx::Error> {
+    if let Some(step) = verify_totp_code(&user_totp.secret, code) {
+        if user_totp
+            .last_used_step
+            .is_some_and(|last_used_step| step <= last_used_step)
+        {
+            return Ok(false);
+        }
+
+        let query = "UPDATE usertotp SET last_used_step = $1 WHERE user_id = $2";
+
+        sqlx::query(query)
+            .bind(step)
+            .bind(user_id)
+            .execute(&mut **transaction)
+            .await?;
+
+        return Ok(true);
+    }
+
+    if user_totp.enabled_date.is_none() {
+        return Ok(false);
+    }
+
+    let query = "UPDATE recoverycode SET used_date = now() \
+        WHERE user_id = $1 AND code_hash = $2 AND used_date IS NULL";
+
+    let used = sqlx::query(query)
+        .bind(user_id)
+        .bind(hash_token(code.trim()))
+        .execute(&mut **transaction)
+        .await?;
+
+    Ok(used.rows_affected() == 1)
+}
+
+async fn replace_recovery_codes(
+    transaction: &mut Transaction<'_, Postgres>,
+    user_id: &Uuid,
+) -> Result<Vec<String>, sqlx::Error> {
+    let query = "DELETE FROM recoverycode WHERE user_id = $1";
+
+    sqlx::query(query)
+        .bind(user_id)
+        .execute(&mut **transaction)
+        .await?;
+
+    let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| genarate_salt(RECOVERY_CODE_LEN))
+        .collect();
+
+    let query = "INSERT INTO recoverycode (user_id, code_hash) VALUES($1, $2)";
+
+    for recovery_code in &recovery_codes {
+        sqlx::query(query)
+            .bind(user_id)
+            .bind(hash_token(recovery_code))
+            .execute(&mut **transaction)
+            .await?;
+    }
+
+    Ok(recovery_codes)
+}
+
+/// Makes a new secret for a user who hasn't enabled 2FA yet, it is only
+/// used once confirmed with `confirm_totp_enrollment`.
+pub async fn start_totp_enrollment(
+    pool: &PgPool,
+    user_id: &Uuid,
+) -> Result<TotpEnrollment, TotpErrors> {
+    let user_info = match get_user_info_by_user_id(pool, user_id).await {
+        Some(user_info) => user_info,
+        None => return Err(TotpErrors::Failed),
+    };
+
+    let secret = generate_totp_secret();
+
+    let query = "INSERT INTO usertotp (user_id, secret) VALUES($1, $2) \
+        ON CONFLICT (user_id) DO UPDATE SET secret = $2, last_used_step = NULL, created_date = now() \
+        WHERE usertotp.enabled_date IS NULL";
+
+    let enrolled = sqlx::query(query)
+        .bind(user_id)
+        .bind(&secret)
+        .execute(pool)
+        .await
+        .map_err(map_totp_error)?;
+
+    if enrolled.rows_affected() == 0 {
+        return Err(TotpErrors::AlreadyEnabled);
+    }
+
+    Ok(TotpEnrollment {
+        otpauth_uri: get_totp_uri(TOTP_ISSUER, &user_info.email, &secret),
+        secret,
+    })
+}
+
+/// Enables 2FA once the user proves their app makes the right codes.
+pub async fn confirm_totp_enrollment(
+    pool: &PgPool,
+    user_id: &Uuid,
+    code: &str,
+) -> Result<RecoveryCodes, TotpErrors> {
+    let mut transaction = pool.begin().await.map_err(map_totp_error)?;
+
+    let user_totp = match get_user_totp(&mut transaction, user_id)
+        .await
+        .map_err(map_totp_error)?
+    {
+        Some(user_totp) if user_totp.enabled_date.is_some() => {
+            return Err(TotpErrors::AlreadyEnabled)
+        }
+        Some(user_totp) => user_totp,
+        None => return Err(TotpErrors::NotEnabled),
+    };
+
+    if !check_second_factor(&mut transaction, user_id, &user_totp, code)
+        .await
+        .map_err(map_totp_error)?
+    {
+        return Err(TotpErrors::InvalidCode);
+    }
+
+    let query = "UPDATE usertotp SET enabled_date = now() WHERE user_id = $1";
+
+    sqlx::query(query)
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await
+        .map_err(map_totp_error)?;
+
+    let recovery_codes = replace_recovery_codes(&mut transaction, user_id)
+        .await
+        .map_err(map_totp_error)?;
+
+    transaction.commit().await.map_err(map_totp_error)?;
+
+    Ok(RecoveryCodes { recovery_codes })
+}
+
+/// Hands out new recovery codes, the old ones stop working.
+pub async fn regenerate_recovery_codes(
+    pool: &PgPool,
+    user_id: &Uuid,
+    code: &str,
+) -> Result<RecoveryCodes, TotpErrors> {
+    let mut transaction = pool.begin().await.map_err(map_totp_error)?;
+
+    let user_totp = match get_user_totp(&mut transaction, user_id)
+        .await
+        .map_err(map_totp_error)?
+    {
+        Some(user_totp) if user_totp.enabled_date.is_some() => user_totp,
+        _ => return Err(TotpErrors::NotEnabled),
+    };
+
+    let throttle_key = reserve_second_factor(pool, user_id).await?;
+
+    if !check_second_factor(&mut transaction, user_id, &user_totp, code)
+        .await
+        .map_err(map_totp_error)?
+    {
+        return Err(TotpErrors::InvalidCode);
+    }
+
+    let recovery_codes = replace_recovery_codes(&mut transaction, user_id)
+        .await
+        .map_err(map_totp_error)?;
+
+    transaction.commit().await.map_err(map_totp_error)?;
+
+    clear_attempts(pool, &throttle_key)
+        .await
+        .map_err(map_totp_error)?;
+
+    Ok(RecoveryCodes { recovery_codes })
+}
+
+/// Turns 2FA off, this needs a TOTP or recovery code as well.
+pub async fn disable_totp(pool: &PgPool, user_id: &Uuid, code: &str) -> Result<(), TotpErrors> {
+    let mut transaction = pool.begin().await.map_err(map_totp_error)?;
+
+    let user_totp = match get_user_totp(&mut transaction, user_id)
+        .await
+        .map_err(map_totp_error)?
+    {
+        Some(user_totp) if user_totp.enabled_date.is_some() => user_totp,
+        _ => return Err(TotpErrors::NotEnabled),
+    };
+
+    let throttle_key = reserve_second_factor(pool, user_id).await?;
+
+    if !check_second_factor(&mut transaction, user_id, &user_totp, code)
+        .await
+        .map_err(map_totp_error)?
+    {
+        return Err(TotpErrors::InvalidCode);
+    }
+
+    let query = "DELETE FROM usertotp WHERE user_id = $1";
+
+    sqlx::query(query)
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await
+        .map_err(map_totp_error)?;
+
+    let query = "DELETE FROM recoverycode WHERE user_id = $1";
+
+    sqlx::query(query)
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await
+        .map_err(map_totp_error)?;
+
+    transaction.commit().await.map_err(map_totp_error)?;
+
+    clear_attempts(pool, &throttle_key)
+        .await
+        .map_err(map_totp_error)
+}
+
+/// Starts the second login step when the user has 2FA enabled, `None`
+/// means the session can be created right away.
+pub async fn create_login_challenge(
+    pool: &PgPool,
+    user_id: &Uuid,
+) -> Result<Option<TotpChallenge>, sqlx::Error> {
+    let query =
+        "SELECT EXISTS (SELECT 1 FROM usertotp WHERE user_id = $1 AND enabled_date IS NOT NULL)";
+
+    let is_enabled = sqlx::query_scalar::<_, bool>(query)
+        .bind(user_id)
+        .fetch_one(pool)
+        .await?;
+
+    if !is_enabled {
+        return Ok(None);
+    }
+
+    let challenge_token = genarate_salt(LOGIN_CHALLENGE_TOKEN_LEN);
+
+    let query = "INSERT INTO loginchallenge (user_id, token_hash, expires_date) VALUES($1, $2, $3)";
+
+    sqlx::query(query)
+        .bind(user_id)
+        .bind(hash_token(&challenge_token))
+        .bind((Utc::now() + Duration::minutes(LOGIN_CHALLENGE_MINUTES)).naive_utc())
+        .execute(pool)
+        .await?;
+
+    Ok(Some(TotpChallenge {
+        challenge_token,
+        expires_in: LOGIN_CHALLENGE_MINUTES * 60,
+    }))
+}
+
+/// Finishes a login with a challenge token and a TOTP or recovery code.
+pub async fn complete_totp_login(
+    pool: &PgPool,
+    totp_login: &TotpLogin,
+    refresh_token_days: i64,
+) -> Result<AuthTokens, TotpErrors> {
+    let mut transaction = pool.begin().await.map_err(map_totp_error)?;
+
+    let query = "SELECT challenge_id, user_id, attempts, expires_date, used_date \
+        FROM loginchallenge WHERE token_hash = $1 FOR UPDATE";
+
+    let login_challenge = sqlx::query_as::<_, LoginChallenge>(query)
+        .bind(hash_token(&totp_login.challenge_token))
+        .fetch_optional(&mut *transaction)
+        .await
+        .map_err(map_totp_error)?;
+
+    let login_challenge = match login_challenge {
+        Some(login_challenge)
+            if login_challenge.used_date.is_none()
+                && login_challenge.attempts < LOGIN_CHALLENGE_ATTEMPTS
+                && login_challenge.expires_date > Utc::now().naive_utc() =>
+        {
+            login_challenge
+        }
+        _ => return Err(TotpErrors::InvalidChallenge),
+    };
+
+    let user_totp = match get_user_totp(&mut transaction, &login_challenge.user_id)
+        .await
+        .map_err(map_totp_error)?
+    {
+        Some(user_totp) if user_totp.enabled_date.is_some() => user_totp,
+        _ => return Err(TotpErrors::InvalidChallenge),
+    };
+
+    let throttle_key = reserve_second_factor(pool, &login_challenge.user_id).await?;
+
+    let is_valid_code = check_second_factor(
+        &mut transaction,
+        &login_challenge.user_id,
+        &user_totp,
+        &totp_login.code,
+    )
+    .await
+    .map_err(map_totp_error)?;
+
+    let query = if is_valid_code {
+        "UPDATE loginchallenge SET used_date = now() WHERE challenge_id = $1"
+    } else {
+        "UPDATE loginchallenge SET attempts = attempts + 1 WHERE challenge_id = $1"
+    };
+
+    sqlx::query(query)
+        .bind(login_challenge.challenge_id)
+        .execute(&mut *transaction)
+        .await
+        .map_err(map_totp_error)?;
+
+    transaction.commit().await.map_err(map_totp_error)?;
+
+    if !is_valid_code {
+        return Err(TotpErrors::InvalidCode);
+    }
+
+    clear_attempts(pool, &throttle_key)
+        .await
+        .map_err(map_totp_error)?;
+
+    let user_info = match get_user_info_by_user_id(pool, &login_challenge.user_id).await {
+        Some(user_info) => user_info,
+        None => return Err(TotpErrors::InvalidChallenge),
+    };
+
+    if user_info.disabled_date.is_some() {
+        return Err(TotpErrors::Disabled);
+    }
+
+    create_auth_session(pool, &user_info, refresh_token_days)
+        .await
+        .map_err(|_| TotpErrors::Failed)
+}
@@ -0,0 +1,146 @@
+use sqlx::{self, PgPool};
+use uuid::Uuid;
+
+use super::user_file::{
+    delete_user_file_record, get_file_info_by_id, UserFile, UserFileErrors, USER_FILE_SELECT,
+};
+
+/*
+  deleting a file only sets UserFile.deleted_date, the file stays in the
+  trash of its owner until it is restored, the trash is emptied or it is
+  older than TRASH_RETENTION_DAYS.
+*/
+
+pub async fn get_user_trash(pool: &PgPool, user_id: &Uuid) -> Option<Vec<UserFile>> {
+    let query = format!(
+        "{} WHERE uf.user_id = $1 AND uf.deleted_date IS NOT NULL ORDER BY uf.deleted_date DESC",
+        USER_FILE_SELECT
+    );
+
+    let query = sqlx::query_as::<_, UserFile>(&query).bind(user_id);
+
+    let files = query.fetch_all(pool).await;
+
+    match files {
+        Ok(files) => Some(files),
+        Err(error) => {
+            println!(
+                "Error occurred while fetching the trash of user {}: {}",
+                user_id, error
+            );
+            None
+        }
+    }
+}
+
+async fn get_trashed_file(
+    pool: &PgPool,
+    user_id: &Uuid,
+    file_id: &Uuid,
+) -> Result<UserFile, UserFileErrors> {
+    let file_info = get_file_info_by_id(pool, file_id).await?;
+
+    // the trash belongs to the owner of the file
+    if &file_info.user_id != user_id {
+        return Err(UserFileErrors::Forbidden);
+    }
+
+    if file_info.deleted_date.is_none() {
+        return Err(UserFileErrors::NotFound);
+    }
+
+    Ok(file_info)
+}
+
+pub async fn restore_trashed_file(
+    pool: &PgPool,
+    user_id: &Uuid,
+    file_id: &Uuid,
+) -> Result<UserFile, UserFileErrors> {
+    let file_info = get_trashed_file(pool, user_id, file_id).await?;
+
+    let query = "UPDATE userfile SET deleted_date = NULL WHERE file_id = $1";
+
+    let query = sqlx::query(query)
+        .bind(file_info.file_id)
+        .execute(pool)
+        .await;
+
+    if let Err(error) = query {
+        println!(
+            "error occurred while restoring file {} from the trash: {}",
+            file_id, error
+        );
+        return Err(UserFileErrors::FailedToSave);
+    }
+
+    get_file_info_by_id(pool, file_id).await
+}
+
+pub async fn purge_trashed_file(
+    pool: &PgPool,
+    data_path: &str,
+    user_id: &Uuid,
+    file_id: &Uuid,
+) -> Result<(), UserFileErrors> {
+    let file_info = get_trashed_file(pool, user_id, file_id).await?;
+
+    match delete_user_file_record(pool, data_path, &file_info).await {
+        Ok(_) => Ok(()),
+        Err(error) => {
+            println!(
+                "error occurred while deleting the file record in db, file id : {} error : {}",
+                file_id, error
+            );
+            Err(UserFileErrors::FailedToDelete)
+        }
+    }
+}
+
+async fn purge_trashed_files(pool: &PgPool, data_path: &str, files: Vec<UserFile>) -> usize {
+    let mut purged = 0;
+
+    for file_info in files {
+        match delete_user_file_record(pool, data_path, &file_info).await {
+            Ok(_) => purged += 1,
+            Err(error) => println!(
+                "error occurred while purging file {} from the trash: {}",
+                file_info.file_id, error
+            ),
+        }
+    }
+
+    purged
+}
+
+/// Permanently deletes every file in the trash of a user.
+pub async fn empty_user_trash(pool: &PgPool, data_path: &str, user_id: &Uuid) -> Option<usize> {
+    let files = get_user_trash(pool, user_id).await?;
+
+    Some(purge_trashed_files(pool, data_path, files).await)
+}
+
+/// Permanently deletes files that have been in the trash for longer than
+/// `trash_retention_days`.
+pub async fn purge_expired_trash(
+    pool: &PgPool,
+    data_path: &str,
+    trash_retention_days: i64,
+) -> usize {
+    let query = format!(
+        "{} WHERE uf.deleted_date < now() - make_interval(days => $1)",
+        USER_FILE_SELECT
+    );
+
+    let query = sqlx::query_as::<_, UserFile>(&query).bind(trash_retention_days as i32);
+
+    let files = query.fetch_all(pool).await;
+
+    match files {
+        Ok(files) => purge_trashed_files(pool, data_path, files).await,
+        Err(error) => {
+            println!("error while purging expired trash: {}", error);
+            0
+        }
+    }
+}
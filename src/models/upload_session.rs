@@ -0,0 +1,565 @@
+use ::serde::{Deserialize, Serialize};
+use actix_web::web;
+use chrono::{Duration, NaiveDateTime, Utc};
+use futures_util::StreamExt;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use sqlx::{self, FromRow, PgPool, Postgres, Transaction};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+use uuid::Uuid;
+
+use super::{
+    bucket::TargetBucket,
+    folder::{resolve_target_folder, TargetFolder},
+    user_file::{
+        get_file_hash, resolve_target_bucket, sanitize_file_name, store_user_file, PartialFile,
+        ReceivedFile, UserFile, UserFileErrors,
+    },
+};
+
+/*
+  a tus upload is written to `.uploads/{upload_id}.part`. Every PATCH locks
+  the row of its session until the offset is updated, so two requests with
+  the same offset can't write into the part file at the same time, the
+  second one is turned away. Once the last byte is in, the part file is
+  stored like any other upload. When that fails for a reason that can pass,
+  like a full bucket, the session and its part file are kept and an empty
+  PATCH at the final offset tries again. An upload of 0 bytes has nothing
+  to PATCH, it is stored as soon as it is created.
+*/
+
+#[derive(Debug, FromRow, Serialize, Deserialize)]
+pub struct UploadSession {
+    pub upload_id: Uuid,
+    pub user_id: Uuid,
+    pub bucket_id: Uuid,
+    pub folder_id: Option<Uuid>,
+    pub folder_path: Option<String>,
+    pub file_name: String,
+    pub upload_length: i64,
+    pub upload_offset: i64,
+    pub created_date: NaiveDateTime,
+    pub expires_date: NaiveDateTime,
+}
+
+#[derive(Debug)]
+pub enum ChecksumAlgorithm {
+    Sha1,
+    Sha256,
+}
+
+#[derive(Debug)]
+pub struct UploadChecksum {
+    pub algorithm: ChecksumAlgorithm,
+    pub digest: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum UploadSessionErrors {
+    NotFound,
+    Forbidden,
+    Expired,
+    OffsetMismatch,
+    // another request is writing to the upload
+    Locked,
+    ExceedsUploadLength,
+    ChecksumMismatch,
+    FailedToSave,
+    File(UserFileErrors),
+}
+
+enum ChunkHasher {
+    Sha1(Sha1),
+    Sha256(Sha256),
+}
+
+impl ChunkHasher {
+    fn new(algorithm: &ChecksumAlgorithm) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::Sha1 => ChunkHasher::Sha1(Sha1::new()),
+            ChecksumAlgorithm::Sha256 => ChunkHasher::Sha256(Sha256::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            ChunkHasher::Sha1(hasher) => hasher.update(data),
+            ChunkHasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    fn finalize(self) -> Vec<u8> {
+        match self {
+            ChunkHasher::Sha1(hasher) => hasher.finalize().to_vec(),
+            ChunkHasher::Sha256(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
+
+pub fn get_upload_path(data_path: &str, upload_id: &Uuid) -> PathBuf {
+    let mut upload_path = PathBuf::from(data_path);
+    upload_path.push(".uploads");
+    upload_path.push(format!("{}.part", upload_id));
+
+    upload_path
+}
+
+// a second name for the part file while it is stored, so a failure only
+// removes this one
+fn get_finishing_path(data_path: &str, upload_id: &Uuid) -> PathBuf {
+    get_upload_path(data_path, upload_id).with_extension("finishing")
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn create_upload_session(
+    pool: &PgPool,
+    data_path: &str,
+    user_id: &Uuid,
+    target_bucket: &TargetBucket,
+    file_name: &str,
+    upload_length: i64,
+    max_upload_size: Option<i64>,
+    expiry: Duration,
+) -> Result<UploadSession, UploadSessionErrors> {
+    let bucket = resolve_target_bucket(pool, user_id, target_bucket)
+        .await
+        .map_err(UploadSessionErrors::File)?;
+
+    if let Some(max_upload_size) = max_upload_size {
+        if upload_length > max_upload_size {
+            return Err(UploadSessionErrors::File(UserFileErrors::FileTooLarge));
+        }
+    }
+
+    if bucket.bucket_size + upload_length > bucket.max_bucket_size {
+        return Err(UploadSessionErrors::File(UserFileErrors::QuotaExceeded));
+    }
+
+    // missing folders are only kept as a path until the upload is stored
+    let (folder_id, folder_path) = match resolve_target_folder(pool, &bucket, target_bucket)
+        .await
+        .map_err(UploadSessionErrors::File)?
+    {
+        TargetFolder::Existing(folder_id) => (folder_id, None),
+        TargetFolder::Missing(folder_path) => (None, Some(folder_path)),
+    };
+
+    let upload_id = Uuid::new_v4();
+    let upload_path = get_upload_path(data_path, &upload_id);
+
+    if let Some(upload_folder_path) = upload_path.parent() {
+        let _ = fs::create_dir_all(upload_folder_path);
+    }
+
+    if let Err(error) = File::create(&upload_path) {
+        println!("error while creating upload file: {}", error);
+        return Err(UploadSessionErrors::FailedToSave);
+    }
+
+    let query = "INSERT INTO uploadsession (upload_id, user_id, bucket_id, folder_id, folder_path, file_name, upload_length, expires_date) \
+        VALUES($1, $2, $3, $4, $5, $6, $7, $8) RETURNING *";
+
+    let query = sqlx::query_as::<_, UploadSession>(query)
+        .bind(upload_id)
+        .bind(user_id)
+        .bind(bucket.bucket_id)
+        .bind(folder_id)
+        .bind(folder_path)
+        .bind(sanitize_file_name(file_name))
+        .bind(upload_length)
+        .bind((Utc::now() + expiry).naive_utc())
+        .fetch_one(pool)
+        .await;
+
+    match query {
+        Ok(upload_session) => Ok(upload_session),
+        Err(error) => {
+            println!("error while creating upload session: {}", error);
+            let _ = fs::remove_file(upload_path);
+            Err(UploadSessionErrors::FailedToSave)
+        }
+    }
+}
+
+fn check_upload_session(
+    upload_session: UploadSession,
+    user_id: &Uuid,
+) -> Result<UploadSession, UploadSessionErrors> {
+    if &upload_session.user_id != user_id {
+        Err(UploadSessionErrors::Forbidden)
+    } else if upload_session.expires_date <= Utc::now().naive_utc() {
+        Err(UploadSessionErrors::Expired)
+    } else {
+        Ok(upload_session)
+    }
+}
+
+pub async fn get_upload_session(
+    pool: &PgPool,
+    user_id: &Uuid,
+    upload_id: &Uuid,
+) -> Result<UploadSession, UploadSessionErrors> {
+    let query = "SELECT * FROM uploadsession WHERE upload_id = $1";
+
+    let query = sqlx::query_as::<_, UploadSession>(query).bind(upload_id);
+
+    let upload_session = query.fetch_optional(pool).await;
+
+    match upload_session {
+        Ok(Some(upload_session)) => check_upload_session(upload_session, user_id),
+        Ok(None) => Err(UploadSessionErrors::NotFound),
+        Err(error) => {
+            println!(
+                "Error occurred while fetching upload session {}: {}",
+                upload_id, error
+            );
+            Err(UploadSessionErrors::NotFound)
+        }
+    }
+}
+
+/// Locks the session until `transaction` ends, without waiting for a
+/// request that already holds it.
+async fn lock_upload_session(
+    transaction: &mut Transaction<'_, Postgres>,
+    user_id: &Uuid,
+    upload_id: &Uuid,
+) -> Result<UploadSession, UploadSessionErrors> {
+    let query = "SELECT * FROM uploadsession WHERE upload_id = $1 FOR UPDATE NOWAIT";
+
+    let upload_session = sqlx::query_as::<_, UploadSession>(query)
+        .bind(upload_id)
+        .fetch_optional(&mut **transaction)
+        .await;
+
+    match upload_session {
+        Ok(Some(upload_session)) => check_upload_session(upload_session, user_id),
+        Ok(None) => Err(UploadSessionErrors::NotFound),
+        // lock_not_available
+        Err(sqlx::Error::Database(error)) if error.code().as_deref() == Some("55P03") => {
+            Err(UploadSessionErrors::Locked)
+        }
+        Err(error) => {
+            println!(
+                "error while locking upload session {}: {}",
+                upload_id, error
+            );
+            Err(UploadSessionErrors::FailedToSave)
+        }
+    }
+}
+
+fn truncate_upload(file: &File, upload_offset: i64) {
+    if let Err(error) = file.set_len(upload_offset as u64) {
+        println!("error while truncating upload file: {}", error);
+    }
+}
+
+/// Appends the request body to the upload at `upload_offset`.
+///
+/// Returns the updated session, and the stored file once the last byte
+/// of the upload has been received.
+#[allow(clippy::too_many_arguments)]
+pub async fn append_to_upload_session(
+    pool: &PgPool,
+    data_path: &str,
+    user_id: &Uuid,
+    upload_id: &Uuid,
+    upload_offset: i64,
+    checksum: Option<UploadChecksum>,
+    expiry: Duration,
+    mut payload: web::Payload,
+) -> Result<(UploadSession, Option<UserFile>), UploadSessionErrors> {
+    let mut transaction = pool.begin().await.map_err(|error| {
+        println!("error while starting upload transaction: {}", error);
+        UploadSessionErrors::FailedToSave
+    })?;
+
+    // held until the offset is updated, or dropped with the transaction
+    let upload_session = lock_upload_session(&mut transaction, user_id, upload_id).await?;
+
+    if upload_session.upload_offset != upload_offset {
+        return Err(UploadSessionErrors::OffsetMismatch);
+    }
+
+    let upload_path = get_upload_path(data_path, upload_id);
+
+    let mut file = match OpenOptions::new().write(true).open(&upload_path) {
+        Ok(file) => file,
+        Err(error) => {
+            println!("error while opening upload file: {}", error);
+            return Err(UploadSessionErrors::NotFound);
+        }
+    };
+
+    // drop bytes of an earlier request that never made it into upload_offset
+    truncate_upload(&file, upload_offset);
+    if let Err(error) = file.seek(SeekFrom::End(0)) {
+        println!("error while seeking upload file: {}", error);
+        return Err(UploadSessionErrors::FailedToSave);
+    }
+
+    let mut hasher = checksum
+        .as_ref()
+        .map(|checksum| ChunkHasher::new(&checksum.algorithm));
+    let remaining = upload_session.upload_length - upload_offset;
+    let mut written: i64 = 0;
+    let mut interrupted = false;
+
+    while let Some(chunk) = payload.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(error) => {
+                println!("error while reading upload chunk: {}", error);
+                interrupted = true;
+                break;
+            }
+        };
+
+        if written + chunk.len() as i64 > remaining {
+            truncate_upload(&file, upload_offset);
+            return Err(UploadSessionErrors::ExceedsUploadLength);
+        }
+
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update(&chunk);
+        }
+
+        written += chunk.len() as i64;
+
+        file = match web::block(move || file.write_all(&chunk).map(|_| file)).await {
+            Ok(Ok(file)) => file,
+            _ => {
+                println!("error while writing upload chunk for {}", upload_id);
+                return Err(UploadSessionErrors::FailedToSave);
+            }
+        };
+    }
+
+    if let (Some(hasher), Some(checksum)) = (hasher, checksum) {
+        // a chunk is only kept when it arrived in full and matches its checksum
+        if interrupted || hasher.finalize() != checksum.digest {
+            truncate_upload(&file, upload_offset);
+            return Err(UploadSessionErrors::ChecksumMismatch);
+        }
+    }
+
+    let query = "UPDATE uploadsession SET upload_offset = $1, expires_date = $2 \
+        WHERE upload_id = $3 RETURNING *";
+
+    let query = sqlx::query_as::<_, UploadSession>(query)
+        .bind(upload_offset + written)
+        .bind((Utc::now() + expiry).naive_utc())
+        .bind(upload_id)
+        .fetch_one(&mut *transaction)
+        .await;
+
+    let upload_session = match query {
+        Ok(upload_session) => upload_session,
+        Err(error) => {
+            println!("error while updating upload session offset: {}", error);
+            return Err(UploadSessionErrors::FailedToSave);
+        }
+    };
+
+    complete_upload_session(pool, data_path, transaction, upload_session).await
+}
+
+/// Stores an upload created with an `Upload-Length` of 0, there is no byte
+/// left for a PATCH to send.
+pub async fn finish_empty_upload_session(
+    pool: &PgPool,
+    data_path: &str,
+    user_id: &Uuid,
+    upload_id: &Uuid,
+) -> Result<(UploadSession, Option<UserFile>), UploadSessionErrors> {
+    let mut transaction = pool.begin().await.map_err(|error| {
+        println!("error while starting upload transaction: {}", error);
+        UploadSessionErrors::FailedToSave
+    })?;
+
+    let upload_session = lock_upload_session(&mut transaction, user_id, upload_id).await?;
+
+    complete_upload_session(pool, data_path, transaction, upload_session).await
+}
+
+/// Commits the offset of a locked session, and stores the file once the
+/// whole upload is in.
+async fn complete_upload_session(
+    pool: &PgPool,
+    data_path: &str,
+    mut transaction: Transaction<'_, Postgres>,
+    upload_session: UploadSession,
+) -> Result<(UploadSession, Option<UserFile>), UploadSessionErrors> {
+    let upload_id = &upload_session.upload_id;
+
+    if upload_session.upload_offset < upload_session.upload_length {
+        return match transaction.commit().await {
+            Ok(_) => Ok((upload_session, None)),
+            Err(error) => {
+                println!("error while updating upload session offset: {}", error);
+                Err(UploadSessionErrors::FailedToSave)
+            }
+        };
+    }
+
+    let user_file = finish_upload_session(pool, data_path, &upload_session).await;
+
+    let is_finished = match &user_file {
+        Ok(_) => true,
+        Err(error) => !is_retryable_error(error),
+    };
+
+    if is_finished {
+        let query = "DELETE FROM uploadsession WHERE upload_id = $1";
+
+        if let Err(error) = sqlx::query(query)
+            .bind(upload_id)
+            .execute(&mut *transaction)
+            .await
+        {
+            println!(
+                "error while deleting upload session {}: {}",
+                upload_id, error
+            );
+        }
+    }
+
+    if let Err(error) = transaction.commit().await {
+        println!(
+            "error while finishing upload session {}: {}",
+            upload_id, error
+        );
+    }
+
+    if is_finished {
+        let _ = fs::remove_file(get_upload_path(data_path, upload_id));
+    }
+
+    match user_file {
+        Ok(user_file) => Ok((upload_session, Some(user_file))),
+        Err(error) => Err(UploadSessionErrors::File(error)),
+    }
+}
+
+/// Whether storing a finished upload can work when it is tried again.
+fn is_retryable_error(error: &UserFileErrors) -> bool {
+    matches!(
+        error,
+        UserFileErrors::QuotaExceeded | UserFileErrors::FailedToSave
+    )
+}
+
+async fn finish_upload_session(
+    pool: &PgPool,
+    data_path: &str,
+    upload_session: &UploadSession,
+) -> Result<UserFile, UserFileErrors> {
+    let finishing_path = get_finishing_path(data_path, &upload_session.upload_id);
+    let _ = fs::remove_file(&finishing_path);
+
+    if let Err(error) = fs::hard_link(
+        get_upload_path(data_path, &upload_session.upload_id),
+        &finishing_path,
+    ) {
+        println!("error while linking finished upload: {}", error);
+        return Err(UserFileErrors::FailedToSave);
+    }
+
+    let partial_file = PartialFile::new(finishing_path);
+
+    let target_bucket = TargetBucket {
+        bucket_id: Some(upload_session.bucket_id),
+        bucket_name: None,
+        path: None,
+    };
+    let bucket = resolve_target_bucket(pool, &upload_session.user_id, &target_bucket).await?;
+
+    let upload_path = partial_file.path.clone();
+    let file_hash = match web::block(move || get_file_hash(&upload_path)).await {
+        Ok(Ok(file_hash)) => file_hash,
+        _ => {
+            println!(
+                "error while hashing finished upload {}",
+                upload_session.upload_id
+            );
+            return Err(UserFileErrors::FailedToSave);
+        }
+    };
+
+    let received_file = ReceivedFile {
+        partial_file,
+        file_name: upload_session.file_name.to_owned(),
+        file_size: upload_session.upload_length,
+        file_hash,
+    };
+
+    let target_folder = match &upload_session.folder_path {
+        Some(folder_path) => TargetFolder::Missing(folder_path.to_owned()),
+        None => TargetFolder::Existing(upload_session.folder_id),
+    };
+
+    store_user_file(
+        pool,
+        data_path,
+        &upload_session.user_id,
+        &bucket,
+        &target_folder,
+        received_file,
+    )
+    .await
+}
+
+async fn delete_upload_session_record(pool: &PgPool, upload_id: &Uuid) {
+    let query = "DELETE FROM uploadsession WHERE upload_id = $1";
+
+    let query = sqlx::query(query).bind(upload_id).execute(pool).await;
+
+    if let Err(error) = query {
+        println!(
+            "error while deleting upload session {}: {}",
+            upload_id, error
+        );
+    }
+}
+
+pub async fn delete_upload_session(
+    pool: &PgPool,
+    data_path: &str,
+    user_id: &Uuid,
+    upload_id: &Uuid,
+) -> Result<(), UploadSessionErrors> {
+    let upload_session = get_upload_session(pool, user_id, upload_id).await?;
+
+    delete_upload_session_record(pool, &upload_session.upload_id).await;
+
+    let _ = fs::remove_file(get_upload_path(data_path, upload_id));
+
+    Ok(())
+}
+
+pub async fn purge_expired_upload_sessions(pool: &PgPool, data_path: &str) -> usize {
+    let query = "DELETE FROM uploadsession WHERE expires_date <= $1 RETURNING *";
+
+    let query = sqlx::query_as::<_, UploadSession>(query).bind(Utc::now().naive_utc());
+
+    let expired_sessions = query.fetch_all(pool).await;
+
+    match expired_sessions {
+        Ok(expired_sessions) => {
+            for upload_session in &expired_sessions {
+                let _ = fs::remove_file(get_upload_path(data_path, &upload_session.upload_id));
+            }
+
+            expired_sessions.len()
+        }
+        Err(error) => {
+            println!("error while purging expired upload sessions: {}", error);
+            0
+        }
+    }
+}
@@ -1,33 +1,144 @@
 use ::serde::{Deserialize, Serialize};
-use actix_multipart::form::{tempfile::TempFile, MultipartForm};
+use actix_files::file_extension_to_mime;
+use actix_multipart::Multipart;
+use actix_web::web;
 use chrono::NaiveDateTime;
+use futures_util::{Stream, StreamExt};
 use sha2::{Digest, Sha256};
 use sqlx::{self, FromRow, PgPool};
 use std::{
+    fmt::Display,
     fs::{self, File},
-    io::{self, BufReader, Read},
+    io::{self, BufReader, Read, Write},
     path::{Path, PathBuf},
+    slice,
 };
 use uuid::Uuid;
 
-use crate::utility::get_file_type;
+use crate::utility::{deserialize_some, get_file_type};
 
-use super::user_info::get_user_info_by_user_id;
+use super::{
+    blob::{
+        add_blob_reference, copy_blob_reference, get_blob_path, release_blob_reference,
+        remove_unused_blobs,
+    },
+    bucket::{
+        add_to_bucket_size, get_bucket_by_id, get_bucket_folder_path, get_target_bucket,
+        user_can_read_bucket, user_can_write_bucket, Bucket, TargetBucket,
+    },
+    file_user::{user_has_file_permission, FILE_DELETE, FILE_READ, FILE_WRITE},
+    file_version::release_file_versions,
+    folder::{create_target_folder, get_folder_by_id, resolve_target_folder, TargetFolder},
+    user_info::get_user_info_by_user_id,
+};
 
 #[derive(Debug, FromRow, Serialize, Deserialize)]
 pub struct UserFile {
     pub file_id: Uuid,
     pub user_id: Uuid,
+    pub bucket_id: Uuid,
+    pub folder_id: Option<Uuid>,
+    pub is_shared: bool,
+    pub is_public: bool,
     pub file_name: String,
+    pub file_size: i64,
+    pub file_type: String,
+    pub file_hash: String,
+    pub version_number: i32,
+    pub uploaded_by: Option<Uuid>,
     pub created_date: NaiveDateTime,
-    pub file_size: i32,
+    pub deleted_date: Option<NaiveDateTime>,
+}
+
+// a UserFile row joined with its FileMetadata
+pub const USER_FILE_SELECT: &str =
+    "SELECT uf.file_id, uf.user_id, uf.bucket_id, uf.folder_id, uf.is_shared, uf.is_public, \
+    fm.file_name, fm.file_size, fm.file_type, fm.file_hash, fm.version_number, fm.uploaded_by, \
+    fm.created_date, uf.deleted_date FROM userfile uf JOIN filemetadata fm ON fm.file_id = uf.file_id";
+
+pub struct NewFileMetadata {
+    pub file_name: String,
+    pub file_size: i64,
+    pub file_type: String,
+    pub file_hash: String,
+}
+
+/// An upload that was fully written next to its bucket, but not stored yet.
+pub struct ReceivedFile {
+    pub partial_file: PartialFile,
+    pub file_name: String,
+    pub file_size: i64,
     pub file_hash: String,
-    pub is_shared: bool,
 }
 
-#[derive(MultipartForm)]
-pub struct UploadFile {
-    pub file: TempFile,
+/// A file being written to disk, removed on drop unless it was kept.
+///
+/// This makes sure an upload that fails or is aborted half way through
+/// never leaves a half written file in the data path.
+pub struct PartialFile {
+    pub path: PathBuf,
+    keep: bool,
+}
+
+impl PartialFile {
+    pub fn new(path: PathBuf) -> Self {
+        PartialFile { path, keep: false }
+    }
+
+    pub fn persist(mut self, file_path: &Path) -> Result<(), UserFileErrors> {
+        match fs::rename(&self.path, file_path) {
+            Ok(_) => {
+                self.keep = true;
+                Ok(())
+            }
+            Err(error) => {
+                println!("error while moving the uploaded file into place: {}", error);
+                Err(UserFileErrors::FailedToSave)
+            }
+        }
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+// a file shared with the current user, with the permissions it was shared with
+#[derive(Debug, FromRow, Serialize)]
+pub struct SharedFile {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub file: UserFile,
+    #[sqlx(rename = "Permissions")]
+    pub permissions: i32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FileFilter {
+    // without a folder, only the files at the root of the bucket
+    pub bucket_id: Option<Uuid>,
+    pub folder_id: Option<Uuid>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FileUpdate {
+    pub file_name: Option<String>,
+    pub bucket_id: Option<Uuid>,
+    // absent keeps the folder (or the root when the bucket changes), null
+    // moves the file to the root of the bucket
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub folder_id: Option<Option<Uuid>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FileCopy {
+    pub file_name: Option<String>,
+    pub bucket_id: Option<Uuid>,
+    pub folder_id: Option<Uuid>,
 }
 
 #[derive(Debug)]
@@ -35,24 +146,110 @@ pub enum UserFileErrors {
     Forbidden,
     NotFound,
     Deleted,
+    FailedToLoad,
     FailedToDelete,
     FailedToSave,
+    InvalidBucket,
+    QuotaExceeded,
+    FileTooLarge,
+    InvalidUpload,
+    InvalidPath,
+    InvalidName,
+    InvalidPermissions,
+    InvalidShareUser,
 }
 
-pub async fn get_all_user_files(pool: &PgPool, user_id: &Uuid) -> Option<Vec<UserFile>> {
-    let user_info = get_user_info_by_user_id(pool, user_id).await;
+/// Lists the files of a user. With a folder or bucket in `file_filter`,
+/// lists the files directly inside that folder or at the root of that
+/// bucket instead, whoever uploaded them, when the user can read it.
+pub async fn get_all_user_files(
+    pool: &PgPool,
+    user_id: &Uuid,
+    file_filter: &FileFilter,
+) -> Result<Vec<UserFile>, UserFileErrors> {
+    let (bucket_id, folder_id) = match (&file_filter.bucket_id, &file_filter.folder_id) {
+        (_, Some(folder_id)) => {
+            let folder = get_folder_by_id(pool, folder_id)
+                .await
+                .ok_or(UserFileErrors::NotFound)?;
 
-    match user_info {
-        Some(user_info) => {
-            let query = "SELECT * FROM userfile where user_id = $1";
+            if file_filter
+                .bucket_id
+                .is_some_and(|bucket_id| bucket_id != folder.bucket_id)
+            {
+                return Err(UserFileErrors::NotFound);
+            }
+
+            (folder.bucket_id, Some(folder.folder_id))
+        }
+        (Some(bucket_id), None) => (bucket_id.to_owned(), None),
+        (None, None) => {
+            let query = format!(
+                "{} WHERE uf.user_id = $1 AND uf.deleted_date IS NULL",
+                USER_FILE_SELECT
+            );
+
+            return sqlx::query_as::<_, UserFile>(&query)
+                .bind(user_id)
+                .fetch_all(pool)
+                .await
+                .map_err(|error| {
+                    println!("error while listing the files of {}: {}", user_id, error);
+                    UserFileErrors::FailedToLoad
+                });
+        }
+    };
+
+    let bucket = get_bucket_by_id(pool, &bucket_id)
+        .await
+        .ok_or(UserFileErrors::NotFound)?;
+
+    if !user_can_read_bucket(pool, &bucket, user_id).await {
+        return Err(UserFileErrors::Forbidden);
+    }
+
+    let query = format!(
+        "{} WHERE uf.bucket_id = $1 AND uf.folder_id IS NOT DISTINCT FROM $2 \
+        AND uf.deleted_date IS NULL",
+        USER_FILE_SELECT
+    );
 
-            let query = sqlx::query_as::<_, UserFile>(query).bind(user_info.user_id);
+    sqlx::query_as::<_, UserFile>(&query)
+        .bind(bucket_id)
+        .bind(folder_id)
+        .fetch_all(pool)
+        .await
+        .map_err(|error| {
+            println!(
+                "error while listing the files of bucket {}: {}",
+                bucket_id, error
+            );
+            UserFileErrors::FailedToLoad
+        })
+}
 
-            let files = query.fetch_all(pool).await.expect("Failed To load Files.");
+/// Lists the files other users have shared with `user_id`.
+pub async fn get_files_shared_with_user(pool: &PgPool, user_id: &Uuid) -> Option<Vec<SharedFile>> {
+    let query = format!(
+        r#"SELECT shared_file.*, fu."Permissions" FROM ({}) shared_file
+        JOIN "FileUsers" fu ON fu.file_id = shared_file.file_id
+        WHERE fu.user_id = $1 AND shared_file.deleted_date IS NULL"#,
+        USER_FILE_SELECT
+    );
 
-            Some(files)
+    let query = sqlx::query_as::<_, SharedFile>(&query).bind(user_id);
+
+    let files = query.fetch_all(pool).await;
+
+    match files {
+        Ok(files) => Some(files),
+        Err(error) => {
+            println!(
+                "Error occurred while fetching files shared with user {}: {}",
+                user_id, error
+            );
+            None
         }
-        None => None,
     }
 }
 
@@ -60,9 +257,9 @@ pub async fn get_file_info_by_id(
     pool: &PgPool,
     file_id: &Uuid,
 ) -> Result<UserFile, UserFileErrors> {
-    let query = "SELECT * FROM userfile where file_id = $1";
+    let query = format!("{} WHERE uf.file_id = $1", USER_FILE_SELECT);
 
-    let query = sqlx::query_as::<_, UserFile>(query).bind(file_id);
+    let query = sqlx::query_as::<_, UserFile>(&query).bind(file_id);
 
     let file_info = query.fetch_one(pool).await;
 
@@ -80,29 +277,75 @@ pub async fn get_file_info_by_id(
     // Some(files)
 }
 
-fn save_file(file_path: &Path, temp_file_path: &Path) -> Result<String, UserFileErrors> {
+/// Streams an upload body into `file_path`, hashing it on the fly.
+///
+/// Returns the number of bytes written and the SHA-256 of the content.
+async fn write_stream_to_file<S, E>(
+    stream: &mut S,
+    file_path: &Path,
+    size_limit: i64,
+) -> Result<(i64, String), UserFileErrors>
+where
+    S: Stream<Item = Result<web::Bytes, E>> + Unpin,
+    E: Display,
+{
     println!("Saving the file at: {}", file_path.display());
 
-    match fs::copy(temp_file_path, file_path) {
-        Ok(_) => {
-            let _ = fs::remove_file(temp_file_path);
-            Ok(get_file_hash(file_path).unwrap())
-        }
+    let mut file = match File::create(file_path) {
+        Ok(file) => file,
         Err(error) => {
-            let _ = fs::remove_file(temp_file_path);
-            println!("error while saving into the disk user file: {}", error);
-            Err(UserFileErrors::FailedToSave)
+            println!("error while creating the user file on disk: {}", error);
+            return Err(UserFileErrors::FailedToSave);
+        }
+    };
+
+    let mut hasher = Sha256::new();
+    let mut file_size: i64 = 0;
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(error) => {
+                println!("error while reading the uploaded file: {}", error);
+                return Err(UserFileErrors::InvalidUpload);
+            }
+        };
+
+        file_size += chunk.len() as i64;
+        if file_size > size_limit {
+            return Err(UserFileErrors::FileTooLarge);
         }
+
+        hasher.update(&chunk);
+
+        file = match web::block(move || file.write_all(&chunk).map(|_| file)).await {
+            Ok(Ok(file)) => file,
+            Ok(Err(error)) => {
+                println!("error while saving into the disk user file: {}", error);
+                return Err(UserFileErrors::FailedToSave);
+            }
+            Err(error) => {
+                println!("error while saving into the disk user file: {}", error);
+                return Err(UserFileErrors::FailedToSave);
+            }
+        };
     }
+
+    if let Err(error) = file.sync_all() {
+        println!("error while flushing the user file to disk: {}", error);
+        return Err(UserFileErrors::FailedToSave);
+    }
+
+    Ok((file_size, format!("{:X}", hasher.finalize())))
 }
 
-fn get_file_hash(path: &Path) -> io::Result<String> {
+pub fn get_file_hash(path: &Path) -> io::Result<String> {
     let input = File::open(path)?;
     let mut reader = BufReader::new(input);
 
     let digest = {
         let mut hasher = Sha256::new();
-        let mut buffer = [0; 1024];
+        let mut buffer = [0; 64 * 1024];
         loop {
             let count = reader.read(&mut buffer)?;
             if count == 0 {
@@ -115,80 +358,335 @@ fn get_file_hash(path: &Path) -> io::Result<String> {
     Ok(format!("{:X}", digest))
 }
 
+/// Sniffs the MIME type from the magic bytes of the file, falling back to
+/// the extension of the original file name.
+pub fn get_mime_type(file_path: &Path, original_file_name: &str) -> String {
+    match infer::get_from_path(file_path) {
+        Ok(Some(kind)) => kind.mime_type().to_owned(),
+        _ => file_extension_to_mime(&get_file_type(original_file_name)).to_string(),
+    }
+}
+
+pub async fn resolve_target_bucket(
+    pool: &PgPool,
+    user_id: &Uuid,
+    target_bucket: &TargetBucket,
+) -> Result<Bucket, UserFileErrors> {
+    let bucket = get_target_bucket(pool, user_id, target_bucket).await;
+
+    match bucket {
+        Some(bucket) => {
+            if user_can_write_bucket(pool, &bucket, user_id).await {
+                Ok(bucket)
+            } else {
+                Err(UserFileErrors::Forbidden)
+            }
+        }
+        None => Err(UserFileErrors::InvalidBucket),
+    }
+}
+
 pub async fn save_user_file(
     pool: &PgPool,
     data_path: &str,
     user_id: &Uuid,
-    upload_file: UploadFile,
-) -> Option<UserFile> {
+    target_bucket: &TargetBucket,
+    max_upload_size: Option<i64>,
+    payload: Multipart,
+) -> Result<UserFile, UserFileErrors> {
     println!("saving an file...");
 
     let user_info = get_user_info_by_user_id(pool, user_id).await;
 
-    match user_info {
-        Some(user_info) => {
-            let file = upload_file.file;
+    let user_info = match user_info {
+        Some(user_info) => user_info,
+        None => return Err(UserFileErrors::NotFound),
+    };
 
-            let file_uuid = Uuid::new_v4();
-            let file_name =
-                file_uuid.to_string() + "." + &get_file_type(&file.file_name.unwrap().to_owned());
+    let bucket = resolve_target_bucket(pool, user_id, target_bucket).await?;
+    let target_folder = resolve_target_folder(pool, &bucket, target_bucket).await?;
 
-            let mut file_path = PathBuf::from(data_path);
-            file_path.push(&file_name);
+    let received_file = receive_upload(payload, data_path, &bucket, max_upload_size).await?;
 
-            let saved_data = save_file(&file_path, file.file.path());
-            let file_hash: String;
+    store_user_file(
+        pool,
+        data_path,
+        &user_info.user_id,
+        &bucket,
+        &target_folder,
+        received_file,
+    )
+    .await
+}
 
-            match saved_data {
-                Ok(hash) => file_hash = hash,
-                Err(error) => {
-                    println!("Error occurred while saving file: {:?}", error);
-                    return None;
-                }
+/// Saves a raw request body as a new file named `file_name`, used by
+/// pre-signed upload URLs.
+pub async fn save_raw_user_file(
+    pool: &PgPool,
+    data_path: &str,
+    user_id: &Uuid,
+    target_bucket: &TargetBucket,
+    max_upload_size: Option<i64>,
+    file_name: String,
+    payload: web::Payload,
+) -> Result<UserFile, UserFileErrors> {
+    if !is_valid_file_name(&file_name) {
+        return Err(UserFileErrors::InvalidName);
+    }
+
+    let bucket = resolve_target_bucket(pool, user_id, target_bucket).await?;
+    let target_folder = resolve_target_folder(pool, &bucket, target_bucket).await?;
+
+    let received_file =
+        receive_raw_upload(payload, data_path, &bucket, max_upload_size, file_name).await?;
+
+    store_user_file(
+        pool,
+        data_path,
+        user_id,
+        &bucket,
+        &target_folder,
+        received_file,
+    )
+    .await
+}
+
+/// Streams the `file` field of a multipart upload next to the bucket,
+/// limited by `max_upload_size` and the free space of the bucket.
+pub async fn receive_upload(
+    mut payload: Multipart,
+    data_path: &str,
+    bucket: &Bucket,
+    max_upload_size: Option<i64>,
+) -> Result<ReceivedFile, UserFileErrors> {
+    let mut field = loop {
+        match payload.next().await {
+            Some(Ok(field)) if field.name() == "file" => break field,
+            Some(Ok(_)) => continue,
+            Some(Err(error)) => {
+                println!("error while reading the multipart upload: {}", error);
+                return Err(UserFileErrors::InvalidUpload);
             }
+            None => return Err(UserFileErrors::InvalidUpload),
+        }
+    };
+
+    let file_name = sanitize_file_name(
+        field
+            .content_disposition()
+            .get_filename()
+            .unwrap_or_default(),
+    );
+
+    receive_stream(&mut field, data_path, bucket, max_upload_size, file_name).await
+}
+
+/// Streams a raw request body, like a pre-signed `PUT`, next to the bucket
+/// with the same limits as `receive_upload`.
+pub async fn receive_raw_upload(
+    mut payload: web::Payload,
+    data_path: &str,
+    bucket: &Bucket,
+    max_upload_size: Option<i64>,
+    file_name: String,
+) -> Result<ReceivedFile, UserFileErrors> {
+    receive_stream(&mut payload, data_path, bucket, max_upload_size, file_name).await
+}
+
+async fn receive_stream<S, E>(
+    stream: &mut S,
+    data_path: &str,
+    bucket: &Bucket,
+    max_upload_size: Option<i64>,
+    file_name: String,
+) -> Result<ReceivedFile, UserFileErrors>
+where
+    S: Stream<Item = Result<web::Bytes, E>> + Unpin,
+    E: Display,
+{
+    let free_bytes = bucket.max_bucket_size - bucket.bucket_size;
+    let size_limit = match max_upload_size {
+        Some(max_upload_size) if max_upload_size < free_bytes => max_upload_size,
+        _ => free_bytes,
+    };
+
+    let partial_file = PartialFile::new(
+        get_bucket_folder_path(data_path, &bucket.bucket_name)
+            .join(format!(".{}.part", Uuid::new_v4())),
+    );
 
-            let query = "INSERT INTO UserFile (file_id, user_id, file_name, file_size, file_hash) VALUES($1, $2, $3, $4, $5)";
-
-            let query = sqlx::query(query)
-                .bind(&file_uuid)
-                .bind(&user_info.user_id)
-                .bind(&file_name)
-                .bind(file.size as i32)
-                .bind(file_hash)
-                .execute(pool)
-                .await;
-
-            match query {
-                Ok(_) => {
-                    let user_file = get_file_info_by_id(pool, &file_uuid).await;
-
-                    match user_file {
-                        Ok(user_file) => Some(user_file),
-                        Err(error) => {
-                            println!("Error while saving the file info to db, error {:?}.", error);
-                            return None;
-                        }
-                    }
-                }
-                Err(error) => {
-                    println!("error while saving user file: {}", error);
-                    None
-                }
+    let (file_size, file_hash) =
+        match write_stream_to_file(stream, &partial_file.path, size_limit).await {
+            Ok(written) => written,
+            Err(UserFileErrors::FileTooLarge) if Some(size_limit) != max_upload_size => {
+                return Err(UserFileErrors::QuotaExceeded)
             }
+            Err(error) => {
+                println!("Error occurred while saving file: {:?}", error);
+                return Err(error);
+            }
+        };
+
+    if file_size == 0 {
+        return Err(UserFileErrors::InvalidUpload);
+    }
+
+    Ok(ReceivedFile {
+        partial_file,
+        file_name,
+        file_size,
+        file_hash,
+    })
+}
+
+impl ReceivedFile {
+    /// Sniffs the MIME type and fills in a name when the client sent none.
+    pub fn into_metadata(self, file_id: &Uuid) -> (NewFileMetadata, PartialFile) {
+        let file_name = if self.file_name.is_empty() {
+            file_id.to_string()
+        } else {
+            self.file_name
+        };
+        let file_type = get_mime_type(&self.partial_file.path, &file_name);
+
+        let new_file = NewFileMetadata {
+            file_name,
+            file_size: self.file_size,
+            file_type,
+            file_hash: self.file_hash,
+        };
+
+        (new_file, self.partial_file)
+    }
+}
+
+/// Moves a fully received upload into the blob store and records it,
+/// shared by the multipart and the resumable upload paths.
+pub async fn store_user_file(
+    pool: &PgPool,
+    data_path: &str,
+    user_id: &Uuid,
+    bucket: &Bucket,
+    target_folder: &TargetFolder,
+    received_file: ReceivedFile,
+) -> Result<UserFile, UserFileErrors> {
+    let file_uuid = Uuid::new_v4();
+    let (new_file, partial_file) = received_file.into_metadata(&file_uuid);
+
+    insert_user_file_record(
+        pool,
+        data_path,
+        &file_uuid,
+        user_id,
+        bucket,
+        target_folder,
+        &new_file,
+        partial_file,
+    )
+    .await?;
+
+    get_file_info_by_id(pool, &file_uuid).await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn insert_user_file_record(
+    pool: &PgPool,
+    data_path: &str,
+    file_id: &Uuid,
+    user_id: &Uuid,
+    bucket: &Bucket,
+    target_folder: &TargetFolder,
+    new_file: &NewFileMetadata,
+    partial_file: PartialFile,
+) -> Result<(), UserFileErrors> {
+    let mut transaction = match pool.begin().await {
+        Ok(transaction) => transaction,
+        Err(error) => {
+            println!("error while starting transaction: {}", error);
+            return Err(UserFileErrors::FailedToSave);
+        }
+    };
+
+    match add_to_bucket_size(&mut transaction, &bucket.bucket_id, new_file.file_size).await {
+        Ok(true) => {}
+        Ok(false) => return Err(UserFileErrors::QuotaExceeded),
+        Err(error) => {
+            println!("error while updating bucket size: {}", error);
+            return Err(UserFileErrors::FailedToSave);
+        }
+    }
+
+    // only once the file fits, and gone again when it isn't stored
+    let folder_id =
+        create_target_folder(&mut transaction, &bucket.bucket_id, target_folder).await?;
+
+    let query =
+        "INSERT INTO UserFile (file_id, user_id, bucket_id, folder_id) VALUES($1, $2, $3, $4)";
+
+    let query = sqlx::query(query)
+        .bind(file_id)
+        .bind(user_id)
+        .bind(bucket.bucket_id)
+        .bind(folder_id)
+        .execute(&mut *transaction)
+        .await;
+
+    if let Err(error) = query {
+        println!("error while saving user file: {}", error);
+        return Err(UserFileErrors::FailedToSave);
+    }
+
+    let stored_blob = add_blob_reference(
+        &mut transaction,
+        data_path,
+        &new_file.file_hash,
+        new_file.file_size,
+        partial_file,
+    )
+    .await?;
+
+    let query = "INSERT INTO FileMetadata (file_id, file_name, file_size, file_type, file_hash, uploaded_by) VALUES($1, $2, $3, $4, $5, $6)";
+
+    let query = sqlx::query(query)
+        .bind(file_id)
+        .bind(&new_file.file_name)
+        .bind(new_file.file_size)
+        .bind(&new_file.file_type)
+        .bind(&new_file.file_hash)
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await;
+
+    let committed = match query {
+        Ok(_) => transaction.commit().await,
+        Err(error) => Err(error),
+    };
+
+    match committed {
+        Ok(_) => Ok(()),
+        Err(error) => {
+            println!("error while saving user file metadata: {}", error);
+            if stored_blob {
+                remove_unused_blobs(pool, data_path, slice::from_ref(&new_file.file_hash)).await;
+            }
+            Err(UserFileErrors::FailedToSave)
         }
-        None => None,
     }
 }
 
-fn user_can_read(file_info: &UserFile, user_id: &Uuid) -> bool {
-    &file_info.user_id == user_id
+pub async fn user_can_read(pool: &PgPool, file_info: &UserFile, user_id: &Uuid) -> bool {
+    user_has_file_permission(pool, file_info, user_id, FILE_READ).await
 }
 
-fn user_can_delete(file_info: &UserFile, user_id: &Uuid) -> bool {
-    &file_info.user_id == user_id
+pub async fn user_can_write(pool: &PgPool, file_info: &UserFile, user_id: &Uuid) -> bool {
+    user_has_file_permission(pool, file_info, user_id, FILE_WRITE).await
 }
 
-async fn check_health_and_reterive_file(
+pub async fn user_can_delete(pool: &PgPool, file_info: &UserFile, user_id: &Uuid) -> bool {
+    user_has_file_permission(pool, file_info, user_id, FILE_DELETE).await
+}
+
+pub async fn check_health_and_reterive_file(
     pool: &PgPool,
     data_path: &str,
     file_id: &Uuid,
@@ -197,8 +695,12 @@ async fn check_health_and_reterive_file(
 
     match file_info {
         Ok(file_info) => {
-            let mut file_path = PathBuf::from(data_path);
-            file_path.push(&file_info.file_name);
+            if file_info.deleted_date.is_some() {
+                // the file is in the trash
+                return Err(UserFileErrors::Deleted);
+            }
+
+            let file_path = get_blob_path(data_path, &file_info.file_hash);
 
             if file_path.exists() {
                 Ok((file_info, file_path))
@@ -215,60 +717,321 @@ pub async fn get_user_file_by_file_id(
     data_path: &str,
     user_id: &Uuid,
     file_id: &Uuid,
-) -> Result<PathBuf, UserFileErrors> {
+) -> Result<(UserFile, PathBuf), UserFileErrors> {
     let file_data = check_health_and_reterive_file(pool, data_path, file_id).await;
 
     match file_data {
         Ok((file_info, file_path)) => {
-            if !user_can_read(&file_info, user_id) {
+            if !user_can_read(pool, &file_info, user_id).await {
                 Err(UserFileErrors::Forbidden)
             } else {
-                Ok(file_path)
+                Ok((file_info, file_path))
             }
         }
         Err(error) => Err(error),
     }
 }
 
-pub async fn delete_user_file_by_file_id(
+fn is_valid_file_name(file_name: &str) -> bool {
+    !file_name.trim().is_empty()
+        && file_name.len() <= 255
+        && file_name != "."
+        && file_name != ".."
+        && !file_name.contains(['/', '\\'])
+}
+
+/// Keeps the last segment of a file name sent by a client, browsers and
+/// upload tools may send a whole path. Empty when nothing valid is left.
+pub fn sanitize_file_name(file_name: &str) -> String {
+    let file_name = file_name
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .trim();
+
+    if is_valid_file_name(file_name) {
+        file_name.to_owned()
+    } else {
+        String::new()
+    }
+}
+
+/// Resolves the bucket a file is moved or copied to, the user has to be
+/// able to write to it.
+async fn get_writable_bucket(
+    pool: &PgPool,
+    user_id: &Uuid,
+    bucket_id: &Uuid,
+) -> Result<Bucket, UserFileErrors> {
+    let bucket = match get_bucket_by_id(pool, bucket_id).await {
+        Some(bucket) => bucket,
+        None => return Err(UserFileErrors::InvalidBucket),
+    };
+
+    if user_can_write_bucket(pool, &bucket, user_id).await {
+        Ok(bucket)
+    } else {
+        Err(UserFileErrors::Forbidden)
+    }
+}
+
+/// Checks that `folder_id` is a folder of `bucket_id`.
+async fn check_target_folder(
+    pool: &PgPool,
+    bucket_id: &Uuid,
+    folder_id: Option<&Uuid>,
+) -> Result<(), UserFileErrors> {
+    let folder_id = match folder_id {
+        Some(folder_id) => folder_id,
+        None => return Ok(()),
+    };
+
+    match get_folder_by_id(pool, folder_id).await {
+        Some(folder) if &folder.bucket_id == bucket_id => Ok(()),
+        _ => Err(UserFileErrors::InvalidPath),
+    }
+}
+
+/// Renames a file and/or moves it to another folder or bucket, the size
+/// of the file and its versions moves with it.
+pub async fn update_user_file(
+    pool: &PgPool,
+    user_id: &Uuid,
+    file_id: &Uuid,
+    file_update: &FileUpdate,
+) -> Result<UserFile, UserFileErrors> {
+    let file_info = get_file_info_by_id(pool, file_id).await?;
+
+    if file_info.deleted_date.is_some() {
+        return Err(UserFileErrors::Deleted);
+    }
+
+    if !user_can_write(pool, &file_info, user_id).await {
+        return Err(UserFileErrors::Forbidden);
+    }
+
+    if let Some(file_name) = &file_update.file_name {
+        if !is_valid_file_name(file_name) {
+            return Err(UserFileErrors::InvalidName);
+        }
+    }
+
+    let target_bucket_id = file_update.bucket_id.unwrap_or(file_info.bucket_id);
+    let is_moved_bucket = target_bucket_id != file_info.bucket_id;
+
+    if is_moved_bucket {
+        // the file leaves its bucket, so both buckets have to be writable
+        get_writable_bucket(pool, user_id, &file_info.bucket_id).await?;
+        get_writable_bucket(pool, user_id, &target_bucket_id).await?;
+    }
+
+    let folder_id = match file_update.folder_id {
+        Some(folder_id) => folder_id,
+        None if is_moved_bucket => None,
+        None => file_info.folder_id,
+    };
+
+    check_target_folder(pool, &target_bucket_id, folder_id.as_ref()).await?;
+
+    let updated = async {
+        let mut transaction = pool.begin().await?;
+
+        if is_moved_bucket {
+            let query =
+                "SELECT COALESCE(SUM(file_size), 0)::BIGINT FROM fileversion WHERE file_id = $1";
+
+            let versions_size = sqlx::query_scalar::<_, i64>(query)
+                .bind(file_id)
+                .fetch_one(&mut *transaction)
+                .await?;
+
+            let moved_size = file_info.file_size + versions_size;
+
+            if !add_to_bucket_size(&mut transaction, &target_bucket_id, moved_size).await? {
+                return Ok(false);
+            }
+
+            add_to_bucket_size(&mut transaction, &file_info.bucket_id, -moved_size).await?;
+        }
+
+        let query = "UPDATE userfile SET bucket_id = $1, folder_id = $2 WHERE file_id = $3";
+
+        sqlx::query(query)
+            .bind(target_bucket_id)
+            .bind(folder_id)
+            .bind(file_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        if let Some(file_name) = &file_update.file_name {
+            let query = "UPDATE filemetadata SET file_name = $1 WHERE file_id = $2";
+
+            sqlx::query(query)
+                .bind(file_name)
+                .bind(file_id)
+                .execute(&mut *transaction)
+                .await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok::<bool, sqlx::Error>(true)
+    }
+    .await;
+
+    match updated {
+        Ok(true) => get_file_info_by_id(pool, file_id).await,
+        Ok(false) => Err(UserFileErrors::QuotaExceeded),
+        Err(error) => {
+            println!("error occurred while updating file {}: {}", file_id, error);
+            Err(UserFileErrors::FailedToSave)
+        }
+    }
+}
+
+/// Copies the current version of a file, the copy shares the blob of the
+/// original so no content is copied on disk.
+pub async fn copy_user_file(
     pool: &PgPool,
     data_path: &str,
     user_id: &Uuid,
     file_id: &Uuid,
-) -> Result<bool, UserFileErrors> {
-    let file_data = check_health_and_reterive_file(pool, data_path, file_id).await;
+    file_copy: &FileCopy,
+) -> Result<UserFile, UserFileErrors> {
+    let (file_info, _) = check_health_and_reterive_file(pool, data_path, file_id).await?;
 
-    match file_data {
-        Ok((file_info, file_path)) => {
-            let query = "delete from userfile where file_id = $1";
-            let query = sqlx::query(query)
-                .bind(&file_info.file_id)
-                .execute(pool)
-                .await;
-
-            match query {
-                Ok(_) => {
-                    if !user_can_delete(&file_info, user_id) {
-                        Err(UserFileErrors::Forbidden)
-                    } else {
-                        match fs::remove_file(file_path) {
-                            Ok(_) => Ok(true),
-                            Err(error) => {
-                                println!(
-                                    "error while deleting the file: \n{}\n, file info : {:#?}",
-                                    error, file_info
-                                );
-                                Err(UserFileErrors::FailedToDelete)
-                            }
-                        }
-                    }
-                }
-                Err(error) => {
-                    println!("error occurred while deleting the file record in db, file id : {} error : {}", file_id, error);
-                    Err(UserFileErrors::FailedToDelete)
-                }
+    if !user_can_read(pool, &file_info, user_id).await {
+        return Err(UserFileErrors::Forbidden);
+    }
+
+    let file_name = match &file_copy.file_name {
+        Some(file_name) if !is_valid_file_name(file_name) => {
+            return Err(UserFileErrors::InvalidName)
+        }
+        Some(file_name) => file_name.to_owned(),
+        None => file_info.file_name.to_owned(),
+    };
+
+    let target_bucket_id = file_copy.bucket_id.unwrap_or(file_info.bucket_id);
+    let bucket = get_writable_bucket(pool, user_id, &target_bucket_id).await?;
+
+    let folder_id = match file_copy.bucket_id {
+        Some(_) => file_copy.folder_id,
+        None => file_copy.folder_id.or(file_info.folder_id),
+    };
+
+    check_target_folder(pool, &bucket.bucket_id, folder_id.as_ref()).await?;
+
+    let copy_id = Uuid::new_v4();
+
+    let copied = async {
+        let mut transaction = pool.begin().await?;
+
+        if !add_to_bucket_size(&mut transaction, &bucket.bucket_id, file_info.file_size).await? {
+            return Ok(false);
+        }
+
+        let query =
+            "INSERT INTO UserFile (file_id, user_id, bucket_id, folder_id) VALUES($1, $2, $3, $4)";
+
+        sqlx::query(query)
+            .bind(copy_id)
+            .bind(user_id)
+            .bind(bucket.bucket_id)
+            .bind(folder_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        copy_blob_reference(&mut transaction, &file_info.file_hash).await?;
+
+        let query = "INSERT INTO FileMetadata (file_id, file_name, file_size, file_type, file_hash, uploaded_by) VALUES($1, $2, $3, $4, $5, $6)";
+
+        sqlx::query(query)
+            .bind(copy_id)
+            .bind(&file_name)
+            .bind(file_info.file_size)
+            .bind(&file_info.file_type)
+            .bind(&file_info.file_hash)
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+
+        Ok::<bool, sqlx::Error>(true)
+    }
+    .await;
+
+    match copied {
+        Ok(true) => get_file_info_by_id(pool, &copy_id).await,
+        Ok(false) => Err(UserFileErrors::QuotaExceeded),
+        Err(error) => {
+            println!("error occurred while copying file {}: {}", file_id, error);
+            Err(UserFileErrors::FailedToSave)
+        }
+    }
+}
+
+/// Moves a file to the trash of its owner, it keeps counting towards the
+/// bucket size until the trash is emptied.
+pub async fn delete_user_file_by_file_id(
+    pool: &PgPool,
+    user_id: &Uuid,
+    file_id: &Uuid,
+) -> Result<(), UserFileErrors> {
+    let file_info = get_file_info_by_id(pool, file_id).await?;
+
+    if !user_can_delete(pool, &file_info, user_id).await {
+        return Err(UserFileErrors::Forbidden);
+    }
+
+    let query = "UPDATE userfile SET deleted_date = now() \
+        WHERE file_id = $1 AND deleted_date IS NULL";
+
+    let query = sqlx::query(query).bind(file_id).execute(pool).await;
+
+    match query {
+        Ok(result) => {
+            if result.rows_affected() == 0 {
+                Err(UserFileErrors::Deleted)
+            } else {
+                Ok(())
             }
         }
-        Err(error) => Err(error),
+        Err(error) => {
+            println!(
+                "error occurred while moving file {} to the trash: {}",
+                file_id, error
+            );
+            Err(UserFileErrors::FailedToDelete)
+        }
     }
 }
+
+pub async fn delete_user_file_record(
+    pool: &PgPool,
+    data_path: &str,
+    file_info: &UserFile,
+) -> Result<(), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let mut unused_blobs = release_file_versions(&mut transaction, file_info).await?;
+
+    let query = "delete from userfile where file_id = $1";
+    sqlx::query(query)
+        .bind(file_info.file_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    add_to_bucket_size(&mut transaction, &file_info.bucket_id, -file_info.file_size).await?;
+
+    if release_blob_reference(&mut transaction, &file_info.file_hash).await? {
+        unused_blobs.push(file_info.file_hash.clone());
+    }
+
+    transaction.commit().await?;
+
+    remove_unused_blobs(pool, data_path, &unused_blobs).await;
+
+    Ok(())
+}
@@ -1,12 +1,22 @@
 use ::serde::{Deserialize, Serialize};
 use chrono::NaiveDateTime;
-use sha2::{Digest, Sha256};
-use sqlx::{self, postgres::PgPool, FromRow};
+use lettre::Address;
+use sqlx::{self, postgres::PgPool, FromRow, Postgres, Transaction};
+use std::sync::OnceLock;
 use uuid::Uuid;
 
-use crate::utility::{genarate_salt, jwt_token::generate_token};
+use crate::utility::{
+    genarate_salt,
+    passcode::{hash_passcode, passcode_needs_rehash, verify_passcode},
+};
 
-use super::bucket::{create_user_bucket, delete_user_buckets, get_all_bucket_names, BucketNames};
+use super::{
+    auth_session::{create_auth_session, revoke_user_sessions, AuthTokens},
+    bucket::{create_user_bucket, get_all_bucket_names, set_bucket_max_size, BucketNames},
+    invite::{claim_invite, RegistrationMode},
+    login_attempt::{record_login_attempt, reserve_login_attempt, LoginResult},
+    totp::{create_login_challenge, TotpChallenge},
+};
 
 #[derive(Debug, FromRow, Serialize, Deserialize)]
 pub struct UserInfo {
@@ -14,6 +24,9 @@ pub struct UserInfo {
     pub user_name: String,
     pub email: String,
     pub created_date: NaiveDateTime,
+    pub email_verified_date: Option<NaiveDateTime>,
+    pub role: String,
+    pub disabled_date: Option<NaiveDateTime>,
 }
 
 #[derive(Debug, FromRow, Serialize, Deserialize)]
@@ -27,21 +40,94 @@ pub struct NewUser {
     pub user_name: String,
     pub email: String,
     pub passcode: String,
+    pub invite_code: Option<String>,
+}
+
+/// What a login hands out, a challenge when the user has 2FA enabled.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Tokens(AuthTokens),
+    TotpChallenge(TotpChallenge),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasscodeChange {
+    pub current_passcode: String,
+    pub new_passcode: String,
 }
 
 #[derive(Debug, Serialize)]
 pub enum NewUserError {
     InvalidEmail,
+    DuplicateEmail,
+    DuplicateUserName,
+    WeakPasscode,
+    InvalidInvite,
+    RegistrationClosed,
+    Failed,
 }
 
 #[derive(Debug, Serialize)]
 pub enum UserError {
     InvalidEmail,
     WrongPasscode,
+    Disabled,
+    // seconds until the next try
+    Locked(i64),
+    Failed,
+}
+
+#[derive(Debug)]
+pub enum PasscodeError {
+    WrongPasscode,
+    InvalidPasscode,
+    InvalidToken,
+    Failed,
+}
+
+pub const USER_ROLE_USER: &str = "user";
+pub const USER_ROLE_ADMIN: &str = "admin";
+
+// advisory lock class of registrations, blob hashes use 1
+const REGISTRATION_LOCK: i32 = 2;
+
+const MIN_PASSCODE_LEN: usize = 10;
+
+// long passcodes only make hashing slow, Argon2 gains nothing past this
+const MAX_PASSCODE_LEN: usize = 128;
+
+pub fn is_valid_email(email: &str) -> bool {
+    email.len() <= 255 && email.trim() == email && email.parse::<Address>().is_ok()
+}
+
+/// A passcode needs 10 to 128 characters, with at least three of
+/// lowercase letters, uppercase letters, digits and other characters.
+pub fn is_valid_passcode(passcode: &str) -> bool {
+    let passcode_len = passcode.chars().count();
+
+    if !(MIN_PASSCODE_LEN..=MAX_PASSCODE_LEN).contains(&passcode_len) {
+        return false;
+    }
+
+    let character_classes = [
+        passcode.chars().any(|c| c.is_lowercase()),
+        passcode.chars().any(|c| c.is_uppercase()),
+        passcode.chars().any(|c| c.is_numeric()),
+        passcode.chars().any(|c| !c.is_alphanumeric()),
+    ];
+
+    character_classes
+        .iter()
+        .filter(|&&has_class| has_class)
+        .count()
+        >= 3
 }
 
 pub async fn get_all_user_info(pool: &PgPool) -> Option<Vec<UserInfo>> {
-    let query = "SELECT user_id, user_name, email, created_date FROM userinfo";
+    let query =
+        "SELECT user_id, user_name, email, created_date, email_verified_date, role, disabled_date \
+        FROM userinfo ORDER BY created_date";
 
     let query = sqlx::query_as::<_, UserInfo>(query);
 
@@ -56,80 +142,133 @@ pub async fn get_all_user_info(pool: &PgPool) -> Option<Vec<UserInfo>> {
     }
 }
 
+fn map_new_user_error(error: sqlx::Error) -> NewUserError {
+    match error {
+        sqlx::Error::Database(error) if error.code().as_deref() == Some("23505") => {
+            match error.constraint() {
+                Some("userinfo_email_unique") => NewUserError::DuplicateEmail,
+                Some("userinfo_user_name_unique") => NewUserError::DuplicateUserName,
+                _ => {
+                    println!("{:#?}", error);
+                    NewUserError::Failed
+                }
+            }
+        }
+        error => {
+            println!("{:#?}", error);
+            NewUserError::Failed
+        }
+    }
+}
+
+/// Registers a user as `registration_mode` allows, see `models::invite`.
+/// The first bucket of the user gets the quota of their invite.
 pub async fn insert_user(
     pool: &PgPool,
     data_path: &str,
     new_user: &NewUser,
+    registration_mode: RegistrationMode,
 ) -> Result<UserInfo, NewUserError> {
-    let mut sha = Sha256::new();
-    let salt = genarate_salt(64);
+    if !is_valid_email(&new_user.email) {
+        return Err(NewUserError::InvalidEmail);
+    }
 
-    sha.update(new_user.passcode.to_owned() + &salt.to_owned());
-    let passcode_hash = sha.finalize();
+    if !is_valid_passcode(&new_user.passcode) {
+        return Err(NewUserError::WeakPasscode);
+    }
 
-    let passcode_hash = format!("{:X}", passcode_hash) + ":" + &salt;
+    let mut transaction = pool.begin().await.map_err(map_new_user_error)?;
+
+    // registrations wait for each other here, so only one can be the first
+    let query = "SELECT pg_advisory_xact_lock($1, 0)";
+
+    sqlx::query(query)
+        .bind(REGISTRATION_LOCK)
+        .execute(&mut *transaction)
+        .await
+        .map_err(map_new_user_error)?;
+
+    let query = "SELECT EXISTS (SELECT 1 FROM userinfo)";
+
+    let has_users = sqlx::query_scalar::<_, bool>(query)
+        .fetch_one(&mut *transaction)
+        .await
+        .map_err(map_new_user_error)?;
+
+    let invite = match &new_user.invite_code {
+        Some(invite_code) => match claim_invite(&mut transaction, invite_code).await {
+            Ok(Some(invite)) => Some(invite),
+            Ok(None) => return Err(NewUserError::InvalidInvite),
+            Err(error) => return Err(map_new_user_error(error)),
+        },
+        None => None,
+    };
+
+    // the first user can always register and becomes the admin
+    let role = match (has_users, registration_mode, &invite) {
+        (false, _, _) => USER_ROLE_ADMIN,
+        (true, RegistrationMode::Open, _) | (true, RegistrationMode::InviteOnly, Some(_)) => {
+            USER_ROLE_USER
+        }
+        (true, RegistrationMode::InviteOnly, None) => return Err(NewUserError::InvalidInvite),
+        (true, RegistrationMode::Closed, _) => return Err(NewUserError::RegistrationClosed),
+    };
 
-    let query = "INSERT INTO userinfo (user_name, email, passcode) VALUES($1, $2, $3)";
+    let query = "INSERT INTO userinfo (user_name, email, passcode, role) VALUES($1, $2, $3, $4)";
 
-    let query = sqlx::query(query)
+    sqlx::query(query)
         .bind(new_user.user_name.to_owned())
-        .bind(new_user.email.to_owned())
-        .bind(passcode_hash)
-        .execute(pool)
-        .await;
+        .bind(new_user.email.to_lowercase())
+        .bind(hash_passcode(&new_user.passcode))
+        .bind(role)
+        .execute(&mut *transaction)
+        .await
+        .map_err(map_new_user_error)?;
 
-    match query {
-        Ok(_) => {
-            let user = get_user_by_email(pool, &new_user.email).await;
+    transaction.commit().await.map_err(map_new_user_error)?;
 
-            let user_info = user.unwrap();
+    let user = get_user_by_email(pool, &new_user.email).await;
 
-            let bucket_names = get_all_bucket_names(pool).await.unwrap();
+    let user_info = user.unwrap();
 
-            loop {
-                let bucket_name = BucketNames {
-                    bucket_name: genarate_salt(16),
-                };
+    let bucket_names = get_all_bucket_names(pool).await.unwrap();
 
-                if !bucket_names.contains(&bucket_name) {
-                    let _bucket = create_user_bucket(
-                        pool,
-                        data_path,
-                        &user_info.user_id,
-                        &bucket_name.bucket_name,
-                    )
-                    .await;
-                    break;
-                }
-            }
+    loop {
+        let bucket_name = BucketNames {
+            bucket_name: genarate_salt(16),
+        };
 
-            Ok(user_info)
-        }
-        Err(e) => {
-            println!("{:#?}", e);
-            Err(NewUserError::InvalidEmail)
+        if !bucket_names.contains(&bucket_name) {
+            let bucket = create_user_bucket(
+                pool,
+                data_path,
+                &user_info.user_id,
+                &bucket_name.bucket_name,
+            )
+            .await;
+
+            let invite_quota = invite.and_then(|invite| invite.max_bucket_size);
+
+            if let (Some(bucket), Some(max_bucket_size)) = (bucket, invite_quota) {
+                set_bucket_max_size(pool, &bucket.bucket_id, max_bucket_size).await;
+            }
+            break;
         }
     }
-}
-
-pub async fn delete_user(pool: &PgPool, data_path: &str, user_id: &Uuid) -> Option<()> {
-    let _delete_buckets = delete_user_buckets(pool, data_path, user_id).await;
 
-    Some(())
+    Ok(user_info)
 }
 
 pub async fn get_user_by_email(pool: &PgPool, email: &str) -> Option<UserInfo> {
-    let query = "SELECT user_id, user_name, email, created_date FROM userinfo where email = $1";
+    let query = "SELECT user_id, user_name, email, created_date, email_verified_date, role, \
+        disabled_date FROM userinfo where lower(email) = lower($1)";
 
     let query = sqlx::query_as::<_, UserInfo>(query).bind(email);
 
-    let users = query.fetch_all(pool).await;
+    let user = query.fetch_optional(pool).await;
 
-    match users {
-        Ok(mut users) => {
-            let user = users.pop().unwrap();
-            Some(user)
-        }
+    match user {
+        Ok(user) => user,
         Err(error) => {
             println!("{}", error);
             None
@@ -137,59 +276,81 @@ pub async fn get_user_by_email(pool: &PgPool, email: &str) -> Option<UserInfo> {
     }
 }
 
-async fn get_user_info_by_email_passcode(
-    pool: &PgPool,
-    user_login: &UserLogin,
-) -> Result<UserInfo, UserError> {
-    let query = "SELECT passcode, email FROM userinfo where email = $1";
-
-    let query = sqlx::query_as::<_, UserLogin>(query).bind(user_login.email.to_owned());
+/// Upgrades a legacy or outdated passcode hash after a successful login,
+/// unless the passcode was changed in the meantime.
+async fn rehash_user_passcode(pool: &PgPool, user_login: &UserLogin, stored_passcode: &str) {
+    let query =
+        "UPDATE userinfo SET passcode = $1 WHERE lower(email) = lower($2) AND passcode = $3";
 
-    let users = query.fetch_all(pool).await;
+    let query = sqlx::query(query)
+        .bind(hash_passcode(&user_login.passcode))
+        .bind(&user_login.email)
+        .bind(stored_passcode)
+        .execute(pool)
+        .await;
 
-    match users {
-        Ok(users) => {
-            // todo: add len check
+    if let Err(error) = query {
+        println!(
+            "error while rehashing the passcode of {}: {}",
+            user_login.email, error
+        );
+    }
+}
 
-            let passcode_parts = users[0].passcode.split(':').collect::<Vec<&str>>();
-            let passcode_hash = passcode_parts[0];
-            let passcode_salt = passcode_parts[1];
+// checked against when the email is unknown, so that takes as long as a
+// wrong passcode
+fn get_unknown_user_passcode_hash() -> &'static str {
+    static UNKNOWN_USER_PASSCODE_HASH: OnceLock<String> = OnceLock::new();
 
-            let user_passcode = user_login.passcode.as_str();
+    UNKNOWN_USER_PASSCODE_HASH.get_or_init(|| hash_passcode(&genarate_salt(32)))
+}
 
-            let mut sha = Sha256::new();
-            sha.update(user_passcode.to_owned() + passcode_salt);
-            let user_passcode_hash = sha.finalize();
+async fn get_user_info_by_email_passcode(
+    pool: &PgPool,
+    user_login: &UserLogin,
+) -> Result<UserInfo, UserError> {
+    let query = "SELECT passcode FROM userinfo where lower(email) = lower($1)";
 
-            let user_passcode_hash = format!("{:X}", user_passcode_hash);
+    let stored_passcode = sqlx::query_scalar::<_, String>(query)
+        .bind(&user_login.email)
+        .fetch_optional(pool)
+        .await;
 
-            if passcode_hash == user_passcode_hash {
-                let user = get_user_by_email(pool, &user_login.email).await.unwrap();
-                Ok(user)
-            } else {
-                Err(UserError::WrongPasscode)
-            }
+    let stored_passcode = match stored_passcode {
+        Ok(Some(stored_passcode)) => stored_passcode,
+        Ok(None) => {
+            verify_passcode(&user_login.passcode, get_unknown_user_passcode_hash());
+            return Err(UserError::InvalidEmail);
         }
         Err(error) => {
             println!("{}", error);
-            Err(UserError::InvalidEmail)
+            return Err(UserError::Failed);
         }
+    };
+
+    if !verify_passcode(&user_login.passcode, &stored_passcode) {
+        return Err(UserError::WrongPasscode);
+    }
+
+    if passcode_needs_rehash(&stored_passcode) {
+        rehash_user_passcode(pool, user_login, &stored_passcode).await;
     }
+
+    get_user_by_email(pool, &user_login.email)
+        .await
+        .ok_or(UserError::Failed)
 }
 
 pub async fn get_user_info_by_user_id(pool: &PgPool, user_id: &Uuid) -> Option<UserInfo> {
-    let query = "SELECT user_id, user_name, email, created_date FROM userinfo where user_id = $1";
+    let query = "SELECT user_id, user_name, email, created_date, email_verified_date, role, \
+        disabled_date FROM userinfo where user_id = $1";
 
     let query = sqlx::query_as::<_, UserInfo>(query).bind(user_id);
 
-    let users = query.fetch_all(pool).await;
+    let user = query.fetch_optional(pool).await;
 
-    match users {
-        Ok(mut users) => {
-            // todo: add len check
-            let user = users.pop().unwrap();
-            Some(user)
-        }
+    match user {
+        Ok(user) => user,
         Err(error) => {
             println!("{}", error);
             None
@@ -197,14 +358,157 @@ pub async fn get_user_info_by_user_id(pool: &PgPool, user_id: &Uuid) -> Option<U
     }
 }
 
+/// Logs a user in unless the account or `ip_address` is locked out after
+/// too many failed logins, see `models::login_attempt`.
 pub async fn login_user_by_email(
     pool: &PgPool,
     user_login: &UserLogin,
-) -> Result<String, UserError> {
+    ip_address: &str,
+    refresh_token_days: i64,
+) -> Result<LoginResponse, UserError> {
+    // counted as a failure until the passcode turns out to be right
+    match reserve_login_attempt(pool, &user_login.email, ip_address).await {
+        Ok(None) => (),
+        Ok(Some(retry_after)) => {
+            let recorded =
+                record_login_attempt(pool, &user_login.email, ip_address, LoginResult::Locked)
+                    .await;
+
+            if let Err(error) = recorded {
+                println!("error while recording a login attempt: {}", error);
+            }
+
+            return Err(UserError::Locked(retry_after));
+        }
+        Err(error) => {
+            println!("error while checking login lockouts: {}", error);
+            return Err(UserError::Failed);
+        }
+    }
+
     let user_info = get_user_info_by_email_passcode(pool, user_login).await;
 
-    match user_info {
-        Ok(user_info) => Ok(generate_token(&user_info)),
-        Err(user_err) => Err(user_err),
+    let login_result = match &user_info {
+        Ok(_) => LoginResult::Success,
+        Err(UserError::InvalidEmail) | Err(UserError::WrongPasscode) => LoginResult::Failure,
+        Err(_) => return Err(UserError::Failed),
+    };
+
+    if let Err(error) =
+        record_login_attempt(pool, &user_login.email, ip_address, login_result).await
+    {
+        println!("error while recording a login attempt: {}", error);
+        return Err(UserError::Failed);
+    }
+
+    let user_info = user_info?;
+
+    if user_info.disabled_date.is_some() {
+        return Err(UserError::Disabled);
+    }
+
+    match create_login_challenge(pool, &user_info.user_id).await {
+        Ok(Some(totp_challenge)) => return Ok(LoginResponse::TotpChallenge(totp_challenge)),
+        Ok(None) => (),
+        Err(error) => {
+            println!("error while creating a login challenge: {}", error);
+            return Err(UserError::Failed);
+        }
+    }
+
+    match create_auth_session(pool, &user_info, refresh_token_days).await {
+        Ok(auth_tokens) => Ok(LoginResponse::Tokens(auth_tokens)),
+        Err(_) => Err(UserError::Failed),
+    }
+}
+
+/// Stores a new passcode and revokes the sessions of the user, except
+/// `except_session_id`.
+pub async fn set_user_passcode(
+    transaction: &mut Transaction<'_, Postgres>,
+    user_id: &Uuid,
+    passcode: &str,
+    except_session_id: Option<&Uuid>,
+) -> Result<(), sqlx::Error> {
+    let query = "UPDATE userinfo SET passcode = $1 WHERE user_id = $2";
+
+    sqlx::query(query)
+        .bind(hash_passcode(passcode))
+        .bind(user_id)
+        .execute(&mut **transaction)
+        .await?;
+
+    revoke_user_sessions(transaction, user_id, except_session_id).await?;
+
+    Ok(())
+}
+
+/// Checks the current passcode of a logged in user before a sensitive
+/// change.
+pub async fn check_user_passcode(
+    pool: &PgPool,
+    user_id: &Uuid,
+    passcode: &str,
+) -> Result<(), PasscodeError> {
+    let query = "SELECT passcode FROM userinfo WHERE user_id = $1";
+
+    let stored_passcode = sqlx::query_scalar::<_, String>(query)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await;
+
+    let stored_passcode = match stored_passcode {
+        Ok(Some(stored_passcode)) => stored_passcode,
+        Ok(None) => return Err(PasscodeError::WrongPasscode),
+        Err(error) => {
+            println!("{}", error);
+            return Err(PasscodeError::Failed);
+        }
+    };
+
+    match verify_passcode(passcode, &stored_passcode) {
+        true => Ok(()),
+        false => Err(PasscodeError::WrongPasscode),
+    }
+}
+
+/// Changes the passcode of a logged in user, their other sessions are
+/// logged out.
+pub async fn change_user_passcode(
+    pool: &PgPool,
+    user_id: &Uuid,
+    session_id: &Uuid,
+    passcode_change: &PasscodeChange,
+) -> Result<(), PasscodeError> {
+    check_user_passcode(pool, user_id, &passcode_change.current_passcode).await?;
+
+    if !is_valid_passcode(&passcode_change.new_passcode) {
+        return Err(PasscodeError::InvalidPasscode);
+    }
+
+    let changed = async {
+        let mut transaction = pool.begin().await?;
+
+        set_user_passcode(
+            &mut transaction,
+            user_id,
+            &passcode_change.new_passcode,
+            Some(session_id),
+        )
+        .await?;
+
+        transaction.commit().await
+    }
+    .await;
+
+    match changed {
+        Ok(_) => Ok(()),
+        Err(error) => {
+            println!(
+                "error while changing the passcode of {}: {}",
+                user_id, error
+            );
+            Err(PasscodeError::Failed)
+        }
     }
 }
@@ -1,9 +1,15 @@
 use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Deserializer};
 use std::fmt::Debug;
 use std::path::PathBuf;
 
 pub mod api;
 pub mod jwt_token;
+pub mod mailer;
+pub mod passcode;
+pub mod signed_url;
+pub mod totp;
+pub mod zip_stream;
 
 pub fn genarate_salt(salt_len: usize) -> String {
     rand::thread_rng()
@@ -15,8 +21,20 @@ pub fn genarate_salt(salt_len: usize) -> String {
 
 pub fn get_file_type(file_path: &str) -> String {
     let path = PathBuf::from(file_path);
-    let ext = path.extension().unwrap();
-    ext.to_str().unwrap().to_owned()
+    match path.extension() {
+        Some(ext) => ext.to_string_lossy().into_owned(),
+        None => "".to_owned(),
+    }
+}
+
+/// Lets an `Option<Option<T>>` field tell an explicit `null` (`Some(None)`)
+/// apart from a missing field (`None`), use together with `#[serde(default)]`.
+pub fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
 }
 
 pub fn get_vec_to_sql_str<T>(vec_data: &Vec<T>) -> String
@@ -4,7 +4,7 @@ use serde::{Deserialize, Serialize};
 use std::env::var;
 use uuid::Uuid;
 
-use crate::models::user_info::UserInfo;
+use crate::models::{api_key::ApiKeyAccess, user_info::UserInfo};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
@@ -15,15 +15,27 @@ pub struct Claims {
     pub id: Uuid,
     pub email: String,
     pub user_name: String,
+    // unique id of this token, for the revocation denylist
+    pub jti: Uuid,
+    // the login session the token was issued for
+    pub sid: Uuid,
+    // unverified users can only ask for a new verification mail
+    pub email_verified: bool,
+    // set when the request was made with an API key instead of a token
+    #[serde(skip)]
+    pub api_key: Option<ApiKeyAccess>,
 }
 
+// access tokens are short lived, clients renew them with a refresh token
+pub const ACCESS_TOKEN_MINUTES: i64 = 20;
+
 #[derive(Debug)]
 pub enum JwtError {
     InvalidToken,
     ExpiredToken,
 }
 
-pub fn generate_token(user_info: &UserInfo) -> String {
+pub fn generate_token(user_info: &UserInfo, session_id: &Uuid) -> String {
     let jwt_secret =
         var("JWT_SECRET").expect("Couldn't find JWT SECRET from environment variable.");
 
@@ -31,12 +43,16 @@ pub fn generate_token(user_info: &UserInfo) -> String {
 
     let claims = Claims {
         iat: current_time.timestamp() as u64,
-        exp: (current_time + Duration::minutes(20)).timestamp() as u64,
+        exp: (current_time + Duration::minutes(ACCESS_TOKEN_MINUTES)).timestamp() as u64,
         issuer: "home_file_server".to_owned(),
         issue: "home_file_server".to_owned(),
         id: user_info.user_id,
         email: user_info.email.to_owned(),
         user_name: user_info.user_name.to_owned(),
+        jti: Uuid::new_v4(),
+        sid: session_id.to_owned(),
+        email_verified: user_info.email_verified_date.is_some(),
+        api_key: None,
     };
 
     let token_str = encode(
@@ -48,12 +64,32 @@ pub fn generate_token(user_info: &UserInfo) -> String {
     token_str.unwrap()
 }
 
+/// Claims for a request made with an API key, these only live as long as
+/// the request and are never encoded.
+pub fn get_api_key_claims(user_info: &UserInfo, api_key: ApiKeyAccess) -> Claims {
+    let current_time = Utc::now().timestamp() as u64;
+
+    Claims {
+        iat: current_time,
+        exp: current_time,
+        issuer: "home_file_server".to_owned(),
+        issue: "home_file_server".to_owned(),
+        id: user_info.user_id,
+        email: user_info.email.to_owned(),
+        user_name: user_info.user_name.to_owned(),
+        jti: api_key.key_id,
+        sid: api_key.key_id,
+        email_verified: user_info.email_verified_date.is_some(),
+        api_key: Some(api_key),
+    }
+}
+
 fn extract_claims_from_token(token: &str) -> Result<Claims, JwtError> {
     let jwt_secret =
         var("JWT_SECRET").expect("Couldn't find JWT SECRET from environment variable.");
 
     let token_msg = decode::<Claims>(
-        &token,
+        token,
         &DecodingKey::from_secret(jwt_secret.as_ref()),
         &Validation::new(Algorithm::HS256),
     );
@@ -63,8 +99,6 @@ fn extract_claims_from_token(token: &str) -> Result<Claims, JwtError> {
             let claims = token.claims;
             let current_time = Utc::now().timestamp() as u64;
 
-            println!("claims: {:#?}", claims);
-
             if claims.iat > current_time {
                 return Err(JwtError::InvalidToken);
             }
@@ -87,8 +121,6 @@ fn extract_claims_from_token(token: &str) -> Result<Claims, JwtError> {
 }
 
 pub fn validate_token(token: &str) -> Result<Claims, JwtError> {
-    println!("jwt token : {token}");
-
     let claims = extract_claims_from_token(token);
 
     match claims {
@@ -99,7 +131,3 @@ pub fn validate_token(token: &str) -> Result<Claims, JwtError> {
         }
     }
 }
-
-pub fn _regenerate_token(_token: String) -> String {
-    "".to_owned()
-}
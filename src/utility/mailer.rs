@@ -0,0 +1,168 @@
+use actix_web::web;
+use chrono::Utc;
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, Message, SmtpTransport,
+    Transport,
+};
+use std::{fmt, fs, path::PathBuf, sync::Arc};
+use uuid::Uuid;
+
+/*
+  mails go out through a `Mailer`, chosen at start up with MAILER:
+  `smtp` sends them through an SMTP relay, `file` writes each mail into
+  MAIL_DIR and `log` (the default) prints them, the last two are meant
+  for local testing.
+*/
+
+#[derive(Debug)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Debug)]
+pub enum MailerError {
+    InvalidAddress,
+    Failed,
+}
+
+pub trait Mailer: fmt::Debug + Send + Sync {
+    fn send(&self, mail: &Mail) -> Result<(), MailerError>;
+}
+
+#[derive(Debug)]
+pub struct LogMailer;
+
+impl Mailer for LogMailer {
+    fn send(&self, mail: &Mail) -> Result<(), MailerError> {
+        println!(
+            "mail to: {}\nsubject: {}\n\n{}\n",
+            mail.to, mail.subject, mail.body
+        );
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct FileMailer {
+    pub mail_dir: PathBuf,
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, mail: &Mail) -> Result<(), MailerError> {
+        let _ = fs::create_dir_all(&self.mail_dir);
+
+        let mail_path = self.mail_dir.join(format!(
+            "{}-{}.eml",
+            Utc::now().format("%Y%m%d%H%M%S"),
+            Uuid::new_v4()
+        ));
+        let mail_content = format!(
+            "To: {}\r\nSubject: {}\r\n\r\n{}\r\n",
+            mail.to, mail.subject, mail.body
+        );
+
+        match fs::write(&mail_path, mail_content) {
+            Ok(_) => Ok(()),
+            Err(error) => {
+                println!(
+                    "error while writing mail to {}: {}",
+                    mail_path.display(),
+                    error
+                );
+                Err(MailerError::Failed)
+            }
+        }
+    }
+}
+
+pub struct SmtpMailer {
+    transport: SmtpTransport,
+    from: Mailbox,
+}
+
+impl fmt::Debug for SmtpMailer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SmtpMailer")
+            .field("from", &self.from)
+            .finish()
+    }
+}
+
+impl SmtpMailer {
+    /// `tls` is one of `starttls`, `tls` or `none`.
+    pub fn new(
+        host: &str,
+        port: u16,
+        tls: &str,
+        credentials: Option<(String, String)>,
+        from: &str,
+    ) -> Self {
+        let transport = match tls {
+            "starttls" => SmtpTransport::starttls_relay(host).expect("Invalid SMTP_HOST."),
+            "tls" => SmtpTransport::relay(host).expect("Invalid SMTP_HOST."),
+            "none" => SmtpTransport::builder_dangerous(host),
+            _ => panic!("SMTP_TLS should be one of starttls, tls or none."),
+        }
+        .port(port);
+
+        let transport = match credentials {
+            Some((username, password)) => {
+                transport.credentials(Credentials::new(username, password))
+            }
+            None => transport,
+        };
+
+        SmtpMailer {
+            transport: transport.build(),
+            from: from
+                .parse()
+                .expect("MAIL_FROM should be a valid mail address."),
+        }
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, mail: &Mail) -> Result<(), MailerError> {
+        let to = match mail.to.parse::<Mailbox>() {
+            Ok(to) => to,
+            Err(_) => return Err(MailerError::InvalidAddress),
+        };
+
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(&mail.subject)
+            .body(mail.body.to_owned());
+
+        let message = match message {
+            Ok(message) => message,
+            Err(error) => {
+                println!("error while building mail to {}: {}", mail.to, error);
+                return Err(MailerError::Failed);
+            }
+        };
+
+        match self.transport.send(&message) {
+            Ok(_) => Ok(()),
+            Err(error) => {
+                println!("error while sending mail to {}: {}", mail.to, error);
+                Err(MailerError::Failed)
+            }
+        }
+    }
+}
+
+/// Sends a mail off the async workers, SMTP can block for a while.
+pub async fn send_mail(mailer: &Arc<dyn Mailer>, mail: Mail) -> Result<(), MailerError> {
+    let mailer = mailer.clone();
+
+    match web::block(move || mailer.send(&mail)).await {
+        Ok(sent) => sent,
+        Err(error) => {
+            println!("error while sending mail: {}", error);
+            Err(MailerError::Failed)
+        }
+    }
+}
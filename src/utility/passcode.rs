@@ -0,0 +1,117 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use sha2::{Digest, Sha256};
+use std::env::var;
+use subtle::ConstantTimeEq;
+
+/*
+  passcodes are hashed with Argon2id and stored in PHC string format,
+  `$argon2id$v=19$m=...,t=...,p=...$salt$hash`, so the cost can be raised
+  later without breaking the hashes already stored. Older passcodes are
+  stored as `SHA256(passcode + salt)` in hex followed by `:salt`, these
+  still verify and are rehashed on the next login.
+*/
+
+fn get_argon2_cost(name: &str, default_cost: u32) -> u32 {
+    match var(name) {
+        Ok(cost) => cost
+            .parse::<u32>()
+            .unwrap_or_else(|_| panic!("{} should be a positive number.", name)),
+        Err(_) => default_cost,
+    }
+}
+
+// defaults are the OWASP recommendation for Argon2id, 19 MiB and 2 passes
+fn get_argon2_params() -> Params {
+    Params::new(
+        get_argon2_cost("ARGON2_MEMORY_KIB", 19 * 1024),
+        get_argon2_cost("ARGON2_ITERATIONS", 2),
+        get_argon2_cost("ARGON2_PARALLELISM", 1),
+        None,
+    )
+    .expect(
+        "ARGON2_MEMORY_KIB, ARGON2_ITERATIONS and ARGON2_PARALLELISM should be valid Argon2 costs.",
+    )
+}
+
+fn get_argon2() -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, get_argon2_params())
+}
+
+pub fn hash_passcode(passcode: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+
+    get_argon2()
+        .hash_password(passcode.as_bytes(), &salt)
+        .expect("Failed to hash the passcode.")
+        .to_string()
+}
+
+fn verify_legacy_passcode(passcode: &str, stored_passcode: &str) -> bool {
+    let (passcode_hash, passcode_salt) = match stored_passcode.split_once(':') {
+        Some(passcode_parts) => passcode_parts,
+        None => return false,
+    };
+
+    let mut sha = Sha256::new();
+    sha.update(passcode.to_owned() + passcode_salt);
+    let user_passcode_hash = format!("{:X}", sha.finalize());
+
+    passcode_hash
+        .as_bytes()
+        .ct_eq(user_passcode_hash.as_bytes())
+        .into()
+}
+
+/// Checks a passcode against its stored hash in constant time, both
+/// Argon2 and legacy SHA-256 hashes are accepted.
+pub fn verify_passcode(passcode: &str, stored_passcode: &str) -> bool {
+    if !stored_passcode.starts_with("$argon2") {
+        return verify_legacy_passcode(passcode, stored_passcode);
+    }
+
+    match PasswordHash::new(stored_passcode) {
+        // the costs are taken from the stored hash
+        Ok(passcode_hash) => get_argon2()
+            .verify_password(passcode.as_bytes(), &passcode_hash)
+            .is_ok(),
+        Err(error) => {
+            println!("error while parsing a stored passcode hash: {}", error);
+            false
+        }
+    }
+}
+
+/// Whether a stored hash is a legacy one, or was made with other costs
+/// than the configured ones.
+pub fn passcode_needs_rehash(stored_passcode: &str) -> bool {
+    let passcode_hash = match PasswordHash::new(stored_passcode) {
+        Ok(passcode_hash) => passcode_hash,
+        Err(_) => return true,
+    };
+
+    if passcode_hash.algorithm != Algorithm::Argon2id.ident() {
+        return true;
+    }
+
+    let current_params = get_argon2_params();
+    match Params::try_from(&passcode_hash) {
+        Ok(params) => {
+            params.m_cost() != current_params.m_cost()
+                || params.t_cost() != current_params.t_cost()
+                || params.p_cost() != current_params.p_cost()
+        }
+        Err(_) => true,
+    }
+}
+
+/// Hashes a random, high entropy token like a refresh token, these need no
+/// salt and can be looked up by their hash.
+pub fn hash_token(token: &str) -> String {
+    let mut sha = Sha256::new();
+    sha.update(token);
+
+    format!("{:X}", sha.finalize())
+}
@@ -0,0 +1,214 @@
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::env::var;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The query string every pre-signed URL carries.
+#[derive(Debug, Deserialize)]
+pub struct SignedUrlQuery {
+    pub expires: i64,
+    pub user_id: Uuid,
+    pub signature: String,
+}
+
+/// The user a verified pre-signed request acts as.
+#[derive(Debug, Clone)]
+pub struct SignedUrlUser {
+    pub id: Uuid,
+}
+
+#[derive(Debug)]
+pub enum SignedUrlError {
+    InvalidSignature,
+    Expired,
+}
+
+fn get_signing_key() -> HmacSha256 {
+    let signing_secret = var("URL_SIGNING_SECRET")
+        .or_else(|_| var("JWT_SECRET"))
+        .expect("Couldn't find URL_SIGNING_SECRET or JWT_SECRET from environment variable.");
+
+    HmacSha256::new_from_slice(signing_secret.as_bytes()).expect("HMAC accepts keys of any size")
+}
+
+fn get_signed_message(
+    method: &str,
+    path: &str,
+    expires: i64,
+    user_id: &Uuid,
+    generation: i32,
+) -> HmacSha256 {
+    let mut mac = get_signing_key();
+    mac.update(
+        format!(
+            "{}\n{}\n{}\n{}\n{}",
+            method, path, expires, user_id, generation
+        )
+        .as_bytes(),
+    );
+
+    mac
+}
+
+/// Signs `method` and `path` for `user_id` until the unix time `expires`,
+/// returning the path with its query string. `generation` is the user's
+/// current URL signing generation, bumping it invalidates the URL.
+pub fn sign_url(method: &str, path: &str, expires: i64, user_id: &Uuid, generation: i32) -> String {
+    let signature = get_signed_message(method, path, expires, user_id, generation)
+        .finalize()
+        .into_bytes();
+
+    format!(
+        "{}?expires={}&user_id={}&signature={}",
+        path,
+        expires,
+        user_id,
+        hex::encode(signature)
+    )
+}
+
+/// Checks the signature of a pre-signed request in constant time against
+/// the current URL signing `generation` of the user it was signed for.
+pub fn verify_signed_url(
+    method: &str,
+    path: &str,
+    signed_url: &SignedUrlQuery,
+    generation: i32,
+) -> Result<Uuid, SignedUrlError> {
+    let signature = match hex::decode(&signed_url.signature) {
+        Ok(signature) => signature,
+        Err(_) => return Err(SignedUrlError::InvalidSignature),
+    };
+
+    let mac = get_signed_message(
+        method,
+        path,
+        signed_url.expires,
+        &signed_url.user_id,
+        generation,
+    );
+    if mac.verify_slice(&signature).is_err() {
+        return Err(SignedUrlError::InvalidSignature);
+    }
+
+    if signed_url.expires <= Utc::now().timestamp() {
+        return Err(SignedUrlError::Expired);
+    }
+
+    Ok(signed_url.user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATH: &str = "/signed/file/5d6e1a8f-4a4e-4bbd-9d1f-3f6cbe0f1d0a";
+
+    fn set_signing_secret() {
+        std::env::set_var("URL_SIGNING_SECRET", "test signing secret");
+    }
+
+    fn parse_signed_url(url: &str) -> SignedUrlQuery {
+        let query = url.split_once('?').expect("a signed URL has a query").1;
+        actix_web::web::Query::<SignedUrlQuery>::from_query(query)
+            .expect("a signed URL has a valid query")
+            .into_inner()
+    }
+
+    fn sign_for_an_hour(method: &str, user_id: &Uuid) -> SignedUrlQuery {
+        let expires = Utc::now().timestamp() + 60 * 60;
+        parse_signed_url(&sign_url(method, PATH, expires, user_id, 0))
+    }
+
+    #[test]
+    fn accepts_an_untouched_url() {
+        set_signing_secret();
+        let user_id = Uuid::new_v4();
+        let signed_url = sign_for_an_hour("GET", &user_id);
+
+        assert_eq!(
+            verify_signed_url("GET", PATH, &signed_url, 0).ok(),
+            Some(user_id)
+        );
+    }
+
+    #[test]
+    fn rejects_another_method_or_path() {
+        set_signing_secret();
+        let signed_url = sign_for_an_hour("GET", &Uuid::new_v4());
+
+        assert!(matches!(
+            verify_signed_url("PUT", PATH, &signed_url, 0),
+            Err(SignedUrlError::InvalidSignature)
+        ));
+        assert!(matches!(
+            verify_signed_url("GET", "/signed/file/other", &signed_url, 0),
+            Err(SignedUrlError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn rejects_tampered_queries() {
+        set_signing_secret();
+        let signed_url = sign_for_an_hour("GET", &Uuid::new_v4());
+
+        let longer = SignedUrlQuery {
+            expires: signed_url.expires + 1,
+            user_id: signed_url.user_id,
+            signature: signed_url.signature.clone(),
+        };
+        let other_user = SignedUrlQuery {
+            expires: signed_url.expires,
+            user_id: Uuid::new_v4(),
+            signature: signed_url.signature.clone(),
+        };
+        let mut signature = signed_url.signature.clone();
+        let last = if signature.ends_with('0') { "1" } else { "0" };
+        signature.replace_range(signature.len() - 1.., last);
+        let other_signature = SignedUrlQuery {
+            expires: signed_url.expires,
+            user_id: signed_url.user_id,
+            signature,
+        };
+        let not_hex = SignedUrlQuery {
+            expires: signed_url.expires,
+            user_id: signed_url.user_id,
+            signature: "not hex".to_owned(),
+        };
+
+        for tampered in [longer, other_user, other_signature, not_hex] {
+            assert!(matches!(
+                verify_signed_url("GET", PATH, &tampered, 0),
+                Err(SignedUrlError::InvalidSignature)
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_urls_of_an_older_generation() {
+        set_signing_secret();
+        let signed_url = sign_for_an_hour("GET", &Uuid::new_v4());
+
+        assert!(matches!(
+            verify_signed_url("GET", PATH, &signed_url, 1),
+            Err(SignedUrlError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn rejects_expired_urls() {
+        set_signing_secret();
+        let user_id = Uuid::new_v4();
+        let expires = Utc::now().timestamp() - 1;
+        let signed_url = parse_signed_url(&sign_url("GET", PATH, expires, &user_id, 0));
+
+        assert!(matches!(
+            verify_signed_url("GET", PATH, &signed_url, 0),
+            Err(SignedUrlError::Expired)
+        ));
+    }
+}
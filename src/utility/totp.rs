@@ -0,0 +1,157 @@
+use chrono::Utc;
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rand::RngCore;
+use sha1::Sha1;
+use subtle::ConstantTimeEq;
+
+/*
+  time based one time passwords as in RFC 6238, with the defaults every
+  authenticator app understands: HMAC-SHA1, 6 digits and 30 second steps.
+  Codes from the step before and after are accepted as well, for clocks
+  that are slightly off.
+*/
+
+type HmacSha1 = Hmac<Sha1>;
+
+const TOTP_STEP_SECONDS: i64 = 30;
+const TOTP_DIGITS: u32 = 6;
+
+// 160 bits, the key size RFC 4226 recommends
+const TOTP_SECRET_LEN: usize = 20;
+
+/// A new random secret, base32 encoded the way authenticator apps expect.
+pub fn generate_totp_secret() -> String {
+    let mut secret = [0u8; TOTP_SECRET_LEN];
+    rand::thread_rng().fill_bytes(&mut secret);
+
+    BASE32_NOPAD.encode(&secret)
+}
+
+pub fn get_totp_uri(issuer: &str, account_name: &str, secret: &str) -> String {
+    let issuer = utf8_percent_encode(issuer, NON_ALPHANUMERIC).to_string();
+    let account_name = utf8_percent_encode(account_name, NON_ALPHANUMERIC).to_string();
+
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        issuer, account_name, secret, issuer, TOTP_DIGITS, TOTP_STEP_SECONDS
+    )
+}
+
+fn get_totp_code(secret: &[u8], step: i64) -> String {
+    let mut mac = HmacSha1::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // dynamic truncation, RFC 4226 section 5.3
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+
+    format!(
+        "{:0width$}",
+        binary % 10u32.pow(TOTP_DIGITS),
+        width = TOTP_DIGITS as usize
+    )
+}
+
+/// Checks a code against the current time and returns the step it was
+/// made for, so the caller can refuse a code that was already used.
+pub fn verify_totp_code(secret: &str, code: &str) -> Option<i64> {
+    verify_totp_code_at(secret, code, Utc::now().timestamp())
+}
+
+fn verify_totp_code_at(secret: &str, code: &str, timestamp: i64) -> Option<i64> {
+    let secret = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let current_step = timestamp / TOTP_STEP_SECONDS;
+
+    (current_step - 1..=current_step + 1).find(|&step| {
+        get_totp_code(&secret, step)
+            .as_bytes()
+            .ct_eq(code.trim().as_bytes())
+            .into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the ASCII key of the SHA1 test vectors in RFC 6238 appendix B
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    fn rfc_secret_base32() -> String {
+        BASE32_NOPAD.encode(RFC_SECRET)
+    }
+
+    #[test]
+    fn matches_rfc_6238_sha1_vectors() {
+        // the RFC lists 8 digit codes, 6 digit codes are their last 6 digits
+        let vectors = [
+            (59, "94287082"),
+            (1111111109, "07081804"),
+            (1111111111, "14050471"),
+            (1234567890, "89005924"),
+            (2000000000, "69279037"),
+            (20000000000, "65353130"),
+        ];
+
+        for (timestamp, code) in vectors {
+            assert_eq!(
+                get_totp_code(RFC_SECRET, timestamp / TOTP_STEP_SECONDS),
+                code[2..],
+                "code at {}",
+                timestamp
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_codes_of_neighbouring_steps() {
+        let secret = rfc_secret_base32();
+        let step = 1234567890 / TOTP_STEP_SECONDS;
+
+        for offset in -1..=1 {
+            let code = get_totp_code(RFC_SECRET, step + offset);
+            assert_eq!(
+                verify_totp_code_at(&secret, &code, 1234567890),
+                Some(step + offset)
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_codes_outside_the_window() {
+        let secret = rfc_secret_base32();
+        let step = 1234567890 / TOTP_STEP_SECONDS;
+
+        for offset in [-3, -2, 2, 3] {
+            let code = get_totp_code(RFC_SECRET, step + offset);
+            assert_eq!(verify_totp_code_at(&secret, &code, 1234567890), None);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_codes_and_secrets() {
+        let secret = rfc_secret_base32();
+
+        assert_eq!(verify_totp_code_at(&secret, "", 1234567890), None);
+        assert_eq!(verify_totp_code_at(&secret, "5924", 1234567890), None);
+        assert_eq!(
+            verify_totp_code_at("not base32!", "005924", 1234567890),
+            None
+        );
+    }
+
+    #[test]
+    fn ignores_surrounding_whitespace() {
+        let secret = rfc_secret_base32();
+
+        assert!(verify_totp_code_at(&secret, " 005924 ", 1234567890).is_some());
+    }
+}
@@ -0,0 +1,448 @@
+use actix_web::web::Bytes;
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use crc32fast::Hasher;
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, Read},
+    path::PathBuf,
+};
+
+/*
+  writes a zip while it is sent, without a temporary file. Entries are
+  stored as is, so their sizes are known when they start and only the
+  CRC has to follow the data in a data descriptor. ZIP64 records are
+  added for entries, offsets and archives too large for the classic
+  format.
+*/
+
+const LOCAL_FILE_HEADER_SIGNATURE: u32 = 0x04034b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x08074b50;
+const CENTRAL_DIRECTORY_HEADER_SIGNATURE: u32 = 0x02014b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+
+const ZIP64_EXTRA_FIELD_ID: u16 = 0x0001;
+
+// sizes and CRC follow the data, the name is UTF-8
+const GENERAL_PURPOSE_FLAGS: u16 = 0x0008 | 0x0800;
+const COMPRESSION_STORED: u16 = 0;
+
+const VERSION_DEFAULT: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+
+const MAX_U16: u64 = u16::MAX as u64;
+const MAX_U32: u64 = u32::MAX as u64;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub struct ZipEntry {
+    pub name: String,
+    pub path: PathBuf,
+    pub modified_date: NaiveDateTime,
+}
+
+struct CentralDirectoryEntry {
+    name: String,
+    dos_time: u16,
+    dos_date: u16,
+    crc: u32,
+    size: u64,
+    offset: u64,
+    is_zip64: bool,
+}
+
+struct CurrentEntry {
+    file: File,
+    hasher: Hasher,
+    written: u64,
+    central_directory_entry: CentralDirectoryEntry,
+}
+
+/// The chunks of a zip of `entries`, read from disk as they are asked for.
+pub struct ZipStream {
+    entries: VecDeque<ZipEntry>,
+    current_entry: Option<CurrentEntry>,
+    central_directory: Vec<CentralDirectoryEntry>,
+    offset: u64,
+    is_finished: bool,
+}
+
+fn get_dos_date_time(date: &NaiveDateTime) -> (u16, u16) {
+    if date.year() < 1980 {
+        // the earliest date a zip can hold, 1980-01-01
+        return (0, (1 << 5) | 1);
+    }
+
+    let dos_time = (date.hour() << 11) | (date.minute() << 5) | (date.second() / 2);
+    let dos_date = ((date.year() as u32 - 1980).min(127) << 9) | (date.month() << 5) | date.day();
+
+    (dos_time as u16, dos_date as u16)
+}
+
+fn put_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buffer: &mut Vec<u8>, value: u64) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+// a value the classic field can't hold is moved to the ZIP64 extra field
+fn clamp_u32(value: u64) -> u32 {
+    value.min(MAX_U32) as u32
+}
+
+impl ZipStream {
+    pub fn new(entries: Vec<ZipEntry>) -> Self {
+        ZipStream {
+            entries: entries.into(),
+            current_entry: None,
+            central_directory: Vec::new(),
+            offset: 0,
+            is_finished: false,
+        }
+    }
+
+    /// Opens the next entry that can be read and writes its local header,
+    /// entries whose file can't be opened are left out.
+    fn start_entry(&mut self) -> Option<Vec<u8>> {
+        while let Some(entry) = self.entries.pop_front() {
+            let (file, size) =
+                match File::open(&entry.path).and_then(|file| Ok((file.metadata()?.len(), file))) {
+                    Ok((size, file)) => (file, size),
+                    Err(error) => {
+                        println!(
+                            "skipping {} in a zip, it can't be read: {}",
+                            entry.path.display(),
+                            error
+                        );
+                        continue;
+                    }
+                };
+
+            let (dos_time, dos_date) = get_dos_date_time(&entry.modified_date);
+            let is_zip64 = size >= MAX_U32;
+
+            let mut header = Vec::with_capacity(30 + entry.name.len() + 20);
+            put_u32(&mut header, LOCAL_FILE_HEADER_SIGNATURE);
+            put_u16(
+                &mut header,
+                if is_zip64 {
+                    VERSION_ZIP64
+                } else {
+                    VERSION_DEFAULT
+                },
+            );
+            put_u16(&mut header, GENERAL_PURPOSE_FLAGS);
+            put_u16(&mut header, COMPRESSION_STORED);
+            put_u16(&mut header, dos_time);
+            put_u16(&mut header, dos_date);
+            // CRC and sizes are in the data descriptor
+            put_u32(&mut header, 0);
+            put_u32(&mut header, if is_zip64 { u32::MAX } else { 0 });
+            put_u32(&mut header, if is_zip64 { u32::MAX } else { 0 });
+            put_u16(&mut header, entry.name.len() as u16);
+            put_u16(&mut header, if is_zip64 { 20 } else { 0 });
+            header.extend_from_slice(entry.name.as_bytes());
+            if is_zip64 {
+                put_u16(&mut header, ZIP64_EXTRA_FIELD_ID);
+                put_u16(&mut header, 16);
+                put_u64(&mut header, 0);
+                put_u64(&mut header, 0);
+            }
+
+            self.current_entry = Some(CurrentEntry {
+                file,
+                hasher: Hasher::new(),
+                written: 0,
+                central_directory_entry: CentralDirectoryEntry {
+                    name: entry.name,
+                    dos_time,
+                    dos_date,
+                    crc: 0,
+                    size,
+                    offset: self.offset,
+                    is_zip64,
+                },
+            });
+
+            return Some(header);
+        }
+
+        None
+    }
+
+    /// Reads the next chunk of the current entry, or finishes it with its
+    /// data descriptor once all of it was read.
+    fn continue_entry(&mut self) -> io::Result<Vec<u8>> {
+        let current_entry = match &mut self.current_entry {
+            Some(current_entry) => current_entry,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let read = current_entry.file.read(&mut chunk)?;
+
+        if read > 0 {
+            chunk.truncate(read);
+            current_entry.hasher.update(&chunk);
+            current_entry.written += read as u64;
+            return Ok(chunk);
+        }
+
+        let CurrentEntry {
+            hasher,
+            written,
+            mut central_directory_entry,
+            ..
+        } = self
+            .current_entry
+            .take()
+            .expect("the current entry was just read");
+
+        if written != central_directory_entry.size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "{} changed while it was zipped",
+                    central_directory_entry.name
+                ),
+            ));
+        }
+
+        central_directory_entry.crc = hasher.finalize();
+
+        let mut descriptor = Vec::with_capacity(24);
+        put_u32(&mut descriptor, DATA_DESCRIPTOR_SIGNATURE);
+        put_u32(&mut descriptor, central_directory_entry.crc);
+        if central_directory_entry.is_zip64 {
+            put_u64(&mut descriptor, written);
+            put_u64(&mut descriptor, written);
+        } else {
+            put_u32(&mut descriptor, written as u32);
+            put_u32(&mut descriptor, written as u32);
+        }
+
+        self.central_directory.push(central_directory_entry);
+
+        Ok(descriptor)
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        let central_directory_offset = self.offset;
+        let mut buffer = Vec::new();
+
+        for entry in &self.central_directory {
+            let is_large_offset = entry.offset >= MAX_U32;
+
+            let mut extra_field = Vec::new();
+            if entry.is_zip64 {
+                put_u64(&mut extra_field, entry.size);
+                put_u64(&mut extra_field, entry.size);
+            }
+            if is_large_offset {
+                put_u64(&mut extra_field, entry.offset);
+            }
+
+            let version = if entry.is_zip64 || is_large_offset {
+                VERSION_ZIP64
+            } else {
+                VERSION_DEFAULT
+            };
+
+            put_u32(&mut buffer, CENTRAL_DIRECTORY_HEADER_SIGNATURE);
+            put_u16(&mut buffer, version);
+            put_u16(&mut buffer, version);
+            put_u16(&mut buffer, GENERAL_PURPOSE_FLAGS);
+            put_u16(&mut buffer, COMPRESSION_STORED);
+            put_u16(&mut buffer, entry.dos_time);
+            put_u16(&mut buffer, entry.dos_date);
+            put_u32(&mut buffer, entry.crc);
+            put_u32(&mut buffer, clamp_u32(entry.size));
+            put_u32(&mut buffer, clamp_u32(entry.size));
+            put_u16(&mut buffer, entry.name.len() as u16);
+            put_u16(
+                &mut buffer,
+                match extra_field.is_empty() {
+                    true => 0,
+                    false => extra_field.len() as u16 + 4,
+                },
+            );
+            // comment length, disk number, internal and external attributes
+            put_u16(&mut buffer, 0);
+            put_u16(&mut buffer, 0);
+            put_u16(&mut buffer, 0);
+            put_u32(&mut buffer, 0);
+            put_u32(&mut buffer, clamp_u32(entry.offset));
+            buffer.extend_from_slice(entry.name.as_bytes());
+            if !extra_field.is_empty() {
+                put_u16(&mut buffer, ZIP64_EXTRA_FIELD_ID);
+                put_u16(&mut buffer, extra_field.len() as u16);
+                buffer.extend_from_slice(&extra_field);
+            }
+        }
+
+        let central_directory_size = buffer.len() as u64;
+        let entry_count = self.central_directory.len() as u64;
+
+        if entry_count >= MAX_U16
+            || central_directory_size >= MAX_U32
+            || central_directory_offset >= MAX_U32
+        {
+            let zip64_end_offset = central_directory_offset + central_directory_size;
+
+            put_u32(&mut buffer, ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+            // size of the rest of the record
+            put_u64(&mut buffer, 44);
+            put_u16(&mut buffer, VERSION_ZIP64);
+            put_u16(&mut buffer, VERSION_ZIP64);
+            put_u32(&mut buffer, 0);
+            put_u32(&mut buffer, 0);
+            put_u64(&mut buffer, entry_count);
+            put_u64(&mut buffer, entry_count);
+            put_u64(&mut buffer, central_directory_size);
+            put_u64(&mut buffer, central_directory_offset);
+
+            put_u32(
+                &mut buffer,
+                ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE,
+            );
+            put_u32(&mut buffer, 0);
+            put_u64(&mut buffer, zip64_end_offset);
+            put_u32(&mut buffer, 1);
+        }
+
+        put_u32(&mut buffer, END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        put_u16(&mut buffer, 0);
+        put_u16(&mut buffer, 0);
+        put_u16(&mut buffer, entry_count.min(MAX_U16) as u16);
+        put_u16(&mut buffer, entry_count.min(MAX_U16) as u16);
+        put_u32(&mut buffer, clamp_u32(central_directory_size));
+        put_u32(&mut buffer, clamp_u32(central_directory_offset));
+        // comment length
+        put_u16(&mut buffer, 0);
+
+        self.is_finished = true;
+
+        buffer
+    }
+}
+
+impl Iterator for ZipStream {
+    type Item = io::Result<Bytes>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.is_finished {
+            return None;
+        }
+
+        let chunk = if self.current_entry.is_some() {
+            match self.continue_entry() {
+                Ok(chunk) => chunk,
+                Err(error) => {
+                    self.is_finished = true;
+                    return Some(Err(error));
+                }
+            }
+        } else {
+            match self.start_entry() {
+                Some(header) => header,
+                None => self.finish(),
+            }
+        };
+
+        self.offset += chunk.len() as u64;
+
+        Some(Ok(Bytes::from(chunk)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, io::Cursor};
+    use zip::ZipArchive;
+
+    fn write_test_file(name: &str, content: &[u8]) -> PathBuf {
+        let mut path = env::temp_dir();
+        path.push(format!("zip-stream-{}-{}", std::process::id(), name));
+        fs::write(&path, content).expect("the test file can be written");
+        path
+    }
+
+    fn collect_zip(entries: Vec<ZipEntry>) -> Vec<u8> {
+        ZipStream::new(entries)
+            .collect::<io::Result<Vec<Bytes>>>()
+            .expect("the zip can be streamed")
+            .concat()
+    }
+
+    fn entry(name: &str, path: &PathBuf) -> ZipEntry {
+        ZipEntry {
+            name: name.to_owned(),
+            path: path.to_owned(),
+            modified_date: NaiveDateTime::from_timestamp_opt(1_700_000_000, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn streams_a_readable_zip() {
+        let first = write_test_file("first", b"hello zip");
+        let second = write_test_file("second", &vec![7u8; CHUNK_SIZE * 2 + 5]);
+
+        let zip = collect_zip(vec![
+            entry("first.txt", &first),
+            entry("photos/second.bin", &second),
+        ]);
+
+        let mut archive = ZipArchive::new(Cursor::new(zip)).expect("the zip can be opened");
+        assert_eq!(archive.len(), 2);
+
+        let mut content = Vec::new();
+        archive
+            .by_name("first.txt")
+            .expect("the first entry exists")
+            .read_to_end(&mut content)
+            .expect("the first entry can be read");
+        assert_eq!(content, b"hello zip");
+
+        let mut content = Vec::new();
+        archive
+            .by_name("photos/second.bin")
+            .expect("the second entry exists")
+            .read_to_end(&mut content)
+            .expect("the second entry can be read, its CRC matches");
+        assert_eq!(content, vec![7u8; CHUNK_SIZE * 2 + 5]);
+
+        let _ = fs::remove_file(first);
+        let _ = fs::remove_file(second);
+    }
+
+    #[test]
+    fn leaves_out_missing_files() {
+        let present = write_test_file("present", b"still here");
+        let missing = env::temp_dir().join("zip-stream-missing-file");
+
+        let zip = collect_zip(vec![entry("missing", &missing), entry("present", &present)]);
+
+        let mut archive = ZipArchive::new(Cursor::new(zip)).expect("the zip can be opened");
+        assert_eq!(archive.len(), 1);
+        assert!(archive.by_name("present").is_ok());
+
+        let _ = fs::remove_file(present);
+    }
+
+    #[test]
+    fn streams_an_empty_zip() {
+        let zip = collect_zip(Vec::new());
+
+        let archive = ZipArchive::new(Cursor::new(zip)).expect("the zip can be opened");
+        assert_eq!(archive.len(), 0);
+    }
+}